@@ -0,0 +1,1049 @@
+use crate::media_tap::AudioStreamConfig;
+use crate::mitm::protos::{
+    Ack, AudioStreamType, Config as AudioConfig, MediaCodecType, ServiceDiscoveryResponse,
+};
+use crate::mitm::Packet;
+use crate::pcm_sink::{s16le_bytes, AckWindow, SinkConfig, SinkSession};
+use crate::resampler::Resampler;
+use crate::web::ServerEvent;
+use serde::{Deserialize, Serialize};
+use simplelog::*;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::broadcast::Sender as BroadcastSender;
+use tokio::sync::mpsc::Sender;
+use tokio::time::{timeout, Instant};
+use tokio_util::sync::CancellationToken;
+
+const NAME: &str = "<i><bright-black> audio-inject: </>";
+const INJECT_SESSION_ID: i32 = 0x494e_4a01; // "INJ\x01"
+/// Duration of audio carried by a single injected DATA packet.
+const CHUNK_MS: u64 = 20;
+/// How far ahead of real time DATA packets are sent.
+const SEND_LEAD: Duration = Duration::from_millis(60);
+/// Give up waiting for HU ACKs after this long and keep sending.
+const ACK_WAIT_TIMEOUT: Duration = Duration::from_millis(500);
+/// Upper bound for WAV files loaded into memory.
+const MAX_WAV_FILE_BYTES: u64 = 32 * 1024 * 1024;
+/// How long a socket source waits for its raw PCM client.
+const ACCEPT_TIMEOUT: Duration = Duration::from_secs(60);
+/// Accepted source sample rates; buffers are sized by the rate.
+const PCM_SAMPLE_RATES: std::ops::RangeInclusive<u32> = 8_000..=192_000;
+const PCM_MAX_CHANNELS: u32 = 8;
+
+/// ws topic accepting injection requests (JSON `InjectRequest`) or `stop`.
+pub const AUDIO_INJECT_TOPIC: &str = "audio-inject";
+/// ws topic on which injection progress is reported.
+pub const AUDIO_INJECT_STATUS_TOPIC: &str = "audio-inject-status";
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum InjectTarget {
+    #[default]
+    Guidance,
+    System,
+    Media,
+}
+
+impl InjectTarget {
+    fn from_audio_type(audio_type: AudioStreamType) -> Option<Self> {
+        match audio_type {
+            AudioStreamType::AUDIO_STREAM_GUIDANCE => Some(Self::Guidance),
+            AudioStreamType::AUDIO_STREAM_SYSTEM_AUDIO => Some(Self::System),
+            AudioStreamType::AUDIO_STREAM_MEDIA => Some(Self::Media),
+            _ => None,
+        }
+    }
+}
+
+fn default_raw_sample_rate() -> u32 {
+    16_000
+}
+
+fn default_raw_channels() -> u32 {
+    1
+}
+
+fn default_gain_percent() -> u32 {
+    100
+}
+
+/// PCM input shared by the injection services.
+///
+/// Exactly one of `file`, `tcp` or `unix` selects the source. Raw PCM
+/// streams are signed 16-bit little-endian in the given rate/channel layout;
+/// WAV files carry their own format. `file` and `unix` paths are resolved
+/// inside `audio_inject_dir`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct PcmSource {
+    /// WAV file to play.
+    pub file: Option<PathBuf>,
+    /// Loopback TCP address to listen on for a single raw PCM stream, e.g.
+    /// `127.0.0.1:5300`.
+    pub tcp: Option<String>,
+    /// Unix socket path to listen on for a single raw PCM stream.
+    pub unix: Option<PathBuf>,
+    #[serde(default = "default_raw_sample_rate")]
+    pub sample_rate: u32,
+    #[serde(default = "default_raw_channels")]
+    pub channels: u32,
+}
+
+/// Injection request accepted from REST, ws and WASM scripts.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct InjectRequest {
+    #[serde(default)]
+    pub target: InjectTarget,
+    #[serde(flatten)]
+    pub source: PcmSource,
+    #[serde(default = "default_gain_percent")]
+    pub gain_percent: u32,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct SinkInfo {
+    channel: u8,
+    config: AudioStreamConfig,
+}
+
+struct ActiveInjection {
+    id: u64,
+    target: InjectTarget,
+    channel: u8,
+    source: String,
+    cancel: CancellationToken,
+    /// Set when the phone started its own session on the sink; our STOP
+    /// would then end the phone's session instead of ours.
+    preempted: Arc<AtomicBool>,
+}
+
+#[derive(Default)]
+struct State {
+    /// Directory `file` and `unix` sources must live in.
+    inject_dir: Option<PathBuf>,
+    tx: Option<Sender<Packet>>,
+    ws_event_tx: Option<BroadcastSender<ServerEvent>>,
+    sinks: HashMap<InjectTarget, SinkInfo>,
+    sink_config: HashMap<u8, SinkConfig>,
+    phone_active: HashSet<u8>,
+    active: Option<ActiveInjection>,
+}
+
+struct Runtime {
+    state: Mutex<State>,
+    next_id: AtomicU64,
+    /// DATA packets of our own session which the HU has not acknowledged yet.
+    acks: AckWindow,
+}
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+fn runtime() -> &'static Runtime {
+    RUNTIME.get_or_init(|| Runtime {
+        state: Mutex::new(State::default()),
+        next_id: AtomicU64::new(1),
+        acks: AckWindow::new(),
+    })
+}
+
+/// Set the directory injection sources (WAV files, unix sockets) are
+/// confined to.
+pub fn set_inject_dir(dir: PathBuf) {
+    runtime().state.lock().unwrap().inject_dir = Some(dir);
+}
+
+fn inject_dir() -> Result<PathBuf> {
+    runtime()
+        .state
+        .lock()
+        .unwrap()
+        .inject_dir
+        .clone()
+        .ok_or_else(|| "no audio injection directory configured".into())
+}
+
+/// Learn the HU PCM sinks from ServiceDiscovery and the tx towards the HU.
+pub fn set_sinks(msg: &ServiceDiscoveryResponse, tx: Option<Sender<Packet>>) {
+    let mut sinks = HashMap::new();
+    for svc in msg.services.iter() {
+        let sink = &svc.media_sink_service;
+        if sink.available_type() != MediaCodecType::MEDIA_CODEC_AUDIO_PCM {
+            continue;
+        }
+        let (Some(target), Some(acfg)) = (
+            InjectTarget::from_audio_type(sink.audio_type()),
+            sink.audio_configs.first(),
+        ) else {
+            continue;
+        };
+        sinks.insert(
+            target,
+            SinkInfo {
+                channel: svc.id() as u8,
+                config: AudioStreamConfig {
+                    sample_rate: acfg.sampling_rate(),
+                    channels: acfg.number_of_channels(),
+                    bits: acfg.number_of_bits(),
+                },
+            },
+        );
+    }
+
+    let mut state = runtime().state.lock().unwrap();
+    if let Some(active) = state.active.take() {
+        active.cancel.cancel();
+    }
+    info!(
+        "{} PCM sinks available for injection: {:?}",
+        NAME,
+        sinks.keys().collect::<Vec<_>>()
+    );
+    state.sinks = sinks;
+    state.tx = tx;
+    state.sink_config.clear();
+    state.phone_active.clear();
+}
+
+/// Record the HU media `Config` (configuration index, ACK window) for a sink.
+pub fn notify_media_config(channel: u8, cfg: &AudioConfig) {
+    let mut state = runtime().state.lock().unwrap();
+    if !state.sinks.values().any(|s| s.channel == channel) {
+        return;
+    }
+    state
+        .sink_config
+        .insert(channel, SinkConfig::from_config(cfg));
+}
+
+/// Track whether the phone has its own media session running on a sink.
+/// The phone always wins: a running injection on that sink is stopped.
+pub fn notify_phone_media(channel: u8, started: bool) {
+    let mut state = runtime().state.lock().unwrap();
+    if !state.sinks.values().any(|s| s.channel == channel) {
+        return;
+    }
+    if started {
+        if state.active.as_ref().is_some_and(|a| a.channel == channel) {
+            let active = state.active.take().unwrap();
+            info!(
+                "{} #{} preempted by phone media on ch=<b>{:#04x}</>",
+                NAME, active.id, channel
+            );
+            active.preempted.store(true, Ordering::SeqCst);
+            active.cancel.cancel();
+        }
+        state.phone_active.insert(channel);
+    } else {
+        state.phone_active.remove(&channel);
+    }
+}
+
+/// Consume HU ACKs belonging to our injected session. Returns `true` when
+/// the ACK must not be forwarded to the phone, which never saw that session.
+pub fn handle_ack(ack: &Ack) -> bool {
+    if ack.session_id() != INJECT_SESSION_ID {
+        return false;
+    }
+    runtime().acks.acked(ack.ack());
+    true
+}
+
+pub fn status() -> serde_json::Value {
+    let state = runtime().state.lock().unwrap();
+    let mut sinks: Vec<_> = state
+        .sinks
+        .iter()
+        .map(|(target, sink)| {
+            serde_json::json!({
+                "target": target,
+                "channel": sink.channel,
+                "sample_rate": sink.config.sample_rate,
+                "channels": sink.config.channels,
+                "bits": sink.config.bits,
+                "phone_active": state.phone_active.contains(&sink.channel),
+            })
+        })
+        .collect();
+    sinks.sort_by_key(|s| s["channel"].as_u64());
+    let active = state.active.as_ref().map(|a| {
+        serde_json::json!({
+            "id": a.id,
+            "target": a.target,
+            "channel": a.channel,
+            "source": a.source,
+        })
+    });
+    serde_json::json!({ "sinks": sinks, "active": active })
+}
+
+/// Cancel the running injection, if any.
+pub fn stop() -> bool {
+    let active = runtime().state.lock().unwrap().active.take();
+    match active {
+        Some(active) => {
+            info!("{} stopping injection #{}", NAME, active.id);
+            active.cancel.cancel();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Validate the request and start injecting in the background.
+/// Returns the injection id.
+pub fn start(req: InjectRequest) -> Result<u64> {
+    req.source.validate()?;
+
+    let rt = runtime();
+    let mut state = rt.state.lock().unwrap();
+    let Some(sink) = state.sinks.get(&req.target).copied() else {
+        return Err(format!("no {:?} PCM sink advertised by the HU", req.target).into());
+    };
+    if sink.config.bits != 16 {
+        return Err(format!("unsupported sink bit depth: {}", sink.config.bits).into());
+    }
+    let Some(tx) = state.tx.clone() else {
+        return Err("no active AA session".into());
+    };
+    if state.active.is_some() {
+        return Err("another injection is already running".into());
+    }
+    if state.phone_active.contains(&sink.channel) {
+        return Err(format!("phone is playing on the {:?} sink", req.target).into());
+    }
+
+    let id = rt.next_id.fetch_add(1, Ordering::SeqCst);
+    let source = req.source.describe();
+    let cancel = CancellationToken::new();
+    let preempted = Arc::new(AtomicBool::new(false));
+    state.active = Some(ActiveInjection {
+        id,
+        target: req.target,
+        channel: sink.channel,
+        source: source.clone(),
+        cancel: cancel.clone(),
+        preempted: preempted.clone(),
+    });
+    let sink_config = state
+        .sink_config
+        .get(&sink.channel)
+        .copied()
+        .unwrap_or_default();
+    drop(state);
+
+    info!(
+        "{} #{} injecting {} into {:?} sink ch=<b>{:#04x}</> ({}Hz, {}ch)",
+        NAME, id, source, req.target, sink.channel, sink.config.sample_rate, sink.config.channels
+    );
+    publish_status(id, "started", &source, None);
+
+    tokio::spawn(async move {
+        let mut job = InjectionJob {
+            tx,
+            sink,
+            sink_config,
+            session: SinkSession::new(
+                sink.channel,
+                INJECT_SESSION_ID,
+                sink.config.sample_rate,
+                sink.config.channels,
+            ),
+            gain_percent: req.gain_percent.max(1),
+            started: false,
+        };
+        let result = tokio::select! {
+            r = run_injection(&mut job, &req) => r,
+            _ = cancel.cancelled() => Err("stopped".into()),
+        };
+        if job.started && !preempted.load(Ordering::SeqCst) {
+            let _ = job.send_stop().await;
+        }
+
+        {
+            let mut state = runtime().state.lock().unwrap();
+            if state.active.as_ref().is_some_and(|a| a.id == id) {
+                state.active = None;
+            }
+        }
+        match result {
+            Ok(()) => {
+                info!("{} #{} finished", NAME, id);
+                publish_status(id, "finished", &source, None);
+            }
+            Err(e) => {
+                warn!("{} #{} ended: {}", NAME, id, e);
+                publish_status(id, "stopped", &source, Some(e.to_string()));
+            }
+        }
+    });
+
+    Ok(id)
+}
+
+fn publish_status(id: u64, state: &str, source: &str, error: Option<String>) {
+    let ws_event_tx = runtime().state.lock().unwrap().ws_event_tx.clone();
+    if let Some(tx) = ws_event_tx {
+        let payload = serde_json::json!({
+            "id": id,
+            "state": state,
+            "source": source,
+            "error": error,
+        });
+        let _ = tx.send(ServerEvent {
+            topic: AUDIO_INJECT_STATUS_TOPIC.to_string(),
+            payload: payload.to_string(),
+        });
+    }
+}
+
+/// Accept injection requests published on the `audio-inject` ws topic, both
+/// from web clients and from WASM scripts (`send-ws-event`).
+pub async fn ws_listener(ws_event_tx: BroadcastSender<ServerEvent>) {
+    runtime().state.lock().unwrap().ws_event_tx = Some(ws_event_tx.clone());
+    let mut rx = ws_event_tx.subscribe();
+    loop {
+        let ev = match rx.recv().await {
+            Ok(ev) => ev,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+        };
+        if ev.topic != AUDIO_INJECT_TOPIC {
+            continue;
+        }
+        if ev.payload.trim() == "stop" {
+            stop();
+            continue;
+        }
+        match serde_json::from_str::<InjectRequest>(&ev.payload) {
+            Ok(req) => {
+                if let Err(e) = start(req) {
+                    warn!("{} ws request rejected: {}", NAME, e);
+                    publish_status(0, "rejected", "ws", Some(e.to_string()));
+                }
+            }
+            Err(e) => warn!("{} invalid ws request: {}", NAME, e),
+        }
+    }
+}
+
+struct InjectionJob {
+    tx: Sender<Packet>,
+    sink: SinkInfo,
+    sink_config: SinkConfig,
+    session: SinkSession,
+    gain_percent: u32,
+    /// START went out, the HU expects a STOP.
+    started: bool,
+}
+
+impl InjectionJob {
+    async fn send_start(&mut self) -> Result<()> {
+        runtime().acks.reset();
+        let pkt = self.session.start(self.sink_config.configuration_index);
+        self.tx.send(pkt).await?;
+        self.started = true;
+        Ok(())
+    }
+
+    async fn send_stop(&self) -> Result<()> {
+        self.tx.send(self.session.stop()).await?;
+        Ok(())
+    }
+
+    async fn send_data(&mut self, pcm: &[i16]) -> Result<()> {
+        let acks = &runtime().acks;
+        if !acks
+            .wait_for_room(self.sink_config.max_unacked, ACK_WAIT_TIMEOUT)
+            .await
+        {
+            debug!("{} HU ACK wait timed out, resetting window", NAME);
+        }
+        let pkt = self.session.data(&s16le_bytes(pcm));
+        self.tx.send(pkt).await?;
+        acks.sent();
+        Ok(())
+    }
+}
+
+/// Source PCM layout (signed 16-bit little-endian).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct PcmFormat {
+    pub(crate) sample_rate: u32,
+    pub(crate) channels: u32,
+}
+
+impl PcmFormat {
+    pub(crate) fn check(&self) -> Result<()> {
+        if !PCM_SAMPLE_RATES.contains(&self.sample_rate)
+            || self.channels == 0
+            || self.channels > PCM_MAX_CHANNELS
+        {
+            return Err(format!(
+                "unsupported PCM format: {} Hz, {} channels",
+                self.sample_rate, self.channels
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Bytes of `ms` milliseconds of audio in this format.
+    pub(crate) fn bytes_for_ms(&self, ms: u64) -> Result<usize> {
+        (self.sample_rate as usize)
+            .checked_mul(ms as usize)
+            .map(|samples| samples / 1000)
+            .and_then(|frames| frames.checked_mul(self.channels as usize * 2))
+            .ok_or_else(|| "PCM buffer size overflows".into())
+    }
+}
+
+impl PcmSource {
+    pub(crate) fn validate(&self) -> Result<()> {
+        let sources = [self.file.is_some(), self.tcp.is_some(), self.unix.is_some()];
+        if sources.iter().filter(|s| **s).count() != 1 {
+            return Err("exactly one of `file`, `tcp` or `unix` must be set".into());
+        }
+        PcmFormat {
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+        }
+        .check()?;
+        if let Some(addr) = &self.tcp {
+            loopback_addr(addr)?;
+        }
+        if let Some(path) = &self.file {
+            resolve_file(&inject_dir()?, path)?;
+        }
+        if let Some(path) = &self.unix {
+            resolve_socket(&inject_dir()?, path)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn describe(&self) -> String {
+        if let Some(path) = &self.file {
+            format!("file:{}", path.display())
+        } else if let Some(addr) = &self.tcp {
+            format!("tcp:{}", addr)
+        } else if let Some(path) = &self.unix {
+            format!("unix:{}", path.display())
+        } else {
+            "none".to_string()
+        }
+    }
+
+    /// Load the WAV file or wait for the single raw PCM socket client.
+    pub(crate) async fn open(
+        &self,
+        log_name: &str,
+    ) -> Result<(Box<dyn AsyncRead + Unpin + Send>, PcmFormat)> {
+        let raw_format = PcmFormat {
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+        };
+        if let Some(path) = &self.file {
+            let path = resolve_file(&inject_dir()?, path)?;
+            let len = tokio::fs::metadata(&path).await?.len();
+            if len > MAX_WAV_FILE_BYTES {
+                return Err(format!("WAV file too large: {} bytes", len).into());
+            }
+            let data = tokio::fs::read(&path).await?;
+            let (format, range) = parse_wav(&data)?;
+            let pcm = data[range].to_vec();
+            return Ok((Box::new(std::io::Cursor::new(pcm)), format));
+        }
+        if let Some(addr) = &self.tcp {
+            let addr = loopback_addr(addr)?;
+            let listener = TcpListener::bind(addr).await?;
+            info!("{} waiting for raw PCM on tcp://{}", log_name, addr);
+            let (stream, peer) = timeout(ACCEPT_TIMEOUT, listener.accept())
+                .await
+                .map_err(|_| "no raw PCM client connected")??;
+            info!("{} raw PCM client connected from {}", log_name, peer);
+            return Ok((Box::new(stream), raw_format));
+        }
+        if let Some(path) = &self.unix {
+            let path = resolve_socket(&inject_dir()?, path)?;
+            remove_stale_socket(&path)?;
+            let listener = UnixListener::bind(&path)?;
+            let _socket = SocketFile(path.clone());
+            info!(
+                "{} waiting for raw PCM on unix:{}",
+                log_name,
+                path.display()
+            );
+            let (stream, _) = timeout(ACCEPT_TIMEOUT, listener.accept())
+                .await
+                .map_err(|_| "no raw PCM client connected")??;
+            info!("{} raw PCM client connected", log_name);
+            return Ok((Box::new(stream), raw_format));
+        }
+        Err("no audio source".into())
+    }
+}
+
+/// Only loopback listeners are accepted: the stream is unauthenticated.
+fn loopback_addr(addr: &str) -> Result<SocketAddr> {
+    let addr: SocketAddr = addr
+        .parse()
+        .map_err(|_| format!("invalid TCP address: {}", addr))?;
+    if !addr.ip().is_loopback() {
+        return Err(format!("TCP source must listen on a loopback address: {}", addr).into());
+    }
+    Ok(addr)
+}
+
+/// Resolve a WAV file path (relative paths are relative to `dir`) and make
+/// sure it does not escape `dir`, including through symlinks.
+fn resolve_file(dir: &Path, path: &Path) -> Result<PathBuf> {
+    let dir = canonical_dir(dir)?;
+    let resolved = dir
+        .join(path)
+        .canonicalize()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if !resolved.starts_with(&dir) || !resolved.is_file() {
+        return Err(format!("{} is not a file in {}", path.display(), dir.display()).into());
+    }
+    Ok(resolved)
+}
+
+/// Resolve a unix socket path inside `dir`. The socket itself may not exist
+/// yet, so only its parent directory is canonicalized.
+fn resolve_socket(dir: &Path, path: &Path) -> Result<PathBuf> {
+    let dir = canonical_dir(dir)?;
+    let joined = dir.join(path);
+    let (Some(parent), Some(name)) = (joined.parent(), joined.file_name()) else {
+        return Err(format!("invalid socket path: {}", path.display()).into());
+    };
+    let parent = parent
+        .canonicalize()
+        .map_err(|e| format!("{}: {}", path.display(), e))?;
+    if !parent.starts_with(&dir) {
+        return Err(format!("{} is outside of {}", path.display(), dir.display()).into());
+    }
+    Ok(parent.join(name))
+}
+
+fn canonical_dir(dir: &Path) -> Result<PathBuf> {
+    dir.canonicalize()
+        .map_err(|e| format!("injection directory {}: {}", dir.display(), e).into())
+}
+
+/// Unlinks a bound socket once it is no longer listened on, including when
+/// the injection is cancelled while waiting for the client.
+struct SocketFile(PathBuf);
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        let _ = remove_stale_socket(&self.0);
+    }
+}
+
+/// Unlink a socket left over from an earlier listener. Anything else at
+/// `path` is left alone.
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => Ok(std::fs::remove_file(path)?),
+        Ok(_) => Err(format!("{} exists and is not a socket", path.display()).into()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn run_injection(job: &mut InjectionJob, req: &InjectRequest) -> Result<()> {
+    let (mut reader, format) = req.source.open(NAME).await?;
+    let out_rate = job.sink.config.sample_rate.max(1);
+    let out_channels = job.sink.config.channels.max(1);
+    let mut converter = PcmConverter::new(format, out_rate, out_channels, job.gain_percent);
+
+    let chunk_samples = (out_rate as u64 * CHUNK_MS / 1000) as usize * out_channels as usize;
+    let in_frame_bytes = format.channels as usize * 2;
+    let mut read_buf = vec![0u8; format.bytes_for_ms(CHUNK_MS)?];
+    let mut carry: Vec<u8> = Vec::new();
+    let mut out: Vec<i16> = Vec::new();
+
+    job.send_start().await?;
+
+    let started_at = Instant::now();
+    let mut eof = false;
+    while !eof || !out.is_empty() {
+        if !eof {
+            let n = reader.read(&mut read_buf).await?;
+            if n == 0 {
+                eof = true;
+            } else {
+                carry.extend_from_slice(&read_buf[..n]);
+                let whole = carry.len() - carry.len() % in_frame_bytes;
+                let samples: Vec<i16> = carry[..whole]
+                    .chunks_exact(2)
+                    .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                    .collect();
+                carry.drain(..whole);
+                converter.process(&samples, &mut out);
+            }
+        }
+
+        while out.len() >= chunk_samples || (eof && !out.is_empty()) {
+            let take = out.len().min(chunk_samples);
+            let chunk: Vec<i16> = out.drain(..take).collect();
+
+            let due = started_at + Duration::from_micros(job.session.pts_us());
+            if let Some(send_at) = due.checked_sub(SEND_LEAD) {
+                tokio::time::sleep_until(send_at).await;
+            }
+            job.send_data(&chunk).await?;
+        }
+    }
+
+    // let the HU play out what was sent before STOP
+    let end = started_at + Duration::from_micros(job.session.pts_us());
+    tokio::time::sleep_until(end).await;
+    Ok(())
+}
+
+/// Parse a PCM WAV file, returning its format and the byte range of samples.
+fn parse_wav(data: &[u8]) -> Result<(PcmFormat, std::ops::Range<usize>)> {
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err("not a RIFF/WAVE file".into());
+    }
+    let mut format = None;
+    let mut pos = 12;
+    while pos + 8 <= data.len() {
+        let id = &data[pos..pos + 4];
+        let len = u32::from_le_bytes(data[pos + 4..pos + 8].try_into()?) as usize;
+        let body = pos + 8;
+        match id {
+            b"fmt " => {
+                if len < 16 || body + 16 > data.len() {
+                    return Err("truncated fmt chunk".into());
+                }
+                let f = &data[body..body + 16];
+                let audio_format = u16::from_le_bytes([f[0], f[1]]);
+                let channels = u16::from_le_bytes([f[2], f[3]]) as u32;
+                let sample_rate = u32::from_le_bytes([f[4], f[5], f[6], f[7]]);
+                let bits = u16::from_le_bytes([f[14], f[15]]);
+                // 1 = PCM, 0xFFFE = WAVE_FORMAT_EXTENSIBLE (assumed PCM)
+                if (audio_format != 1 && audio_format != 0xFFFE) || bits != 16 {
+                    return Err(format!(
+                        "unsupported WAV encoding: format={:#x}, bits={}",
+                        audio_format, bits
+                    )
+                    .into());
+                }
+                let wav_format = PcmFormat {
+                    sample_rate,
+                    channels,
+                };
+                wav_format.check()?;
+                format = Some(wav_format);
+            }
+            b"data" => {
+                let format = format.ok_or("WAV data chunk before fmt chunk")?;
+                let end = body.saturating_add(len).min(data.len());
+                return Ok((format, body..end));
+            }
+            _ => {}
+        }
+        // chunks are padded to an even size; a bogus size ends the scan
+        match body
+            .checked_add(len)
+            .and_then(|end| end.checked_add(len & 1))
+        {
+            Some(next) => pos = next,
+            None => break,
+        }
+    }
+    Err("WAV file without data chunk".into())
+}
+
+/// Streaming channel remapper + windowed-sinc resampler for s16 interleaved PCM.
+pub(crate) struct PcmConverter {
+    resampler: Resampler,
+    gain_percent: i32,
+}
+
+impl PcmConverter {
+    pub(crate) fn new(
+        input: PcmFormat,
+        out_rate: u32,
+        out_channels: u32,
+        gain_percent: u32,
+    ) -> Self {
+        Self {
+            resampler: Resampler::new(input.sample_rate, out_rate, input.channels, out_channels),
+            gain_percent: gain_percent as i32,
+        }
+    }
+
+    pub(crate) fn process(&mut self, input: &[i16], out: &mut Vec<i16>) {
+        let start = out.len();
+        self.resampler.process(input, out);
+        if self.gain_percent != 100 {
+            for sample in &mut out[start..] {
+                let scaled = *sample as i32 * self.gain_percent / 100;
+                *sample = scaled.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = id.to_vec();
+        out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        out.extend_from_slice(body);
+        if body.len() % 2 == 1 {
+            out.push(0);
+        }
+        out
+    }
+
+    fn fmt_body(format: u16, channels: u16, rate: u32, bits: u16) -> Vec<u8> {
+        let block_align = channels * bits / 8;
+        let mut f = Vec::new();
+        f.extend_from_slice(&format.to_le_bytes());
+        f.extend_from_slice(&channels.to_le_bytes());
+        f.extend_from_slice(&rate.to_le_bytes());
+        f.extend_from_slice(&(rate * block_align as u32).to_le_bytes());
+        f.extend_from_slice(&block_align.to_le_bytes());
+        f.extend_from_slice(&bits.to_le_bytes());
+        f
+    }
+
+    fn riff(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let body: Vec<u8> = chunks.concat();
+        let mut out = b"RIFF".to_vec();
+        out.extend_from_slice(&(body.len() as u32 + 4).to_le_bytes());
+        out.extend_from_slice(b"WAVE");
+        out.extend_from_slice(&body);
+        out
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("audio-inject-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn tcp_request(tcp: &str) -> InjectRequest {
+        serde_json::from_value(serde_json::json!({ "tcp": tcp })).unwrap()
+    }
+
+    #[test]
+    fn parse_wav_skips_padded_chunks() {
+        let pcm = [1u8, 0, 2, 0, 3, 0, 4, 0];
+        let data = riff(&[
+            chunk(b"fmt ", &fmt_body(1, 2, 22_050, 16)),
+            chunk(b"LIST", b"odd"),
+            chunk(b"data", &pcm),
+        ]);
+
+        let (format, range) = parse_wav(&data).unwrap();
+
+        assert_eq!(
+            format,
+            PcmFormat {
+                sample_rate: 22_050,
+                channels: 2
+            }
+        );
+        assert_eq!(&data[range], &pcm);
+    }
+
+    #[test]
+    fn parse_wav_rejects_non_pcm_encodings() {
+        for (format, bits) in [(3, 32), (1, 8), (1, 24), (6, 8)] {
+            let data = riff(&[
+                chunk(b"fmt ", &fmt_body(format, 1, 16_000, bits)),
+                chunk(b"data", &[0; 4]),
+            ]);
+            assert!(parse_wav(&data).is_err(), "format={} bits={}", format, bits);
+        }
+    }
+
+    #[test]
+    fn parse_wav_rejects_truncated_files() {
+        let full = riff(&[
+            chunk(b"fmt ", &fmt_body(1, 1, 16_000, 16)),
+            chunk(b"data", &[0; 4]),
+        ]);
+        // header only, cut inside the fmt chunk, cut before the data chunk
+        for len in [12, 30, 36] {
+            assert!(parse_wav(&full[..len]).is_err(), "len={}", len);
+        }
+        assert!(parse_wav(b"RIFF\0\0\0\0WAVX").is_err());
+    }
+
+    #[test]
+    fn parse_wav_handles_bad_chunk_sizes() {
+        // data chunk claiming more bytes than the file holds is clamped
+        let mut data = riff(&[
+            chunk(b"fmt ", &fmt_body(1, 1, 16_000, 16)),
+            chunk(b"data", &[0; 4]),
+        ]);
+        let data_len_at = data.len() - 8;
+        data[data_len_at..data_len_at + 4].copy_from_slice(&1000u32.to_le_bytes());
+        let (_, range) = parse_wav(&data).unwrap();
+        assert_eq!(range, data.len() - 4..data.len());
+
+        // a huge chunk size must not wrap around or loop
+        let mut junk = chunk(b"junk", &[0; 2]);
+        junk[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        let data = riff(&[chunk(b"fmt ", &fmt_body(1, 1, 16_000, 16)), junk]);
+        assert!(parse_wav(&data).is_err());
+
+        // fmt chunk shorter than 16 bytes
+        let data = riff(&[chunk(b"fmt ", &[1, 0, 1, 0]), chunk(b"data", &[0; 4])]);
+        assert!(parse_wav(&data).is_err());
+
+        // data before fmt
+        let data = riff(&[
+            chunk(b"data", &[0; 4]),
+            chunk(b"fmt ", &fmt_body(1, 1, 16_000, 16)),
+        ]);
+        assert!(parse_wav(&data).is_err());
+    }
+
+    #[test]
+    fn converter_maps_channels_at_same_rate() {
+        let mono = PcmFormat {
+            sample_rate: 16_000,
+            channels: 1,
+        };
+        let mut out = Vec::new();
+        PcmConverter::new(mono, 16_000, 2, 100).process(&[100, -200], &mut out);
+        assert_eq!(out, vec![100, 100, -200, -200]);
+
+        let stereo = PcmFormat {
+            sample_rate: 16_000,
+            channels: 2,
+        };
+        let mut out = Vec::new();
+        PcmConverter::new(stereo, 16_000, 1, 100).process(&[100, 300, -100, -300], &mut out);
+        assert_eq!(out, vec![200, -200]);
+    }
+
+    #[test]
+    fn converter_gain_clips_to_i16() {
+        let format = PcmFormat {
+            sample_rate: 8_000,
+            channels: 1,
+        };
+        let mut out = Vec::new();
+        PcmConverter::new(format, 8_000, 1, 300).process(&[20_000, -20_000, 1_000], &mut out);
+        assert_eq!(out, vec![i16::MAX, i16::MIN, 3_000]);
+
+        let mut out = Vec::new();
+        PcmConverter::new(format, 8_000, 1, 50).process(&[1_000], &mut out);
+        assert_eq!(out, vec![500]);
+    }
+
+    #[test]
+    fn converter_resamples_to_sink_rate() {
+        let format = PcmFormat {
+            sample_rate: 16_000,
+            channels: 1,
+        };
+        let mut converter = PcmConverter::new(format, 48_000, 2, 100);
+        let mut out = Vec::new();
+        for _ in 0..10 {
+            converter.process(&[1_000; 1_600], &mut out);
+        }
+        // 1 s of 16 kHz mono becomes about 1 s of 48 kHz stereo
+        let frames = out.len() / 2;
+        assert!((47_000..=48_000).contains(&frames), "frames={}", frames);
+        // settled DC level survives the filter and channel duplication
+        let tail = &out[out.len() - 200..];
+        assert!(tail.iter().all(|s| (990..=1_010).contains(s)));
+    }
+
+    #[test]
+    fn request_defaults_and_source_validation() {
+        let req = tcp_request("127.0.0.1:5300");
+        assert_eq!(req.target, InjectTarget::Guidance);
+        assert_eq!(req.gain_percent, 100);
+        assert_eq!((req.source.sample_rate, req.source.channels), (16_000, 1));
+        assert!(req.source.validate().is_ok());
+        assert!(tcp_request("[::1]:5300").source.validate().is_ok());
+
+        for addr in ["0.0.0.0:5300", "10.0.0.1:5300", "localhost:5300", "5300"] {
+            assert!(tcp_request(addr).source.validate().is_err(), "{}", addr);
+        }
+
+        let none: InjectRequest = serde_json::from_str(r#"{"target":"media"}"#).unwrap();
+        assert!(none.source.validate().is_err());
+        let both: InjectRequest =
+            serde_json::from_str(r#"{"tcp":"127.0.0.1:5300","unix":"pcm.sock"}"#).unwrap();
+        assert!(both.source.validate().is_err());
+        let bad_format: InjectRequest =
+            serde_json::from_str(r#"{"tcp":"127.0.0.1:5300","channels":0}"#).unwrap();
+        assert!(bad_format.source.validate().is_err());
+        for rate in [0, 7_999, 192_001, 4_000_000_000] {
+            let mut req = tcp_request("127.0.0.1:5300");
+            req.source.sample_rate = rate;
+            assert!(req.source.validate().is_err(), "{}", rate);
+        }
+        let mut hi_res = tcp_request("127.0.0.1:5300");
+        hi_res.source.sample_rate = 192_000;
+        hi_res.source.channels = 8;
+        assert!(hi_res.source.validate().is_ok());
+        assert!(serde_json::from_str::<InjectRequest>(r#"{"target":"phone"}"#).is_err());
+    }
+
+    #[test]
+    fn file_sources_stay_inside_inject_dir() {
+        let dir = temp_dir("file");
+        std::fs::write(dir.join("chime.wav"), b"x").unwrap();
+        std::os::unix::fs::symlink("/etc/passwd", dir.join("link.wav")).unwrap();
+        let canonical = dir.canonicalize().unwrap();
+
+        assert_eq!(
+            resolve_file(&dir, Path::new("chime.wav")).unwrap(),
+            canonical.join("chime.wav")
+        );
+        assert!(resolve_file(&dir, &dir.join("chime.wav")).is_ok());
+        assert!(resolve_file(&dir, Path::new("../../etc/passwd")).is_err());
+        assert!(resolve_file(&dir, Path::new("/etc/passwd")).is_err());
+        assert!(resolve_file(&dir, Path::new("link.wav")).is_err());
+        assert!(resolve_file(&dir, Path::new("missing.wav")).is_err());
+        assert!(resolve_file(&dir, Path::new(".")).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn socket_sources_only_replace_sockets() {
+        let dir = temp_dir("unix");
+        std::fs::write(dir.join("file"), b"keep").unwrap();
+
+        let path = resolve_socket(&dir, Path::new("pcm.sock")).unwrap();
+        assert_eq!(path, dir.canonicalize().unwrap().join("pcm.sock"));
+        assert!(resolve_socket(&dir, Path::new("../pcm.sock")).is_err());
+        assert!(resolve_socket(&dir, Path::new("/tmp/pcm.sock")).is_err());
+
+        // nothing there yet, a stale socket, and a regular file
+        assert!(remove_stale_socket(&path).is_ok());
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(remove_stale_socket(&path).is_ok());
+        assert!(!path.exists());
+        let file = resolve_socket(&dir, Path::new("file")).unwrap();
+        assert!(remove_stale_socket(&file).is_err());
+        assert!(file.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,10 +1,16 @@
+use crate::bt_pairing;
 use crate::btle;
 use crate::config::Action;
+use crate::config::SharedWifiConfig;
 use crate::config::WifiConfig;
 use crate::config::IDENTITY_NAME;
 use crate::config_types::BluetoothAddressList;
+use crate::hfp::{self, HfpOptions};
+use crate::known_devices;
+use crate::multi_phone;
 use crate::sdr_ui;
 use crate::web::AppState;
+use crate::wifi_security;
 use anyhow::anyhow;
 use backon::{ExponentialBuilder, Retryable};
 use bluer::{
@@ -43,12 +49,11 @@ pub const AAWG_PROFILE_UUID: Uuid = Uuid::from_u128(0x4de17a0052cb11e6bdf4080020
 pub const BTLE_PROFILE_UUID: Uuid = Uuid::from_u128(0x9b3f6c10a4d2418ea2b90700300de8f4);
 const HSP_HS_UUID: Uuid = Uuid::from_u128(0x0000110800001000800000805f9b34fb);
 const HSP_AG_UUID: Uuid = Uuid::from_u128(0x0000111200001000800000805f9b34fb);
-pub const KNOWN_DEVICES_FILE: &str = concat!(crate::base_config_dir!(), "/known_devices");
 
 #[derive(Debug, Clone, PartialEq)]
 #[repr(u16)]
 #[allow(unused)]
-enum MessageId {
+pub(crate) enum MessageId {
     WifiStartRequest = 1,
     WifiInfoRequest = 2,
     WifiInfoResponse = 3,
@@ -65,6 +70,11 @@ pub struct Bluetooth {
     adv_handle: Option<bluer::adv::AdvertisementHandle>,
     current_index: usize,
     dongle_mode: bool,
+    /// HFP hands-free is registered instead of HSP, so connect to the phone's
+    /// HFP audio gateway.
+    hfp_enabled: bool,
+    /// Pairing agent stays registered while the handle is alive.
+    _agent_handle: Option<bluer::agent::AgentHandle>,
 }
 
 // Create and configure the Bluetooth adapter
@@ -72,6 +82,7 @@ pub async fn init(
     btalias: Option<String>,
     advertise: bool,
     dongle_mode: bool,
+    pairing_timeout: Option<Duration>,
 ) -> Result<Bluetooth> {
     let session = bluer::Session::new().await?;
     let adapter = session.default_adapter().await?;
@@ -114,6 +125,21 @@ pub async fn init(
     let handle_aa = session.register_profile(profile).await?;
     info!("{} 📱 AA Wireless Profile: registered", NAME);
 
+    // forward passkey/confirmation requests to the web UI
+    let agent_handle = match pairing_timeout {
+        Some(timeout) => {
+            let agent = bt_pairing::agent(adapter.clone(), timeout);
+            let handle = session.register_agent(agent).await?;
+            info!(
+                "{} 🔑 Pairing agent: registered, requests time out after {}s",
+                NAME,
+                timeout.as_secs()
+            );
+            Some(handle)
+        }
+        None => None,
+    };
+
     Ok(Bluetooth {
         adapter,
         handle_aa,
@@ -121,6 +147,8 @@ pub async fn init(
         adv_handle: None,
         current_index: 0,
         dongle_mode,
+        hfp_enabled: false,
+        _agent_handle: agent_handle,
     })
 }
 
@@ -135,71 +163,24 @@ pub async fn get_cpu_serial_number_suffix() -> Result<String> {
     Ok(serial)
 }
 
-/// Load previously successful AA device addresses from persistent file.
-pub fn load_known_devices() -> Vec<Address> {
-    let path = std::path::Path::new(KNOWN_DEVICES_FILE);
-    let contents = match std::fs::read_to_string(path) {
-        Ok(c) => c,
-        Err(_) => return Vec::new(),
-    };
-    let addrs: Vec<Address> = contents
-        .lines()
-        .filter_map(|line| {
-            let trimmed = line.trim();
-            if trimmed.is_empty() {
-                return None;
-            }
-            match trimmed.parse::<Address>() {
-                Ok(addr) if addr != Address::any() => Some(addr),
-                _ => {
-                    warn!("{} known_devices: skipping invalid line: {}", NAME, trimmed);
-                    None
-                }
-            }
-        })
-        .collect();
-    if !addrs.is_empty() {
-        info!(
-            "{} 📋 Loaded {} known device(s) from {}",
-            NAME,
-            addrs.len(),
-            KNOWN_DEVICES_FILE
-        );
+/// Every secured network is announced as WPA2 personal, whatever its
+/// `security`: the AA protocol has no SAE security mode. Phones join
+/// transition mode APs with WPA2-PSK, and Android upgrades a PSK profile to
+/// SAE when the AP only offers SAE.
+fn security_mode(wifi_config: &WifiConfig) -> SecurityMode {
+    if wifi_config.station && wifi_config.wpa_key.is_empty() {
+        return SecurityMode::OPEN;
     }
-    addrs
+    SecurityMode::WPA2_PERSONAL
 }
 
-/// Append a device address to the known-good devices file (if not already present).
-fn save_known_device(addr: Address) {
-    if addr == Address::any() {
-        return;
-    }
-    // Read existing entries to avoid duplicates
-    let existing = load_known_devices();
-    if existing.contains(&addr) {
-        debug!("{} known_devices: {} already recorded", NAME, addr);
-        return;
-    }
-    let addr_str = format!("{}\n", addr);
-    match std::fs::OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(KNOWN_DEVICES_FILE)
-    {
-        Ok(mut file) => {
-            use std::io::Write;
-            if let Err(e) = file.write_all(addr_str.as_bytes()) {
-                warn!("{} known_devices: failed to write {}: {}", NAME, addr, e);
-            } else {
-                info!("{} 💾 Saved {} to known devices", NAME, addr);
-            }
-        }
-        Err(e) => {
-            warn!(
-                "{} known_devices: failed to open file for writing: {}",
-                NAME, e
-            );
-        }
+/// Our own hotspot only exists while aa-proxy-rs runs, a joined network is
+/// permanent infrastructure.
+fn access_point_type(wifi_config: &WifiConfig) -> AccessPointType {
+    if wifi_config.station {
+        AccessPointType::STATIC
+    } else {
+        AccessPointType::DYNAMIC
     }
 }
 
@@ -403,12 +384,12 @@ impl Bluetooth {
             if !stopped {
                 let adapter_cloned = self.adapter.clone();
 
-                let addresses: Vec<Address> = if addresses_to_connect
+                let from_store = addresses_to_connect
                     .iter()
-                    .any(|addr| *addr == Address::any())
-                {
+                    .any(|addr| *addr == Address::any());
+                let addresses: Vec<Address> = if from_store {
                     // Only use known-good devices, no fallback to all paired devices
-                    let known = load_known_devices();
+                    let known = known_devices::auto_connect_addresses();
                     if !known.is_empty() {
                         info!("{} 🥏 Using {} known-good device(s)...", NAME, known.len());
                     } else {
@@ -418,16 +399,27 @@ impl Bluetooth {
                 } else {
                     addresses_to_connect
                 };
+                // known devices are already sorted by preference, always start
+                // with the first one instead of rotating through the list
+                if from_store {
+                    self.current_index = 0;
+                }
                 // exit if we don't have anything to connect to
                 if !addresses.is_empty() {
                     info!("{} 🧲 Attempting to start an AndroidAuto session via bluetooth with the following devices, in this order: {:?}", NAME, addresses);
                     if !self.dongle_mode {
+                        let profile_uuid = if self.hfp_enabled {
+                            hfp::HFP_AG_UUID
+                        } else {
+                            HSP_AG_UUID
+                        };
                         let try_connect_bluetooth_addresses_retry = || async {
                             let next_index = Bluetooth::try_connect_bluetooth_addresses(
                                 &adapter_cloned,
                                 &addresses,
                                 self.current_index,
                                 bt_connect_timeout,
+                                profile_uuid,
                             )
                             .await?;
 
@@ -514,6 +506,7 @@ impl Bluetooth {
         addresses: &Vec<Address>,
         start_index: usize,
         bt_connect_timeout: Duration,
+        profile_uuid: Uuid,
     ) -> Result<usize> {
         let n = addresses.len();
 
@@ -557,18 +550,21 @@ impl Bluetooth {
                     NAME, addr, dev_name, j, ATTEMPTS
                 );
                 if let Ok(true) = device.is_paired().await {
-                    match timeout(bt_connect_timeout, device.connect_profile(&HSP_AG_UUID)).await {
+                    match timeout(bt_connect_timeout, device.connect_profile(&profile_uuid)).await {
                         Ok(Ok(_)) => {
                             info!(
                                 "{} 🔗 Successfully connected to device: {}{}",
                                 NAME, addr, dev_name
                             );
+                            known_devices::record_connect_attempt(*addr, true);
                             return Ok((*idx + 1) % n);
                         }
                         Ok(Err(e)) => {
+                            known_devices::record_connect_attempt(*addr, false);
                             warn!("{} 🔇 {}{}: Error connecting: {}", NAME, addr, dev_name, e)
                         }
                         Err(_) => {
+                            known_devices::record_connect_attempt(*addr, false);
                             warn!(
                                 "{} ⏱️ {}{}: connect_profile timed out after {}s",
                                 NAME,
@@ -609,15 +605,22 @@ impl Bluetooth {
         read_message(stream, stage, MessageId::WifiInfoRequest, started).await?;
 
         let mut info = WifiInfoResponse::new();
+        // a per-phone passphrase is that phone's own credential, keep it out
+        // of the log
+        let logged_key = if wifi_config.per_phone_psk {
+            "<per-phone>"
+        } else {
+            wifi_config.wpa_key.as_str()
+        };
         info!(
-            "{} 🛜 Sending Host SSID and Password: {}, {}",
-            NAME, wifi_config.ssid, wifi_config.wpa_key
+            "{} 🛜 Sending Host SSID and Password: {}, {} (network security: {})",
+            NAME, wifi_config.ssid, logged_key, wifi_config.security
         );
+        info.set_security_mode(security_mode(&wifi_config));
+        info.set_access_point_type(access_point_type(&wifi_config));
         info.set_ssid(wifi_config.ssid);
         info.set_key(wifi_config.wpa_key);
         info.set_bssid(wifi_config.bssid);
-        info.set_security_mode(SecurityMode::WPA2_PERSONAL);
-        info.set_access_point_type(AccessPointType::DYNAMIC);
         stage += 1;
         send_message(stream, stage, MessageId::WifiInfoResponse, info).await?;
         stage += 1;
@@ -630,6 +633,23 @@ impl Bluetooth {
         Ok(())
     }
 
+    /// Current Wi-Fi parameters for the phone at `address`, with its own
+    /// passphrase when per-phone passphrases are enabled.
+    async fn phone_wifi_config(
+        wifi_config: &SharedWifiConfig,
+        address: Address,
+    ) -> Result<WifiConfig> {
+        let mut config = wifi_config
+            .read()
+            .await
+            .clone()
+            .ok_or("Wi-Fi config not available")?;
+        if config.per_phone_psk {
+            config.wpa_key = wifi_security::phone_passphrase(address)?;
+        }
+        Ok(config)
+    }
+
     /// Drop HSP session here - this unregisters the profile from BlueZ.
     /// We do it explicitly with a small delay to give BlueZ time to clean up.
     async fn unregister_hsp(hsp_session: Option<bluer::Session>) {
@@ -644,7 +664,7 @@ impl Bluetooth {
     pub async fn aa_handshake(
         &mut self,
         connect: BluetoothAddressList,
-        wifi_config: WifiConfig,
+        wifi_config: SharedWifiConfig,
         tcp_start: Arc<Notify>,
         bt_timeout: Duration,
         bt_connect_timeout: Duration,
@@ -653,6 +673,7 @@ impl Bluetooth {
         bt_poweroff: bool,
         bt_sco: bool,
         bt_sco_keep_bluetooth_alive: bool,
+        hfp_options: Option<HfpOptions>,
         mut need_restart: BroadcastReceiver<Option<Action>>,
         restart_tx: BroadcastSender<Option<Action>>,
         profile_connected: Arc<AtomicBool>,
@@ -661,49 +682,73 @@ impl Bluetooth {
             let _ = self.adapter.set_powered(true).await;
         }
         //
-        // --- HSP PROFILE REGISTRATION ---
+        // --- HSP/HFP PROFILE REGISTRATION ---
         //
         let mut hsp_handle = None;
+        self.hfp_enabled = hfp_options.is_some() && !self.dongle_mode;
 
         if !self.dongle_mode {
             let session = bluer::Session::new().await?;
-            let profile = Profile {
-                uuid: HSP_HS_UUID,
-                name: Some("HSP HS".to_string()),
-                require_authentication: Some(false),
-                require_authorization: Some(false),
-                ..Default::default()
+            let (profile, label) = match &hfp_options {
+                Some(options) => (
+                    Profile {
+                        uuid: hfp::HFP_HF_UUID,
+                        name: Some("Hands-Free".to_string()),
+                        version: Some(hfp::HFP_VERSION),
+                        features: Some(options.sdp_features()),
+                        require_authentication: Some(false),
+                        require_authorization: Some(false),
+                        ..Default::default()
+                    },
+                    "Hands-Free Profile (HFP)",
+                ),
+                None => (
+                    Profile {
+                        uuid: HSP_HS_UUID,
+                        name: Some("HSP HS".to_string()),
+                        require_authentication: Some(false),
+                        require_authorization: Some(false),
+                        ..Default::default()
+                    },
+                    "Headset Profile (HSP)",
+                ),
             };
 
             match session.register_profile(profile).await {
                 Ok(handle) => {
-                    info!("{} 🎧 Headset Profile (HSP): registered", NAME);
+                    info!("{} 🎧 {}: registered", NAME, label);
 
-                    // Move ownership of handle into task. Keep the old safe behavior:
-                    // accept and immediately drop the HSP control stream so Android Auto
-                    // Bluetooth handshakes are not affected. The SCO/eSCO audio socket is
-                    // handled separately by the bt_sco listener.
+                    // Move ownership of handle into task. With HFP the RFCOMM stream is
+                    // driven by the hands-free state machine. Otherwise keep the old safe
+                    // behavior: accept and immediately drop the HSP control stream so
+                    // Android Auto Bluetooth handshakes are not affected. The SCO/eSCO
+                    // audio socket is handled separately by the bt_sco listener.
                     tokio::spawn(async move {
                         let mut h = handle;
                         loop {
                             let req = match h.next().await {
                                 Some(req) => req,
                                 None => {
-                                    warn!(
-                                        "{} 🎧 Headset Profile (HSP): no more connect requests",
-                                        NAME
-                                    );
+                                    warn!("{} 🎧 {}: no more connect requests", NAME, label);
                                     break;
                                 }
                             };
 
                             let device = req.device().clone();
-                            info!(
-                                "{} 🎧 Headset Profile (HSP): connect from <b>{}</>",
-                                NAME, device
-                            );
+                            info!("{} 🎧 {}: connect from <b>{}</>", NAME, label, device);
 
                             match req.accept() {
+                                Ok(stream) if hfp_options.is_some() => {
+                                    info!(
+                                        "{} 🎧 {}: accepted from <b>{}</>, starting AT session",
+                                        NAME, label, device
+                                    );
+                                    tokio::spawn(hfp::run_session(
+                                        stream,
+                                        device.to_string(),
+                                        hfp_options.clone().unwrap_or_default(),
+                                    ));
+                                }
                                 Ok(stream) => {
                                     // IMPORTANT: Do not keep the HSP RFCOMM control stream open yet.
                                     // Keeping it open without a proper HSP/HFP AT-command state machine can
@@ -722,10 +767,8 @@ impl Bluetooth {
                                 }
                                 Err(e) => {
                                     warn!(
-                                        "{} 🎧 Headset Profile (HSP): accept error from <b>{}</>: {}",
-                                        NAME,
-                                        device,
-                                        e
+                                        "{} 🎧 {}: accept error from <b>{}</>: {}",
+                                        NAME, label, device, e
                                     );
                                 }
                             }
@@ -736,10 +779,7 @@ impl Bluetooth {
                     hsp_handle = Some(session);
                 }
                 Err(e) => {
-                    warn!(
-                        "{} 🎧 Headset Profile (HSP) registering error: {}, ignoring",
-                        NAME, e
-                    );
+                    warn!("{} 🎧 {} registering error: {}, ignoring", NAME, label, e);
                 }
             }
         }
@@ -756,14 +796,18 @@ impl Bluetooth {
             Ok(device) => device.name().await.ok().flatten(),
             Err(_) => None,
         };
-        sdr_ui::set_current_phone_from_bt(&address.to_string(), phone_name);
-
-        Self::send_params(wifi_config.clone(), &mut stream).await?;
-
-        // Record this device as a known-good AA device (only when using wildcard connect)
-        if is_wildcard_connect {
-            save_known_device(address);
-        }
+        sdr_ui::set_current_phone_from_bt(&address.to_string(), phone_name.clone());
+        multi_phone::bootstrapped(address, phone_name.clone());
+
+        Self::send_params(
+            Self::phone_wifi_config(&wifi_config, address).await?,
+            &mut stream,
+        )
+        .await?;
+
+        // Record this device as a known-good AA device (new entries only when
+        // using wildcard connect)
+        known_devices::record_session(address, phone_name, is_wildcard_connect);
         tcp_start.notify_one();
 
         if quick_reconnect {
@@ -830,8 +874,15 @@ impl Bluetooth {
                         }
                     }
 
-                    // now restart handshake with the same params
-                    match Self::send_params(wifi_config.clone(), &mut stream).await {
+                    // now restart handshake with the current params
+                    let params = match Self::phone_wifi_config(&wifi_config, address).await {
+                        Ok(params) => params,
+                        Err(e) => {
+                            error!("{} handshake restart error: {}", NAME, e);
+                            break;
+                        }
+                    };
+                    match Self::send_params(params, &mut stream).await {
                         Ok(_) => {
                             tcp_start.notify_one();
                             continue;
@@ -879,7 +930,15 @@ impl Bluetooth {
                 let mut held_stream = stream;
                 let _held_hsp_session = hsp_session;
 
-                match keepalive_restart_rx.recv().await {
+                let action = loop {
+                    match keepalive_restart_rx.recv().await {
+                        // io_loop holds the HU session over a handover, the
+                        // phone's call audio has to stay as well
+                        Ok(Some(Action::Handover)) => continue,
+                        action => break action,
+                    }
+                };
+                match action {
                     Ok(action) => {
                         info!(
                             "{} 🎧 bt_sco keepalive ending after restart notification: {:?}",
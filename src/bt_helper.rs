@@ -1,22 +1,57 @@
-use axum::{extract::Path, http::StatusCode, response::IntoResponse, Json};
-
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use bluer::{Adapter, AdapterEvent, Address, Device};
+use futures::StreamExt;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tokio::process::Command;
+use simplelog::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use tokio::sync::broadcast::Sender as BroadcastSender;
 use tokio::time::{timeout, Duration};
+use tokio_util::sync::CancellationToken;
+
+use crate::web::{AppState, ServerEvent};
+
+const NAME: &str = "<i><bright-black> bt-helper: </>";
+
+/// Discovery results are streamed as `{"event": "found"|"removed"|"stopped", ...}`.
+pub const BT_DISCOVERY_TOPIC: &str = "bt-discovery";
+/// Discovery stops by itself after this long unless the request asks otherwise.
+const DEFAULT_DISCOVERY_SECS: u64 = 30;
+const MAX_DISCOVERY_SECS: u64 = 300;
+/// Pairing includes the user confirming the passkey on the phone.
+const PAIR_TIMEOUT: Duration = Duration::from_secs(60);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Running discovery session: id and stop token.
+static DISCOVERY: OnceLock<Mutex<Option<(u64, CancellationToken)>>> = OnceLock::new();
+static DISCOVERY_ID: AtomicU64 = AtomicU64::new(0);
 
-#[derive(Debug, Serialize)]
-pub struct BtCommandResponse {
-    pub ok: bool,
-    pub stdout: String,
-    pub stderr: String,
+fn discovery() -> &'static Mutex<Option<(u64, CancellationToken)>> {
+    DISCOVERY.get_or_init(|| Mutex::new(None))
 }
 
-#[derive(Debug, Clone, serde::Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct BtDevice {
     pub mac: String,
     pub name: String,
+    pub alias: String,
     pub paired: bool,
+    pub trusted: bool,
+    pub blocked: bool,
+    pub connected: bool,
+    pub rssi: Option<i16>,
+    pub tx_power: Option<i16>,
+    /// Class of Device, e.g. `0x5a020c` for a smartphone.
+    pub class: Option<u32>,
+    pub icon: Option<String>,
+    pub uuids: Vec<String>,
+    pub battery_percent: Option<u8>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -24,6 +59,19 @@ pub struct BtDeviceRequest {
     pub mac: String,
 }
 
+#[derive(Debug, Default, Deserialize)]
+pub struct BtDeviceUpdate {
+    pub trusted: Option<bool>,
+    pub blocked: Option<bool>,
+    pub alias: Option<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct BtDiscoveryRequest {
+    /// Stop discovery automatically after this many seconds.
+    pub seconds: Option<u64>,
+}
+
 pub fn validate_bt_mac(mac: &str) -> bool {
     let parts: Vec<&str> = mac.split(':').collect();
 
@@ -33,215 +81,419 @@ pub fn validate_bt_mac(mac: &str) -> bool {
             .all(|p| p.len() == 2 && p.chars().all(|c| c.is_ascii_hexdigit()))
 }
 
-pub async fn run_bluetoothctl(args: &[&str]) -> Result<BtCommandResponse, String> {
-    let fut = Command::new("bluetoothctl").args(args).output();
-
-    let output = timeout(Duration::from_secs(20), fut)
-        .await
-        .map_err(|_| "bluetoothctl timed out".to_string())?
-        .map_err(|e| format!("failed to run bluetoothctl: {e}"))?;
-
-    Ok(BtCommandResponse {
-        ok: output.status.success(),
-        stdout: String::from_utf8_lossy(&output.stdout).to_string(),
-        stderr: String::from_utf8_lossy(&output.stderr).to_string(),
-    })
+fn parse_mac(mac: &str) -> Result<Address, Response> {
+    if !validate_bt_mac(mac) {
+        return Err(error_response(
+            StatusCode::BAD_REQUEST,
+            mac,
+            "Invalid Bluetooth MAC address".to_string(),
+        ));
+    }
+    mac.parse::<Address>()
+        .map_err(|e| error_response(StatusCode::BAD_REQUEST, mac, e.to_string()))
 }
 
-pub fn parse_bluetoothctl_devices(output: &str, paired: bool) -> Vec<BtDevice> {
-    output
-        .lines()
-        .filter_map(|line| {
-            let line = line.trim();
+fn error_response(status: StatusCode, mac: &str, error: String) -> Response {
+    (
+        status,
+        Json(json!({
+            "ok": false,
+            "mac": mac,
+            "error": error,
+        })),
+    )
+        .into_response()
+}
 
-            let rest = line.strip_prefix("Device ")?;
-            let mut parts = rest.splitn(2, char::is_whitespace);
+fn bluer_error(mac: &str, e: bluer::Error) -> Response {
+    let status = match e.kind {
+        bluer::ErrorKind::NotFound | bluer::ErrorKind::DoesNotExist => StatusCode::NOT_FOUND,
+        bluer::ErrorKind::InProgress | bluer::ErrorKind::AlreadyExists => StatusCode::CONFLICT,
+        bluer::ErrorKind::AuthenticationCanceled
+        | bluer::ErrorKind::AuthenticationFailed
+        | bluer::ErrorKind::AuthenticationRejected
+        | bluer::ErrorKind::AuthenticationTimeout => StatusCode::UNAUTHORIZED,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    error_response(status, mac, e.to_string())
+}
 
-            let mac = parts.next()?.trim();
-            let name = parts.next().unwrap_or("").trim();
+async fn default_adapter() -> bluer::Result<Adapter> {
+    let session = bluer::Session::new().await?;
+    session.default_adapter().await
+}
 
-            if !validate_bt_mac(mac) {
-                return None;
-            }
+/// Read the properties of a device known to BlueZ.
+pub async fn device_info(device: &Device) -> bluer::Result<BtDevice> {
+    let mut uuids: Vec<String> = device
+        .uuids()
+        .await?
+        .unwrap_or_default()
+        .iter()
+        .map(|uuid| uuid.to_string())
+        .collect();
+    uuids.sort();
 
-            Some(BtDevice {
-                mac: mac.to_string(),
-                name: name.to_string(),
-                paired: paired,
-            })
-        })
-        .collect()
+    Ok(BtDevice {
+        mac: device.address().to_string(),
+        name: device.name().await?.unwrap_or_default(),
+        alias: device.alias().await?,
+        paired: device.is_paired().await?,
+        trusted: device.is_trusted().await?,
+        blocked: device.is_blocked().await?,
+        connected: device.is_connected().await?,
+        rssi: device.rssi().await?,
+        tx_power: device.tx_power().await?,
+        class: device.class().await?,
+        icon: device.icon().await?,
+        uuids,
+        // only present when the device exposes the battery service
+        battery_percent: device.battery_percentage().await.ok().flatten(),
+    })
 }
 
-pub async fn bt_devices_handler() -> impl IntoResponse {
-    match run_bluetoothctl(&["devices"]).await {
-        Ok(result) => {
-            let devices = if result.ok {
-                parse_bluetoothctl_devices(&result.stdout, false)
-            } else {
-                Vec::new()
-            };
-
-            let status = if result.ok {
-                StatusCode::OK
-            } else {
-                StatusCode::INTERNAL_SERVER_ERROR
-            };
-
-            (
-                status,
-                Json(json!({
-                    "ok": result.ok,
-                    "devices": devices,
-                    "stdout": result.stdout,
-                    "stderr": result.stderr,
-                })),
-            )
-                .into_response()
+async fn list_devices(paired_only: bool) -> bluer::Result<Vec<BtDevice>> {
+    let adapter = default_adapter().await?;
+    let mut devices = Vec::new();
+    for addr in adapter.device_addresses().await? {
+        let device = adapter.device(addr)?;
+        match device_info(&device).await {
+            Ok(info) if !paired_only || info.paired => devices.push(info),
+            Ok(_) => {}
+            // the device can vanish between listing and reading it
+            Err(e) => debug!("{} skipping {}: {}", NAME, addr, e),
         }
+    }
+    devices.sort_by(|a, b| b.paired.cmp(&a.paired).then(a.mac.cmp(&b.mac)));
+    Ok(devices)
+}
+
+fn devices_response(result: bluer::Result<Vec<BtDevice>>) -> Response {
+    match result {
+        Ok(devices) => Json(json!({
+            "ok": true,
+            "devices": devices,
+        }))
+        .into_response(),
         Err(e) => (
             StatusCode::INTERNAL_SERVER_ERROR,
             Json(json!({
                 "ok": false,
                 "devices": [],
-                "error": e
+                "error": e.to_string(),
             })),
         )
             .into_response(),
     }
 }
 
+pub async fn bt_devices_handler() -> impl IntoResponse {
+    devices_response(list_devices(false).await)
+}
+
 pub async fn bt_paired_devices_handler() -> impl IntoResponse {
-    match run_bluetoothctl(&["devices Paired"]).await {
-        Ok(result) => {
-            let devices = if result.ok {
-                parse_bluetoothctl_devices(&result.stdout, true)
-            } else {
-                Vec::new()
-            };
-
-            let status = if result.ok {
-                StatusCode::OK
-            } else {
-                StatusCode::INTERNAL_SERVER_ERROR
-            };
-
-            (
-                status,
-                Json(json!({
-                    "ok": result.ok,
-                    "devices": devices,
-                    "stdout": result.stdout,
-                    "stderr": result.stderr,
-                })),
-            )
-                .into_response()
+    devices_response(list_devices(true).await)
+}
+
+pub async fn bt_device_handler(Path(mac): Path<String>) -> impl IntoResponse {
+    let addr = match parse_mac(&mac) {
+        Ok(addr) => addr,
+        Err(resp) => return resp,
+    };
+    let result = async {
+        let device = default_adapter().await?.device(addr)?;
+        device_info(&device).await
+    }
+    .await;
+
+    match result {
+        Ok(device) => Json(json!({ "ok": true, "device": device })).into_response(),
+        Err(e) => bluer_error(&mac, e),
+    }
+}
+
+/// Change trust/block state or the alias of a device.
+pub async fn bt_update_device_handler(
+    Path(mac): Path<String>,
+    Json(req): Json<BtDeviceUpdate>,
+) -> impl IntoResponse {
+    let addr = match parse_mac(&mac) {
+        Ok(addr) => addr,
+        Err(resp) => return resp,
+    };
+    let result = async {
+        let device = default_adapter().await?.device(addr)?;
+        if let Some(trusted) = req.trusted {
+            device.set_trusted(trusted).await?;
         }
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({
-                "ok": false,
-                "devices": [],
-                "error": e
-            })),
-        )
-            .into_response(),
+        if let Some(blocked) = req.blocked {
+            device.set_blocked(blocked).await?;
+        }
+        if let Some(alias) = req.alias {
+            device.set_alias(alias).await?;
+        }
+        device_info(&device).await
+    }
+    .await;
+
+    match result {
+        Ok(device) => {
+            info!(
+                "{} updated <b>{}</>: trusted={}, blocked={}, alias={:?}",
+                NAME, mac, device.trusted, device.blocked, device.alias
+            );
+            Json(json!({ "ok": true, "device": device })).into_response()
+        }
+        Err(e) => bluer_error(&mac, e),
     }
 }
 
+/// Remove the device and its pairing from BlueZ.
+pub async fn remove_device(addr: Address) -> bluer::Result<()> {
+    default_adapter().await?.remove_device(addr).await
+}
+
 pub async fn bt_remove_device_handler(Path(mac): Path<String>) -> impl IntoResponse {
-    if !validate_bt_mac(&mac) {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(json!({
-                "ok": false,
-                "error": "Invalid Bluetooth MAC address",
-                "mac": mac
-            })),
-        )
-            .into_response();
-    }
+    let addr = match parse_mac(&mac) {
+        Ok(addr) => addr,
+        Err(resp) => return resp,
+    };
 
-    match run_bluetoothctl(&["remove", &mac]).await {
-        Ok(result) => {
-            let status = if result.ok {
-                StatusCode::OK
-            } else {
-                StatusCode::INTERNAL_SERVER_ERROR
-            };
-
-            (
-                status,
-                Json(json!({
-                    "ok": result.ok,
-                    "mac": mac,
-                    "stdout": result.stdout,
-                    "stderr": result.stderr,
-                })),
-            )
-                .into_response()
+    match remove_device(addr).await {
+        Ok(()) => {
+            info!("{} removed <b>{}</>", NAME, mac);
+            Json(json!({ "ok": true, "mac": mac })).into_response()
         }
-        Err(e) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({
-                "ok": false,
-                "mac": mac,
-                "error": e
-            })),
-        )
-            .into_response(),
+        Err(e) => bluer_error(&mac, e),
     }
 }
 
+/// Pair, trust and connect a device. The response is sent once BlueZ reports
+/// the outcome, i.e. after the passkey was confirmed on both sides.
 pub async fn bt_pair_device_handler(Json(req): Json<BtDeviceRequest>) -> impl IntoResponse {
-    if !validate_bt_mac(&req.mac) {
-        return (
-            StatusCode::BAD_REQUEST,
-            Json(json!({
-                "ok": false,
-                "error": "Invalid Bluetooth MAC address"
-            })),
-        )
-            .into_response();
+    let addr = match parse_mac(&req.mac) {
+        Ok(addr) => addr,
+        Err(resp) => return resp,
+    };
+    let device = match default_adapter()
+        .await
+        .and_then(|adapter| adapter.device(addr))
+    {
+        Ok(device) => device,
+        Err(e) => return bluer_error(&req.mac, e),
+    };
+
+    if !device.is_paired().await.unwrap_or(false) {
+        info!("{} pairing <b>{}</>", NAME, req.mac);
+        match timeout(PAIR_TIMEOUT, device.pair()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => {
+                warn!("{} pairing <b>{}</> failed: {}", NAME, req.mac, e);
+                return bluer_error(&req.mac, e);
+            }
+            Err(_) => {
+                let _ = device.cancel_pairing().await;
+                return error_response(
+                    StatusCode::GATEWAY_TIMEOUT,
+                    &req.mac,
+                    "pairing was not confirmed in time".to_string(),
+                );
+            }
+        }
+    }
+
+    let trust = device.set_trusted(true).await.map_err(|e| e.to_string());
+    let connect = match timeout(CONNECT_TIMEOUT, device.connect()).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(_) => Err("connect timed out".to_string()),
+    };
+    let info = device_info(&device).await.ok();
+    info!(
+        "{} paired <b>{}</>: trust={:?}, connect={:?}",
+        NAME, req.mac, trust, connect
+    );
+
+    Json(json!({
+        "ok": info.as_ref().is_some_and(|d| d.paired),
+        "mac": req.mac,
+        "device": info,
+        "trust": trust.err(),
+        "connect": connect.err(),
+    }))
+    .into_response()
+}
+
+fn publish(ws_event_tx: &BroadcastSender<ServerEvent>, payload: serde_json::Value) {
+    let _ = ws_event_tx.send(ServerEvent {
+        topic: BT_DISCOVERY_TOPIC.to_string(),
+        payload: payload.to_string(),
+    });
+}
+
+async fn run_discovery(
+    adapter: Adapter,
+    cancel: CancellationToken,
+    duration: Duration,
+    ws_event_tx: BroadcastSender<ServerEvent>,
+) -> bluer::Result<()> {
+    // property changes (RSSI, name, ...) are reported as repeated DeviceAdded events
+    let events = adapter.discover_devices_with_changes().await?;
+    tokio::pin!(events);
+    let deadline = tokio::time::sleep(duration);
+    tokio::pin!(deadline);
+
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => break,
+            _ = &mut deadline => break,
+            event = events.next() => match event {
+                Some(AdapterEvent::DeviceAdded(addr)) => match adapter.device(addr) {
+                    Ok(device) => {
+                        if let Ok(info) = device_info(&device).await {
+                            publish(&ws_event_tx, json!({ "event": "found", "device": info }));
+                        }
+                    }
+                    // gone again before we got to it, keep discovering the rest
+                    Err(e) => debug!("{} discovery: skipping {}: {}", NAME, addr, e),
+                },
+                Some(AdapterEvent::DeviceRemoved(addr)) => {
+                    publish(
+                        &ws_event_tx,
+                        json!({ "event": "removed", "mac": addr.to_string() }),
+                    );
+                }
+                Some(AdapterEvent::PropertyChanged(_)) => {}
+                None => break,
+            }
+        }
     }
+    Ok(())
+}
+
+pub async fn bt_discovery_status_handler() -> impl IntoResponse {
+    let active = discovery().lock().unwrap().is_some();
+    Json(json!({ "ok": true, "active": active }))
+}
 
-    let pair = match run_bluetoothctl(&["pair", &req.mac]).await {
-        Ok(r) => r,
-        Err(e) => {
+/// Start a discovery session; results are streamed on the `bt-discovery` ws topic.
+pub async fn bt_discovery_start_handler(
+    State(state): State<Arc<AppState>>,
+    req: Option<Json<BtDiscoveryRequest>>,
+) -> impl IntoResponse {
+    let req = req.map(|Json(req)| req).unwrap_or_default();
+    let seconds = req
+        .seconds
+        .unwrap_or(DEFAULT_DISCOVERY_SECS)
+        .clamp(1, MAX_DISCOVERY_SECS);
+
+    let adapter = match default_adapter().await {
+        Ok(adapter) => adapter,
+        Err(e) => return bluer_error("", e),
+    };
+
+    let (id, cancel) = {
+        let mut active = discovery().lock().unwrap();
+        if active.is_some() {
             return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                Json(json!({ "ok": false, "step": "pair", "error": e })),
+                StatusCode::CONFLICT,
+                Json(json!({ "ok": false, "error": "discovery already running" })),
             )
                 .into_response();
         }
+        let id = DISCOVERY_ID.fetch_add(1, Ordering::Relaxed) + 1;
+        let cancel = CancellationToken::new();
+        *active = Some((id, cancel.clone()));
+        (id, cancel)
     };
 
-    if !pair.ok {
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            Json(json!({
-                "ok": false,
-                "step": "pair",
-                "mac": req.mac,
-                "stdout": pair.stdout,
-                "stderr": pair.stderr,
-            })),
+    info!("{} discovery started for {}s", NAME, seconds);
+    let ws_event_tx = state.ws_event_tx.clone();
+    tokio::spawn(async move {
+        let result = run_discovery(
+            adapter,
+            cancel,
+            Duration::from_secs(seconds),
+            ws_event_tx.clone(),
         )
-            .into_response();
-    }
+        .await;
+        {
+            let mut active = discovery().lock().unwrap();
+            // a newer session may already be registered after a stop
+            if active
+                .as_ref()
+                .is_some_and(|(active_id, _)| *active_id == id)
+            {
+                *active = None;
+            }
+        }
+        let error = result.err().map(|e| e.to_string());
+        if let Some(e) = &error {
+            warn!("{} discovery failed: {}", NAME, e);
+        } else {
+            info!("{} discovery stopped", NAME);
+        }
+        publish(&ws_event_tx, json!({ "event": "stopped", "error": error }));
+    });
+
+    Json(json!({ "ok": true, "seconds": seconds })).into_response()
+}
 
-    let trust = run_bluetoothctl(&["trust", &req.mac]).await.ok();
-    let connect = run_bluetoothctl(&["connect", &req.mac]).await.ok();
+pub async fn bt_discovery_stop_handler() -> impl IntoResponse {
+    let stopped = match discovery().lock().unwrap().take() {
+        Some((_, cancel)) => {
+            cancel.cancel();
+            true
+        }
+        None => false,
+    };
+    Json(json!({ "ok": true, "stopped": stopped }))
+}
 
-    (
-        StatusCode::OK,
-        Json(json!({
-            "ok": true,
-            "mac": req.mac,
-            "pair": pair,
-            "trust": trust,
-            "connect": connect,
-        })),
-    )
-        .into_response()
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_only_colon_separated_addresses() {
+        assert!(validate_bt_mac("AA:BB:CC:dd:ee:01"));
+        assert!(!validate_bt_mac("AA:BB:CC:DD:EE"));
+        assert!(!validate_bt_mac("AA-BB-CC-DD-EE-01"));
+        assert!(!validate_bt_mac("AABBCCDDEE01"));
+        assert!(!validate_bt_mac("AA:BB:CC:DD:EE:0G"));
+        assert!(!validate_bt_mac("AA:BB:CC:DD:EE:001"));
+
+        assert_eq!(
+            parse_mac("AA:BB:CC:DD:EE:01").unwrap(),
+            Address::new([0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x01])
+        );
+        let rejected = parse_mac("not-a-mac").unwrap_err();
+        assert_eq!(rejected.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn maps_bluez_errors_to_http_status() {
+        let status = |kind| {
+            bluer_error(
+                "AA:BB:CC:DD:EE:01",
+                bluer::Error {
+                    kind,
+                    message: String::new(),
+                },
+            )
+            .status()
+        };
+        assert_eq!(
+            status(bluer::ErrorKind::DoesNotExist),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(status(bluer::ErrorKind::InProgress), StatusCode::CONFLICT);
+        assert_eq!(
+            status(bluer::ErrorKind::AuthenticationRejected),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(
+            status(bluer::ErrorKind::Failed),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
 }
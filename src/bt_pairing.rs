@@ -0,0 +1,448 @@
+use crate::web::ServerEvent;
+use bluer::agent::{
+    Agent, AuthorizeService, DisplayPasskey, DisplayPinCode, ReqError, ReqResult,
+    RequestAuthorization, RequestConfirmation, RequestPasskey, RequestPinCode,
+};
+use bluer::{Adapter, Address, Uuid};
+use serde::{Deserialize, Serialize};
+use simplelog::*;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::sync::broadcast::Sender as BroadcastSender;
+use tokio::sync::oneshot;
+
+// module name for logging engine
+const NAME: &str = "<i><bright-black> bt-pairing: </>";
+
+/// Pairing requests and their outcome are published on this ws topic.
+pub const PAIRING_TOPIC: &str = "bt-pairing";
+/// Answers are accepted as JSON `PairingReply` on this ws topic.
+pub const PAIRING_ANSWER_TOPIC: &str = "bt-pairing-answer";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PairingKind {
+    /// Numeric comparison: confirm that the phone shows the same passkey.
+    Confirm,
+    /// Enter the passkey displayed on the remote device.
+    Passkey,
+    /// Legacy PIN code pairing.
+    Pin,
+    /// Just-works pairing without a passkey.
+    Authorize,
+    /// Untrusted device connecting to a service.
+    Service,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PairingRequest {
+    pub id: u64,
+    pub kind: PairingKind,
+    pub mac: String,
+    pub name: Option<String>,
+    /// Six digit passkey to compare, only for `confirm`.
+    pub passkey: Option<String>,
+    /// Service UUID, only for `service`.
+    pub service: Option<String>,
+    pub timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct PairingAnswer {
+    pub accept: bool,
+    /// Required when accepting a `passkey` request.
+    #[serde(default)]
+    pub passkey: Option<u32>,
+    /// Required when accepting a `pin` request.
+    #[serde(default)]
+    pub pin: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PairingReply {
+    pub id: u64,
+    #[serde(flatten)]
+    pub answer: PairingAnswer,
+}
+
+#[derive(Debug)]
+pub enum AnswerError {
+    /// No pending request with this id, it was answered, cancelled or timed out.
+    NotFound,
+    Invalid(&'static str),
+}
+
+impl fmt::Display for AnswerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AnswerError::NotFound => write!(f, "no pending pairing request with this id"),
+            AnswerError::Invalid(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+struct Pending {
+    request: PairingRequest,
+    reply: oneshot::Sender<PairingAnswer>,
+}
+
+#[derive(Default)]
+struct Runtime {
+    pending: BTreeMap<u64, Pending>,
+    ws_event_tx: Option<BroadcastSender<ServerEvent>>,
+}
+
+static RUNTIME: OnceLock<Mutex<Runtime>> = OnceLock::new();
+static REQUEST_ID: AtomicU64 = AtomicU64::new(0);
+
+fn runtime() -> &'static Mutex<Runtime> {
+    RUNTIME.get_or_init(|| Mutex::new(Runtime::default()))
+}
+
+fn publish(runtime: &Runtime, payload: serde_json::Value) {
+    if let Some(tx) = &runtime.ws_event_tx {
+        let _ = tx.send(ServerEvent {
+            topic: PAIRING_TOPIC.to_string(),
+            payload: payload.to_string(),
+        });
+    }
+}
+
+/// Requests currently waiting for an answer, oldest first.
+pub fn pending() -> Vec<PairingRequest> {
+    runtime()
+        .lock()
+        .unwrap()
+        .pending
+        .values()
+        .map(|p| p.request.clone())
+        .collect()
+}
+
+/// Answer pending request `id`. The agent callback replies to BlueZ as soon as
+/// the answer arrives.
+pub fn answer(id: u64, answer: PairingAnswer) -> Result<(), AnswerError> {
+    let mut runtime = runtime().lock().unwrap();
+    let kind = runtime
+        .pending
+        .get(&id)
+        .ok_or(AnswerError::NotFound)?
+        .request
+        .kind;
+    validate(kind, &answer)?;
+
+    let pending = runtime.pending.remove(&id).ok_or(AnswerError::NotFound)?;
+    info!(
+        "{} {} pairing request {} from {}",
+        NAME,
+        if answer.accept {
+            "accepted"
+        } else {
+            "rejected"
+        },
+        id,
+        pending.request.mac
+    );
+    publish(
+        &runtime,
+        serde_json::json!({
+            "event": "resolved",
+            "id": id,
+            "accepted": answer.accept,
+            "reason": "answered",
+        }),
+    );
+    // the callback may have been cancelled by BlueZ in the meantime
+    let _ = pending.reply.send(answer);
+    Ok(())
+}
+
+fn validate(kind: PairingKind, answer: &PairingAnswer) -> Result<(), AnswerError> {
+    if !answer.accept {
+        return Ok(());
+    }
+    match kind {
+        PairingKind::Passkey if !answer.passkey.is_some_and(|p| p <= 999_999) => Err(
+            AnswerError::Invalid("passkey must be a number between 0 and 999999"),
+        ),
+        PairingKind::Pin
+            if !answer
+                .pin
+                .as_ref()
+                .is_some_and(|p| (1..=16).contains(&p.len())) =>
+        {
+            Err(AnswerError::Invalid("pin must be 1 to 16 characters"))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Publish pairing events and accept answers on the `bt-pairing-answer` ws topic.
+pub async fn ws_listener(ws_event_tx: BroadcastSender<ServerEvent>) {
+    runtime().lock().unwrap().ws_event_tx = Some(ws_event_tx.clone());
+    let mut rx = ws_event_tx.subscribe();
+    loop {
+        let ev = match rx.recv().await {
+            Ok(ev) => ev,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+        };
+        if ev.topic != PAIRING_ANSWER_TOPIC {
+            continue;
+        }
+        match serde_json::from_str::<PairingReply>(&ev.payload) {
+            Ok(reply) => {
+                if let Err(e) = answer(reply.id, reply.answer) {
+                    warn!("{} ws answer for {} failed: {}", NAME, reply.id, e);
+                }
+            }
+            Err(e) => warn!("{} invalid ws answer: {}", NAME, e),
+        }
+    }
+}
+
+/// Removes the request when the callback finishes without an answer: on
+/// timeout, or when BlueZ cancels the pairing and drops the future.
+struct PendingGuard {
+    id: u64,
+    reason: &'static str,
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        let mut runtime = runtime().lock().unwrap();
+        if runtime.pending.remove(&self.id).is_some() {
+            info!("{} pairing request {} {}", NAME, self.id, self.reason);
+            publish(
+                &runtime,
+                serde_json::json!({
+                    "event": "resolved",
+                    "id": self.id,
+                    "accepted": false,
+                    "reason": self.reason,
+                }),
+            );
+        }
+    }
+}
+
+async fn device_name(adapter: &Adapter, address: Address) -> Option<String> {
+    adapter.device(address).ok()?.name().await.ok().flatten()
+}
+
+/// Publish a request and wait for its answer. Unanswered requests are rejected
+/// after `timeout`.
+async fn ask(
+    adapter: Adapter,
+    timeout: Duration,
+    kind: PairingKind,
+    device: Address,
+    passkey: Option<u32>,
+    service: Option<Uuid>,
+) -> ReqResult<PairingAnswer> {
+    let id = REQUEST_ID.fetch_add(1, Ordering::Relaxed) + 1;
+    let request = PairingRequest {
+        id,
+        kind,
+        mac: device.to_string(),
+        name: device_name(&adapter, device).await,
+        passkey: passkey.map(|p| format!("{:06}", p)),
+        service: service.map(|u| u.to_string()),
+        timeout_secs: timeout.as_secs(),
+    };
+    info!(
+        "{} 🔑 {:?} request {} from {} ({})",
+        NAME,
+        kind,
+        id,
+        request.mac,
+        request.name.as_deref().unwrap_or("unknown")
+    );
+
+    let (tx, rx) = oneshot::channel();
+    {
+        let mut runtime = runtime().lock().unwrap();
+        if runtime.ws_event_tx.is_none() {
+            warn!(
+                "{} no web clients can be notified, answer via REST /bt/pairing",
+                NAME
+            );
+        }
+        publish(
+            &runtime,
+            serde_json::json!({
+                "event": "request",
+                "request": request,
+            }),
+        );
+        runtime.pending.insert(id, Pending { request, reply: tx });
+    }
+    let mut guard = PendingGuard {
+        id,
+        reason: "cancelled",
+    };
+
+    match tokio::time::timeout(timeout, rx).await {
+        Ok(Ok(answer)) if answer.accept => Ok(answer),
+        Ok(Ok(_)) => Err(ReqError::Rejected),
+        Ok(Err(_)) => Err(ReqError::Canceled),
+        Err(_) => {
+            guard.reason = "timed out";
+            Err(ReqError::Rejected)
+        }
+    }
+}
+
+fn display(kind: &str, device: Address, code: String, entered: Option<u16>) {
+    info!("{} 🔑 {} for {}: {}", NAME, kind, device, code);
+    publish(
+        &runtime().lock().unwrap(),
+        serde_json::json!({
+            "event": "display",
+            "kind": kind,
+            "mac": device.to_string(),
+            "code": code,
+            "entered": entered,
+        }),
+    );
+}
+
+/// Build the default BlueZ agent that forwards every pairing decision to the
+/// web UI. Trusted devices are authorized for services without asking.
+pub fn agent(adapter: Adapter, timeout: Duration) -> Agent {
+    let (a1, a2, a3, a4, a5) = (
+        adapter.clone(),
+        adapter.clone(),
+        adapter.clone(),
+        adapter.clone(),
+        adapter,
+    );
+
+    Agent {
+        request_default: true,
+        request_pin_code: Some(Box::new(move |req: RequestPinCode| {
+            let adapter = a1.clone();
+            Box::pin(async move {
+                let answer =
+                    ask(adapter, timeout, PairingKind::Pin, req.device, None, None).await?;
+                answer.pin.ok_or(ReqError::Rejected)
+            })
+        })),
+        display_pin_code: Some(Box::new(|req: DisplayPinCode| {
+            display("pin", req.device, req.pincode, None);
+            Box::pin(async { Ok(()) })
+        })),
+        request_passkey: Some(Box::new(move |req: RequestPasskey| {
+            let adapter = a2.clone();
+            Box::pin(async move {
+                let answer = ask(
+                    adapter,
+                    timeout,
+                    PairingKind::Passkey,
+                    req.device,
+                    None,
+                    None,
+                )
+                .await?;
+                answer.passkey.ok_or(ReqError::Rejected)
+            })
+        })),
+        display_passkey: Some(Box::new(|req: DisplayPasskey| {
+            display(
+                "passkey",
+                req.device,
+                format!("{:06}", req.passkey),
+                Some(req.entered),
+            );
+            Box::pin(async { Ok(()) })
+        })),
+        request_confirmation: Some(Box::new(move |req: RequestConfirmation| {
+            let adapter = a3.clone();
+            Box::pin(async move {
+                ask(
+                    adapter,
+                    timeout,
+                    PairingKind::Confirm,
+                    req.device,
+                    Some(req.passkey),
+                    None,
+                )
+                .await
+                .map(|_| ())
+            })
+        })),
+        request_authorization: Some(Box::new(move |req: RequestAuthorization| {
+            let adapter = a4.clone();
+            Box::pin(async move {
+                ask(
+                    adapter,
+                    timeout,
+                    PairingKind::Authorize,
+                    req.device,
+                    None,
+                    None,
+                )
+                .await
+                .map(|_| ())
+            })
+        })),
+        authorize_service: Some(Box::new(move |req: AuthorizeService| {
+            let adapter = a5.clone();
+            Box::pin(async move {
+                let trusted = match adapter.device(req.device) {
+                    Ok(device) => device.is_trusted().await.unwrap_or(false),
+                    Err(_) => false,
+                };
+                if trusted {
+                    return Ok(());
+                }
+                ask(
+                    adapter,
+                    timeout,
+                    PairingKind::Service,
+                    req.device,
+                    None,
+                    Some(req.service),
+                )
+                .await
+                .map(|_| ())
+            })
+        })),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepting_requires_the_requested_secret() {
+        let accept = |passkey, pin: Option<&str>| PairingAnswer {
+            accept: true,
+            passkey,
+            pin: pin.map(str::to_string),
+        };
+
+        assert!(validate(PairingKind::Confirm, &accept(None, None)).is_ok());
+        assert!(validate(PairingKind::Passkey, &accept(Some(123_456), None)).is_ok());
+        assert!(validate(PairingKind::Passkey, &accept(None, None)).is_err());
+        assert!(validate(PairingKind::Passkey, &accept(Some(1_000_000), None)).is_err());
+        assert!(validate(PairingKind::Pin, &accept(None, Some("0000"))).is_ok());
+        assert!(validate(PairingKind::Pin, &accept(None, Some(""))).is_err());
+        // rejecting never needs a secret
+        assert!(validate(PairingKind::Pin, &PairingAnswer::default()).is_ok());
+    }
+
+    #[test]
+    fn reply_accepts_flat_json() {
+        let reply: PairingReply =
+            serde_json::from_str(r#"{"id":3,"accept":true,"passkey":42}"#).unwrap();
+        assert_eq!(reply.id, 3);
+        assert!(reply.answer.accept);
+        assert_eq!(reply.answer.passkey, Some(42));
+    }
+}
@@ -1,11 +1,16 @@
 use crate::bt_sco_echo::{self, BtScoEchoSettings};
-use crate::config::BtScoMediaBridgeResampler;
+use crate::bt_sco_msbc::{MsbcDecoder, MsbcEncoder, MSBC_FRAME_SAMPLES};
+use crate::bt_sco_tap::CallTap;
+use crate::bt_sco_voice::{self, BtScoVoiceSettings};
+use crate::config::{BtScoCodec, BtScoMediaBridgeResampler};
+use crate::resampler::Resampler;
 use simplelog::*;
 use std::collections::{BTreeMap, VecDeque};
 use std::io;
 use std::mem;
 use std::os::unix::io::RawFd;
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
 use std::sync::{Arc, Mutex, OnceLock};
 use std::thread;
 use std::time::{Duration, Instant};
@@ -21,10 +26,14 @@ const SOL_BLUETOOTH: libc::c_int = 274;
 
 const SCO_OPTIONS: libc::c_int = 0x01;
 const SCO_CONNINFO: libc::c_int = 0x02;
+const BT_DEFER_SETUP: libc::c_int = 7;
 const BT_VOICE: libc::c_int = 11;
 const BT_SNDMTU: libc::c_int = 12;
 const BT_RCVMTU: libc::c_int = 13;
 
+const BT_VOICE_CVSD_16BIT: u16 = 0x0060;
+const BT_VOICE_TRANSPARENT: u16 = 0x0003;
+
 /// Target size for bridge chunks: about 20ms of 48kHz stereo s16le PCM.
 /// The SCO packet cadence produces 60-byte input packets, so actual chunks are
 /// usually 22.5ms / 4320 bytes after the 6x stereo expansion.
@@ -33,6 +42,42 @@ const DEFAULT_AA_PCM_RING_CAPACITY: usize = 128;
 pub const SCO_UPLINK_PACKET_BYTES: usize = 60;
 const DEFAULT_SCO_UPLINK_RING_CAPACITY: usize = 256;
 const SCO_DOWNLINK_AUDIO_LOG_PEAK_THRESHOLD: i16 = 64;
+/// A transparent SCO stream without any H2-framed mSBC frame after this many
+/// packets is dropped and CVSD renegotiated.
+const MSBC_SYNC_TIMEOUT_PACKETS: u64 = 50;
+
+/// Voice codec carried by the active SCO connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScoVoiceCodec {
+    /// 8 kHz linear PCM, CVSD-coded on air by the controller.
+    Cvsd,
+    /// 16 kHz mSBC in transparent mode, coded by aa-proxy-rs.
+    Msbc,
+}
+
+impl ScoVoiceCodec {
+    fn as_u8(self) -> u8 {
+        match self {
+            Self::Cvsd => 1,
+            Self::Msbc => 2,
+        }
+    }
+
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(Self::Cvsd),
+            2 => Some(Self::Msbc),
+            _ => None,
+        }
+    }
+
+    pub fn sample_rate_hz(self) -> u32 {
+        match self {
+            Self::Cvsd => SCO_LINEAR_PCM_SAMPLE_RATE_HZ,
+            Self::Msbc => SCO_MSBC_SAMPLE_RATE_HZ,
+        }
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct AaPcmFrame {
@@ -45,6 +90,18 @@ static SCO_UPLINK_RING: OnceLock<Arc<Mutex<VecDeque<Vec<u8>>>>> = OnceLock::new(
 static SCO_UPLINK_PENDING: OnceLock<Arc<Mutex<Vec<u8>>>> = OnceLock::new();
 static SCO_CONNECTED: AtomicBool = AtomicBool::new(false);
 static SCO_GENERATION: AtomicU64 = AtomicU64::new(0);
+static SCO_CODEC: AtomicU8 = AtomicU8::new(0);
+static NEGOTIATED_CODEC: AtomicU8 = AtomicU8::new(0);
+static MSBC_UPLINK: OnceLock<Mutex<MsbcUplink>> = OnceLock::new();
+static MIC_UPLINK_RESAMPLER: OnceLock<Mutex<Option<Resampler>>> = OnceLock::new();
+
+/// Uplink encoder state for wideband connections, fed with mic samples
+/// already processed at 16 kHz.
+#[derive(Default)]
+struct MsbcUplink {
+    encoder: MsbcEncoder,
+    pending: Vec<i16>,
+}
 
 #[derive(Debug, Clone)]
 pub struct BtScoOptions {
@@ -67,6 +124,14 @@ pub struct BtScoOptions {
     pub sco_uplink_ring_capacity: usize,
     /// Microphone echo handling settings for SCO uplink.
     pub echo_settings: BtScoEchoSettings,
+    /// Noise suppression and AGC settings for SCO uplink, applied after echo control.
+    pub voice_settings: BtScoVoiceSettings,
+    /// SCO voice codec. Anything but `Cvsd` defers incoming SCO connections so
+    /// the voice setting can be chosen per connection.
+    pub codec: BtScoCodec,
+    /// Record every SCO generation as a stereo WAV (left downlink, right
+    /// uplink) into this directory.
+    pub record_dir: Option<PathBuf>,
 }
 
 #[repr(C)]
@@ -226,6 +291,7 @@ impl ScoStats {
 /// bridges downlink/uplink audio to Android Auto media/microphone channels.
 pub fn spawn(options: BtScoOptions) -> io::Result<thread::JoinHandle<()>> {
     bt_sco_echo::configure(options.echo_settings.clone());
+    bt_sco_voice::configure(options.voice_settings.clone());
 
     if options.bridge_aa_media_pcm {
         enable_aa_pcm_ring();
@@ -245,10 +311,13 @@ pub fn spawn(options: BtScoOptions) -> io::Result<thread::JoinHandle<()>> {
 
 fn run(options: BtScoOptions) -> io::Result<()> {
     let listener = create_sco_listener()?;
+    let deferred = options.codec != BtScoCodec::Cvsd && enable_defer_setup(listener);
 
     debug!(
-        "{} listening for incoming SCO/eSCO audio, bridge_aa_media_pcm={}, media_ring_capacity={}, bridge_sco_uplink_pcm={}, uplink_ring_capacity={}",
+        "{} listening for incoming SCO/eSCO audio, codec={}, deferred_setup={}, bridge_aa_media_pcm={}, media_ring_capacity={}, bridge_sco_uplink_pcm={}, uplink_ring_capacity={}",
         NAME,
+        options.codec,
+        deferred,
         options.bridge_aa_media_pcm,
         effective_ring_capacity(options.bridge_ring_capacity),
         options.bridge_sco_uplink_pcm,
@@ -278,23 +347,34 @@ fn run(options: BtScoOptions) -> io::Result<()> {
             continue;
         }
 
+        let codec = if deferred {
+            let negotiated = ScoVoiceCodec::from_u8(NEGOTIATED_CODEC.load(Ordering::SeqCst));
+            accept_deferred_sco(fd, wants_msbc(options.codec, negotiated))
+        } else {
+            ScoVoiceCodec::Cvsd
+        };
+
         let generation = SCO_GENERATION.fetch_add(1, Ordering::SeqCst) + 1;
         clear_aa_pcm_queue();
+        SCO_CODEC.store(codec.as_u8(), Ordering::SeqCst);
         SCO_CONNECTED.store(true, Ordering::SeqCst);
         clear_sco_uplink_queue();
         bt_sco_echo::reset_for_sco_generation(generation);
+        bt_sco_voice::reset_for_sco_generation(generation);
 
         debug!(
-            "{} SCO/eSCO connected from {}, generation={}",
+            "{} SCO/eSCO connected from {}, generation={}, codec={:?}",
             NAME,
             format_bdaddr(peer.sco_bdaddr),
-            generation
+            generation,
+            codec
         );
         log_sco_socket_info(fd);
 
-        handle_sco_connection(fd, &options, generation);
+        handle_sco_connection(fd, &options, generation, codec);
 
         SCO_CONNECTED.store(false, Ordering::SeqCst);
+        SCO_CODEC.store(0, Ordering::SeqCst);
         clear_aa_pcm_queue();
         clear_sco_uplink_queue();
     }
@@ -341,7 +421,66 @@ fn create_sco_listener() -> io::Result<RawFd> {
     Ok(fd)
 }
 
-fn handle_sco_connection(fd: RawFd, options: &BtScoOptions, generation: u64) {
+fn enable_defer_setup(listener: RawFd) -> bool {
+    match setsockopt_value::<libc::c_int>(listener, SOL_BLUETOOTH, BT_DEFER_SETUP, 1) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!(
+                "{} BT_DEFER_SETUP unavailable, wideband SCO disabled, falling back to CVSD: {}",
+                NAME, e
+            );
+            false
+        }
+    }
+}
+
+/// Whether the next deferred SCO connection should be accepted as mSBC.
+/// A codec negotiated over HFP always wins; `Msbc` only assumes wideband when
+/// nothing was negotiated.
+fn wants_msbc(codec: BtScoCodec, negotiated: Option<ScoVoiceCodec>) -> bool {
+    match codec {
+        BtScoCodec::Cvsd => false,
+        BtScoCodec::Msbc => negotiated != Some(ScoVoiceCodec::Cvsd),
+        BtScoCodec::Auto => negotiated == Some(ScoVoiceCodec::Msbc),
+    }
+}
+
+/// Pick the voice setting of a deferred SCO connection and accept it.
+fn accept_deferred_sco(fd: RawFd, msbc: bool) -> ScoVoiceCodec {
+    let setting = if msbc {
+        BT_VOICE_TRANSPARENT
+    } else {
+        BT_VOICE_CVSD_16BIT
+    };
+    let codec = match setsockopt_value(fd, SOL_BLUETOOTH, BT_VOICE, BtVoice { setting }) {
+        Ok(()) if msbc => ScoVoiceCodec::Msbc,
+        Ok(()) => ScoVoiceCodec::Cvsd,
+        Err(e) => {
+            if msbc {
+                warn!(
+                    "{} transparent SCO not supported by the controller, falling back to CVSD: {}",
+                    NAME, e
+                );
+            }
+            ScoVoiceCodec::Cvsd
+        }
+    };
+
+    // With BT_DEFER_SETUP the kernel accepts the connection on the first read,
+    // which returns 0 without consuming any audio.
+    let mut byte = 0u8;
+    let rc = unsafe { libc::read(fd, &mut byte as *mut u8 as *mut libc::c_void, 1) };
+    if rc < 0 {
+        warn!(
+            "{} deferred SCO accept failed: {}",
+            NAME,
+            io::Error::last_os_error()
+        );
+    }
+    codec
+}
+
+fn handle_sco_connection(fd: RawFd, options: &BtScoOptions, generation: u64, codec: ScoVoiceCodec) {
     let started = Instant::now();
 
     let mut buf = [0u8; 2048];
@@ -360,6 +499,14 @@ fn handle_sco_connection(fd: RawFd, options: &BtScoOptions, generation: u64) {
     let mut uplink_mic_packets = 0u64;
     let mut uplink_silence_packets = 0u64;
     let mut uplink_write_errors = 0u64;
+    let mut msbc_decoder = (codec == ScoVoiceCodec::Msbc).then(MsbcDecoder::new);
+    let mut decoded: Vec<i16> = Vec::with_capacity(MSBC_FRAME_SAMPLES * 2);
+    let mut decoded_pcm: Vec<u8> = Vec::with_capacity(MSBC_FRAME_SAMPLES * 4);
+    let mut msbc_given_up = false;
+    let mut call_tap = CallTap::new(generation, options.record_dir.as_deref());
+    // decodes the mSBC packets written toward the phone, only while tapped
+    let mut uplink_decoder = MsbcDecoder::new();
+    let mut uplink_decoded: Vec<i16> = Vec::with_capacity(MSBC_FRAME_SAMPLES * 2);
 
     loop {
         let n = unsafe {
@@ -384,8 +531,45 @@ fn handle_sco_connection(fd: RawFd, options: &BtScoOptions, generation: u64) {
 
         let n = n as usize;
         stats.observe(n, now);
-        let (packet_peak, packet_energy, packet_samples, packet_rms) =
-            audio_metrics_s16le(&buf[..n]);
+
+        if !msbc_given_up
+            && msbc_decoder
+                .as_ref()
+                .is_some_and(|decoder| decoder.frames() == 0)
+            && stats.packets > MSBC_SYNC_TIMEOUT_PACKETS
+        {
+            // The socket stays in transparent mode, so CVSD needs a new link:
+            // the next deferred accept uses the CVSD voice setting.
+            set_negotiated_codec(Some(ScoVoiceCodec::Cvsd));
+            if crate::hfp::request_narrowband() {
+                warn!(
+                    "{} no mSBC frames after {} transparent SCO packets, closing the link for CVSD",
+                    NAME,
+                    stats.packets - 1
+                );
+                break;
+            }
+            // without HFP nothing would set the link up again, keep it for
+            // the rest of the call
+            warn!(
+                "{} no mSBC frames after {} transparent SCO packets and no HFP to renegotiate, keeping the link, the next one uses CVSD",
+                NAME,
+                stats.packets - 1
+            );
+            msbc_given_up = true;
+        }
+        let wideband = msbc_decoder.is_some();
+        if let Some(decoder) = msbc_decoder.as_mut() {
+            decoded.clear();
+            decoder.push(&buf[..n], &mut decoded);
+            decoded_pcm.clear();
+            for sample in &decoded {
+                decoded_pcm.extend_from_slice(&sample.to_le_bytes());
+            }
+        }
+        let pcm: &[u8] = if wideband { &decoded_pcm } else { &buf[..n] };
+
+        let (packet_peak, packet_energy, packet_samples, packet_rms) = audio_metrics_s16le(pcm);
         audio_window_peak = audio_window_peak.max(packet_peak);
         audio_window_energy = audio_window_energy.saturating_add(packet_energy);
         audio_window_samples = audio_window_samples.saturating_add(packet_samples);
@@ -418,15 +602,29 @@ fn handle_sco_connection(fd: RawFd, options: &BtScoOptions, generation: u64) {
             );
         }
 
-        bt_sco_echo::observe_downlink_sco_8k_mono(&buf[..n]);
+        let pcm_rate = if wideband {
+            SCO_MSBC_SAMPLE_RATE_HZ
+        } else {
+            SCO_LINEAR_PCM_SAMPLE_RATE_HZ
+        };
+        bt_sco_echo::observe_downlink_sco_mono(pcm, pcm_rate);
 
         if options.bridge_aa_media_pcm {
-            sco_s16le_mono_8k_to_aa_pcm_s16le_stereo_48k(
-                &buf[..n],
-                &mut aa_pcm_chunk,
-                options.media_resampler,
-                &mut downlink_resampler_state,
-            );
+            if wideband {
+                sco_s16le_mono_16k_to_aa_pcm_s16le_stereo_48k(
+                    pcm,
+                    &mut aa_pcm_chunk,
+                    options.media_resampler,
+                    &mut downlink_resampler_state,
+                );
+            } else {
+                sco_s16le_mono_8k_to_aa_pcm_s16le_stereo_48k(
+                    pcm,
+                    &mut aa_pcm_chunk,
+                    options.media_resampler,
+                    &mut downlink_resampler_state,
+                );
+            }
             if aa_pcm_chunk.len() >= AA_MEDIA_PCM_TARGET_CHUNK_BYTES {
                 push_aa_pcm_frame(
                     std::mem::take(&mut aa_pcm_chunk),
@@ -436,9 +634,16 @@ fn handle_sco_connection(fd: RawFd, options: &BtScoOptions, generation: u64) {
             }
         }
 
+        let tap_active = call_tap.is_active();
+        let mut tapped_uplink: Option<Vec<u8>> = None;
+
         if options.bridge_sco_uplink_pcm {
             let (uplink, from_mic) = match pop_sco_uplink_frame(n) {
                 Some(frame) => (frame, true),
+                None if wideband => (
+                    msbc_uplink_silence(n, options.sco_uplink_ring_capacity),
+                    false,
+                ),
                 None => (vec![0u8; n], false),
             };
             let written = unsafe {
@@ -464,6 +669,28 @@ fn handle_sco_connection(fd: RawFd, options: &BtScoOptions, generation: u64) {
                     uplink_silence_packets += 1;
                 }
             }
+
+            if tap_active && wideband {
+                uplink_decoded.clear();
+                uplink_decoder.push(&uplink, &mut uplink_decoded);
+                tapped_uplink = Some(
+                    uplink_decoded
+                        .iter()
+                        .flat_map(|s| s.to_le_bytes())
+                        .collect(),
+                );
+            } else if tap_active {
+                tapped_uplink = Some(uplink);
+            }
+        }
+
+        if tap_active {
+            let sample_rate = if wideband {
+                SCO_MSBC_SAMPLE_RATE_HZ
+            } else {
+                SCO_LINEAR_PCM_SAMPLE_RATE_HZ
+            };
+            call_tap.push(sample_rate, pcm, tapped_uplink.as_deref());
         }
 
         if last_log.elapsed() >= Duration::from_secs(5) {
@@ -509,6 +736,16 @@ fn handle_sco_connection(fd: RawFd, options: &BtScoOptions, generation: u64) {
     unsafe {
         libc::close(fd);
     }
+    call_tap.finish();
+
+    if let Some(decoder) = &msbc_decoder {
+        debug!(
+            "{} mSBC downlink: frames={}, concealed={}",
+            NAME,
+            decoder.frames(),
+            decoder.errors()
+        );
+    }
 
     debug!(
         "{} SCO disconnected: {}, uplink[written={}, mic_packets={}, silence_packets={}, write_errors={}, queued={}], elapsed={}s",
@@ -633,6 +870,17 @@ pub fn sco_generation() -> u64 {
     SCO_GENERATION.load(Ordering::SeqCst)
 }
 
+/// Codec of the active SCO connection, `None` while disconnected.
+pub fn sco_codec() -> Option<ScoVoiceCodec> {
+    ScoVoiceCodec::from_u8(SCO_CODEC.load(Ordering::SeqCst))
+}
+
+/// Record the codec selected by HFP codec negotiation (`+BCS`), used for the
+/// next SCO connection in `auto` mode. `None` clears it, e.g. on HFP disconnect.
+pub fn set_negotiated_codec(codec: Option<ScoVoiceCodec>) {
+    NEGOTIATED_CODEC.store(codec.map_or(0, ScoVoiceCodec::as_u8), Ordering::SeqCst);
+}
+
 pub fn clear_sco_uplink_queue() {
     if let Some(ring) = SCO_UPLINK_RING.get() {
         ring.lock().unwrap().clear();
@@ -640,6 +888,44 @@ pub fn clear_sco_uplink_queue() {
     if let Some(pending) = SCO_UPLINK_PENDING.get() {
         pending.lock().unwrap().clear();
     }
+    if let Some(uplink) = MSBC_UPLINK.get() {
+        *uplink.lock().unwrap() = MsbcUplink::default();
+    }
+    if let Some(resampler) = MIC_UPLINK_RESAMPLER.get() {
+        *resampler.lock().unwrap() = None;
+    }
+}
+
+fn msbc_uplink() -> &'static Mutex<MsbcUplink> {
+    MSBC_UPLINK.get_or_init(|| Mutex::new(MsbcUplink::default()))
+}
+
+/// Queue processed 16 kHz mic samples as mSBC packets.
+fn push_msbc_uplink_samples(samples_16k: &[i16], capacity: usize) {
+    let mut packets = Vec::new();
+    {
+        let mut uplink = msbc_uplink().lock().unwrap();
+        uplink.pending.extend_from_slice(samples_16k);
+        while uplink.pending.len() >= MSBC_FRAME_SAMPLES {
+            let frame: Vec<i16> = uplink.pending.drain(..MSBC_FRAME_SAMPLES).collect();
+            packets.push(uplink.encoder.encode_packet(&frame));
+        }
+    }
+    for packet in packets {
+        push_sco_uplink_packet(packet.to_vec(), capacity);
+    }
+}
+
+/// Transparent SCO cannot carry zero bytes as silence; queue an encoded silent
+/// mSBC frame instead so the phone's decoder stays in sync.
+fn msbc_uplink_silence(len: usize, capacity: usize) -> Vec<u8> {
+    let packet = msbc_uplink()
+        .lock()
+        .unwrap()
+        .encoder
+        .encode_packet(&[0; MSBC_FRAME_SAMPLES]);
+    push_sco_uplink_packet(packet.to_vec(), capacity);
+    pop_sco_uplink_frame(len).unwrap_or_else(|| vec![0u8; len])
 }
 
 fn push_sco_uplink_packet(packet: Vec<u8>, capacity: usize) {
@@ -683,9 +969,14 @@ fn sco_uplink_queue_len() -> usize {
 /// Convert Android Auto microphone/source PCM to the SCO uplink format observed on
 /// the target setup: signed 16-bit little-endian, mono, 8 kHz, 60-byte packets.
 ///
-/// Current HUs usually expose the mic source as 16 kHz mono PCM, so the common
-/// path simply keeps every second sample. For 48 kHz sources it keeps every sixth
-/// sample. If the source is already 8 kHz, it passes samples through.
+/// Current HUs usually expose the mic source as 16 kHz mono PCM. Any source rate
+/// and channel count goes through the windowed-sinc resampler, which low-pass
+/// filters before decimating so HU mic noise above 4 kHz does not alias into
+/// the call. If the source is already 8 kHz mono, samples pass through.
+///
+/// On wideband (mSBC) connections the mic is resampled to 16 kHz instead, so
+/// echo control and noise suppression run on the full band, and queued as
+/// H2-framed mSBC packets.
 pub fn push_sco_uplink_pcm_from_aa_mic(
     input: &[u8],
     sample_rate: u32,
@@ -714,30 +1005,49 @@ pub fn push_sco_uplink_pcm_from_aa_mic(
         return;
     }
 
-    let step = if sample_rate >= SCO_LINEAR_PCM_SAMPLE_RATE_HZ {
-        (sample_rate / SCO_LINEAR_PCM_SAMPLE_RATE_HZ).max(1) as usize
+    let samples: Vec<i16> = input
+        .chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+        .collect();
+    let wideband = sco_codec() == Some(ScoVoiceCodec::Msbc);
+    let sco_rate = if wideband {
+        SCO_MSBC_SAMPLE_RATE_HZ
     } else {
-        1
+        SCO_LINEAR_PCM_SAMPLE_RATE_HZ
     };
+    let mut sco_samples: Vec<i16> = Vec::with_capacity(samples.len() / 2 + 1);
+    {
+        let mut resampler = MIC_UPLINK_RESAMPLER
+            .get_or_init(|| Mutex::new(None))
+            .lock()
+            .unwrap();
+        // Stereo/dual-mic PCM is downmixed to mono by the resampler.
+        if !resampler
+            .as_ref()
+            .is_some_and(|r| r.matches(sample_rate, sco_rate, channels, 1))
+        {
+            *resampler = Some(Resampler::new(sample_rate, sco_rate, channels, 1));
+        }
+        if let Some(resampler) = resampler.as_mut() {
+            resampler.process(&samples, &mut sco_samples);
+        }
+    }
 
-    let frames = input.len() / frame_bytes;
-    let mut samples_8k: Vec<i16> = Vec::with_capacity(frames / step + 1);
-
-    for frame_idx in (0..frames).step_by(step) {
-        let offset = frame_idx * frame_bytes;
-        // Use the first channel when the HU ever exposes stereo/dual-mic PCM.
-        samples_8k.push(i16::from_le_bytes([input[offset], input[offset + 1]]));
+    bt_sco_echo::process_mic_samples(&mut sco_samples, sco_rate);
+    bt_sco_voice::process_mic_samples(&mut sco_samples);
+    if sco_samples.is_empty() {
+        return;
     }
 
-    bt_sco_echo::process_mic_8k_samples(&mut samples_8k);
-    if samples_8k.is_empty() {
+    if wideband {
+        push_msbc_uplink_samples(&sco_samples, capacity);
         return;
     }
 
     let pending_arc = enable_sco_uplink_pending();
     let mut pending = pending_arc.lock().unwrap();
 
-    for sample in samples_8k {
+    for sample in sco_samples {
         pending.extend_from_slice(&sample.to_le_bytes());
 
         while pending.len() >= SCO_UPLINK_PACKET_BYTES {
@@ -836,6 +1146,29 @@ fn getsockopt_int(fd: RawFd, level: libc::c_int, optname: libc::c_int) -> io::Re
     getsockopt_value::<libc::c_int>(fd, level, optname)
 }
 
+fn setsockopt_value<T: Copy>(
+    fd: RawFd,
+    level: libc::c_int,
+    optname: libc::c_int,
+    value: T,
+) -> io::Result<()> {
+    let rc = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            optname,
+            &value as *const T as *const libc::c_void,
+            mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+
+    if rc < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
 fn describe_bt_voice(setting: u16) -> &'static str {
     // These are the common HCI voice-setting low bits used by Linux/BlueZ.
     // Exact transport codec is still best confirmed with HCI events, but this
@@ -844,7 +1177,7 @@ fn describe_bt_voice(setting: u16) -> &'static str {
         0x0000 => "linear/input coding",
         0x0001 => "u-law/input coding",
         0x0002 => "a-law/input coding",
-        0x0003 => "reserved/input coding",
+        0x0003 => "transparent data (mSBC wideband)",
         _ => "unknown",
     }
 }
@@ -858,15 +1191,19 @@ pub const SCO_LINEAR_PCM_SAMPLE_RATE_HZ: u32 = 8_000;
 pub const SCO_LINEAR_PCM_CHANNELS: u16 = 1;
 pub const SCO_LINEAR_PCM_BITS_PER_SAMPLE: u16 = 16;
 
+/// Wideband SCO: transparent mode carrying mSBC, decoded to 16 kHz s16 mono.
+pub const SCO_MSBC_SAMPLE_RATE_HZ: u32 = crate::bt_sco_msbc::MSBC_SAMPLE_RATE_HZ;
+
 /// Target format for the HU media sink that advertised PCM MEDIA:
 /// 48 kHz, signed 16-bit, stereo.
 pub const AA_MEDIA_PCM_SAMPLE_RATE_HZ: u32 = 48_000;
 pub const AA_MEDIA_PCM_CHANNELS: u16 = 2;
 pub const AA_MEDIA_PCM_BITS_PER_SAMPLE: u16 = 16;
 
-#[derive(Debug, Default)]
+#[derive(Default)]
 pub struct DownlinkResamplerState {
     last_sample: Option<i16>,
+    sinc: Option<Resampler>,
 }
 
 /// Convert one chunk of SCO linear PCM (`s16le`, mono, 8 kHz) into the
@@ -875,51 +1212,90 @@ pub struct DownlinkResamplerState {
 /// The default `Repeat` mode preserves the proven first implementation: each
 /// 8 kHz sample becomes six identical 48 kHz stereo frames. `Linear` keeps the
 /// same output size/timing but interpolates between adjacent samples, which can
-/// reduce rough edges without pulling in a resampler dependency.
+/// reduce rough edges. `Sinc` runs the windowed-sinc resampler, which holds back
+/// a few milliseconds of look-ahead, so chunk sizes vary slightly.
 pub fn sco_s16le_mono_8k_to_aa_pcm_s16le_stereo_48k(
     input: &[u8],
     output: &mut Vec<u8>,
     resampler: BtScoMediaBridgeResampler,
     state: &mut DownlinkResamplerState,
+) {
+    sco_s16le_mono_to_aa_pcm_s16le_stereo_48k(input, output, 6, resampler, state);
+}
+
+/// Same as the 8 kHz variant for decoded wideband (mSBC) SCO audio, where each
+/// 16 kHz sample becomes three 48 kHz stereo frames.
+pub fn sco_s16le_mono_16k_to_aa_pcm_s16le_stereo_48k(
+    input: &[u8],
+    output: &mut Vec<u8>,
+    resampler: BtScoMediaBridgeResampler,
+    state: &mut DownlinkResamplerState,
+) {
+    sco_s16le_mono_to_aa_pcm_s16le_stereo_48k(input, output, 3, resampler, state);
+}
+
+fn sco_s16le_mono_to_aa_pcm_s16le_stereo_48k(
+    input: &[u8],
+    output: &mut Vec<u8>,
+    factor: i32,
+    resampler: BtScoMediaBridgeResampler,
+    state: &mut DownlinkResamplerState,
 ) {
     let even_len = input.len() & !1;
 
-    // Each input i16 sample becomes 6 stereo frames.
-    // 2 input bytes -> 6 * 2 channels * 2 output bytes = 24 output bytes.
-    output.reserve((even_len / 2) * 24);
+    if resampler == BtScoMediaBridgeResampler::Sinc {
+        let in_rate = AA_MEDIA_PCM_SAMPLE_RATE_HZ / factor as u32;
+        let out_channels = AA_MEDIA_PCM_CHANNELS as u32;
+        if !state
+            .sinc
+            .as_ref()
+            .is_some_and(|r| r.matches(in_rate, AA_MEDIA_PCM_SAMPLE_RATE_HZ, 1, out_channels))
+        {
+            state.sinc = Some(Resampler::new(
+                in_rate,
+                AA_MEDIA_PCM_SAMPLE_RATE_HZ,
+                1,
+                out_channels,
+            ));
+        }
+        if let Some(sinc) = state.sinc.as_mut() {
+            sinc.process_s16le(&input[..even_len], output);
+        }
+        return;
+    }
+
+    // Each input i16 sample becomes `factor` stereo frames of 4 bytes.
+    output.reserve((even_len / 2) * factor as usize * 4);
 
     for sample in input[..even_len].chunks_exact(2) {
         let current = i16::from_le_bytes([sample[0], sample[1]]);
-        match resampler {
-            BtScoMediaBridgeResampler::Repeat => {
-                push_stereo_repeated_sample(output, current);
-            }
-            BtScoMediaBridgeResampler::Linear => {
-                let previous = state.last_sample.unwrap_or(current);
-                push_stereo_linear_6x(output, previous, current);
-            }
+        if resampler == BtScoMediaBridgeResampler::Linear {
+            let previous = state.last_sample.unwrap_or(current);
+            push_stereo_linear(output, previous, current, factor);
+        } else {
+            push_stereo_repeated_sample(output, current, factor);
         }
         state.last_sample = Some(current);
     }
 }
 
-fn push_stereo_repeated_sample(output: &mut Vec<u8>, sample: i16) {
+fn push_stereo_repeated_sample(output: &mut Vec<u8>, sample: i16, factor: i32) {
     let bytes = sample.to_le_bytes();
-    for _ in 0..6 {
+    for _ in 0..factor {
         output.extend_from_slice(&bytes);
         output.extend_from_slice(&bytes);
     }
 }
 
-fn push_stereo_linear_6x(output: &mut Vec<u8>, previous: i16, current: i16) {
+fn push_stereo_linear(output: &mut Vec<u8>, previous: i16, current: i16, factor: i32) {
     let previous = previous as i32;
     let current = current as i32;
     let delta = current - previous;
 
-    // Six output frames bridge the previous sample to the current sample.
-    // step=6 lands exactly on `current`, preserving timing and chunk size.
-    for step in 1..=6 {
-        let interpolated = previous + (delta * step) / 6;
+    // `factor` output frames bridge the previous sample to the current sample.
+    // The last step lands exactly on `current`, preserving timing and chunk size.
+    for step in 1..=factor {
+        let interpolated = previous + (delta * step) / factor;
         let sample = interpolated.clamp(i16::MIN as i32, i16::MAX as i32) as i16;
         let bytes = sample.to_le_bytes();
         output.extend_from_slice(&bytes);
@@ -938,6 +1314,7 @@ fn format_bdaddr(addr: BdAddr) -> String {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::bt_sco_msbc::MSBC_PACKET_BYTES;
 
     #[test]
     fn sco_converter_expands_8k_mono_to_48k_stereo() {
@@ -980,4 +1357,103 @@ mod tests {
 
         assert_eq!(output.len(), 24);
     }
+
+    #[test]
+    fn wideband_converter_expands_16k_mono_to_48k_stereo() {
+        let input = [0x00, 0x00, 0x30, 0x00];
+        let mut output = Vec::new();
+
+        sco_s16le_mono_16k_to_aa_pcm_s16le_stereo_48k(
+            &input,
+            &mut output,
+            BtScoMediaBridgeResampler::Linear,
+            &mut DownlinkResamplerState::default(),
+        );
+
+        // 2 mono samples * 3x upsample * 2 stereo channels * 2 bytes.
+        assert_eq!(output.len(), 24);
+        let left: Vec<i16> = output
+            .chunks_exact(4)
+            .map(|frame| i16::from_le_bytes([frame[0], frame[1]]))
+            .collect();
+        assert_eq!(left, vec![0, 0, 0, 0x10, 0x20, 0x30]);
+    }
+
+    #[test]
+    fn sinc_converter_keeps_stereo_channels_in_step() {
+        let input: Vec<u8> = (0..160i16)
+            .flat_map(|n| ((n % 16) * 1000).to_le_bytes())
+            .collect();
+        let mut output = Vec::new();
+        let mut state = DownlinkResamplerState::default();
+
+        for chunk in input.chunks(60) {
+            sco_s16le_mono_8k_to_aa_pcm_s16le_stereo_48k(
+                chunk,
+                &mut output,
+                BtScoMediaBridgeResampler::Sinc,
+                &mut state,
+            );
+        }
+
+        // 20 ms of 8 kHz audio minus the filter look-ahead, upsampled 6x.
+        let frames = output.len() / 4;
+        assert!(frames > 800 && frames <= 960, "frames={}", frames);
+        assert!(output
+            .chunks_exact(4)
+            .all(|frame| frame[0..2] == frame[2..4]));
+    }
+
+    #[test]
+    fn msbc_mode_prefers_negotiated_codec() {
+        assert!(wants_msbc(BtScoCodec::Msbc, None));
+        assert!(!wants_msbc(BtScoCodec::Auto, None));
+        assert!(!wants_msbc(BtScoCodec::Cvsd, None));
+        assert!(wants_msbc(BtScoCodec::Auto, Some(ScoVoiceCodec::Msbc)));
+        assert!(!wants_msbc(BtScoCodec::Msbc, Some(ScoVoiceCodec::Cvsd)));
+    }
+
+    #[test]
+    fn msbc_uplink_keeps_wideband_mic_audio() {
+        const RATE: f32 = 16_000.0;
+        const TONE_HZ: f32 = 6_000.0;
+        const AMPLITUDE: f32 = 8_000.0;
+
+        SCO_CODEC.store(ScoVoiceCodec::Msbc.as_u8(), Ordering::SeqCst);
+        clear_sco_uplink_queue();
+
+        // 500 ms of a 6 kHz tone from a 16 kHz HU mic, in 20 ms chunks
+        let mic: Vec<u8> = (0..8_000)
+            .flat_map(|n| {
+                let t = n as f32 / RATE;
+                let sample = AMPLITUDE * (2.0 * std::f32::consts::PI * TONE_HZ * t).sin();
+                (sample as i16).to_le_bytes()
+            })
+            .collect();
+        for chunk in mic.chunks(640) {
+            push_sco_uplink_pcm_from_aa_mic(chunk, 16_000, 1, 16, 1_000);
+        }
+
+        let mut decoder = MsbcDecoder::new();
+        let mut decoded = Vec::new();
+        while let Some(packet) = pop_sco_uplink_frame(MSBC_PACKET_BYTES) {
+            decoder.push(&packet, &mut decoded);
+        }
+        SCO_CODEC.store(0, Ordering::SeqCst);
+        clear_sco_uplink_queue();
+
+        // skip the resampler and codec start-up, measure the tone amplitude
+        let window = &decoded[1_600..decoded.len().min(7_200)];
+        let (mut re, mut im) = (0.0f64, 0.0f64);
+        for (n, sample) in window.iter().enumerate() {
+            let phase = 2.0 * std::f64::consts::PI * TONE_HZ as f64 * n as f64 / RATE as f64;
+            re += *sample as f64 * phase.cos();
+            im += *sample as f64 * phase.sin();
+        }
+        let amplitude = 2.0 * (re * re + im * im).sqrt() / window.len() as f64;
+        assert!(
+            amplitude > 0.7 * AMPLITUDE as f64,
+            "6 kHz tone lost on the mSBC uplink, amplitude={amplitude:.0}"
+        );
+    }
 }
@@ -1,10 +1,41 @@
 use crate::config::BtScoMicEchoControl;
 use simplelog::*;
+use std::collections::VecDeque;
 use std::sync::{Arc, Mutex, OnceLock};
 use std::time::{Duration, Instant};
 
 const NAME: &str = "<i><bright-black> bt-sco-echo: </>";
 
+/// Narrowband SCO rate the sample counts below are tuned for; at wideband
+/// rates they are scaled up so they keep covering the same time.
+const AEC_SAMPLE_RATE_HZ: u32 = 8_000;
+/// NLMS step size.
+const AEC_STEP_SIZE: f32 = 0.4;
+/// Regularization of the NLMS normalization, keeps quiet references stable.
+const AEC_REGULARIZATION: f32 = 1.0e5;
+/// Sub-block length used by the double-talk detector (5 ms).
+const AEC_DTD_BLOCK: usize = 40;
+/// Geigel threshold: near-end louder than this fraction of the reference
+/// peak means near-end speech is present.
+const AEC_DTD_THRESHOLD: f32 = 0.6;
+/// Keep adaptation frozen this long after double-talk was detected (60 ms).
+const AEC_DTD_HANGOVER: usize = 480;
+/// Reference peak below which the far end is considered silent.
+const AEC_FAR_ACTIVE_LEVEL: f32 = 150.0;
+/// Delay estimation works on the mean magnitude of blocks of this many samples.
+const AEC_ENVELOPE_BLOCK: usize = 8;
+/// Length of near-end history correlated against the reference (256 ms).
+const AEC_DELAY_WINDOW: usize = 2048;
+/// Re-estimate the bulk delay after this many near-end samples (250 ms).
+const AEC_DELAY_UPDATE_INTERVAL: usize = 2000;
+/// Minimum normalized envelope correlation to accept a delay estimate.
+const AEC_DELAY_MIN_CORRELATION: f32 = 0.5;
+/// The adaptive filter starts this many samples before the estimated delay
+/// so that a slightly early echo path is still covered.
+const AEC_DELAY_MARGIN: usize = 32;
+/// Bulk delay changes smaller than this keep the converged filter.
+const AEC_DELAY_HYSTERESIS: usize = 16;
+
 #[derive(Debug, Clone)]
 pub struct BtScoEchoSettings {
     pub control: BtScoMicEchoControl,
@@ -12,6 +43,10 @@ pub struct BtScoEchoSettings {
     pub duck_threshold: i16,
     pub duck_percent: u32,
     pub duck_hold_ms: u32,
+    /// Echo tail covered by the adaptive filter.
+    pub aec_filter_ms: u32,
+    /// Largest downlink → mic delay searched by the delay estimator.
+    pub aec_max_delay_ms: u32,
 }
 
 impl Default for BtScoEchoSettings {
@@ -22,6 +57,8 @@ impl Default for BtScoEchoSettings {
             duck_threshold: 700,
             duck_percent: 35,
             duck_hold_ms: 180,
+            aec_filter_ms: 64,
+            aec_max_delay_ms: 400,
         }
     }
 }
@@ -31,6 +68,9 @@ struct EchoState {
     downlink_active_until: Option<Instant>,
     last_downlink_peak: i16,
     generation: u64,
+    /// Rate of the mic samples the canceller was built for.
+    sample_rate: u32,
+    aec: Option<EchoCanceller>,
 }
 
 impl Default for EchoState {
@@ -40,6 +80,8 @@ impl Default for EchoState {
             downlink_active_until: None,
             last_downlink_peak: 0,
             generation: 0,
+            sample_rate: AEC_SAMPLE_RATE_HZ,
+            aec: None,
         }
     }
 }
@@ -59,18 +101,25 @@ pub fn configure(settings: BtScoEchoSettings) {
         || state.settings.mic_gain_percent != settings.mic_gain_percent
         || state.settings.duck_threshold != settings.duck_threshold
         || state.settings.duck_percent != settings.duck_percent
-        || state.settings.duck_hold_ms != settings.duck_hold_ms;
+        || state.settings.duck_hold_ms != settings.duck_hold_ms
+        || state.settings.aec_filter_ms != settings.aec_filter_ms
+        || state.settings.aec_max_delay_ms != settings.aec_max_delay_ms;
 
     state.settings = settings.clone();
+    if changed || (settings.control == BtScoMicEchoControl::Adaptive) != state.aec.is_some() {
+        state.aec = new_echo_canceller(&settings, state.sample_rate);
+    }
     if changed {
         debug!(
-            "{} configured mic echo_control={}, mic_gain={}%, duck_threshold={}, duck_percent={}%, duck_hold={}ms",
+            "{} configured mic echo_control={}, mic_gain={}%, duck_threshold={}, duck_percent={}%, duck_hold={}ms, aec_filter={}ms, aec_max_delay={}ms",
             NAME,
             settings.control,
             settings.mic_gain_percent,
             settings.duck_threshold,
             settings.duck_percent,
             settings.duck_hold_ms,
+            settings.aec_filter_ms,
+            settings.aec_max_delay_ms,
         );
     }
 }
@@ -81,13 +130,18 @@ pub fn reset_for_sco_generation(generation: u64) {
     state.generation = generation;
     state.downlink_active_until = None;
     state.last_downlink_peak = 0;
+    if let Some(aec) = state.aec.as_mut() {
+        aec.reset();
+    }
     debug!(
         "{} reset echo state for SCO generation={}",
         NAME, generation
     );
 }
 
-pub fn observe_downlink_sco_8k_mono(input: &[u8]) {
+/// Feed the SCO downlink (s16le mono at `sample_rate`) as the far-end
+/// reference. The canceller only takes it at the rate the mic runs at.
+pub fn observe_downlink_sco_mono(input: &[u8], sample_rate: u32) {
     if input.is_empty() {
         return;
     }
@@ -97,6 +151,15 @@ pub fn observe_downlink_sco_8k_mono(input: &[u8]) {
     let mut state = state.lock().unwrap();
     state.last_downlink_peak = peak;
 
+    let same_rate = state.sample_rate == sample_rate;
+    if let Some(aec) = state.aec.as_mut().filter(|_| same_rate) {
+        let samples: Vec<i16> = input
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        aec.push_far(&samples);
+    }
+
     if state.settings.control != BtScoMicEchoControl::Ducking {
         return;
     }
@@ -108,18 +171,40 @@ pub fn observe_downlink_sco_8k_mono(input: &[u8]) {
     }
 }
 
-pub fn process_mic_8k_samples(samples: &mut [i16]) {
+/// Echo control for mono mic samples at the SCO rate (8 kHz narrowband,
+/// 16 kHz on mSBC connections).
+pub fn process_mic_samples(samples: &mut [i16], sample_rate: u32) {
     if samples.is_empty() {
         return;
     }
 
     let state_arc = state();
-    let state = state_arc.lock().unwrap();
+    let mut state = state_arc.lock().unwrap();
     let settings = state.settings.clone();
+    if state.sample_rate != sample_rate {
+        debug!("{} mic rate changed to {} Hz", NAME, sample_rate);
+        state.sample_rate = sample_rate;
+        state.aec = new_echo_canceller(&settings, sample_rate);
+    }
 
     match settings.control {
         BtScoMicEchoControl::Off => {}
         BtScoMicEchoControl::Ducking => apply_ducking_if_active(samples, &state, &settings),
+        BtScoMicEchoControl::Adaptive => {
+            if let Some(aec) = state.aec.as_mut() {
+                aec.process_near(samples);
+                if aec.last_report.elapsed() >= Duration::from_secs(5) {
+                    aec.last_report = Instant::now();
+                    debug!(
+                        "{} aec delay={:?} samples, erle={:.1}dB, double_talk={}",
+                        NAME,
+                        aec.delay(),
+                        aec.erle_db(),
+                        aec.double_talk()
+                    );
+                }
+            }
+        }
     }
 
     apply_gain(samples, settings.mic_gain_percent);
@@ -169,3 +254,379 @@ fn peak_s16le(input: &[u8]) -> i16 {
     }
     peak.min(i16::MAX as i32) as i16
 }
+
+fn new_echo_canceller(settings: &BtScoEchoSettings, sample_rate: u32) -> Option<EchoCanceller> {
+    if settings.control != BtScoMicEchoControl::Adaptive {
+        return None;
+    }
+    let ms_to_samples = |ms: u32| (sample_rate as usize * ms as usize / 1000).max(1);
+    Some(EchoCanceller::for_rate(
+        sample_rate,
+        ms_to_samples(settings.aec_filter_ms.clamp(8, 256)),
+        ms_to_samples(settings.aec_max_delay_ms.min(1000)),
+    ))
+}
+
+/// NLMS acoustic echo canceller for the SCO mic uplink.
+///
+/// The downlink observed on the SCO socket is the far-end reference. Near
+/// blocks are aligned with the most recent reference samples; the bulk
+/// delay between them (Bluetooth, AA and cabin latency) is estimated by
+/// correlating signal envelopes, and the adaptive filter covers the echo
+/// tail behind it. A Geigel double-talk detector freezes adaptation while
+/// the near end talks.
+pub struct EchoCanceller {
+    /// Multiple of `AEC_SAMPLE_RATE_HZ` the canceller runs at.
+    scale: usize,
+    taps: usize,
+    max_delay: usize,
+    weights: Vec<f32>,
+    /// Reference history, newest sample last.
+    far: VecDeque<f32>,
+    /// Near-end (mic) history for delay estimation, newest sample last.
+    near: VecDeque<f32>,
+    delay: Option<usize>,
+    samples_since_delay_update: usize,
+    double_talk_hold: usize,
+    /// Double-talk was detected since the last delay estimation; the near
+    /// history then does not represent the echo path.
+    double_talk_seen: bool,
+    echo_energy: f64,
+    residual_energy: f64,
+    last_report: Instant,
+}
+
+impl EchoCanceller {
+    pub fn new(taps: usize, max_delay: usize) -> Self {
+        Self::for_rate(AEC_SAMPLE_RATE_HZ, taps, max_delay)
+    }
+
+    /// `taps` and `max_delay` are in samples at `sample_rate`.
+    pub fn for_rate(sample_rate: u32, taps: usize, max_delay: usize) -> Self {
+        Self {
+            scale: (sample_rate / AEC_SAMPLE_RATE_HZ).max(1) as usize,
+            taps,
+            max_delay,
+            weights: vec![0.0; taps],
+            far: VecDeque::new(),
+            near: VecDeque::new(),
+            delay: None,
+            samples_since_delay_update: 0,
+            double_talk_hold: 0,
+            double_talk_seen: false,
+            echo_energy: 0.0,
+            residual_energy: 0.0,
+            last_report: Instant::now(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        let sample_rate = AEC_SAMPLE_RATE_HZ * self.scale as u32;
+        *self = Self::for_rate(sample_rate, self.taps, self.max_delay);
+    }
+
+    /// Estimated bulk delay in samples, once the estimator locked on.
+    pub fn delay(&self) -> Option<usize> {
+        self.delay
+    }
+
+    pub fn double_talk(&self) -> bool {
+        self.double_talk_hold > 0
+    }
+
+    /// Echo return loss enhancement measured while only the far end talked.
+    pub fn erle_db(&self) -> f64 {
+        if self.residual_energy <= 0.0 || self.echo_energy <= 0.0 {
+            return 0.0;
+        }
+        10.0 * (self.echo_energy / self.residual_energy).log10()
+    }
+
+    pub fn push_far(&mut self, samples: &[i16]) {
+        self.far.extend(samples.iter().map(|s| *s as f32));
+        let keep = self.max_delay
+            + self.taps
+            + (AEC_DELAY_WINDOW + AEC_DELAY_UPDATE_INTERVAL) * self.scale;
+        while self.far.len() > keep {
+            self.far.pop_front();
+        }
+    }
+
+    pub fn process_near(&mut self, samples: &mut [i16]) {
+        let scale = self.scale;
+        self.near.extend(samples.iter().map(|s| *s as f32));
+        while self.near.len() > AEC_DELAY_WINDOW * scale {
+            self.near.pop_front();
+        }
+        self.samples_since_delay_update += samples.len();
+        if self.samples_since_delay_update >= AEC_DELAY_UPDATE_INTERVAL * scale {
+            self.samples_since_delay_update = 0;
+            if !self.double_talk_seen {
+                self.update_delay();
+            }
+            self.double_talk_seen = false;
+        }
+
+        let Some(delay) = self.delay else {
+            return;
+        };
+        let bulk = delay.saturating_sub(AEC_DELAY_MARGIN * scale);
+        // the newest near sample is aligned with the newest reference sample
+        let first_aligned = self.far.len() as isize - samples.len() as isize;
+
+        let dtd_block = AEC_DTD_BLOCK * scale;
+        for (block_idx, block) in samples.chunks_mut(dtd_block).enumerate() {
+            let block_start = first_aligned + (block_idx * dtd_block) as isize;
+
+            let far_peak = (0..block.len() + self.taps)
+                .map(|k| far_at(&self.far, block_start + block.len() as isize - 1, bulk + k).abs())
+                .fold(0.0f32, f32::max);
+            let near_peak = block
+                .iter()
+                .map(|s| (*s as f32).abs())
+                .fold(0.0f32, f32::max);
+            let far_active = far_peak >= AEC_FAR_ACTIVE_LEVEL;
+            if far_active && near_peak > AEC_DTD_THRESHOLD * far_peak {
+                self.double_talk_hold = AEC_DTD_HANGOVER * scale;
+                self.double_talk_seen = true;
+            }
+
+            for (i, sample) in block.iter_mut().enumerate() {
+                let aligned = block_start + i as isize;
+                let mut estimate = 0.0f32;
+                let mut power = 0.0f32;
+                for (k, w) in self.weights.iter().enumerate() {
+                    let x = far_at(&self.far, aligned, bulk + k);
+                    estimate += w * x;
+                    power += x * x;
+                }
+                let near = *sample as f32;
+                let error = near - estimate;
+
+                let adapt = far_active && self.double_talk_hold == 0;
+                if adapt {
+                    let mu = AEC_STEP_SIZE * error / (power + AEC_REGULARIZATION);
+                    for (k, w) in self.weights.iter_mut().enumerate() {
+                        *w += mu * far_at(&self.far, aligned, bulk + k);
+                    }
+                    self.echo_energy = self.echo_energy * 0.999 + (near * near) as f64;
+                    self.residual_energy = self.residual_energy * 0.999 + (error * error) as f64;
+                }
+                self.double_talk_hold = self.double_talk_hold.saturating_sub(1);
+
+                *sample = error.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+            }
+        }
+    }
+
+    /// Find the reference lag whose envelope best matches the recent near end.
+    fn update_delay(&mut self) {
+        let window = AEC_DELAY_WINDOW * self.scale;
+        let envelope_block = AEC_ENVELOPE_BLOCK * self.scale;
+        let window_blocks = window / envelope_block;
+        let max_lag_blocks = self.max_delay / envelope_block;
+        if self.near.len() < window || self.far.len() < window + max_lag_blocks * envelope_block {
+            return;
+        }
+
+        let near_env = envelope(self.near.iter().copied(), envelope_block);
+        let far_len_blocks = window_blocks + max_lag_blocks;
+        let far_start = self.far.len() - far_len_blocks * envelope_block;
+        let far_env = envelope(self.far.range(far_start..).copied(), envelope_block);
+
+        let (near_mean, near_norm) = mean_and_norm(&near_env);
+        if near_norm <= f32::EPSILON {
+            return;
+        }
+
+        let mut best: Option<(usize, f32)> = None;
+        for lag in 0..=max_lag_blocks {
+            let end = far_env.len() - lag;
+            let far_win = &far_env[end - window_blocks..end];
+            let (far_mean, far_norm) = mean_and_norm(far_win);
+            if far_norm <= f32::EPSILON {
+                continue;
+            }
+            let dot: f32 = near_env
+                .iter()
+                .zip(far_win)
+                .map(|(n, f)| (n - near_mean) * (f - far_mean))
+                .sum();
+            let corr = dot / (near_norm * far_norm);
+            if best.is_none_or(|(_, c)| corr > c) {
+                best = Some((lag, corr));
+            }
+        }
+
+        let Some((lag, corr)) = best else {
+            return;
+        };
+        if corr < AEC_DELAY_MIN_CORRELATION {
+            return;
+        }
+        let delay = lag * envelope_block;
+        let changed = self
+            .delay
+            .is_none_or(|d| d.abs_diff(delay) >= AEC_DELAY_HYSTERESIS * self.scale);
+        if changed {
+            debug!(
+                "{} aec bulk delay {:?} -> {} samples (corr={:.2})",
+                NAME, self.delay, delay, corr
+            );
+            self.delay = Some(delay);
+            self.weights.iter_mut().for_each(|w| *w = 0.0);
+        }
+    }
+}
+
+/// Reference sample `back` samples before the one aligned with the current
+/// near sample at `aligned` (index into `far`).
+fn far_at(far: &VecDeque<f32>, aligned: isize, back: usize) -> f32 {
+    let idx = aligned - back as isize;
+    if idx < 0 {
+        0.0
+    } else {
+        far.get(idx as usize).copied().unwrap_or(0.0)
+    }
+}
+
+/// Mean magnitude of consecutive blocks of `block` samples.
+fn envelope(samples: impl Iterator<Item = f32>, block: usize) -> Vec<f32> {
+    let samples: Vec<f32> = samples.collect();
+    samples
+        .chunks(block)
+        .map(|b| b.iter().map(|s| s.abs()).sum::<f32>() / b.len() as f32)
+        .collect()
+}
+
+fn mean_and_norm(values: &[f32]) -> (f32, f32) {
+    let mean = values.iter().sum::<f32>() / values.len().max(1) as f32;
+    let norm = values
+        .iter()
+        .map(|v| (v - mean) * (v - mean))
+        .sum::<f32>()
+        .sqrt();
+    (mean, norm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = 160;
+    const ECHO_DELAY: usize = 200;
+    const ECHO_PATH: [f32; 4] = [0.5, 0.0, -0.2, 0.1];
+
+    /// Speech-like far-end signal: noise with a slow syllable envelope.
+    fn far_signal(len: usize) -> Vec<i16> {
+        let mut seed = 0x1234_5678u32;
+        (0..len)
+            .map(|n| {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let noise = (seed >> 16) as f32 / 32768.0 - 1.0;
+                let t = n as f32 / AEC_SAMPLE_RATE_HZ as f32;
+                let envelope = 0.55 + 0.45 * (2.0 * std::f32::consts::PI * 3.0 * t).sin();
+                (noise * 6000.0 * envelope) as i16
+            })
+            .collect()
+    }
+
+    fn echo_of(far: &[i16], n: usize) -> f32 {
+        ECHO_PATH
+            .iter()
+            .enumerate()
+            .map(|(k, h)| {
+                n.checked_sub(ECHO_DELAY + k)
+                    .map_or(0.0, |idx| h * far[idx] as f32)
+            })
+            .sum()
+    }
+
+    fn energy(samples: impl Iterator<Item = f32>) -> f64 {
+        samples.map(|s| (s as f64) * (s as f64)).sum()
+    }
+
+    /// Run far/near blocks through the canceller; `near_talk(n)` is added to the echo.
+    fn run(
+        aec: &mut EchoCanceller,
+        far: &[i16],
+        range: std::ops::Range<usize>,
+        near_talk: impl Fn(usize) -> f32,
+    ) -> Vec<i16> {
+        let mut out = Vec::with_capacity(range.len());
+        for start in range.step_by(BLOCK) {
+            aec.push_far(&far[start..start + BLOCK]);
+            let mut near: Vec<i16> = (start..start + BLOCK)
+                .map(|n| (echo_of(far, n) + near_talk(n)) as i16)
+                .collect();
+            aec.process_near(&mut near);
+            out.extend_from_slice(&near);
+        }
+        out
+    }
+
+    #[test]
+    fn aec_estimates_delay_and_cancels_echo() {
+        let far = far_signal(8000 * 5);
+        let mut aec = EchoCanceller::new(256, 3200);
+        run(&mut aec, &far, 0..8000 * 4, |_| 0.0);
+
+        let delay = aec.delay().expect("delay estimate");
+        assert!(
+            delay.abs_diff(ECHO_DELAY) <= AEC_ENVELOPE_BLOCK,
+            "delay={delay}"
+        );
+
+        let out = run(&mut aec, &far, 8000 * 4..8000 * 5, |_| 0.0);
+        let echo = energy((8000 * 4..8000 * 5).map(|n| echo_of(&far, n)));
+        let residual = energy(out.iter().map(|s| *s as f32));
+        let erle = 10.0 * (echo / residual).log10();
+        assert!(erle > 20.0, "erle={erle:.1}dB");
+    }
+
+    #[test]
+    fn aec_keeps_near_end_speech_during_double_talk() {
+        let far = far_signal(8000 * 6);
+        let mut aec = EchoCanceller::new(256, 3200);
+        run(&mut aec, &far, 0..8000 * 4, |_| 0.0);
+
+        let talk = |n: usize| {
+            let t = n as f32 / AEC_SAMPLE_RATE_HZ as f32;
+            4000.0 * (2.0 * std::f32::consts::PI * 440.0 * t).sin()
+        };
+        let range = 8000 * 4..8000 * 5;
+        let out = run(&mut aec, &far, range.clone(), talk);
+
+        // what is left after removing the near-end talker must be far below the echo
+        let echo = energy(range.clone().map(|n| echo_of(&far, n)));
+        let residual = energy(
+            range
+                .clone()
+                .zip(out.iter())
+                .map(|(n, s)| *s as f32 - talk(n)),
+        );
+        assert!(
+            10.0 * (echo / residual).log10() > 15.0,
+            "double talk must not break the echo path estimate"
+        );
+
+        // the converged filter survives the double-talk period
+        let out = run(&mut aec, &far, 8000 * 5..8000 * 6, |_| 0.0);
+        let echo = energy((8000 * 5..8000 * 6).map(|n| echo_of(&far, n)));
+        let residual = energy(out.iter().map(|s| *s as f32));
+        assert!(10.0 * (echo / residual).log10() > 20.0);
+    }
+
+    #[test]
+    fn aec_passes_near_end_through_without_reference() {
+        let mut aec = EchoCanceller::new(256, 3200);
+        let silence = vec![0i16; BLOCK];
+        let original: Vec<i16> = (0..BLOCK as i16).map(|n| n * 50).collect();
+        for _ in 0..100 {
+            aec.push_far(&silence);
+            let mut near = original.clone();
+            aec.process_near(&mut near);
+            assert_eq!(near, original);
+        }
+    }
+}
@@ -1,10 +1,9 @@
 use crate::bt_sco;
 use crate::config::BtScoMediaBridgeLimiter;
-use crate::mitm::protos::{
-    ChannelOpenResponse, Config as AudioConfig, MediaMessageId, Start, Stop,
-};
-use crate::mitm::{Packet, ENCRYPTED, FRAME_TYPE_FIRST, FRAME_TYPE_LAST};
-use protobuf::Message;
+use crate::mitm::protos::{ChannelOpenResponse, Config as AudioConfig, MediaMessageId};
+use crate::mitm::Packet;
+use crate::pcm_sink::{media_packet, SinkConfig, SinkSession};
+use crate::resampler::Resampler;
 use simplelog::*;
 use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, Ordering};
 use std::sync::{Arc, Mutex, OnceLock};
@@ -250,8 +249,10 @@ pub fn notify_media_config(channel: u8, cfg: &AudioConfig) {
         return;
     }
 
-    let config_index = cfg.configuration_indices.first().copied().unwrap_or(0);
-    let max_unacked = cfg.max_unacked();
+    let SinkConfig {
+        configuration_index: config_index,
+        max_unacked,
+    } = SinkConfig::from_config(cfg);
     let epoch = runtime.target_epoch.load(Ordering::SeqCst);
 
     runtime
@@ -298,8 +299,7 @@ pub fn notify_channel_open_response(_resp: &ChannelOpenResponse) {}
 
 fn bridge_loop(runtime: Arc<Runtime>) {
     let mut active_epoch = 0u64;
-    let mut pts_us = 0u64;
-    let mut data_packets = 0u64;
+    let mut session: Option<SinkSession> = None;
     let mut dropped_before_ready = 0u64;
     let mut last_data_log = Instant::now();
     let mut last_wait_log = Instant::now();
@@ -309,6 +309,7 @@ fn bridge_loop(runtime: Arc<Runtime>) {
     let mut silent_frames_before_start = 0u64;
     let mut next_cadence_send_at: Option<Instant> = None;
     let mut last_mic_wait_log = Instant::now();
+    let mut target_resampler: Option<Resampler> = None;
 
     loop {
         handle_microphone_request_state(&runtime, &mut last_mic_wait_log);
@@ -323,8 +324,7 @@ fn bridge_loop(runtime: Arc<Runtime>) {
         let epoch = runtime.target_epoch.load(Ordering::SeqCst);
         if epoch != active_epoch {
             active_epoch = epoch;
-            pts_us = 0;
-            data_packets = 0;
+            session = None;
             dropped_before_ready = 0;
             last_data_log = Instant::now();
             last_wait_log = Instant::now();
@@ -359,22 +359,34 @@ fn bridge_loop(runtime: Arc<Runtime>) {
             continue;
         }
 
+        // the sink format is final once the HU CONFIG for this epoch arrived
+        let session = session.get_or_insert_with(|| {
+            SinkSession::new(
+                channel,
+                DEFAULT_SESSION_ID,
+                runtime.target_sample_rate.load(Ordering::SeqCst),
+                runtime.target_channels.load(Ordering::SeqCst),
+            )
+        });
+
         if handle_media_disconnected_state(
             &runtime,
-            channel,
+            session,
             &mut media_started_generation,
             &mut pending_start_generation,
             &mut pending_start_started_at,
             &mut silent_frames_before_start,
-            &mut pts_us,
-            &mut data_packets,
             &mut next_cadence_send_at,
         ) {
             thread::sleep(IDLE_SLEEP);
             continue;
         }
 
-        if should_wait_for_fixed_cadence(&runtime, data_packets, &mut next_cadence_send_at) {
+        if should_wait_for_fixed_cadence(
+            &runtime,
+            session.data_packets(),
+            &mut next_cadence_send_at,
+        ) {
             thread::sleep(IDLE_SLEEP);
             continue;
         }
@@ -412,9 +424,16 @@ fn bridge_loop(runtime: Arc<Runtime>) {
 
         // The SCO reader currently emits 48kHz stereo s16le chunks. Adapt that
         // to the actual selected sink: MEDIA is usually stereo, GUIDANCE is
-        // often mono. This lets us test GUIDANCE without changing the SCO reader.
-        let mut adapted =
-            adapt_48k_stereo_s16le_to_target(&frame.pcm, target_channels, gain_percent, limiter);
+        // often mono and may run at 16kHz. This lets us test GUIDANCE without
+        // changing the SCO reader.
+        let adapted = adapt_48k_stereo_s16le_to_target(
+            &frame.pcm,
+            target_sample_rate,
+            target_channels,
+            gain_percent,
+            limiter,
+            &mut target_resampler,
+        );
         let bytes_per_frame = target_channels as usize * 2;
         if bytes_per_frame == 0 || adapted.len() < bytes_per_frame {
             continue;
@@ -425,37 +444,23 @@ fn bridge_loop(runtime: Arc<Runtime>) {
 
         if !ensure_media_started_for_frame(
             &runtime,
-            channel,
+            session,
             epoch,
             peak,
             &mut media_started_generation,
             &mut pending_start_generation,
             &mut pending_start_started_at,
             &mut silent_frames_before_start,
-            &mut pts_us,
-            &mut data_packets,
             &mut next_cadence_send_at,
         ) {
             continue;
         }
 
-        let mut payload = Vec::with_capacity(2 + 8 + adapted.len());
-        push_media_message_id(&mut payload, MediaMessageId::MEDIA_MESSAGE_DATA as u16);
-        payload.extend_from_slice(&pts_us.to_be_bytes());
-        payload.append(&mut adapted);
-
-        let pkt = Packet {
-            channel,
-            flags: ENCRYPTED | FRAME_TYPE_FIRST | FRAME_TYPE_LAST,
-            final_length: None,
-            payload,
-        };
-
+        let pkt = session.data(&adapted);
         if send_packet(&runtime, pkt, "DATA").is_ok() {
-            data_packets += 1;
-            pts_us += frames * 1_000_000 / (target_sample_rate as u64);
             advance_fixed_cadence(&runtime, &mut next_cadence_send_at);
 
+            let data_packets = session.data_packets();
             if data_packets <= 5 || last_data_log.elapsed() >= Duration::from_secs(5) {
                 let config_index = runtime.configuration_index.load(Ordering::SeqCst);
                 let max_unacked = runtime.max_unacked.load(Ordering::SeqCst);
@@ -464,7 +469,7 @@ fn bridge_loop(runtime: Arc<Runtime>) {
                     NAME,
                     channel,
                     data_packets,
-                    pts_us,
+                    session.pts_us(),
                     frames,
                     frames * bytes_per_frame as u64,
                     peak,
@@ -526,26 +531,28 @@ fn advance_fixed_cadence(runtime: &Runtime, next_send_at: &mut Option<Instant>)
 
 fn handle_media_disconnected_state(
     runtime: &Runtime,
-    channel: u8,
+    session: &mut SinkSession,
     media_started_generation: &mut u64,
     pending_start_generation: &mut u64,
     pending_start_started_at: &mut Option<Instant>,
     silent_frames_before_start: &mut u64,
-    pts_us: &mut u64,
-    data_packets: &mut u64,
     next_cadence_send_at: &mut Option<Instant>,
 ) -> bool {
     if bt_sco::is_sco_connected() {
         return false;
     }
 
+    let channel = session.channel();
     if *media_started_generation != 0 {
         if runtime.stop_existing_on_disconnect.load(Ordering::SeqCst) {
-            let _ = send_media_stop(runtime, channel);
+            let _ = send_media_stop(runtime, session);
         }
         debug!(
             "{} media bridge stopped for ch=<b>{:#04x}</>, sco_generation={}, packets_sent={}",
-            NAME, channel, *media_started_generation, *data_packets
+            NAME,
+            channel,
+            *media_started_generation,
+            session.data_packets()
         );
     } else if *pending_start_generation != 0 {
         debug!(
@@ -558,8 +565,7 @@ fn handle_media_disconnected_state(
     *pending_start_generation = 0;
     *pending_start_started_at = None;
     *silent_frames_before_start = 0;
-    *pts_us = 0;
-    *data_packets = 0;
+    session.reset();
     *next_cadence_send_at = None;
     drop_stale_sco_frames();
     true
@@ -567,17 +573,16 @@ fn handle_media_disconnected_state(
 
 fn ensure_media_started_for_frame(
     runtime: &Runtime,
-    channel: u8,
+    session: &mut SinkSession,
     epoch: u64,
     frame_peak: i16,
     media_started_generation: &mut u64,
     pending_start_generation: &mut u64,
     pending_start_started_at: &mut Option<Instant>,
     silent_frames_before_start: &mut u64,
-    pts_us: &mut u64,
-    data_packets: &mut u64,
     next_cadence_send_at: &mut Option<Instant>,
 ) -> bool {
+    let channel = session.channel();
     let generation = bt_sco::sco_generation();
     if generation == 0 {
         return false;
@@ -589,7 +594,7 @@ fn ensure_media_started_for_frame(
 
     if *media_started_generation != 0 && *media_started_generation != generation {
         if runtime.stop_existing_on_disconnect.load(Ordering::SeqCst) {
-            let _ = send_media_stop(runtime, channel);
+            let _ = send_media_stop(runtime, session);
         }
         debug!(
             "{} media bridge generation switch: stopped previous ch=<b>{:#04x}</>, old_generation={}, new_generation={}, packets_sent={}",
@@ -597,11 +602,10 @@ fn ensure_media_started_for_frame(
             channel,
             *media_started_generation,
             generation,
-            *data_packets
+            session.data_packets()
         );
         *media_started_generation = 0;
-        *pts_us = 0;
-        *data_packets = 0;
+        session.reset();
         *next_cadence_send_at = None;
     }
 
@@ -609,8 +613,7 @@ fn ensure_media_started_for_frame(
         *pending_start_generation = generation;
         *pending_start_started_at = Some(Instant::now());
         *silent_frames_before_start = 0;
-        *pts_us = 0;
-        *data_packets = 0;
+        session.reset();
         *next_cadence_send_at = None;
 
         let start_on_first_audio = runtime.start_on_first_audio.load(Ordering::SeqCst);
@@ -663,12 +666,13 @@ fn ensure_media_started_for_frame(
 
     if runtime.start_existing.load(Ordering::SeqCst) {
         let config_index = runtime.configuration_index.load(Ordering::SeqCst);
-        if send_media_start(runtime, channel, DEFAULT_SESSION_ID, config_index).is_ok() {
+        let pkt = session.start(config_index);
+        if send_packet(runtime, pkt, "MEDIA_MESSAGE_START(existing)").is_ok() {
             debug!(
                 "{} media bridge START sent for existing ch=<b>{:#04x}</>, session_id={}, config_index={}, sco_generation={}, epoch={}, reason={}, peak={}, threshold={}, silent_frames_before_start={}, elapsed={}ms",
                 NAME,
                 channel,
-                DEFAULT_SESSION_ID,
+                session.session_id(),
                 config_index,
                 generation,
                 epoch,
@@ -697,52 +701,18 @@ fn ensure_media_started_for_frame(
     *pending_start_generation = 0;
     *pending_start_started_at = None;
     *silent_frames_before_start = 0;
-    *pts_us = 0;
-    *data_packets = 0;
+    session.reset();
     *next_cadence_send_at = None;
     true
 }
 
-fn send_media_start(
-    runtime: &Runtime,
-    channel: u8,
-    session_id: i32,
-    configuration_index: u32,
-) -> std::result::Result<(), ()> {
-    let mut start = Start::new();
-    start.set_session_id(session_id);
-    start.set_configuration_index(configuration_index);
-
-    let mut payload = start.write_to_bytes().unwrap_or_default();
-    push_media_message_id(&mut payload, MediaMessageId::MEDIA_MESSAGE_START as u16);
-
-    let pkt = Packet {
-        channel,
-        flags: ENCRYPTED | FRAME_TYPE_FIRST | FRAME_TYPE_LAST,
-        final_length: None,
-        payload,
-    };
-
-    send_packet(runtime, pkt, "MEDIA_MESSAGE_START(existing)")
-}
-
-fn send_media_stop(runtime: &Runtime, channel: u8) -> std::result::Result<(), ()> {
-    let stop = Stop::new();
-    let mut payload = stop.write_to_bytes().unwrap_or_default();
-    push_media_message_id(&mut payload, MediaMessageId::MEDIA_MESSAGE_STOP as u16);
-
-    let pkt = Packet {
-        channel,
-        flags: ENCRYPTED | FRAME_TYPE_FIRST | FRAME_TYPE_LAST,
-        final_length: None,
-        payload,
-    };
-
-    let result = send_packet(runtime, pkt, "MEDIA_MESSAGE_STOP(existing)");
+fn send_media_stop(runtime: &Runtime, session: &SinkSession) -> std::result::Result<(), ()> {
+    let result = send_packet(runtime, session.stop(), "MEDIA_MESSAGE_STOP(existing)");
     if result.is_ok() {
         debug!(
             "{} MEDIA_MESSAGE_STOP existing ch=<b>{:#04x}</>",
-            NAME, channel
+            NAME,
+            session.channel()
         );
     }
     result
@@ -798,18 +768,11 @@ fn send_microphone_request(
     channel: u8,
     open: bool,
 ) -> std::result::Result<(), ()> {
-    let mut payload = build_microphone_request_payload(open);
-    push_media_message_id(
-        &mut payload,
-        MediaMessageId::MEDIA_MESSAGE_MICROPHONE_REQUEST as u16,
-    );
-
-    let pkt = Packet {
+    let pkt = media_packet(
         channel,
-        flags: ENCRYPTED | FRAME_TYPE_FIRST | FRAME_TYPE_LAST,
-        final_length: None,
-        payload,
-    };
+        MediaMessageId::MEDIA_MESSAGE_MICROPHONE_REQUEST,
+        build_microphone_request_payload(open),
+    );
 
     send_packet(
         runtime,
@@ -839,13 +802,47 @@ fn build_microphone_request_payload(open: bool) -> Vec<u8> {
 
 fn adapt_48k_stereo_s16le_to_target(
     input: &[u8],
+    target_sample_rate: u32,
     target_channels: u32,
     gain_percent: u32,
     limiter: BtScoMediaBridgeLimiter,
+    resampler: &mut Option<Resampler>,
 ) -> Vec<u8> {
     let even_len = input.len() & !1;
     let gain = gain_percent.max(1) as i32;
 
+    if target_sample_rate != bt_sco::AA_MEDIA_PCM_SAMPLE_RATE_HZ {
+        // Other sink rates need a real rate conversion; the resampler also
+        // downmixes to the sink channel count and is kept across chunks.
+        let source_rate = bt_sco::AA_MEDIA_PCM_SAMPLE_RATE_HZ;
+        let source_channels = bt_sco::AA_MEDIA_PCM_CHANNELS as u32;
+        if !resampler.as_ref().is_some_and(|r| {
+            r.matches(
+                source_rate,
+                target_sample_rate,
+                source_channels,
+                target_channels,
+            )
+        }) {
+            *resampler = Some(Resampler::new(
+                source_rate,
+                target_sample_rate,
+                source_channels,
+                target_channels,
+            ));
+        }
+        let mut converted = Vec::with_capacity(even_len);
+        if let Some(resampler) = resampler.as_mut() {
+            resampler.process_s16le(&input[..even_len], &mut converted);
+        }
+        let mut out = Vec::with_capacity(converted.len());
+        for sample in converted.chunks_exact(2) {
+            let sample = i16::from_le_bytes([sample[0], sample[1]]);
+            out.extend_from_slice(&apply_gain(sample, gain, limiter).to_le_bytes());
+        }
+        return out;
+    }
+
     if target_channels == 1 {
         // Stereo 48k -> mono 48k by taking the left channel. The source is a
         // duplicated mono SCO signal, so L/R are identical in the common path.
@@ -923,8 +920,3 @@ fn send_packet(runtime: &Runtime, pkt: Packet, label: &str) -> std::result::Resu
         }
     }
 }
-
-fn push_media_message_id(payload: &mut Vec<u8>, id: u16) {
-    payload.insert(0, (id & 0xff) as u8);
-    payload.insert(0, (id >> 8) as u8);
-}
@@ -0,0 +1,339 @@
+use crate::media_record::{self, WavWriter};
+use crate::media_tap::{AudioStreamConfig, MediaSink};
+use crate::mitm::protos::MediaCodecType;
+use crate::resampler::Resampler;
+use simplelog::*;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use tokio_util::sync::CancellationToken;
+
+const NAME: &str = "<i><bright-black> bt-sco-tap: </>";
+
+/// MPEG-TS LPCM has no 8/16 kHz rate codes, so the taps are published at 48 kHz.
+const TAP_SAMPLE_RATE_HZ: u32 = 48_000;
+/// Call recordings use the wideband rate for both codecs, so every file has
+/// the same format regardless of the negotiated codec.
+const RECORD_SAMPLE_RATE_HZ: u32 = 16_000;
+/// Label of the call recording in the `/media/record` list.
+pub const CALL_RECORDING_LABEL: &str = "sco-call";
+
+struct TapSink {
+    sink: MediaSink,
+    /// 48 kHz samples published so far, drives the PTS across calls.
+    samples: u64,
+}
+
+struct TapSinks {
+    downlink: TapSink,
+    uplink: TapSink,
+}
+
+static TAP_SINKS: OnceLock<Mutex<Option<TapSinks>>> = OnceLock::new();
+
+fn tap_sinks() -> &'static Mutex<Option<TapSinks>> {
+    TAP_SINKS.get_or_init(|| Mutex::new(None))
+}
+
+/// Publish SCO downlink/uplink audio on these media tap sinks (48 kHz mono PCM).
+pub async fn set_sinks(downlink: MediaSink, uplink: MediaSink) {
+    let config = AudioStreamConfig {
+        sample_rate: TAP_SAMPLE_RATE_HZ,
+        channels: 1,
+        bits: 16,
+    };
+    for sink in [&downlink, &uplink] {
+        sink.set_audio_stream_info(MediaCodecType::MEDIA_CODEC_AUDIO_PCM, None, Some(config))
+            .await;
+    }
+    *tap_sinks().lock().unwrap() = Some(TapSinks {
+        downlink: TapSink {
+            sink: downlink,
+            samples: 0,
+        },
+        uplink: TapSink {
+            sink: uplink,
+            samples: 0,
+        },
+    });
+}
+
+fn taps_have_subscribers() -> bool {
+    tap_sinks().lock().unwrap().as_ref().is_some_and(|taps| {
+        taps.downlink.sink.has_subscribers() || taps.uplink.sink.has_subscribers()
+    })
+}
+
+struct CallRecorder {
+    writer: WavWriter,
+    path: PathBuf,
+    cancel: CancellationToken,
+    downlink: Resampler,
+    uplink: Resampler,
+    pending_downlink: Vec<i16>,
+    pending_uplink: Vec<i16>,
+}
+
+impl CallRecorder {
+    fn create(dir: &Path, generation: u64) -> std::io::Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!(
+            "sco-call-{}-{}.wav",
+            generation,
+            chrono::Local::now().format("%Y%m%d-%H%M%S")
+        ));
+        let writer = WavWriter::create(&path, RECORD_SAMPLE_RATE_HZ, 2, 16)?;
+        let cancel = media_record::register_recording(CALL_RECORDING_LABEL, &path)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::AlreadyExists, e.to_string()))?;
+        Ok(Self {
+            writer,
+            path,
+            cancel,
+            downlink: Resampler::new(RECORD_SAMPLE_RATE_HZ, RECORD_SAMPLE_RATE_HZ, 1, 1),
+            uplink: Resampler::new(RECORD_SAMPLE_RATE_HZ, RECORD_SAMPLE_RATE_HZ, 1, 1),
+            pending_downlink: Vec::new(),
+            pending_uplink: Vec::new(),
+        })
+    }
+
+    fn push(&mut self, sample_rate: u32, downlink: &[i16], uplink: &[i16]) -> std::io::Result<()> {
+        convert(
+            &mut self.downlink,
+            sample_rate,
+            RECORD_SAMPLE_RATE_HZ,
+            downlink,
+            &mut self.pending_downlink,
+        );
+        convert(
+            &mut self.uplink,
+            sample_rate,
+            RECORD_SAMPLE_RATE_HZ,
+            uplink,
+            &mut self.pending_uplink,
+        );
+        self.write_pending(false)
+    }
+
+    /// Write complete stereo frames (left downlink, right uplink). With
+    /// `flush` the shorter side is padded with silence.
+    fn write_pending(&mut self, flush: bool) -> std::io::Result<()> {
+        let frames = if flush {
+            self.pending_downlink.len().max(self.pending_uplink.len())
+        } else {
+            self.pending_downlink.len().min(self.pending_uplink.len())
+        };
+        if frames == 0 {
+            return Ok(());
+        }
+        self.pending_downlink
+            .resize(frames.max(self.pending_downlink.len()), 0);
+        self.pending_uplink
+            .resize(frames.max(self.pending_uplink.len()), 0);
+
+        let mut pcm = Vec::with_capacity(frames * 4);
+        for (left, right) in self
+            .pending_downlink
+            .drain(..frames)
+            .zip(self.pending_uplink.drain(..frames))
+        {
+            pcm.extend_from_slice(&left.to_le_bytes());
+            pcm.extend_from_slice(&right.to_le_bytes());
+        }
+        self.writer.write_pcm(&pcm)
+    }
+
+    fn finish(mut self) {
+        let flushed = self.write_pending(true);
+        media_record::unregister_recording(CALL_RECORDING_LABEL, &self.path);
+        match flushed.and_then(|_| self.writer.finalize()) {
+            Ok(bytes) => info!(
+                "{} call recording finished: {} ({} bytes of PCM)",
+                NAME,
+                self.path.display(),
+                bytes
+            ),
+            Err(e) => error!(
+                "{} call recording {} failed: {}",
+                NAME,
+                self.path.display(),
+                e
+            ),
+        }
+    }
+}
+
+/// Per-connection SCO audio tap: publishes both directions on the media taps
+/// and optionally records the call as a stereo WAV (left downlink, right uplink).
+pub struct CallTap {
+    generation: u64,
+    downlink_tap: Resampler,
+    uplink_tap: Resampler,
+    recorder: Option<CallRecorder>,
+}
+
+impl CallTap {
+    /// `record_dir` enables the per-call recorder for this SCO generation.
+    pub fn new(generation: u64, record_dir: Option<&Path>) -> Self {
+        let recorder = record_dir.and_then(|dir| match CallRecorder::create(dir, generation) {
+            Ok(recorder) => {
+                info!(
+                    "{} recording SCO generation {} to {}",
+                    NAME,
+                    generation,
+                    recorder.path.display()
+                );
+                Some(recorder)
+            }
+            Err(e) => {
+                warn!(
+                    "{} cannot record SCO generation {}: {}",
+                    NAME, generation, e
+                );
+                None
+            }
+        });
+        Self {
+            generation,
+            downlink_tap: Resampler::new(TAP_SAMPLE_RATE_HZ, TAP_SAMPLE_RATE_HZ, 1, 1),
+            uplink_tap: Resampler::new(TAP_SAMPLE_RATE_HZ, TAP_SAMPLE_RATE_HZ, 1, 1),
+            recorder,
+        }
+    }
+
+    /// True when a recorder or a tap client consumes the audio, so callers can
+    /// skip extra work such as decoding the mSBC uplink otherwise.
+    pub fn is_active(&self) -> bool {
+        self.recorder.is_some() || taps_have_subscribers()
+    }
+
+    /// Feed one SCO packet worth of s16le mono PCM at `sample_rate`. `uplink`
+    /// is `None` when nothing was written toward the phone, recorded as silence.
+    pub fn push(&mut self, sample_rate: u32, downlink: &[u8], uplink: Option<&[u8]>) {
+        if self
+            .recorder
+            .as_ref()
+            .is_some_and(|recorder| recorder.cancel.is_cancelled())
+        {
+            info!("{} call recording stopped on request", NAME);
+            if let Some(recorder) = self.recorder.take() {
+                recorder.finish();
+            }
+        }
+        if !self.is_active() {
+            return;
+        }
+
+        let downlink = s16le_samples(downlink);
+        let uplink = match uplink {
+            Some(uplink) => s16le_samples(uplink),
+            None => vec![0; downlink.len()],
+        };
+
+        if let Some(taps) = tap_sinks().lock().unwrap().as_mut() {
+            publish(
+                &mut taps.downlink,
+                &mut self.downlink_tap,
+                sample_rate,
+                &downlink,
+            );
+            publish(&mut taps.uplink, &mut self.uplink_tap, sample_rate, &uplink);
+        }
+
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(e) = recorder.push(sample_rate, &downlink, &uplink) {
+                error!(
+                    "{} call recording of SCO generation {} failed: {}",
+                    NAME, self.generation, e
+                );
+                self.recorder = None;
+            }
+        }
+    }
+
+    /// Flush and close the call recording, if any.
+    pub fn finish(mut self) {
+        if let Some(recorder) = self.recorder.take() {
+            recorder.finish();
+        }
+    }
+}
+
+fn publish(tap: &mut TapSink, resampler: &mut Resampler, sample_rate: u32, samples: &[i16]) {
+    if !tap.sink.has_subscribers() {
+        return;
+    }
+    let mut converted = Vec::new();
+    convert(
+        resampler,
+        sample_rate,
+        TAP_SAMPLE_RATE_HZ,
+        samples,
+        &mut converted,
+    );
+    if converted.is_empty() {
+        return;
+    }
+    let pts_us = tap.samples * 1_000_000 / TAP_SAMPLE_RATE_HZ as u64;
+    tap.samples += converted.len() as u64;
+    let pcm = converted.iter().flat_map(|s| s.to_le_bytes()).collect();
+    tap.sink.send_frame(pts_us, pcm);
+}
+
+/// Resample mono PCM, rebuilding the resampler when the SCO rate changes
+/// (mSBC falling back to CVSD).
+fn convert(
+    resampler: &mut Resampler,
+    in_rate: u32,
+    out_rate: u32,
+    input: &[i16],
+    output: &mut Vec<i16>,
+) {
+    if !resampler.matches(in_rate, out_rate, 1, 1) {
+        *resampler = Resampler::new(in_rate, out_rate, 1, 1);
+    }
+    resampler.process(input, output);
+}
+
+fn s16le_samples(pcm: &[u8]) -> Vec<i16> {
+    pcm.chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recorder_interleaves_downlink_left_and_uplink_right() {
+        let dir = std::env::temp_dir().join(format!("bt-sco-tap-test-{}", std::process::id()));
+        let mut tap = CallTap::new(7, Some(&dir));
+        let path = tap.recorder.as_ref().unwrap().path.clone();
+
+        let downlink: Vec<u8> = [1000i16; 120]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        let uplink: Vec<u8> = [-2000i16; 120]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        for _ in 0..10 {
+            tap.push(16_000, &downlink, Some(&uplink));
+        }
+        tap.push(16_000, &downlink, None);
+        tap.finish();
+
+        let wav = std::fs::read(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(&wav[22..24], &2u16.to_le_bytes());
+        assert_eq!(&wav[24..28], &RECORD_SAMPLE_RATE_HZ.to_le_bytes());
+        let data_len = u32::from_le_bytes(wav[40..44].try_into().unwrap()) as usize;
+        assert_eq!(data_len, 11 * 120 * 4);
+
+        let frame = &wav[44 + 200 * 4..44 + 201 * 4];
+        assert_eq!(i16::from_le_bytes([frame[0], frame[1]]), 1000);
+        assert_eq!(i16::from_le_bytes([frame[2], frame[3]]), -2000);
+        let last = &wav[wav.len() - 4..];
+        assert_eq!(i16::from_le_bytes([last[2], last[3]]), 0);
+    }
+}
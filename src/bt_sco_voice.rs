@@ -0,0 +1,573 @@
+use serde::Serialize;
+use simplelog::*;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+const NAME: &str = "<i><bright-black> bt-sco-voice: </>";
+
+/// Noise suppressor STFT frame (32 ms at 8 kHz) and hop (50% overlap).
+const NS_FRAME: usize = 256;
+const NS_HOP: usize = NS_FRAME / 2;
+const NS_BINS: usize = NS_FRAME / 2 + 1;
+/// Frames averaged into the initial noise estimate.
+const NS_INIT_FRAMES: u64 = 8;
+/// Per-frame rise of the noise estimate while the signal is above it
+/// (about 0.5 dB/s), lets the floor follow a slowly increasing road noise.
+const NS_NOISE_RISE: f32 = 1.002;
+/// Recursive smoothing of the per-bin power the noise minimum is taken from.
+const NS_POWER_SMOOTHING: f32 = 0.7;
+/// Compensates the minimum of the smoothed power sitting below the mean.
+const NS_NOISE_BIAS: f32 = 2.0;
+/// Lowest gain applied to a bin (-20 dB), avoids "musical noise".
+const NS_GAIN_FLOOR: f32 = 0.1;
+/// Over-subtraction factor at 100% strength.
+const NS_OVER_SUBTRACTION: f32 = 2.0;
+/// Temporal smoothing of the per-bin gains.
+const NS_GAIN_SMOOTHING: f32 = 0.5;
+
+/// AGC analysis block (10 ms at 8 kHz).
+const AGC_BLOCK: usize = 80;
+/// Blocks quieter than this are not speech and do not steer the gain.
+const AGC_GATE_DBFS: f32 = -55.0;
+/// Lowest AGC gain (-12 dB) applied to overly loud microphones.
+const AGC_MIN_GAIN_DB: f32 = -12.0;
+/// Fraction of the distance to a lower target gain taken per block.
+const AGC_ATTACK: f32 = 0.3;
+/// Gain increase per block (3 dB/s).
+const AGC_RELEASE_DB_PER_BLOCK: f32 = 0.03;
+
+/// Smoothing of the reported levels.
+const METRICS_SMOOTHING: f32 = 0.9;
+const LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct BtScoVoiceSettings {
+    pub noise_suppression: bool,
+    /// Noise suppression strength in percent; 100 is the tuned default.
+    pub ns_strength_percent: u32,
+    pub agc: bool,
+    /// Speech level the AGC steers towards.
+    pub agc_target_dbfs: i32,
+    /// Largest gain the AGC may apply.
+    pub agc_max_gain_db: u32,
+}
+
+impl Default for BtScoVoiceSettings {
+    fn default() -> Self {
+        Self {
+            noise_suppression: false,
+            ns_strength_percent: 100,
+            agc: false,
+            agc_target_dbfs: -20,
+            agc_max_gain_db: 18,
+        }
+    }
+}
+
+/// Microphone uplink levels, as reported by `/bt/sco/voice`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct BtScoVoiceMetrics {
+    pub generation: u64,
+    pub noise_suppression: bool,
+    pub agc: bool,
+    /// Level entering the voice processing (after echo control).
+    pub input_dbfs: Option<f32>,
+    /// Level written to the SCO uplink.
+    pub output_dbfs: Option<f32>,
+    pub noise_floor_dbfs: Option<f32>,
+    pub ns_attenuation_db: Option<f32>,
+    pub agc_gain_db: Option<f32>,
+}
+
+struct VoiceState {
+    settings: BtScoVoiceSettings,
+    ns: Option<NoiseSuppressor>,
+    agc: Option<Agc>,
+    metrics: BtScoVoiceMetrics,
+    last_log: Instant,
+}
+
+impl Default for VoiceState {
+    fn default() -> Self {
+        Self {
+            settings: BtScoVoiceSettings::default(),
+            ns: None,
+            agc: None,
+            metrics: BtScoVoiceMetrics::default(),
+            last_log: Instant::now(),
+        }
+    }
+}
+
+static VOICE_STATE: OnceLock<Arc<Mutex<VoiceState>>> = OnceLock::new();
+
+fn state() -> Arc<Mutex<VoiceState>> {
+    VOICE_STATE
+        .get_or_init(|| Arc::new(Mutex::new(VoiceState::default())))
+        .clone()
+}
+
+impl VoiceState {
+    fn rebuild(&mut self) {
+        self.ns = self
+            .settings
+            .noise_suppression
+            .then(|| NoiseSuppressor::new(self.settings.ns_strength_percent));
+        self.agc = self.settings.agc.then(|| {
+            Agc::new(
+                self.settings.agc_target_dbfs as f32,
+                self.settings.agc_max_gain_db as f32,
+            )
+        });
+        self.metrics = BtScoVoiceMetrics {
+            generation: self.metrics.generation,
+            noise_suppression: self.settings.noise_suppression,
+            agc: self.settings.agc,
+            ..Default::default()
+        };
+    }
+}
+
+pub fn configure(settings: BtScoVoiceSettings) {
+    let state = state();
+    let mut state = state.lock().unwrap();
+    debug!(
+        "{} configured noise_suppression={} (strength={}%), agc={} (target={}dBFS, max_gain={}dB)",
+        NAME,
+        settings.noise_suppression,
+        settings.ns_strength_percent,
+        settings.agc,
+        settings.agc_target_dbfs,
+        settings.agc_max_gain_db
+    );
+    state.settings = settings;
+    state.rebuild();
+}
+
+pub fn reset_for_sco_generation(generation: u64) {
+    let state = state();
+    let mut state = state.lock().unwrap();
+    state.metrics.generation = generation;
+    state.rebuild();
+}
+
+pub fn metrics() -> BtScoVoiceMetrics {
+    state().lock().unwrap().metrics.clone()
+}
+
+/// Noise suppression and AGC for the mono mic uplink at the SCO rate. The
+/// suppressor works on STFT frames, so `samples` may come back shorter or
+/// longer than passed in; the stream as a whole is delayed by one hop (16 ms
+/// at 8 kHz, 8 ms at 16 kHz).
+pub fn process_mic_samples(samples: &mut Vec<i16>) {
+    if samples.is_empty() {
+        return;
+    }
+
+    let state = state();
+    let mut state = state.lock().unwrap();
+    let state = &mut *state;
+    if state.ns.is_none() && state.agc.is_none() {
+        return;
+    }
+
+    smooth_level(&mut state.metrics.input_dbfs, rms_dbfs(samples));
+    if let Some(ns) = state.ns.as_mut() {
+        ns.process(samples);
+        state.metrics.noise_floor_dbfs = ns.noise_floor_dbfs();
+        state.metrics.ns_attenuation_db = Some(ns.attenuation_db);
+    }
+    if let Some(agc) = state.agc.as_mut() {
+        agc.process(samples);
+        state.metrics.agc_gain_db = Some(agc.gain_db);
+    }
+    if !samples.is_empty() {
+        smooth_level(&mut state.metrics.output_dbfs, rms_dbfs(samples));
+    }
+
+    if state.last_log.elapsed() >= LOG_INTERVAL {
+        state.last_log = Instant::now();
+        let m = &state.metrics;
+        debug!(
+            "{} mic in={:?}dBFS out={:?}dBFS noise={:?}dBFS ns_att={:?}dB agc={:?}dB",
+            NAME,
+            m.input_dbfs,
+            m.output_dbfs,
+            m.noise_floor_dbfs,
+            m.ns_attenuation_db,
+            m.agc_gain_db
+        );
+    }
+}
+
+fn smooth_level(level: &mut Option<f32>, value: f32) {
+    *level = Some(match *level {
+        Some(prev) => prev * METRICS_SMOOTHING + value * (1.0 - METRICS_SMOOTHING),
+        None => value,
+    });
+}
+
+fn rms_dbfs(samples: &[i16]) -> f32 {
+    if samples.is_empty() {
+        return -96.0;
+    }
+    let energy: f64 = samples.iter().map(|s| (*s as f64) * (*s as f64)).sum();
+    power_dbfs((energy / samples.len() as f64) as f32)
+}
+
+fn power_dbfs(power: f32) -> f32 {
+    (10.0 * (power / (32768.0 * 32768.0)).max(1.0e-10).log10()).max(-96.0)
+}
+
+/// Spectral subtraction noise suppressor with a minimum-tracking noise estimate.
+pub struct NoiseSuppressor {
+    over_subtraction: f32,
+    /// sqrt-Hann window, used for analysis and synthesis (perfect
+    /// reconstruction at 50% overlap).
+    window: Vec<f32>,
+    fft: Fft,
+    pending: Vec<f32>,
+    frame: Vec<f32>,
+    overlap: Vec<f32>,
+    smoothed: Vec<f32>,
+    noise: Vec<f32>,
+    gains: Vec<f32>,
+    frames: u64,
+    output: VecDeque<i16>,
+    window_energy: f32,
+    attenuation_db: f32,
+}
+
+impl NoiseSuppressor {
+    pub fn new(strength_percent: u32) -> Self {
+        let window: Vec<f32> = (0..NS_FRAME)
+            .map(|n| {
+                let hann =
+                    0.5 - 0.5 * (2.0 * std::f32::consts::PI * n as f32 / NS_FRAME as f32).cos();
+                hann.sqrt()
+            })
+            .collect();
+        let window_energy = window.iter().map(|w| w * w).sum();
+        Self {
+            over_subtraction: NS_OVER_SUBTRACTION * strength_percent.min(300) as f32 / 100.0,
+            window,
+            fft: Fft::new(NS_FRAME),
+            pending: Vec::with_capacity(NS_HOP),
+            frame: vec![0.0; NS_FRAME],
+            overlap: vec![0.0; NS_HOP],
+            smoothed: vec![0.0; NS_BINS],
+            noise: vec![0.0; NS_BINS],
+            gains: vec![1.0; NS_BINS],
+            frames: 0,
+            output: VecDeque::new(),
+            window_energy,
+            attenuation_db: 0.0,
+        }
+    }
+
+    /// Current noise floor estimate, once the initial frames were seen.
+    pub fn noise_floor_dbfs(&self) -> Option<f32> {
+        if self.frames < NS_INIT_FRAMES {
+            return None;
+        }
+        let spectrum_power = NS_NOISE_BIAS
+            * (self.noise[0]
+                + self.noise[NS_BINS - 1]
+                + 2.0 * self.noise[1..NS_BINS - 1].iter().sum::<f32>());
+        Some(power_dbfs(
+            spectrum_power / (NS_FRAME as f32 * self.window_energy),
+        ))
+    }
+
+    pub fn process(&mut self, samples: &mut Vec<i16>) {
+        for sample in samples.iter() {
+            self.pending.push(*sample as f32);
+            if self.pending.len() == NS_HOP {
+                self.process_hop();
+            }
+        }
+        samples.clear();
+        samples.extend(self.output.drain(..));
+    }
+
+    fn process_hop(&mut self) {
+        self.frame.copy_within(NS_HOP.., 0);
+        self.frame[NS_FRAME - NS_HOP..].copy_from_slice(&self.pending);
+        self.pending.clear();
+
+        let mut re: Vec<f32> = self
+            .frame
+            .iter()
+            .zip(&self.window)
+            .map(|(x, w)| x * w)
+            .collect();
+        let mut im = vec![0.0f32; NS_FRAME];
+        self.fft.transform(&mut re, &mut im, false);
+
+        self.frames += 1;
+        let mut in_energy = 0.0f32;
+        let mut out_energy = 0.0f32;
+        for bin in 0..NS_BINS {
+            let power = re[bin] * re[bin] + im[bin] * im[bin];
+            let smoothed = &mut self.smoothed[bin];
+            *smoothed = NS_POWER_SMOOTHING * *smoothed + (1.0 - NS_POWER_SMOOTHING) * power;
+            let noise = &mut self.noise[bin];
+            if self.frames <= NS_INIT_FRAMES {
+                *noise += (power - *noise) / self.frames as f32;
+            } else if *smoothed < *noise {
+                *noise = *smoothed;
+            } else {
+                *noise *= NS_NOISE_RISE;
+            }
+
+            let gain = if power > 0.0 {
+                (1.0 - self.over_subtraction * NS_NOISE_BIAS * *noise / power).max(NS_GAIN_FLOOR)
+            } else {
+                NS_GAIN_FLOOR
+            };
+            let gain = NS_GAIN_SMOOTHING * self.gains[bin] + (1.0 - NS_GAIN_SMOOTHING) * gain;
+            self.gains[bin] = gain;
+
+            in_energy += power;
+            out_energy += power * gain * gain;
+            re[bin] *= gain;
+            im[bin] *= gain;
+            // keep the spectrum conjugate-symmetric
+            if bin > 0 && bin < NS_BINS - 1 {
+                re[NS_FRAME - bin] *= gain;
+                im[NS_FRAME - bin] *= gain;
+            }
+        }
+        if in_energy > 0.0 {
+            self.attenuation_db = 10.0 * (in_energy / out_energy.max(1.0e-10)).log10();
+        }
+
+        self.fft.transform(&mut re, &mut im, true);
+        for ((sample, window), overlap) in re[..NS_HOP]
+            .iter()
+            .zip(&self.window[..NS_HOP])
+            .zip(&self.overlap)
+        {
+            let value = sample * window + overlap;
+            self.output
+                .push_back(value.round().clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+        }
+        for ((overlap, sample), window) in self
+            .overlap
+            .iter_mut()
+            .zip(&re[NS_HOP..])
+            .zip(&self.window[NS_HOP..])
+        {
+            *overlap = sample * window;
+        }
+    }
+}
+
+/// Block-based automatic gain control with fast attack and slow release.
+pub struct Agc {
+    target_dbfs: f32,
+    max_gain_db: f32,
+    gain_db: f32,
+    pending: Vec<i16>,
+}
+
+impl Agc {
+    pub fn new(target_dbfs: f32, max_gain_db: f32) -> Self {
+        Self {
+            target_dbfs: target_dbfs.clamp(-40.0, -3.0),
+            max_gain_db: max_gain_db.clamp(0.0, 40.0),
+            gain_db: 0.0,
+            pending: Vec::with_capacity(AGC_BLOCK),
+        }
+    }
+
+    pub fn gain_db(&self) -> f32 {
+        self.gain_db
+    }
+
+    /// Apply the gain in place; the gain itself is steered once per block.
+    pub fn process(&mut self, samples: &mut [i16]) {
+        for sample in samples.iter_mut() {
+            self.pending.push(*sample);
+            if self.pending.len() == AGC_BLOCK {
+                self.update_gain();
+                self.pending.clear();
+            }
+            let gain = 10f32.powf(self.gain_db / 20.0);
+            *sample = (*sample as f32 * gain)
+                .round()
+                .clamp(i16::MIN as f32, i16::MAX as f32) as i16;
+        }
+    }
+
+    fn update_gain(&mut self) {
+        let level = rms_dbfs(&self.pending);
+        if level < AGC_GATE_DBFS {
+            return;
+        }
+        let desired = (self.target_dbfs - level).clamp(AGC_MIN_GAIN_DB, self.max_gain_db);
+        if desired < self.gain_db {
+            self.gain_db += (desired - self.gain_db) * AGC_ATTACK;
+        } else {
+            self.gain_db = (self.gain_db + AGC_RELEASE_DB_PER_BLOCK).min(desired);
+        }
+    }
+}
+
+/// Iterative radix-2 complex FFT for power-of-two sizes.
+struct Fft {
+    size: usize,
+    cos: Vec<f32>,
+    sin: Vec<f32>,
+}
+
+impl Fft {
+    fn new(size: usize) -> Self {
+        assert!(size.is_power_of_two());
+        let (cos, sin) = (0..size / 2)
+            .map(|k| {
+                let angle = -2.0 * std::f32::consts::PI * k as f32 / size as f32;
+                (angle.cos(), angle.sin())
+            })
+            .unzip();
+        Self { size, cos, sin }
+    }
+
+    fn transform(&self, re: &mut [f32], im: &mut [f32], inverse: bool) {
+        let n = self.size;
+        let bits = n.trailing_zeros();
+        for i in 0..n {
+            let j = i.reverse_bits() >> (usize::BITS - bits);
+            if j > i {
+                re.swap(i, j);
+                im.swap(i, j);
+            }
+        }
+
+        let mut len = 2;
+        while len <= n {
+            let step = n / len;
+            for start in (0..n).step_by(len) {
+                for k in 0..len / 2 {
+                    let wr = self.cos[k * step];
+                    let wi = if inverse {
+                        -self.sin[k * step]
+                    } else {
+                        self.sin[k * step]
+                    };
+                    let a = start + k;
+                    let b = a + len / 2;
+                    let tr = re[b] * wr - im[b] * wi;
+                    let ti = re[b] * wi + im[b] * wr;
+                    re[b] = re[a] - tr;
+                    im[b] = im[a] - ti;
+                    re[a] += tr;
+                    im[a] += ti;
+                }
+            }
+            len *= 2;
+        }
+
+        if inverse {
+            let scale = 1.0 / n as f32;
+            re.iter_mut().for_each(|v| *v *= scale);
+            im.iter_mut().for_each(|v| *v *= scale);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noise(len: usize, amplitude: f32) -> Vec<f32> {
+        let mut seed = 0x2468_ace1u32;
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                ((seed >> 16) as f32 / 32768.0 - 1.0) * amplitude
+            })
+            .collect()
+    }
+
+    fn tone(len: usize, freq: f32, amplitude: f32) -> Vec<f32> {
+        (0..len)
+            .map(|n| amplitude * (2.0 * std::f32::consts::PI * freq * n as f32 / 8000.0).sin())
+            .collect()
+    }
+
+    fn to_i16(samples: &[f32]) -> Vec<i16> {
+        samples.iter().map(|s| *s as i16).collect()
+    }
+
+    fn run_ns(ns: &mut NoiseSuppressor, input: &[i16]) -> Vec<i16> {
+        let mut out = Vec::new();
+        for chunk in input.chunks(160) {
+            let mut block = chunk.to_vec();
+            ns.process(&mut block);
+            out.extend(block);
+        }
+        out
+    }
+
+    #[test]
+    fn ns_without_subtraction_reconstructs_input() {
+        let input = to_i16(&tone(4000, 440.0, 8000.0));
+        let mut ns = NoiseSuppressor::new(0);
+        let out = run_ns(&mut ns, &input);
+        // one hop of latency, one partial hop still pending
+        assert_eq!(out.len(), input.len() / NS_HOP * NS_HOP);
+        for (o, i) in out[NS_HOP..].iter().zip(&input) {
+            assert!((*o as i32 - *i as i32).abs() <= 1);
+        }
+    }
+
+    #[test]
+    fn ns_attenuates_stationary_noise_and_keeps_speech() {
+        let len = 8000 * 4;
+        let background = noise(len, 1000.0);
+        let speech = tone(len, 500.0, 8000.0);
+        // noise only for 3 s, then the "talker" joins
+        let input: Vec<i16> = (0..len)
+            .map(|n| {
+                let talk = if n >= 8000 * 3 { speech[n] } else { 0.0 };
+                (background[n] + talk) as i16
+            })
+            .collect();
+        let mut ns = NoiseSuppressor::new(100);
+        let out = run_ns(&mut ns, &input);
+
+        let level = |s: &[i16]| rms_dbfs(s);
+        let noise_in = level(&input[8000 * 2..8000 * 3]);
+        let noise_out = level(&out[8000 * 2 + NS_HOP..8000 * 3]);
+        assert!(noise_in - noise_out > 10.0, "in={noise_in} out={noise_out}");
+
+        let speech_in = level(&input[8000 * 3 + 800..]);
+        let speech_out = level(&out[8000 * 3 + 800 + NS_HOP..]);
+        assert!((speech_in - speech_out).abs() < 1.5);
+        assert!(ns.noise_floor_dbfs().is_some());
+    }
+
+    #[test]
+    fn agc_brings_quiet_and_loud_speech_to_target() {
+        for amplitude in [1000.0, 8000.0] {
+            let mut agc = Agc::new(-20.0, 18.0);
+            let mut samples = to_i16(&tone(8000 * 10, 300.0, amplitude));
+            agc.process(&mut samples);
+            let level = rms_dbfs(&samples[8000 * 9..]);
+            assert!(
+                (level + 20.0).abs() < 1.0,
+                "amplitude={amplitude} level={level}"
+            );
+        }
+    }
+
+    #[test]
+    fn agc_ignores_silence() {
+        let mut agc = Agc::new(-20.0, 18.0);
+        let mut samples = to_i16(&noise(8000, 20.0));
+        agc.process(&mut samples);
+        assert_eq!(agc.gain_db(), 0.0);
+    }
+}
@@ -1,7 +1,10 @@
 use crate::bluetooth::BTLE_PROFILE_UUID;
 use crate::config::BASE_CONFIG_DIR;
 use crate::config::{Action, AppConfig};
+use crate::crash;
+use crate::device_info;
 use crate::ev::{send_ev_data, BatteryData, EV_MODEL_FILE};
+use crate::sdr_ui;
 use crate::web::AppState;
 use bluer::gatt::local::{
     Application, Characteristic, CharacteristicNotify, CharacteristicNotifyMethod,
@@ -30,6 +33,12 @@ struct Request {
     b: Option<String>,
     #[serde(default)]
     p: Option<String>,
+    /// Protocol version of the client, missing for version 1 clients.
+    #[serde(default)]
+    v: Option<u16>,
+    /// Request id echoed in the response (version 2+).
+    #[serde(default)]
+    id: Option<u32>,
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -39,6 +48,41 @@ struct Response {
     b: Option<String>,
 }
 
+/// Version 2+ responses carry the protocol version and the request id.
+#[derive(Serialize, Debug)]
+struct VersionedResponse<'a> {
+    #[serde(flatten)]
+    response: &'a Response,
+    v: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<u32>,
+}
+
+/// Version 1: compressed response split at MTU size, followed by `FINISH_SIGNAL`.
+/// Version 2: every notification starts with a 4 byte header (`seq`, `count`,
+/// both u16 LE), so the client knows when the response is complete and can
+/// detect lost chunks. There is no finish marker.
+pub const PROTOCOL_VERSION: u16 = 2;
+const CHUNK_HEADER_LEN: usize = 4;
+/// Operations available to version 2 clients, returned by `/version`.
+const OPERATIONS: &[&str] = &[
+    "/version",
+    "/status",
+    "/get-config",
+    "/get-config-data",
+    "/get-config-keys",
+    "/update-config",
+    "/set-config-keys",
+    "/reconnect",
+    "/restart",
+    "/reboot",
+    "/crashes",
+    "/crash",
+    "/battery",
+    "/update-hex-model",
+    "/update-certs",
+];
+
 const FINISH_SIGNAL: u32 = u32::MAX;
 pub const SERVICE_UUID_16: &str = "2fbe6";
 const CHAR_UUID: &str = "2fbe6aa1-844b-41fa-9d03-fd4453a88c36";
@@ -57,6 +101,36 @@ fn decompress_data(data: &[u8]) -> Vec<u8> {
     decoder.finish().unwrap()
 }
 
+/// Split a compressed response into notifications for a client speaking
+/// protocol `version`.
+fn notification_frames(data: &[u8], mtu: usize, version: u16) -> Vec<Vec<u8>> {
+    let budget = mtu.saturating_sub(4).max(1);
+    if version < 2 {
+        let mut frames: Vec<Vec<u8>> = data.chunks(budget).map(|c| c.to_vec()).collect();
+        frames.push(FINISH_SIGNAL.to_le_bytes().to_vec());
+        return frames;
+    }
+
+    let payload = budget.saturating_sub(CHUNK_HEADER_LEN).max(1);
+    let chunks: Vec<&[u8]> = if data.is_empty() {
+        vec![&[]]
+    } else {
+        data.chunks(payload).collect()
+    };
+    let count = chunks.len() as u16;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(seq, chunk)| {
+            let mut frame = Vec::with_capacity(CHUNK_HEADER_LEN + chunk.len());
+            frame.extend_from_slice(&(seq as u16).to_le_bytes());
+            frame.extend_from_slice(&count.to_le_bytes());
+            frame.extend_from_slice(chunk);
+            frame
+        })
+        .collect()
+}
+
 pub async fn run_btle_server(
     adapter: &Adapter,
     state: AppState,
@@ -213,7 +287,17 @@ pub async fn run_btle_server(
                                             // Build response (may use blocking inside craft_response; it already blocks internally)
                                             let resp =
                                                 craft_response(&parsed_req, state.clone()).await;
-                                            let data = match serde_json::to_vec(&resp) {
+                                            let version = parsed_req.v.unwrap_or(1);
+                                            let serialized = if version >= 2 {
+                                                serde_json::to_vec(&VersionedResponse {
+                                                    response: &resp,
+                                                    v: PROTOCOL_VERSION,
+                                                    id: parsed_req.id,
+                                                })
+                                            } else {
+                                                serde_json::to_vec(&resp)
+                                            };
+                                            let data = match serialized {
                                                 Ok(d) => d,
                                                 Err(e) => {
                                                     error!(
@@ -228,13 +312,18 @@ pub async fn run_btle_server(
 
                                             // Send response if we have a writer (notify subscription)
                                             if let Some(writer) = writer_opt.as_mut() {
-                                                debug!("{} 🥏 Writing {} compressed bytes back (writer mtu={})", NAME, compressed.len(), writer.mtu());
-                                                for c in compressed.chunks(writer.mtu() - 4) {
-                                                    match writer.write_all(c).await {
+                                                let frames = notification_frames(
+                                                    &compressed,
+                                                    writer.mtu(),
+                                                    version,
+                                                );
+                                                debug!("{} 🥏 Writing {} compressed bytes back in {} notification(s) (writer mtu={}, protocol v{})", NAME, compressed.len(), frames.len(), writer.mtu(), version);
+                                                for frame in frames {
+                                                    match writer.write_all(&frame).await {
                                                         Ok(_) => debug!(
                                                             "{} 🥏 wrote chunk {} bytes",
                                                             NAME,
-                                                            c.len()
+                                                            frame.len()
                                                         ),
                                                         Err(e) => {
                                                             error!(
@@ -245,19 +334,6 @@ pub async fn run_btle_server(
                                                         }
                                                     }
                                                 }
-                                                match writer
-                                                    .write_all(&FINISH_SIGNAL.to_le_bytes())
-                                                    .await
-                                                {
-                                                    Ok(_) => debug!(
-                                                        "{} 🥏 Finish marker written to client",
-                                                        NAME
-                                                    ),
-                                                    Err(e) => error!(
-                                                        "{} 🥏 Error writing finish marker: {}",
-                                                        NAME, e
-                                                    ),
-                                                }
                                             } else {
                                                 warn!("{} 🥏 No notifier/writer attached, cannot send response", NAME);
                                             }
@@ -300,9 +376,61 @@ fn decrypt_and_parse(buf: &[u8]) -> Request {
     serde_json::from_slice(&dec).unwrap()
 }
 
+fn json_response(req: &Request, s: u16, body: serde_json::Value) -> Response {
+    Response {
+        s,
+        pt: req.pt.clone(),
+        b: Some(body.to_string()),
+    }
+}
+
+fn error_response(req: &Request, s: u16, msg: impl Into<String>) -> Response {
+    json_response(
+        req,
+        s,
+        serde_json::json!({ "status": 0, "msg": msg.into() }),
+    )
+}
+
+/// Merge `updates` into the current config. Unknown keys are rejected so typos
+/// do not get silently dropped.
+fn merge_config_keys(
+    cfg: &AppConfig,
+    updates: serde_json::Map<String, serde_json::Value>,
+) -> std::result::Result<AppConfig, String> {
+    let mut value = serde_json::to_value(cfg).map_err(|e| e.to_string())?;
+    let current = value.as_object_mut().ok_or("config is not an object")?;
+    for (key, new_value) in updates {
+        match current.get_mut(&key) {
+            Some(slot) => *slot = new_value,
+            None => return Err(format!("unknown config key: {}", key)),
+        }
+    }
+    let mut merged: AppConfig = serde_json::from_value(value).map_err(|e| e.to_string())?;
+    // runtime state is not serialized, keep it
+    merged.action_requested = cfg.action_requested.clone();
+    merged.runtime_mitm_failed = cfg.runtime_mitm_failed;
+    Ok(merged)
+}
+
 // FIXME below function should use/translate direct requests to main webserver
 // REWRITE THIS !!!
 async fn craft_response(req: &Request, state: AppState) -> Response {
+    // lets clients negotiate the protocol before knowing the password
+    if req.pt == "/version" {
+        return json_response(
+            req,
+            200,
+            serde_json::json!({
+                "protocol": PROTOCOL_VERSION,
+                "version": env!("CARGO_PKG_VERSION"),
+                "board": device_info::board_prefix(),
+                "model": device_info::get_sbc_model().ok(),
+                "ops": OPERATIONS,
+            }),
+        );
+    }
+
     {
         let cfg_guard = state.config.read().await;
         let expected_password = cfg_guard.ble_password.clone();
@@ -392,6 +520,12 @@ async fn craft_response(req: &Request, state: AppState) -> Response {
                 }
             };
 
+            // a config that fails validation would keep the dongle from booting
+            if let Err(e) = parsed_cfg.validate() {
+                error!("{} 🥏 /update-config - {}", NAME, e);
+                return error_response(req, 400, e);
+            }
+
             // write to shared config and persist
             {
                 let mut cfg = state.config.write().await;
@@ -530,7 +664,104 @@ async fn craft_response(req: &Request, state: AppState) -> Response {
                 b: Some("{ \"status\": 1 }".to_string()),
             }
         }
-        "/restart" => {
+        "/status" => {
+            let aa_connected = state.tx.lock().await.is_some();
+            let speed = *state.last_speed.read().await;
+            let battery = state
+                .last_battery_data
+                .read()
+                .await
+                .as_ref()
+                .and_then(|d| serde_json::to_value(d).ok());
+
+            json_response(
+                req,
+                200,
+                serde_json::json!({
+                    "status": 1,
+                    "aa_connected": aa_connected,
+                    "phone": sdr_ui::current_phone(),
+                    "speed": speed,
+                    "battery": battery,
+                }),
+            )
+        }
+        "/get-config-keys" => {
+            // body: JSON array of config keys
+            let keys: Vec<String> = match req.b.as_deref().map(serde_json::from_str) {
+                Some(Ok(keys)) => keys,
+                Some(Err(e)) => return error_response(req, 400, format!("invalid keys: {}", e)),
+                None => return error_response(req, 400, "missing body"),
+            };
+            let cfg = serde_json::to_value(&*state.config.read().await).unwrap_or_default();
+            let mut values = serde_json::Map::new();
+            for key in keys {
+                match cfg.get(&key) {
+                    Some(value) => {
+                        values.insert(key, value.clone());
+                    }
+                    None => {
+                        return error_response(req, 404, format!("unknown config key: {}", key))
+                    }
+                }
+            }
+            json_response(req, 200, serde_json::Value::Object(values))
+        }
+        "/set-config-keys" => {
+            // body: JSON object with the keys to change
+            let updates: serde_json::Map<String, serde_json::Value> =
+                match req.b.as_deref().map(serde_json::from_str) {
+                    Some(Ok(updates)) => updates,
+                    Some(Err(e)) => {
+                        return error_response(req, 400, format!("invalid body: {}", e))
+                    }
+                    None => return error_response(req, 400, "missing body"),
+                };
+            let keys: Vec<String> = updates.keys().cloned().collect();
+
+            let mut cfg = state.config.write().await;
+            let new_cfg = match merge_config_keys(&cfg, updates) {
+                Ok(new_cfg) => new_cfg,
+                Err(e) => {
+                    error!("{} 🥏 /set-config-keys - {}", NAME, e);
+                    return error_response(req, 400, e);
+                }
+            };
+            if let Err(e) = new_cfg.validate() {
+                error!("{} 🥏 /set-config-keys - {}", NAME, e);
+                return error_response(req, 400, e);
+            }
+            crash::set_crash_handler_enabled(new_cfg.crash_handler_enabled);
+            crash::set_crash_dir(new_cfg.crash_dir.clone());
+            *cfg = new_cfg;
+            cfg.save((&state.config_file).to_path_buf());
+            info!("{} 🥏 config updated via BLE: {:?}", NAME, keys);
+
+            json_response(req, 200, serde_json::json!({ "status": 1, "keys": keys }))
+        }
+        "/crashes" => {
+            let crash_dir = state.config.read().await.crash_dir.clone();
+            match crash::list_crashes(&crash_dir) {
+                Ok(files) => json_response(req, 200, serde_json::json!({ "files": files })),
+                Err(e) => error_response(req, 500, format!("failed to list crashes: {}", e)),
+            }
+        }
+        "/crash" => {
+            // body: crash file name from /crashes
+            let Some(filename) = req.b.as_deref() else {
+                return error_response(req, 400, "missing body");
+            };
+            let crash_dir = state.config.read().await.crash_dir.clone();
+            match crash::read_crash_file(&crash_dir, filename) {
+                Ok(content) => Response {
+                    s: 200,
+                    pt: req.pt.clone(),
+                    b: Some(content),
+                },
+                Err(e) => error_response(req, 404, format!("failed to read crash: {}", e)),
+            }
+        }
+        "/restart" | "/reconnect" => {
             state.config.write().await.action_requested = Some(Action::Reconnect);
 
             Response {
@@ -665,3 +896,73 @@ async fn craft_response(req: &Request, state: AppState) -> Response {
         },
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::WifiMode;
+
+    #[test]
+    fn v1_frames_end_with_finish_signal() {
+        let data: Vec<u8> = (0..50).collect();
+        let frames = notification_frames(&data, 24, 1);
+        assert_eq!(frames.len(), 4);
+        assert_eq!(frames[0].len(), 20);
+        assert_eq!(frames[3], FINISH_SIGNAL.to_le_bytes());
+        assert_eq!(frames[..3].concat(), data);
+    }
+
+    #[test]
+    fn v2_frames_carry_sequence_and_count() {
+        let data: Vec<u8> = (0..50).collect();
+        let frames = notification_frames(&data, 24, 2);
+        // 16 payload bytes per notification after the header
+        assert_eq!(frames.len(), 4);
+        let mut joined = Vec::new();
+        for (seq, frame) in frames.iter().enumerate() {
+            assert!(frame.len() <= 20);
+            assert_eq!(u16::from_le_bytes([frame[0], frame[1]]), seq as u16);
+            assert_eq!(u16::from_le_bytes([frame[2], frame[3]]), 4);
+            joined.extend_from_slice(&frame[CHUNK_HEADER_LEN..]);
+        }
+        assert_eq!(joined, data);
+
+        // an empty response is still one notification
+        assert_eq!(notification_frames(&[], 24, 2), vec![vec![0, 0, 1, 0]]);
+    }
+
+    #[test]
+    fn config_keys_merge_rejects_unknown_keys() {
+        let cfg = AppConfig {
+            runtime_mitm_failed: true,
+            ..Default::default()
+        };
+        let mut updates = serde_json::Map::new();
+        updates.insert("dpi".to_string(), serde_json::json!(200));
+        let merged = merge_config_keys(&cfg, updates).unwrap();
+        assert_eq!(merged.dpi, 200);
+        assert!(merged.runtime_mitm_failed);
+
+        let mut updates = serde_json::Map::new();
+        updates.insert("no_such_key".to_string(), serde_json::json!(true));
+        assert!(merge_config_keys(&cfg, updates).is_err());
+    }
+
+    #[test]
+    fn config_keys_merge_result_is_validated() {
+        let cfg = AppConfig {
+            wifi_mode: WifiMode::Ap,
+            ..Default::default()
+        };
+        let mut updates = serde_json::Map::new();
+        updates.insert("wireless_hu".to_string(), serde_json::json!(true));
+        let merged = merge_config_keys(&cfg, updates).unwrap();
+        assert!(merged.validate().is_err());
+
+        let mut updates = serde_json::Map::new();
+        updates.insert("wireless_hu".to_string(), serde_json::json!(true));
+        updates.insert("wifi_mode".to_string(), serde_json::json!("station"));
+        let merged = merge_config_keys(&cfg, updates).unwrap();
+        assert!(merged.validate().is_ok());
+    }
+}
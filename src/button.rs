@@ -1,5 +1,6 @@
 use crate::config::Action;
 use crate::config::SharedConfig;
+use crate::multi_phone;
 use anyhow::anyhow;
 use evdev::enumerate;
 use evdev::{Device, EventType, KeyCode};
@@ -117,6 +118,16 @@ async fn handle_action(config: &mut SharedConfig, action: &str) -> Result<()> {
             config.write().await.action_requested = Some(Action::Reconnect);
             info!("{} 🔁 Button pressed - reconnecting now!", NAME);
         }
+        "double_press" => {
+            // project the other phone with multi_phone
+            if config.read().await.multi_phone {
+                if multi_phone::request_switch(None) {
+                    info!("{} 🔀 Button pressed - switching phones!", NAME);
+                } else {
+                    info!("{} 🔀 Button pressed - no other phone is waiting", NAME);
+                }
+            }
+        }
         _ => (),
     }
 
@@ -0,0 +1,402 @@
+use serde::{Deserialize, Serialize};
+use simplelog::*;
+use std::collections::HashMap;
+use std::process::Command;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// module name for logging engine
+const NAME: &str = "<i><bright-black> channel-survey: </>";
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Outcome of the last survey. Written by `--generate-hostapd`, which runs
+/// before hostapd takes the interface, and served by the web UI.
+pub const SURVEY_FILE: &str = "/var/run/aa-proxy-channel-survey.json";
+
+/// Signal below which a neighbour no longer matters.
+const NOISE_FLOOR_DBM: i32 = -95;
+/// Time for the new regulatory domain to be applied to the wiphy.
+const REGDOM_SETTLE: Duration = Duration::from_secs(1);
+/// `iw list` channel flags (lowercase) ruling a channel out for the AP.
+const UNUSABLE_FLAGS: &[&str] = &[
+    "disabled",
+    "no ir",
+    "radar detection",
+    "passive scan",
+    "no ibss",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct ChannelScore {
+    pub channel: u8,
+    pub freq: u32,
+    /// Networks on or overlapping this channel.
+    pub networks: usize,
+    pub strongest_dbm: Option<i32>,
+    /// Share of time the channel was busy during the scan.
+    pub busy_percent: Option<f64>,
+    /// Lower is better.
+    pub score: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SurveyReport {
+    /// Unix time of the survey.
+    pub timestamp: u64,
+    pub band: String,
+    pub country_code: String,
+    pub channel: u8,
+    /// Set when the survey failed and the band default was used instead.
+    pub error: Option<String>,
+    /// Legal candidates, best first.
+    pub candidates: Vec<ChannelScore>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Bss {
+    freq: u32,
+    signal_dbm: i32,
+}
+
+fn in_band(band: &str, freq: u32) -> bool {
+    match band {
+        "2.4" => (2400..2500).contains(&freq),
+        "5" => (5150..=5925).contains(&freq),
+        "6" => (5935..=7125).contains(&freq),
+        _ => false,
+    }
+}
+
+/// Channel used when the survey is not possible, same as the config default.
+pub fn default_channel(band: &str) -> u8 {
+    match band {
+        "5" => 36,
+        "6" => 37,
+        _ => 6,
+    }
+}
+
+/// Channels of `band` we may start an AP on right away, from `iw list`.
+/// Disabled, no-IR and DFS channels are skipped: a radar channel would need
+/// a minute of CAC before every start.
+fn parse_channels(iw_list: &str, band: &str) -> Vec<(u8, u32)> {
+    let mut channels: Vec<(u8, u32)> = Vec::new();
+    let mut current: Option<(u8, u32, bool)> = None;
+    let mut flush = |current: &mut Option<(u8, u32, bool)>| {
+        if let Some((ch, freq, usable)) = current.take() {
+            if usable && in_band(band, freq) && !channels.iter().any(|c| c.0 == ch) {
+                channels.push((ch, freq));
+            }
+        }
+    };
+    for line in iw_list.lines() {
+        let trimmed = line.trim();
+        let lower = trimmed.to_lowercase();
+        // "* 5180.0 MHz [36] (20.0 dBm)" or "* 5260 MHz [52] (20.0 dBm) (no IR, radar detection)"
+        let entry = trimmed.strip_prefix("* ").and_then(|rest| {
+            let (freq, rest) = rest.split_once(" MHz [")?;
+            let (ch, _) = rest.split_once(']')?;
+            Some((
+                ch.parse::<u8>().ok()?,
+                freq.parse::<f64>().ok()?.round() as u32,
+            ))
+        });
+        if let Some((ch, freq)) = entry {
+            flush(&mut current);
+            current = Some((ch, freq, true));
+        } else if trimmed.starts_with('*') || trimmed.ends_with(':') {
+            // next list or section, e.g. bitrates or "Band 2:"
+            flush(&mut current);
+            continue;
+        }
+        // newer iw versions put the flags on continuation lines
+        if let Some((_, _, usable)) = current.as_mut() {
+            if UNUSABLE_FLAGS.iter().any(|flag| lower.contains(flag)) {
+                *usable = false;
+            }
+        }
+    }
+    flush(&mut current);
+    channels
+}
+
+/// Neighbouring networks from `iw dev <iface> scan`.
+fn parse_scan(output: &str) -> Vec<Bss> {
+    let mut networks: Vec<Bss> = Vec::new();
+    let mut freq: Option<u32> = None;
+    let mut signal: Option<i32> = None;
+    let mut push = |freq: &mut Option<u32>, signal: &mut Option<i32>| {
+        if let (Some(freq), Some(signal_dbm)) = (freq.take(), signal.take()) {
+            networks.push(Bss { freq, signal_dbm });
+        }
+    };
+    for line in output.lines() {
+        if line.starts_with("BSS ") {
+            push(&mut freq, &mut signal);
+            continue;
+        }
+        let value = |v: &str| v.split_whitespace().next()?.parse::<f64>().ok();
+        match line.trim().split_once(':') {
+            Some(("freq", v)) => freq = value(v).map(|f| f.round() as u32),
+            Some(("signal", v)) => signal = value(v).map(|s| s.round() as i32),
+            _ => {}
+        }
+    }
+    push(&mut freq, &mut signal);
+    networks
+}
+
+/// Busy share per frequency from `iw dev <iface> survey dump`. Only channels
+/// visited by the preceding scan have meaningful counters.
+fn parse_survey(output: &str) -> HashMap<u32, f64> {
+    let mut busy: HashMap<u32, f64> = HashMap::new();
+    let mut freq: Option<u32> = None;
+    let mut active: Option<f64> = None;
+    for line in output.lines() {
+        let value = |v: &str| v.split_whitespace().next()?.parse::<f64>().ok();
+        match line.trim().split_once(':') {
+            Some(("frequency", v)) => {
+                freq = value(v).map(|f| f.round() as u32);
+                active = None;
+            }
+            Some(("channel active time", v)) => active = value(v),
+            Some(("channel busy time", v)) => {
+                if let (Some(freq), Some(active), Some(busy_ms)) = (freq, active, value(v)) {
+                    if active > 0.0 {
+                        busy.insert(freq, (busy_ms / active * 100.0).min(100.0));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    busy
+}
+
+/// How much a network on `other` MHz disturbs a 20 MHz channel at `freq`.
+/// 2.4 GHz channels are 5 MHz apart but 20 MHz wide, so neighbours up to four
+/// channels away overlap. On 5/6 GHz only the primary channel of wide
+/// neighbours is announced, their secondary use shows up in the busy time.
+fn overlap(band: &str, freq: u32, other: u32) -> f64 {
+    let distance = freq.abs_diff(other) as f64;
+    if band == "2.4" {
+        (1.0 - distance / 25.0).max(0.0)
+    } else if distance == 0.0 {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+/// Rank `channels`, least congested first. Every neighbour adds its signal
+/// above the noise floor weighted by overlap, plus the measured busy share.
+fn rank(
+    band: &str,
+    channels: &[(u8, u32)],
+    networks: &[Bss],
+    busy: &HashMap<u32, f64>,
+) -> Vec<ChannelScore> {
+    // 2.4 GHz: stay on the non-overlapping channels when they are legal;
+    // 6 GHz: preferred scanning channels, phones look there first
+    let preferred: Vec<(u8, u32)> = channels
+        .iter()
+        .copied()
+        .filter(|(ch, _)| match band {
+            "2.4" => [1, 6, 11].contains(ch),
+            "6" => ch % 16 == 5,
+            _ => true,
+        })
+        .collect();
+    let candidates = if preferred.is_empty() {
+        channels
+    } else {
+        &preferred
+    };
+
+    let mut scores: Vec<ChannelScore> = candidates
+        .iter()
+        .map(|&(channel, freq)| {
+            let mut score = 0.0;
+            let mut count = 0;
+            let mut strongest: Option<i32> = None;
+            for bss in networks {
+                let weight = overlap(band, freq, bss.freq);
+                if weight == 0.0 {
+                    continue;
+                }
+                count += 1;
+                strongest = strongest.max(Some(bss.signal_dbm));
+                score += weight * (bss.signal_dbm - NOISE_FLOOR_DBM).max(0) as f64;
+            }
+            let busy_percent = busy.get(&freq).copied();
+            score += busy_percent.unwrap_or(0.0);
+            ChannelScore {
+                channel,
+                freq,
+                networks: count,
+                strongest_dbm: strongest,
+                busy_percent,
+                score,
+            }
+        })
+        .collect();
+    // stable sort keeps the lowest channel first among equals
+    scores.sort_by(|a, b| a.score.total_cmp(&b.score));
+    scores
+}
+
+fn iw(args: &[&str]) -> Result<String> {
+    let output = Command::new("iw").args(args).output()?;
+    if !output.status.success() {
+        return Err(format!(
+            "iw {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+fn survey(iface: &str, band: &str, country_code: &str) -> Result<Vec<ChannelScore>> {
+    // scanning needs the interface up, hostapd is not running yet
+    Command::new("ip")
+        .args(["link", "set", "dev", iface, "up"])
+        .status()?;
+    if let Err(e) = iw(&["reg", "set", country_code]) {
+        warn!("{} {}", NAME, e);
+    }
+    std::thread::sleep(REGDOM_SETTLE);
+
+    let channels = parse_channels(&iw(&["list"])?, band);
+    if channels.is_empty() {
+        return Err(format!(
+            "no usable {} GHz channels for country {}",
+            band, country_code
+        )
+        .into());
+    }
+    let networks = parse_scan(&iw(&["dev", iface, "scan"])?);
+    let busy = match iw(&["dev", iface, "survey", "dump"]) {
+        Ok(output) => parse_survey(&output),
+        Err(e) => {
+            // not every driver reports channel time
+            debug!("{} {}", NAME, e);
+            HashMap::new()
+        }
+    };
+    info!(
+        "{} 📡 {} network(s) around, {} usable channel(s)",
+        NAME,
+        networks.iter().filter(|b| in_band(band, b.freq)).count(),
+        channels.len()
+    );
+    Ok(rank(band, &channels, &networks, &busy))
+}
+
+/// Survey `band` and return the least congested channel, or the band default
+/// when that fails. The decision is stored in [`SURVEY_FILE`].
+pub fn select_channel(iface: &str, band: &str, country_code: &str) -> u8 {
+    info!(
+        "{} 📡 surveying {} GHz channels on <b>{}</> for country {}",
+        NAME, band, iface, country_code
+    );
+    let (channel, error, candidates) = match survey(iface, band, country_code) {
+        Ok(candidates) if !candidates.is_empty() => {
+            let best = &candidates[0];
+            info!(
+                "{} 📡 selected channel <b><green>{}</> ({} MHz, {} network(s), score {:.1})",
+                NAME, best.channel, best.freq, best.networks, best.score
+            );
+            (best.channel, None, candidates)
+        }
+        Ok(_) => (
+            default_channel(band),
+            Some("no candidates".to_string()),
+            Vec::new(),
+        ),
+        Err(e) => (default_channel(band), Some(e.to_string()), Vec::new()),
+    };
+    if let Some(e) = &error {
+        warn!("{} survey failed ({}), using channel {}", NAME, e, channel);
+    }
+
+    let report = SurveyReport {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        band: band.to_string(),
+        country_code: country_code.to_string(),
+        channel,
+        error,
+        candidates,
+    };
+    match serde_json::to_string_pretty(&report) {
+        Ok(json) => {
+            if let Err(e) = std::fs::write(SURVEY_FILE, json) {
+                warn!("{} failed to write {}: {}", NAME, SURVEY_FILE, e);
+            }
+        }
+        Err(e) => warn!("{} failed to serialize report: {}", NAME, e),
+    }
+    channel
+}
+
+/// Result of the survey done for the running AP, if any.
+pub fn last_report() -> Option<SurveyReport> {
+    let json = std::fs::read_to_string(SURVEY_FILE).ok()?;
+    serde_json::from_str(&json).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_usable_channels() {
+        let iw_list = "Wiphy phy0
+\tBand 1:
+\t\tFrequencies:
+\t\t\t* 2412.0 MHz [1] (20.0 dBm)
+\t\t\t* 2467.0 MHz [12] (20.0 dBm) (no IR)
+\t\t\t* 2484.0 MHz [14] (disabled)
+\t\tBitrates (non-HT):
+\t\t\t* 1.0 Mbps
+\tBand 2:
+\t\tFrequencies:
+\t\t\t* 5180.0 MHz [36] (23.0 dBm)
+\t\t\t* 5260.0 MHz [52] (20.0 dBm)
+\t\t\t  radar detection
+\t\t\t* 5745 MHz [149] (30.0 dBm)
+\tSupported commands:
+";
+        assert_eq!(parse_channels(iw_list, "2.4"), [(1, 2412)]);
+        assert_eq!(parse_channels(iw_list, "5"), [(36, 5180), (149, 5745)]);
+    }
+
+    #[test]
+    fn picks_least_congested_channel() {
+        let networks = parse_scan(
+            "BSS 00:11:22:33:44:01(on wlan0)\n\tfreq: 2437\n\tsignal: -40.00 dBm\n\
+             BSS 00:11:22:33:44:02(on wlan0)\n\tfreq: 2412.0\n\tsignal: -85.00 dBm\n\
+             BSS 00:11:22:33:44:03(on wlan0)\n\tfreq: 2457\n\tsignal: -60.00 dBm\n",
+        );
+        assert_eq!(networks.len(), 3);
+        let channels = [(1, 2412), (3, 2422), (6, 2437), (11, 2462)];
+        let ranked = rank("2.4", &channels, &networks, &HashMap::new());
+        let order: Vec<u8> = ranked.iter().map(|s| s.channel).collect();
+        // channel 3 is never proposed, 11 overlaps with the -60 dBm network on 10
+        assert_eq!(order, [1, 11, 6]);
+
+        let busy = parse_survey(
+            "Survey data from wlan0\n\tfrequency:\t\t\t5180 MHz\n\
+             \tchannel active time:\t\t200 ms\n\tchannel busy time:\t\t150 ms\n\
+             Survey data from wlan0\n\tfrequency:\t\t\t5745 MHz\n\
+             \tchannel active time:\t\t200 ms\n\tchannel busy time:\t\t10 ms\n",
+        );
+        assert_eq!(busy.get(&5180), Some(&75.0));
+        let ranked = rank("5", &[(36, 5180), (149, 5745)], &[], &busy);
+        assert_eq!(ranked[0].channel, 149);
+    }
+}
@@ -25,19 +25,28 @@ pub const BASE_CONFIG_DIR: &str = base_config_dir!();
 pub const DEFAULT_WLAN_ADDR: &str = "10.0.0.1";
 pub const TCP_SERVER_PORT: i32 = 5288;
 pub const TCP_DHU_PORT: i32 = 5277;
+pub const TCP_MIRROR_PORT: i32 = 5276;
 
 pub const DEFAULT_WASM_HOOKS_DIR: &str = "/data/wasm-hooks";
 pub const DEFAULT_CRASH_DIR: &str = "/data/aa-proxy-rs/crashes";
+pub const DEFAULT_MEDIA_RECORD_DIR: &str = "/data/aa-proxy-rs/recordings";
+pub const DEFAULT_AUDIO_INJECT_DIR: &str = "/data/aa-proxy-rs/inject";
 pub const DEFAULT_SDR_UI_OVERRIDE_FILE: &str = "/data/aa-proxy-rs/sdr-ui-overrides.toml";
 
 pub type SharedConfig = Arc<RwLock<AppConfig>>;
 pub type SharedConfigJson = Arc<RwLock<ConfigJson>>;
+/// Wi-Fi parameters handed to phones in the Bluetooth handshake. Read right
+/// before they are sent, so runtime AP or station changes reach the phone.
+pub type SharedWifiConfig = Arc<RwLock<Option<WifiConfig>>>;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Action {
     Reconnect,
     Reboot,
     Stop,
+    /// The phone dropped while the HU session is held: redo only the phone
+    /// side handshake.
+    Handover,
 }
 
 #[derive(Clone)]
@@ -47,6 +56,12 @@ pub struct WifiConfig {
     pub ssid: String,
     pub bssid: String,
     pub wpa_key: String,
+    pub security: WifiSecurity,
+    /// Hand out the phone's own passphrase from the known-devices store
+    /// instead of `wpa_key`.
+    pub per_phone_psk: bool,
+    /// Joined an existing network via wpa_supplicant instead of running the AP.
+    pub station: bool,
 }
 
 pub fn empty_string_as_none<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
@@ -134,6 +149,7 @@ impl BtScoMediaBridgeLimiter {
 pub enum BtScoMediaBridgeResampler {
     Repeat,
     Linear,
+    Sinc,
 }
 
 impl Default for BtScoMediaBridgeResampler {
@@ -147,6 +163,110 @@ impl Display for BtScoMediaBridgeResampler {
         f.write_str(match self {
             Self::Repeat => "repeat",
             Self::Linear => "linear",
+            Self::Sinc => "sinc",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WifiMode {
+    /// Own access point run by hostapd.
+    Ap,
+    /// Join an existing network (car hotspot, lab Wi-Fi) via wpa_supplicant.
+    Station,
+}
+
+impl Default for WifiMode {
+    fn default() -> Self {
+        Self::Ap
+    }
+}
+
+impl Display for WifiMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Ap => "ap",
+            Self::Station => "station",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WifiSecurity {
+    /// WPA2-PSK only.
+    Wpa2,
+    /// WPA2-PSK and WPA3-SAE on the same BSS, optional management frame protection.
+    Transition,
+    /// WPA3-SAE only, management frame protection required.
+    Wpa3,
+}
+
+impl Default for WifiSecurity {
+    fn default() -> Self {
+        Self::Wpa2
+    }
+}
+
+impl Display for WifiSecurity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Wpa2 => "wpa2",
+            Self::Transition => "transition",
+            Self::Wpa3 => "wpa3",
+        })
+    }
+}
+
+/// Which phone is projected when another one arrives during a session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MultiPhonePolicy {
+    /// The projected phone stays, the newcomer waits.
+    Active,
+    /// The newcomer takes over.
+    Newest,
+    /// The newcomer takes over when its known-devices priority is higher.
+    Priority,
+}
+
+impl Default for MultiPhonePolicy {
+    fn default() -> Self {
+        Self::Active
+    }
+}
+
+impl Display for MultiPhonePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Active => "active",
+            Self::Newest => "newest",
+            Self::Priority => "priority",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BtScoCodec {
+    Cvsd,
+    Msbc,
+    Auto,
+}
+
+impl Default for BtScoCodec {
+    fn default() -> Self {
+        Self::Cvsd
+    }
+}
+
+impl Display for BtScoCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Cvsd => "cvsd",
+            Self::Msbc => "msbc",
+            Self::Auto => "auto",
         })
     }
 }
@@ -156,6 +276,7 @@ impl Display for BtScoMediaBridgeResampler {
 pub enum BtScoMicEchoControl {
     Off,
     Ducking,
+    Adaptive,
 }
 
 impl Default for BtScoMicEchoControl {
@@ -169,6 +290,7 @@ impl Display for BtScoMicEchoControl {
         f.write_str(match self {
             Self::Off => "off",
             Self::Ducking => "ducking",
+            Self::Adaptive => "adaptive",
         })
     }
 }
@@ -247,12 +369,34 @@ pub struct AppConfig {
     /// TOML file that stores per-vehicle and optional per-phone SDR UI overrides.
     pub sdr_ui_override_file: PathBuf,
     pub stats_interval: u16,
+    /// Seconds between Wi-Fi link quality samples of the phone, 0 disables.
+    pub wifi_link_interval: u16,
     #[serde(default, deserialize_with = "empty_string_as_none")]
     pub udc: Option<String>,
     pub iface: String,
     #[serde(default, deserialize_with = "empty_string_as_none")]
     pub btalias: Option<String>,
     pub timeout_secs: u16,
+    /// Keep the HU session alive this long while the phone reconnects,
+    /// 0 tears both sides down as before. Requires `mitm`.
+    pub handover_grace_secs: u16,
+    /// Let a second phone finish the Wi-Fi bootstrap while one is projected
+    /// and switch between them inside the HU session. Requires `mitm`.
+    pub multi_phone: bool,
+    pub multi_phone_policy: MultiPhonePolicy,
+    /// Accept a second, read-only HU on TCP_MIRROR_PORT which gets the
+    /// phone's video and audio as well. Requires `mitm`.
+    pub mirror: bool,
+    /// Pass the mirror HU's touch input to the phone.
+    pub mirror_touch: bool,
+    /// Record each session to `media_record_dir` for `demo_phone`.
+    /// Requires `mitm`.
+    pub session_record: bool,
+    /// Stop a session recording once it reaches this size, 0 = no limit.
+    pub session_record_max_mb: u32,
+    /// Play this session recording to the HU instead of waiting for a phone.
+    #[serde(default, deserialize_with = "empty_string_as_none")]
+    pub demo_phone: Option<PathBuf>,
     #[serde(
         default = "webserver_default_bind",
         deserialize_with = "empty_string_as_none"
@@ -277,6 +421,14 @@ pub struct AppConfig {
     /// Optional direct TCP address for Android Auto Head Unit Server on the MD/phone side.
     /// Empty keeps the normal USB/Bluetooth/Wi-Fi MD transport behavior.
     pub aa_server_tcp_addr: String,
+    /// Act as the phone towards a wireless head unit: run its Bluetooth/Wi-Fi
+    /// bootstrap and join its AP instead of running our own.
+    pub wireless_hu: bool,
+    /// Bluetooth address of the paired wireless head unit.
+    pub wireless_hu_bt_addr: String,
+    /// Optional `host:port` of the head unit's AA endpoint, skips the
+    /// bootstrap (e.g. for a local stand-in).
+    pub wireless_hu_tcp_addr: String,
     pub ev: bool,
     pub odometer: bool,
     pub tire_pressure: bool,
@@ -317,13 +469,31 @@ pub struct AppConfig {
     pub channel: u8,
     pub ssid: String,
     pub wpa_passphrase: String,
+    /// AP key management: `wpa2`, `transition` (WPA2 + WPA3-SAE) or `wpa3`.
+    pub wifi_security: WifiSecurity,
+    /// Generate a passphrase per known phone and serve them to hostapd via
+    /// `wpa_psk_file`, so removing a phone from the known devices revokes its
+    /// Wi-Fi access. Requires `wifi_security = "wpa2"`.
+    pub wifi_per_phone_psk: bool,
+    /// `ap` runs our own hotspot, `station` joins `sta_ssid` through
+    /// wpa_supplicant and hands that network to the phone.
+    pub wifi_mode: WifiMode,
+    /// Network joined in station mode.
+    pub sta_ssid: String,
+    /// Passphrase of `sta_ssid`, empty for an open network.
+    pub sta_passphrase: String,
+    /// wpa_supplicant `ctrl_interface` directory.
+    pub wpa_supplicant_ctrl_dir: String,
+    /// hostapd `ctrl_interface` directory, used to manage the AP at runtime.
+    pub hostapd_ctrl_dir: String,
     pub eth_mode: String,
     pub startup_delay: u8,
     pub ble_password: String,
     pub external_antenna: bool,
     /// Base TCP port for media stream tapping. One port is allocated per media service
     /// using fixed offsets: +0 video main, +1 video cluster, +2 video aux, +3 TTS audio,
-    /// +4 system audio, +5 media audio, +6 telephony audio.
+    /// +4 system audio, +5 media audio, +6 telephony audio, +7 HU microphone,
+    /// +8 Bluetooth SCO call downlink, +9 Bluetooth SCO call uplink.
     /// Requires mitm = true. Connect with e.g. `vlc tcp://127.0.0.1:12345`.
     #[serde(default)]
     pub media_dump_base_port: Option<u16>,
@@ -331,6 +501,11 @@ pub struct AppConfig {
     /// true  = wait for a fresh live IDR before forwarding inter-frames (clean decode)
     /// false = forward immediately after cached-IDR preview (lower latency, may artifact)
     pub media_wait_for_live_idr: bool,
+    /// Directory where WAV recordings of PCM media taps (e.g. `audio-mic`) are written.
+    pub media_record_dir: PathBuf,
+    /// Directory WAV files and unix sockets of audio/microphone injection
+    /// requests are confined to.
+    pub audio_inject_dir: PathBuf,
     pub collect_speed: bool,
     pub disable_driving_status: bool,
     /// Optional shell command invoked on HU media-key long press.
@@ -357,6 +532,21 @@ pub struct AppConfig {
     /// should keep routing call audio to aa-proxy-rs instead of dropping BT after
     /// the AA Wi-Fi setup phase.
     pub bt_sco_keep_bluetooth_alive: bool,
+    /// SCO voice codec. `cvsd` keeps the proven 8 kHz linear PCM path; `msbc`
+    /// accepts SCO in transparent mode and decodes 16 kHz wideband mSBC;
+    /// `auto` uses mSBC only when the HFP codec negotiation selected it.
+    /// mSBC falls back to CVSD when the transparent setup fails.
+    pub bt_sco_codec: BtScoCodec,
+    /// Register the HFP hands-free profile instead of HSP and run the AT
+    /// state machine (indicators, codec negotiation, call control, volume)
+    /// on its RFCOMM stream.
+    pub bt_hfp: bool,
+    /// Register a BlueZ pairing agent that forwards passkey/confirmation
+    /// requests to the `bt-pairing` ws topic and `/bt/pairing`. Without it
+    /// BlueZ falls back to just-works pairing.
+    pub bt_pairing_agent: bool,
+    /// Seconds to wait for a pairing answer before the request is rejected.
+    pub bt_pairing_timeout_secs: u16,
     /// Experimental downlink bridge: SCO call audio -> AA PCM media sink.
     /// Disabled by default. Requires `mitm = true`.
     pub bt_sco_media_bridge: bool,
@@ -373,7 +563,9 @@ pub struct AppConfig {
     /// compresses peaks more gently.
     pub bt_sco_media_bridge_limiter: BtScoMediaBridgeLimiter,
     /// SCO 8 kHz -> AA 48 kHz resampler. `repeat` preserves the proven path;
-    /// `linear` smooths the 6x upsampling and can reduce roughness/crackle.
+    /// `linear` smooths the 6x upsampling and can reduce roughness/crackle;
+    /// `sinc` uses the windowed-sinc resampler and removes imaging above the
+    /// SCO band at the cost of about 2 ms of latency.
     pub bt_sco_media_bridge_resampler: BtScoMediaBridgeResampler,
     /// Converted AA PCM chunk ring capacity for the SCO media bridge.
     /// Higher values tolerate stalls; lower values reduce latency. 128 is safe.
@@ -412,7 +604,8 @@ pub struct AppConfig {
     /// Maximum 60-byte SCO uplink packets buffered for the mic bridge.
     pub bt_sco_mic_uplink_ring_capacity: usize,
     /// Echo handling for the microphone uplink. `off` preserves the current
-    /// proven path; `ducking` lowers mic gain while downlink audio is active.
+    /// proven path; `ducking` lowers mic gain while downlink audio is active;
+    /// `adaptive` cancels the downlink echo with an NLMS filter.
     pub bt_sco_mic_echo_control: BtScoMicEchoControl,
     /// Microphone uplink gain percent after echo processing. 100 means no gain.
     pub bt_sco_mic_gain_percent: u32,
@@ -422,6 +615,23 @@ pub struct AppConfig {
     pub bt_sco_mic_duck_percent: u32,
     /// How long to keep ducking after the last active downlink frame.
     pub bt_sco_mic_duck_hold_ms: u32,
+    /// Echo tail length covered by the adaptive echo canceller.
+    pub bt_sco_mic_aec_filter_ms: u32,
+    /// Largest downlink to microphone delay searched by the adaptive echo canceller.
+    pub bt_sco_mic_aec_max_delay_ms: u32,
+    /// Spectral subtraction noise suppression on the microphone uplink.
+    pub bt_sco_mic_noise_suppression: bool,
+    /// Noise suppression strength percent. 100 is the tuned default.
+    pub bt_sco_mic_ns_strength_percent: u32,
+    /// Automatic gain control on the microphone uplink, after noise suppression.
+    pub bt_sco_mic_agc: bool,
+    /// Speech level the microphone AGC steers towards.
+    pub bt_sco_mic_agc_target_dbfs: i32,
+    /// Largest gain the microphone AGC may apply.
+    pub bt_sco_mic_agc_max_gain_db: u32,
+    /// Record every SCO call as a stereo WAV (left downlink, right uplink) into
+    /// `media_record_dir`, for tuning echo, gain and jitter settings.
+    pub bt_sco_record_calls: bool,
 
     /// Directory where `.wasm` hook files are loaded from.
     /// Each script gets read-only WASI access only to a private subfolder named
@@ -559,10 +769,19 @@ impl Default for AppConfig {
             sdr_ui_override_autocreate_profiles: true,
             sdr_ui_override_file: DEFAULT_SDR_UI_OVERRIDE_FILE.into(),
             stats_interval: 0,
+            wifi_link_interval: 2,
             udc: None,
             iface: "wlan0".to_string(),
             btalias: None,
             timeout_secs: 10,
+            handover_grace_secs: 0,
+            multi_phone: false,
+            multi_phone_policy: MultiPhonePolicy::Active,
+            mirror: false,
+            mirror_touch: false,
+            session_record: false,
+            session_record_max_mb: 512,
+            demo_phone: None,
             webserver: webserver_default_bind(),
             bt_timeout_secs: 120,
             bt_connect_timeout_secs: 10,
@@ -578,6 +797,9 @@ impl Default for AppConfig {
             wired: None,
             dhu: false,
             aa_server_tcp_addr: String::new(),
+            wireless_hu: false,
+            wireless_hu_bt_addr: String::new(),
+            wireless_hu_tcp_addr: String::new(),
             ev: false,
             odometer: false,
             tire_pressure: false,
@@ -630,17 +852,30 @@ impl Default for AppConfig {
             },
             ssid: String::from(IDENTITY_NAME),
             wpa_passphrase: String::from(IDENTITY_NAME),
+            wifi_security: WifiSecurity::Wpa2,
+            wifi_per_phone_psk: false,
+            wifi_mode: WifiMode::Ap,
+            sta_ssid: String::new(),
+            sta_passphrase: String::new(),
+            wpa_supplicant_ctrl_dir: "/var/run/wpa_supplicant".to_string(),
+            hostapd_ctrl_dir: "/var/run/hostapd".to_string(),
             eth_mode: String::new(),
             startup_delay: 0,
             ble_password: String::new(),
             external_antenna: false,
             media_dump_base_port: None,
             media_wait_for_live_idr: true,
+            media_record_dir: DEFAULT_MEDIA_RECORD_DIR.into(),
+            audio_inject_dir: DEFAULT_AUDIO_INJECT_DIR.into(),
             collect_speed: false,
             disable_driving_status: false,
             hu_button_handler: None,
             bt_sco: false,
             bt_sco_keep_bluetooth_alive: true,
+            bt_sco_codec: BtScoCodec::Cvsd,
+            bt_hfp: false,
+            bt_pairing_agent: false,
+            bt_pairing_timeout_secs: 60,
             bt_sco_media_bridge: true,
             bt_sco_media_bridge_audio_type: BtScoMediaBridgeAudioType::Media,
             bt_sco_media_bridge_gain_percent: 300,
@@ -663,6 +898,14 @@ impl Default for AppConfig {
             bt_sco_mic_duck_threshold: 700,
             bt_sco_mic_duck_percent: 35,
             bt_sco_mic_duck_hold_ms: 180,
+            bt_sco_mic_aec_filter_ms: 64,
+            bt_sco_mic_aec_max_delay_ms: 400,
+            bt_sco_mic_noise_suppression: false,
+            bt_sco_mic_ns_strength_percent: 100,
+            bt_sco_mic_agc: false,
+            bt_sco_mic_agc_target_dbfs: -20,
+            bt_sco_mic_agc_max_gain_db: 18,
+            bt_sco_record_calls: false,
             wasm_hooks_dir: DEFAULT_WASM_HOOKS_DIR.into(),
             wasm_script_memory_limit_mb: 5,
             wasm_script_instance_limit: 16,
@@ -771,7 +1014,19 @@ impl AppConfig {
             return Err(Box::new(e));
         }
 
-        Ok(file_config.unwrap())
+        let cfg: Self = file_config.unwrap();
+        cfg.validate()?;
+        Ok(cfg)
+    }
+
+    /// Reject option combinations that cannot work together.
+    pub fn validate(&self) -> Result<(), String> {
+        // the head unit bootstrap joins the car's AP on the interface our own
+        // AP would run on
+        if self.wireless_hu && self.wifi_mode == WifiMode::Ap {
+            return Err("wireless_hu requires wifi_mode = \"station\"".to_string());
+        }
+        Ok(())
     }
 
     pub fn save(&self, config_file: PathBuf) {
@@ -815,6 +1070,7 @@ impl AppConfig {
             value(self.sdr_ui_override_autocreate_profiles);
         doc["sdr_ui_override_file"] = value(self.sdr_ui_override_file.display().to_string());
         doc["stats_interval"] = value(self.stats_interval as i64);
+        doc["wifi_link_interval"] = value(self.wifi_link_interval as i64);
         if let Some(udc) = &self.udc {
             doc["udc"] = value(udc);
         }
@@ -823,6 +1079,16 @@ impl AppConfig {
             doc["btalias"] = value(alias);
         }
         doc["timeout_secs"] = value(self.timeout_secs as i64);
+        doc["handover_grace_secs"] = value(self.handover_grace_secs as i64);
+        doc["multi_phone"] = value(self.multi_phone);
+        doc["multi_phone_policy"] = value(self.multi_phone_policy.to_string());
+        doc["mirror"] = value(self.mirror);
+        doc["mirror_touch"] = value(self.mirror_touch);
+        doc["session_record"] = value(self.session_record);
+        doc["session_record_max_mb"] = value(self.session_record_max_mb as i64);
+        if let Some(demo_phone) = &self.demo_phone {
+            doc["demo_phone"] = value(demo_phone.display().to_string());
+        }
         if let Some(webserver) = &self.webserver {
             doc["webserver"] = value(webserver);
         }
@@ -840,6 +1106,9 @@ impl AppConfig {
         doc["wired"] = value(self.wired.as_ref().map_or(String::new(), |w| w.to_string()));
         doc["dhu"] = value(self.dhu);
         doc["aa_server_tcp_addr"] = value(self.aa_server_tcp_addr.to_string());
+        doc["wireless_hu"] = value(self.wireless_hu);
+        doc["wireless_hu_bt_addr"] = value(self.wireless_hu_bt_addr.to_string());
+        doc["wireless_hu_tcp_addr"] = value(self.wireless_hu_tcp_addr.to_string());
         doc["ev"] = value(self.ev);
         doc["odometer"] = value(self.odometer);
         doc["tire_pressure"] = value(self.tire_pressure);
@@ -879,6 +1148,13 @@ impl AppConfig {
         doc["channel"] = value(self.channel as i64);
         doc["ssid"] = value(&self.ssid);
         doc["wpa_passphrase"] = value(&self.wpa_passphrase);
+        doc["wifi_security"] = value(self.wifi_security.to_string());
+        doc["wifi_per_phone_psk"] = value(self.wifi_per_phone_psk);
+        doc["wifi_mode"] = value(self.wifi_mode.to_string());
+        doc["sta_ssid"] = value(&self.sta_ssid);
+        doc["sta_passphrase"] = value(&self.sta_passphrase);
+        doc["wpa_supplicant_ctrl_dir"] = value(&self.wpa_supplicant_ctrl_dir);
+        doc["hostapd_ctrl_dir"] = value(&self.hostapd_ctrl_dir);
         doc["eth_mode"] = value(&self.eth_mode);
         doc["startup_delay"] = value(self.startup_delay as i64);
         doc["ble_password"] = value(&self.ble_password);
@@ -887,6 +1163,8 @@ impl AppConfig {
             doc["media_dump_base_port"] = value(port as i64);
         }
         doc["media_wait_for_live_idr"] = value(self.media_wait_for_live_idr);
+        doc["media_record_dir"] = value(self.media_record_dir.display().to_string());
+        doc["audio_inject_dir"] = value(self.audio_inject_dir.display().to_string());
         doc["collect_speed"] = value(self.collect_speed);
         doc["disable_driving_status"] = value(self.disable_driving_status);
         if let Some(cmd) = &self.hu_button_handler {
@@ -894,6 +1172,10 @@ impl AppConfig {
         }
         doc["bt_sco"] = value(self.bt_sco);
         doc["bt_sco_keep_bluetooth_alive"] = value(self.bt_sco_keep_bluetooth_alive);
+        doc["bt_sco_codec"] = value(self.bt_sco_codec.to_string());
+        doc["bt_hfp"] = value(self.bt_hfp);
+        doc["bt_pairing_agent"] = value(self.bt_pairing_agent);
+        doc["bt_pairing_timeout_secs"] = value(self.bt_pairing_timeout_secs as i64);
         doc["bt_sco_media_bridge"] = value(self.bt_sco_media_bridge);
         doc["bt_sco_media_bridge_audio_type"] =
             value(self.bt_sco_media_bridge_audio_type.to_string());
@@ -925,6 +1207,14 @@ impl AppConfig {
         doc["bt_sco_mic_duck_threshold"] = value(self.bt_sco_mic_duck_threshold as i64);
         doc["bt_sco_mic_duck_percent"] = value(self.bt_sco_mic_duck_percent as i64);
         doc["bt_sco_mic_duck_hold_ms"] = value(self.bt_sco_mic_duck_hold_ms as i64);
+        doc["bt_sco_mic_aec_filter_ms"] = value(self.bt_sco_mic_aec_filter_ms as i64);
+        doc["bt_sco_mic_aec_max_delay_ms"] = value(self.bt_sco_mic_aec_max_delay_ms as i64);
+        doc["bt_sco_mic_noise_suppression"] = value(self.bt_sco_mic_noise_suppression);
+        doc["bt_sco_mic_ns_strength_percent"] = value(self.bt_sco_mic_ns_strength_percent as i64);
+        doc["bt_sco_mic_agc"] = value(self.bt_sco_mic_agc);
+        doc["bt_sco_mic_agc_target_dbfs"] = value(self.bt_sco_mic_agc_target_dbfs as i64);
+        doc["bt_sco_mic_agc_max_gain_db"] = value(self.bt_sco_mic_agc_max_gain_db as i64);
+        doc["bt_sco_record_calls"] = value(self.bt_sco_record_calls);
         doc["wasm_hooks_dir"] = value(self.wasm_hooks_dir.display().to_string());
         doc["wasm_script_memory_limit_mb"] = value(self.wasm_script_memory_limit_mb as i64);
         doc["wasm_script_instance_limit"] = value(self.wasm_script_instance_limit as i64);
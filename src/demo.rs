@@ -0,0 +1,763 @@
+//! Plays a recorded phone session to the head unit, no phone needed.
+//!
+//! With `session_record` io_loop writes the packets passing the HU-side
+//! proxy to `media_record_dir`: everything the phone sends and the HU's
+//! service discovery response. With `demo_phone` set to such a recording
+//! io_loop skips the phone and [`play`] takes its place: the HU-side
+//! handshake of [`hu_endpoint`], then the recording through a
+//! [`MirrorState`], which maps the recorded channels to the HU's services.
+//! [`DemoPhone`] paces the media by the recorded timestamps and the HU's
+//! ACKs, follows its video focus and starts over at the end.
+use crate::config::SharedConfig;
+use crate::handover::{control_id, is, message_id};
+use crate::io_uring::{Endpoint, IoDevice};
+use crate::mirror::{MirrorState, Route, Tapped};
+use crate::mitm::protos::Config as MediaConfig;
+use crate::mitm::protos::ControlMessageType::*;
+use crate::mitm::protos::MediaMessageId::*;
+use crate::mitm::protos::*;
+use crate::mitm::{
+    endpoint_reader, hu_endpoint, Packet, ENCRYPTED, FRAME_TYPE_FIRST, FRAME_TYPE_LAST,
+};
+use protobuf::Message;
+use simplelog::*;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{self, Receiver};
+
+// module name for logging engine
+const NAME: &str = "<i><bright-black> demo: </>";
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+const RECORDING_MAGIC: &[u8; 8] = b"AASESSN1";
+const RECORDING_EXTENSION: &str = "aasession";
+// an HU that stops ACKing does not stall the demo for good
+const ACK_TIMEOUT: Duration = Duration::from_secs(1);
+const DEMO_QUEUE_CAPACITY: usize = 10;
+// a new session recording deletes the oldest beyond this many
+const MAX_RECORDINGS: usize = 10;
+
+/// Bytes a packet takes in a recording: 15 bytes of header and the payload.
+fn record_len(pkt: &Packet) -> u64 {
+    15 + pkt.payload.len() as u64
+}
+
+/// One recorded packet: offset, direction (0 = phone, 1 = HU), channel,
+/// flags, final length (0 = none), payload length and payload.
+fn write_record(out: &mut impl Write, offset: Duration, tapped: &Tapped) -> std::io::Result<()> {
+    let (direction, pkt) = match tapped {
+        Tapped::FromPhone(pkt) => (0u8, pkt),
+        Tapped::FromHu(pkt) => (1u8, pkt),
+        Tapped::Lost(_) => return Ok(()),
+    };
+    out.write_all(&(offset.as_millis() as u32).to_be_bytes())?;
+    out.write_all(&[direction, pkt.channel, pkt.flags])?;
+    out.write_all(&pkt.final_length.unwrap_or(0).to_be_bytes())?;
+    out.write_all(&(pkt.payload.len() as u32).to_be_bytes())?;
+    out.write_all(&pkt.payload)
+}
+
+fn parse_recording(data: &[u8]) -> Result<Vec<(Duration, Tapped)>> {
+    let mut data = data
+        .strip_prefix(RECORDING_MAGIC)
+        .ok_or("not a session recording")?;
+    let mut events = vec![];
+    while !data.is_empty() {
+        if data.len() < 15 {
+            return Err("truncated session recording".into());
+        }
+        let u32_at = |at: usize| u32::from_be_bytes(data[at..at + 4].try_into().unwrap());
+        let offset = Duration::from_millis(u32_at(0).into());
+        let final_length = Some(u32_at(7)).filter(|len| *len != 0);
+        let len = u32_at(11) as usize;
+        let payload = data
+            .get(15..15 + len)
+            .ok_or("truncated session recording")?
+            .to_vec();
+        let pkt = Packet {
+            channel: data[5],
+            flags: data[6],
+            final_length,
+            payload,
+        };
+        events.push((
+            offset,
+            match data[4] {
+                0 => Tapped::FromPhone(pkt),
+                _ => Tapped::FromHu(pkt),
+            },
+        ));
+        data = &data[15 + len..];
+    }
+    Ok(events)
+}
+
+/// Read a recording written by [`record`].
+pub fn load(path: &Path) -> Result<Vec<(Duration, Tapped)>> {
+    parse_recording(&std::fs::read(path)?)
+}
+
+/// Session recordings among `paths` beyond the newest `keep`. The names
+/// start with the recording time, so they sort oldest first.
+fn expired_recordings(mut paths: Vec<PathBuf>, keep: usize) -> Vec<PathBuf> {
+    paths.retain(|path| {
+        path.extension()
+            .is_some_and(|ext| ext == RECORDING_EXTENSION)
+    });
+    paths.sort();
+    paths.truncate(paths.len().saturating_sub(keep));
+    paths
+}
+
+/// How writing a session recording ended.
+#[derive(Debug, PartialEq)]
+enum RecordingEnd {
+    /// The session ended, with this many packets recorded.
+    Closed(usize),
+    /// The next packet would have grown the recording beyond its limit.
+    Full(usize),
+    /// Copies of packets on this channel were dropped.
+    Lost(u8),
+}
+
+/// Write the packets tapped into `taps` to `out`, up to `max_bytes` of
+/// them (0 = no limit). Records are written whole, so a recording cut at
+/// the limit ends on a packet boundary and plays back up to there.
+fn write_session(
+    taps: &mut Receiver<Tapped>,
+    out: &mut impl Write,
+    max_bytes: u64,
+) -> std::io::Result<RecordingEnd> {
+    let started = Instant::now();
+    let mut written = RECORDING_MAGIC.len() as u64;
+    let mut count = 0;
+    while let Some(tapped) = taps.blocking_recv() {
+        let pkt = match &tapped {
+            Tapped::FromHu(pkt) if pkt.channel != 0 => continue,
+            Tapped::FromHu(pkt) | Tapped::FromPhone(pkt) => pkt,
+            Tapped::Lost(channel) => return Ok(RecordingEnd::Lost(*channel)),
+        };
+        written += record_len(pkt);
+        if max_bytes > 0 && written > max_bytes {
+            return Ok(RecordingEnd::Full(count));
+        }
+        write_record(out, started.elapsed(), &tapped)?;
+        count += 1;
+    }
+    Ok(RecordingEnd::Closed(count))
+}
+
+/// Write the session tapped into `taps` to a new file in `dir`, until the
+/// session ends or the file would grow beyond `max_bytes` (0 = no limit).
+/// Of the HU's packets only channel 0 is kept, for its service discovery
+/// response. A recording with lost packets would not play back, it is
+/// deleted instead. Only the newest [`MAX_RECORDINGS`] are kept. Blocks on
+/// file I/O, run it with `spawn_blocking`.
+pub fn record(mut taps: Receiver<Tapped>, dir: PathBuf, max_bytes: u64) -> Result<()> {
+    std::fs::create_dir_all(&dir)?;
+    let existing = std::fs::read_dir(&dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .collect();
+    for old in expired_recordings(existing, MAX_RECORDINGS - 1) {
+        match std::fs::remove_file(&old) {
+            Ok(()) => info!("{} 🎬 removed old recording {}", NAME, old.display()),
+            Err(e) => warn!("{} 🎬 failed to remove {}: {}", NAME, old.display(), e),
+        }
+    }
+    let path = dir.join(format!(
+        "session-{}.{}",
+        chrono::Local::now().format("%Y%m%d-%H%M%S"),
+        RECORDING_EXTENSION
+    ));
+    let mut out = BufWriter::new(File::create(&path)?);
+    out.write_all(RECORDING_MAGIC)?;
+    info!("{} 🎬 recording the session to {}", NAME, path.display());
+
+    let count = match write_session(&mut taps, &mut out, max_bytes)? {
+        RecordingEnd::Closed(count) => count,
+        RecordingEnd::Full(count) => {
+            warn!(
+                "{} 🎬 recording reached its limit of {} bytes, stopped",
+                NAME, max_bytes
+            );
+            count
+        }
+        RecordingEnd::Lost(channel) => {
+            drop(out);
+            std::fs::remove_file(&path)?;
+            return Err(format!(
+                "packets on channel {} were dropped, discarded {}",
+                channel,
+                path.display()
+            )
+            .into());
+        }
+    };
+    out.flush()?;
+    info!(
+        "{} 🎬 recorded {} packets to {}",
+        NAME,
+        count,
+        path.display()
+    );
+    Ok(())
+}
+
+/// ACK window of a media channel of the HU.
+#[derive(Default)]
+struct Flow {
+    max_unacked: u32,
+    unacked: u32,
+}
+
+impl Flow {
+    fn blocked(&self) -> bool {
+        self.max_unacked > 0 && self.unacked >= self.max_unacked
+    }
+}
+
+/// Media messages the HU ACKs.
+fn is_acked(pkt: &Packet) -> bool {
+    let id = message_id(pkt);
+    pkt.channel != 0 && (is(id, MEDIA_MESSAGE_DATA) || is(id, MEDIA_MESSAGE_CODEC_CONFIG))
+}
+
+/// The HU plays the part of the mirror, nothing goes back to a phone.
+fn for_hu(routes: Vec<Route>) -> Vec<Packet> {
+    routes
+        .into_iter()
+        .filter_map(|route| match route {
+            Route::ToMirror(pkt) => Some(pkt),
+            Route::ToPhone(_) => None,
+        })
+        .collect()
+}
+
+/// The recorded phone, towards one head unit.
+pub struct DemoPhone {
+    mirror: MirrorState,
+    /// What the phone sent after the setup, by offset.
+    timeline: Vec<(Duration, Packet)>,
+    next: usize,
+    /// Start of the current run through the timeline, None until the HU
+    /// answered the service discovery.
+    started: Option<Instant>,
+    /// Packets of the current timeline entry waiting for ACKs, and since when.
+    held: Option<(Vec<Packet>, Instant)>,
+    flows: HashMap<u8, Flow>,
+    /// HU channels with a running media stream.
+    streaming: HashSet<u8>,
+    /// Video channels the HU took back for its own UI.
+    unfocused: HashSet<u8>,
+}
+
+impl DemoPhone {
+    pub fn new(recording: Vec<(Duration, Tapped)>) -> Result<Self> {
+        let mut mirror = MirrorState::new(false);
+        let mut timeline: Vec<(Duration, Packet)> = vec![];
+        let mut handshake = (false, false);
+        for (offset, tapped) in recording {
+            let pkt = match tapped {
+                Tapped::FromPhone(pkt) => pkt,
+                from_hu => {
+                    mirror.on_tapped(from_hu);
+                    continue;
+                }
+            };
+            let id = control_id(&pkt);
+            let setup = if pkt.channel == 0 {
+                handshake.0 |= is(id, MESSAGE_VERSION_RESPONSE);
+                handshake.1 |= is(id, MESSAGE_SERVICE_DISCOVERY_REQUEST);
+                // focus requests are part of the show, pings are not
+                if is(id, MESSAGE_AUDIO_FOCUS_REQUEST) || is(id, MESSAGE_NAV_FOCUS_REQUEST) {
+                    timeline.push((offset, pkt));
+                    continue;
+                }
+                true
+            } else {
+                is(id, MESSAGE_CHANNEL_OPEN_REQUEST) || is(message_id(&pkt), MEDIA_MESSAGE_SETUP)
+            };
+            if setup {
+                mirror.on_tapped(Tapped::FromPhone(pkt));
+            } else {
+                timeline.push((offset, pkt));
+            }
+        }
+        if handshake != (true, true) {
+            return Err("the recording misses the start of the phone session".into());
+        }
+        // poll() would start over without end
+        let Some(&(first, _)) = timeline.first() else {
+            return Err("the recording has nothing to play after the setup".into());
+        };
+        for (offset, _) in timeline.iter_mut() {
+            *offset -= first;
+        }
+        mirror.attach();
+        Ok(Self {
+            mirror,
+            timeline,
+            next: 0,
+            started: None,
+            held: None,
+            flows: HashMap::new(),
+            streaming: HashSet::new(),
+            unfocused: HashSet::new(),
+        })
+    }
+
+    /// Packet from the HU, returns the answers.
+    pub fn on_hu_packet(&mut self, pkt: Packet) -> Vec<Packet> {
+        let id = message_id(&pkt);
+        if pkt.channel != 0 && pkt.payload.len() >= 2 {
+            let body = &pkt.payload[2..];
+            if is(id, MEDIA_MESSAGE_CONFIG) {
+                if let Ok(config) = MediaConfig::parse_from_bytes(body) {
+                    self.flows.entry(pkt.channel).or_default().max_unacked = config.max_unacked();
+                }
+            } else if is(id, MEDIA_MESSAGE_ACK) {
+                if let (Ok(ack), Some(flow)) = (
+                    Ack::parse_from_bytes(body),
+                    self.flows.get_mut(&pkt.channel),
+                ) {
+                    flow.unacked = flow.unacked.saturating_sub(ack.ack().max(1));
+                }
+            } else if is(id, MEDIA_MESSAGE_VIDEO_FOCUS_NOTIFICATION) {
+                if let Ok(focus) = VideoFocusNotification::parse_from_bytes(body) {
+                    if focus.focus() == VideoFocusMode::VIDEO_FOCUS_PROJECTED {
+                        // the video starts over with a key frame
+                        if self.unfocused.remove(&pkt.channel) {
+                            self.next = self.timeline.len();
+                            self.held = None;
+                        }
+                    } else {
+                        self.unfocused.insert(pkt.channel);
+                    }
+                }
+            }
+        }
+        let answers = for_hu(self.mirror.on_mirror_packet(pkt));
+        if self.started.is_none() && self.mirror.discovered() {
+            self.started = Some(Instant::now());
+        }
+        // a stream that was running before the HU's setup starts now
+        self.sent(&answers);
+        answers
+    }
+
+    /// Account for packets about to go to the HU.
+    fn sent(&mut self, pkts: &[Packet]) {
+        for pkt in pkts {
+            let id = message_id(pkt);
+            if is(id, MEDIA_MESSAGE_START) {
+                self.streaming.insert(pkt.channel);
+            } else if is(id, MEDIA_MESSAGE_STOP) {
+                self.streaming.remove(&pkt.channel);
+            } else if is_acked(pkt) {
+                self.flows.entry(pkt.channel).or_default().unacked += 1;
+            }
+        }
+    }
+
+    fn blocked(&self, pkts: &[Packet]) -> bool {
+        pkts.iter()
+            .filter(|pkt| is_acked(pkt))
+            .any(|pkt| self.flows.get(&pkt.channel).is_some_and(Flow::blocked))
+    }
+
+    /// Stop the running streams before the timeline starts over.
+    fn stop_all(&mut self) -> Vec<Packet> {
+        let body = Stop::new().write_to_bytes().unwrap_or_default();
+        let mut channels: Vec<u8> = self.streaming.drain().collect();
+        channels.sort();
+        channels
+            .into_iter()
+            .map(|channel| {
+                let mut payload = (MEDIA_MESSAGE_STOP as u16).to_be_bytes().to_vec();
+                payload.extend(&body);
+                Packet {
+                    channel,
+                    flags: ENCRYPTED | FRAME_TYPE_FIRST | FRAME_TYPE_LAST,
+                    final_length: None,
+                    payload,
+                }
+            })
+            .collect()
+    }
+
+    /// What is due at `now` for the HU.
+    pub fn poll(&mut self, now: Instant) -> Vec<Packet> {
+        let Some(mut started) = self.started else {
+            return vec![];
+        };
+        let mut out = vec![];
+        if let Some((pkts, since)) = self.held.take() {
+            if self.blocked(&pkts) && now < since + ACK_TIMEOUT {
+                self.held = Some((pkts, since));
+                return out;
+            }
+            if self.blocked(&pkts) {
+                warn!("{} ⏳ head unit stopped ACKing, carrying on", NAME);
+                self.flows.values_mut().for_each(|flow| flow.unacked = 0);
+            }
+            // the rest of the recording keeps its pace
+            started += now - since;
+            self.started = Some(started);
+            self.sent(&pkts);
+            out.extend(pkts);
+        }
+        loop {
+            if self.next >= self.timeline.len() {
+                out.extend(self.stop_all());
+                self.next = 0;
+                self.started = Some(now);
+                info!("{} 🔁 starting the recording over", NAME);
+                return out;
+            }
+            let (offset, ref pkt) = self.timeline[self.next];
+            if started + offset > now {
+                return out;
+            }
+            let pkt = pkt.clone();
+            self.next += 1;
+            let pkts = if pkt.channel == 0 {
+                vec![pkt]
+            } else {
+                let pkts = for_hu(self.mirror.on_tapped(Tapped::FromPhone(pkt)));
+                pkts.into_iter()
+                    .filter(|pkt| !(self.unfocused.contains(&pkt.channel) && is_acked(pkt)))
+                    .collect()
+            };
+            if self.blocked(&pkts) {
+                self.held = Some((pkts, now));
+                return out;
+            }
+            self.sent(&pkts);
+            out.extend(pkts);
+        }
+    }
+
+    /// When [`poll`](Self::poll) has something next, None while waiting for
+    /// the HU.
+    pub fn next_wake(&self) -> Option<Instant> {
+        let started = self.started.filter(|_| !self.timeline.is_empty())?;
+        if let Some((_, since)) = &self.held {
+            return Some(*since + ACK_TIMEOUT);
+        }
+        Some(match self.timeline.get(self.next) {
+            Some((offset, _)) => started + *offset,
+            None => Instant::now(),
+        })
+    }
+}
+
+/// Be the phone of the HU on `hu_r`/`hu_w` with the recording at `path`,
+/// until the HU goes away.
+pub async fn play<A: Endpoint<A> + 'static>(
+    hu_r: IoDevice<A>,
+    hu_w: IoDevice<A>,
+    path: &Path,
+    config: SharedConfig,
+) -> Result<()> {
+    let mut demo = DemoPhone::new(load(path)?)?;
+    info!("{} 🎬 playing {} to the head unit", NAME, path.display());
+
+    let (txr, rxr) = mpsc::channel(DEMO_QUEUE_CAPACITY);
+    let (tx, mut from_hu) = mpsc::channel(DEMO_QUEUE_CAPACITY);
+    let (to_hu, rx) = mpsc::channel(DEMO_QUEUE_CAPACITY);
+    let reader = tokio_uring::spawn(endpoint_reader(hu_r, txr, true));
+    let mut endpoint = tokio_uring::spawn(hu_endpoint(hu_w, tx, rx, rxr, config));
+
+    let res: Result<()> = async {
+        loop {
+            let wake = demo.next_wake();
+            let sleep = tokio::time::sleep_until(wake.unwrap_or_else(Instant::now).into());
+            tokio::select! {
+                pkt = from_hu.recv() => {
+                    let Some(pkt) = pkt else {
+                        return match (&mut endpoint).await {
+                            Ok(Err(e)) => Err(e),
+                            _ => Err("head unit is gone".into()),
+                        };
+                    };
+                    for pkt in demo.on_hu_packet(pkt) {
+                        to_hu.send(pkt).await?;
+                    }
+                }
+                _ = sleep, if wake.is_some() => {}
+            }
+            for pkt in demo.poll(Instant::now()) {
+                to_hu.send(pkt).await?;
+            }
+        }
+    }
+    .await;
+    reader.abort();
+    endpoint.abort();
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mitm::test_packets::{control, discovery, media, open_request, video_service};
+
+    fn ids(pkts: &[Packet]) -> Vec<(u8, Option<u16>)> {
+        pkts.iter()
+            .map(|pkt| (pkt.channel, message_id(pkt)))
+            .collect()
+    }
+
+    fn ack(channel: u8) -> Packet {
+        let mut ack = Ack::new();
+        ack.set_session_id(0);
+        ack.set_ack(1);
+        media(
+            channel,
+            MEDIA_MESSAGE_ACK as u16,
+            ack.write_to_bytes().unwrap(),
+        )
+    }
+
+    /// A phone projecting video on channel 2 to an HU with its video on 3.
+    fn recording() -> Vec<(Duration, Tapped)> {
+        let ms = Duration::from_millis;
+        vec![
+            (
+                ms(0),
+                Tapped::FromPhone(control(0, MESSAGE_VERSION_RESPONSE as u16, vec![0, 1])),
+            ),
+            (
+                ms(5),
+                Tapped::FromPhone(control(0, MESSAGE_SERVICE_DISCOVERY_REQUEST as u16, vec![])),
+            ),
+            (ms(10), Tapped::FromHu(discovery(vec![video_service(3)]))),
+            (ms(20), Tapped::FromPhone(open_request(2, 3))),
+            (
+                ms(30),
+                Tapped::FromPhone(media(2, MEDIA_MESSAGE_SETUP as u16, vec![1])),
+            ),
+            (
+                ms(100),
+                Tapped::FromPhone(media(2, MEDIA_MESSAGE_START as u16, vec![2])),
+            ),
+            (
+                ms(100),
+                Tapped::FromPhone(media(2, MEDIA_MESSAGE_CODEC_CONFIG as u16, vec![3])),
+            ),
+            (
+                ms(140),
+                Tapped::FromPhone(media(2, MEDIA_MESSAGE_DATA as u16, vec![4])),
+            ),
+            (
+                ms(180),
+                Tapped::FromPhone(media(2, MEDIA_MESSAGE_DATA as u16, vec![5])),
+            ),
+        ]
+    }
+
+    #[test]
+    fn rejects_a_recording_without_a_timeline() {
+        let mut setup_only = recording();
+        setup_only.truncate(5);
+        assert!(DemoPhone::new(setup_only).is_err());
+    }
+
+    #[test]
+    fn expires_the_oldest_recordings() {
+        let paths = [
+            "session-20260102-080000.aasession",
+            "left.wav",
+            "session-20260101-080000.aasession",
+            "session-20260103-080000.aasession",
+        ]
+        .map(PathBuf::from)
+        .to_vec();
+        assert_eq!(
+            expired_recordings(paths.clone(), 2),
+            [PathBuf::from("session-20260101-080000.aasession")]
+        );
+        assert!(expired_recordings(paths, 3).is_empty());
+    }
+
+    #[test]
+    fn stops_recording_at_the_size_limit() {
+        let recording = recording();
+        let record = |max_bytes: u64| {
+            let (tx, mut rx) = mpsc::channel(recording.len());
+            for (_, tapped) in &recording {
+                tx.try_send(tapped.clone()).unwrap();
+            }
+            drop(tx);
+            let mut data = RECORDING_MAGIC.to_vec();
+            let end = write_session(&mut rx, &mut data, max_bytes).unwrap();
+            (end, data)
+        };
+
+        let (end, _) = record(0);
+        assert_eq!(end, RecordingEnd::Closed(recording.len()));
+
+        // room for the first three packets only
+        let max_bytes = RECORDING_MAGIC.len() as u64
+            + recording[..3]
+                .iter()
+                .map(|(_, tapped)| match tapped {
+                    Tapped::FromPhone(pkt) | Tapped::FromHu(pkt) => record_len(pkt),
+                    Tapped::Lost(_) => 0,
+                })
+                .sum::<u64>()
+            + 10;
+        let (end, data) = record(max_bytes);
+        assert_eq!(end, RecordingEnd::Full(3));
+        assert!(data.len() as u64 <= max_bytes);
+        assert_eq!(parse_recording(&data).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn reads_back_a_recording() {
+        let recording = recording();
+        let mut data = RECORDING_MAGIC.to_vec();
+        for (offset, tapped) in &recording {
+            write_record(&mut data, *offset, tapped).unwrap();
+        }
+        let mut fragment = media(2, MEDIA_MESSAGE_DATA as u16, vec![6; 3]);
+        fragment.final_length = Some(1000);
+        write_record(
+            &mut data,
+            Duration::from_millis(200),
+            &Tapped::FromPhone(fragment),
+        )
+        .unwrap();
+
+        let read = parse_recording(&data).unwrap();
+        assert_eq!(read.len(), recording.len() + 1);
+        for ((offset, tapped), (expected_offset, expected)) in read.iter().zip(&recording) {
+            assert_eq!(offset, expected_offset);
+            let (pkt, expected) = match (tapped, expected) {
+                (Tapped::FromPhone(pkt), Tapped::FromPhone(expected))
+                | (Tapped::FromHu(pkt), Tapped::FromHu(expected)) => (pkt, expected),
+                _ => panic!("direction of a packet changed"),
+            };
+            assert_eq!(
+                (pkt.channel, pkt.flags, &pkt.payload),
+                (expected.channel, expected.flags, &expected.payload)
+            );
+        }
+        let Some((_, Tapped::FromPhone(fragment))) = read.last() else {
+            panic!("fragment missing");
+        };
+        assert_eq!(fragment.final_length, Some(1000));
+
+        assert!(parse_recording(&data[..data.len() - 1]).is_err());
+        assert!(parse_recording(b"not a recording").is_err());
+    }
+
+    #[test]
+    fn plays_the_recording_paced_by_acks_and_loops() {
+        let mut demo = DemoPhone::new(recording()).unwrap();
+        let hu = |demo: &mut DemoPhone, pkt| ids(&demo.on_hu_packet(pkt));
+
+        assert_eq!(
+            hu(
+                &mut demo,
+                control(0, MESSAGE_VERSION_REQUEST as u16, vec![])
+            ),
+            [(0, Some(MESSAGE_VERSION_RESPONSE as u16))]
+        );
+        assert_eq!(
+            hu(&mut demo, control(0, MESSAGE_AUTH_COMPLETE as u16, vec![])),
+            [(0, Some(MESSAGE_SERVICE_DISCOVERY_REQUEST as u16))]
+        );
+        assert!(demo.poll(Instant::now()).is_empty());
+
+        // this HU has its video on channel 1
+        assert_eq!(
+            hu(&mut demo, discovery(vec![video_service(1)])),
+            [(1, Some(MESSAGE_CHANNEL_OPEN_REQUEST as u16))]
+        );
+        let t0 = demo.started.unwrap();
+        assert_eq!(
+            hu(
+                &mut demo,
+                control(1, MESSAGE_CHANNEL_OPEN_RESPONSE as u16, vec![8, 0])
+            ),
+            [(1, Some(MEDIA_MESSAGE_SETUP as u16))]
+        );
+        let mut window = MediaConfig::new();
+        window.set_status(config::Status::STATUS_READY);
+        window.set_max_unacked(1);
+        assert!(hu(
+            &mut demo,
+            media(
+                1,
+                MEDIA_MESSAGE_CONFIG as u16,
+                window.write_to_bytes().unwrap()
+            )
+        )
+        .is_empty());
+
+        let ms = Duration::from_millis;
+        assert_eq!(
+            ids(&demo.poll(t0)),
+            [
+                (1, Some(MEDIA_MESSAGE_START as u16)),
+                (1, Some(MEDIA_MESSAGE_CODEC_CONFIG as u16))
+            ]
+        );
+        // the codec config is not ACKed yet
+        assert!(demo.poll(t0 + ms(40)).is_empty());
+        assert_eq!(demo.next_wake(), Some(t0 + ms(40) + ACK_TIMEOUT));
+        assert!(demo.on_hu_packet(ack(1)).is_empty());
+        let data = demo.poll(t0 + ms(50));
+        assert_eq!(data[0].payload, [0, 0, 4]);
+        // the delay moved the rest of the recording
+        assert_eq!(demo.next_wake(), Some(t0 + ms(90)));
+
+        // without ACKs the demo carries on after a while
+        assert!(demo.poll(t0 + ms(90)).is_empty());
+        let end = t0 + ms(90) + ACK_TIMEOUT;
+        assert_eq!(
+            ids(&demo.poll(end)),
+            [
+                (1, Some(MEDIA_MESSAGE_DATA as u16)),
+                (1, Some(MEDIA_MESSAGE_STOP as u16))
+            ]
+        );
+        assert_eq!(demo.next_wake(), Some(end));
+
+        // after a detour to the HU's own UI the video starts over
+        demo.on_hu_packet(ack(1));
+        let mut focus = VideoFocusNotification::new();
+        focus.set_focus(VideoFocusMode::VIDEO_FOCUS_NATIVE);
+        demo.on_hu_packet(media(
+            1,
+            MEDIA_MESSAGE_VIDEO_FOCUS_NOTIFICATION as u16,
+            focus.write_to_bytes().unwrap(),
+        ));
+        assert_eq!(
+            ids(&demo.poll(end + ms(40))),
+            [(1, Some(MEDIA_MESSAGE_START as u16))]
+        );
+        focus.set_focus(VideoFocusMode::VIDEO_FOCUS_PROJECTED);
+        demo.on_hu_packet(media(
+            1,
+            MEDIA_MESSAGE_VIDEO_FOCUS_NOTIFICATION as u16,
+            focus.write_to_bytes().unwrap(),
+        ));
+        assert_eq!(
+            ids(&demo.poll(end + ms(50))),
+            [(1, Some(MEDIA_MESSAGE_STOP as u16))]
+        );
+        assert_eq!(
+            ids(&demo.poll(end + ms(50))),
+            [
+                (1, Some(MEDIA_MESSAGE_START as u16)),
+                (1, Some(MEDIA_MESSAGE_CODEC_CONFIG as u16))
+            ]
+        );
+    }
+}
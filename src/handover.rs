@@ -0,0 +1,924 @@
+//! Keeps the head unit session alive while the phone reconnects.
+//!
+//! With `handover_grace_secs` the HU-side and phone-side proxies are not wired
+//! directly but through [`Relay`]. The relay remembers what the phone needs to
+//! resume (version request, auth complete, service discovery response, channel
+//! open responses, media configs, video focus and started sensors). While no
+//! phone is attached it answers the HU's pings, video focus and sensor
+//! requests itself and drops the rest. A reconnecting phone gets the cached
+//! answers, so the HU never sees a second handshake.
+//!
+//! The same mechanism switches between phones with `multi_phone`, see
+//! [`crate::multi_phone`].
+use crate::mitm::protos::ControlMessageType::*;
+use crate::mitm::protos::MediaMessageId::*;
+use crate::mitm::protos::*;
+use crate::mitm::{Packet, CONTROL, ENCRYPTED, FRAME_TYPE_FIRST, FRAME_TYPE_LAST, FRAME_TYPE_MASK};
+use crate::multi_phone;
+use protobuf::Message;
+use simplelog::*;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::task::JoinHandle;
+
+// module name for logging engine
+const NAME: &str = "<i><bright-black> handover: </>";
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Where the relay sends a packet.
+pub enum Route {
+    ToHu(Packet),
+    ToMd(Packet),
+    /// The phone asked to hand the session over to a parked phone.
+    Switch(i32),
+}
+
+pub(crate) fn message_id(pkt: &Packet) -> Option<u16> {
+    if pkt.flags & FRAME_TYPE_FIRST == 0 || pkt.payload.len() < 2 {
+        return None;
+    }
+    Some(u16::from_be_bytes([pkt.payload[0], pkt.payload[1]]))
+}
+
+/// Control messages travel on channel 0, or flagged on a service channel.
+pub(crate) fn control_id(pkt: &Packet) -> Option<u16> {
+    if pkt.channel == 0 || pkt.flags & CONTROL != 0 {
+        message_id(pkt)
+    } else {
+        None
+    }
+}
+
+pub(crate) fn is(id: Option<u16>, message: impl protobuf::Enum) -> bool {
+    id == Some(message.value() as u16)
+}
+
+/// Single-frame reply on the channel of `request`.
+pub(crate) fn reply_to(request: &Packet, message_id: u16, body: Vec<u8>) -> Packet {
+    let mut payload = message_id.to_be_bytes().to_vec();
+    payload.extend(body);
+    Packet {
+        channel: request.channel,
+        flags: (request.flags & !FRAME_TYPE_MASK) | FRAME_TYPE_FIRST | FRAME_TYPE_LAST,
+        final_length: None,
+        payload,
+    }
+}
+
+/// Device switcher requests from the phone, answered with the parked phones.
+fn asks_for_switch_targets(pkt: &Packet) -> bool {
+    let id = control_id(pkt);
+    pkt.channel == 0
+        && (is(id, MESSAGE_CAR_CONNECTED_DEVICES_REQUEST) || is(id, MESSAGE_USER_SWITCH_REQUEST))
+}
+
+/// Successful sensor response on the channel of `request`.
+fn sensor_ok(request: &Packet) -> Option<Packet> {
+    let mut response = SensorResponse::new();
+    response.set_status(MessageStatus::STATUS_SUCCESS);
+    let body = response.write_to_bytes().ok()?;
+    Some(reply_to(
+        request,
+        SensorMessageId::SENSOR_MESSAGE_RESPONSE as u16,
+        body,
+    ))
+}
+
+/// Refuse the channel open `request` of the phone.
+fn refuse_open(request: &Packet) -> Option<Packet> {
+    let mut response = ChannelOpenResponse::new();
+    response.set_status(MessageStatus::STATUS_INVALID_CHANNEL);
+    let body = response.write_to_bytes().ok()?;
+    Some(reply_to(
+        request,
+        MESSAGE_CHANNEL_OPEN_RESPONSE as u16,
+        body,
+    ))
+}
+
+/// Service id of the sensor source in a complete service discovery response.
+fn sensor_service(frames: &[Packet]) -> Option<i32> {
+    let payload: Vec<u8> = frames.iter().flat_map(|pkt| pkt.payload.clone()).collect();
+    let response = ServiceDiscoveryResponse::parse_from_bytes(payload.get(2..)?).ok()?;
+    response
+        .services
+        .iter()
+        .find(|svc| !svc.sensor_source_service.sensors.is_empty())
+        .map(|svc| svc.id())
+}
+
+/// Answer a head unit's ping in the phone's place.
+pub(crate) fn answer_ping(pkt: &Packet) -> Option<Packet> {
+    if pkt.channel != 0 || !is(control_id(pkt), MESSAGE_PING_REQUEST) {
+        return None;
+    }
+    let request = PingRequest::parse_from_bytes(&pkt.payload[2..]).ok()?;
+    let mut response = PingResponse::new();
+    response.set_timestamp(request.timestamp());
+    let body = response.write_to_bytes().ok()?;
+    Some(reply_to(pkt, MESSAGE_PING_RESPONSE as u16, body))
+}
+
+fn connected_device(id: i32, name: &str) -> ConnectedDevice {
+    let mut device = ConnectedDevice::new();
+    device.set_device_id(id);
+    device.set_device_name(name.to_string());
+    device
+}
+
+pub(crate) fn on_channel(pkt: &Packet, channel: u8) -> Packet {
+    Packet {
+        channel,
+        ..pkt.clone()
+    }
+}
+
+/// What the relay learned from the first phone session, and the channel map
+/// of the current one.
+#[derive(Default)]
+pub struct SessionCache {
+    version_request: Option<Packet>,
+    auth_complete: Option<Packet>,
+    /// All frames of the service discovery response.
+    service_discovery: Vec<Packet>,
+    collecting_service_discovery: bool,
+    /// Service requested per channel until the HU answers the open request.
+    pending_open: HashMap<u8, i32>,
+    /// HU channel of every opened service.
+    service_channels: HashMap<i32, u8>,
+    open_responses: HashMap<u8, Packet>,
+    /// HU channels the phone has set up as media streams.
+    media_channels: HashSet<u8>,
+    media_configs: HashMap<u8, Packet>,
+    /// Last video focus the HU granted per channel.
+    video_focus: HashMap<u8, Packet>,
+    /// Service id of the HU's sensor source.
+    sensor_service: Option<i32>,
+    /// Sensor types a phone started, the HU keeps streaming them.
+    started_sensors: HashSet<i32>,
+    /// Phone channel -> HU channel, only for a resumed session.
+    md_to_hu: HashMap<u8, u8>,
+    attached: bool,
+    resumed: bool,
+    /// The resumed phone got the service discovery response.
+    discovered: bool,
+    /// Parked phones the projected one may switch to: id and name.
+    switch_targets: Vec<(i32, String)>,
+}
+
+impl SessionCache {
+    /// Enough is known to let another phone session take over.
+    pub fn resumable(&self) -> bool {
+        self.version_request.is_some()
+            && self.auth_complete.is_some()
+            && !self.service_discovery.is_empty()
+            && !self.collecting_service_discovery
+    }
+
+    /// A phone-side proxy was attached, returns what to feed it first.
+    pub fn attach(&mut self) -> Vec<Packet> {
+        self.attached = true;
+        self.md_to_hu.clear();
+        self.pending_open.clear();
+        self.resumed = self.resumable();
+        self.discovered = !self.resumed;
+        if !self.resumed {
+            return vec![];
+        }
+        // the phone-side proxy reads the version request during its
+        // handshake, auth complete waits in the queue until SSL is done
+        [&self.version_request, &self.auth_complete]
+            .into_iter()
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    pub fn detach(&mut self) {
+        self.attached = false;
+        self.pending_open.clear();
+    }
+
+    pub fn set_switch_targets(&mut self, targets: Vec<(i32, String)>) {
+        self.switch_targets = targets;
+    }
+
+    fn hu_channel(&self, md_channel: u8) -> u8 {
+        *self.md_to_hu.get(&md_channel).unwrap_or(&md_channel)
+    }
+
+    fn is_sensor_channel(&self, hu_channel: u8) -> bool {
+        self.sensor_service
+            .and_then(|id| self.service_channels.get(&id))
+            .is_some_and(|channel| *channel == hu_channel)
+    }
+
+    /// Whether `hu_channel` carries a service of this or an earlier session.
+    fn hu_channel_taken(&self, hu_channel: u8) -> bool {
+        self.service_channels.values().any(|c| *c == hu_channel)
+            || self.md_to_hu.values().any(|c| *c == hu_channel)
+    }
+
+    fn md_channel(&self, hu_channel: u8) -> Option<u8> {
+        if hu_channel == 0 || !self.resumed {
+            return Some(hu_channel);
+        }
+        self.md_to_hu
+            .iter()
+            .find(|(_, hu)| **hu == hu_channel)
+            .map(|(md, _)| *md)
+    }
+
+    fn learn_from_hu(&mut self, pkt: &Packet) {
+        let id = control_id(pkt);
+        if pkt.channel == 0 {
+            if self.collecting_service_discovery && pkt.flags & FRAME_TYPE_FIRST == 0 {
+                self.service_discovery.push(pkt.clone());
+                self.collecting_service_discovery = pkt.flags & FRAME_TYPE_LAST == 0;
+                if !self.collecting_service_discovery {
+                    self.sensor_service = sensor_service(&self.service_discovery);
+                }
+                return;
+            }
+            if is(id, MESSAGE_VERSION_REQUEST) {
+                self.version_request = Some(pkt.clone());
+            } else if is(id, MESSAGE_AUTH_COMPLETE) {
+                self.auth_complete = Some(pkt.clone());
+            } else if is(id, MESSAGE_SERVICE_DISCOVERY_RESPONSE) {
+                self.service_discovery = vec![pkt.clone()];
+                self.collecting_service_discovery = pkt.flags & FRAME_TYPE_LAST == 0;
+                if !self.collecting_service_discovery {
+                    self.sensor_service = sensor_service(&self.service_discovery);
+                }
+            }
+        } else if is(id, MESSAGE_CHANNEL_OPEN_RESPONSE) {
+            if let Some(service_id) = self.pending_open.remove(&pkt.channel) {
+                self.service_channels.insert(service_id, pkt.channel);
+                self.open_responses.insert(pkt.channel, pkt.clone());
+            }
+        } else if self.media_channels.contains(&pkt.channel) {
+            let id = message_id(pkt);
+            if is(id, MEDIA_MESSAGE_CONFIG) {
+                self.media_configs.insert(pkt.channel, pkt.clone());
+            } else if is(id, MEDIA_MESSAGE_VIDEO_FOCUS_NOTIFICATION) {
+                self.video_focus.insert(pkt.channel, pkt.clone());
+            }
+        }
+    }
+
+    /// Answer for the HU while no phone is attached: pings, video focus
+    /// requests with the focus it granted last and sensor requests, so it
+    /// keeps the projection up. Everything else can wait for the next phone.
+    fn hold(&self, pkt: &Packet) -> Option<Packet> {
+        if let Some(pong) = answer_ping(pkt) {
+            return Some(pong);
+        }
+        let id = message_id(pkt);
+        if self.media_channels.contains(&pkt.channel) && is(id, MEDIA_MESSAGE_VIDEO_FOCUS_REQUEST) {
+            let focus = self.video_focus.get(&pkt.channel)?;
+            return Some(reply_to(
+                pkt,
+                MEDIA_MESSAGE_VIDEO_FOCUS_NOTIFICATION as u16,
+                focus.payload.get(2..)?.to_vec(),
+            ));
+        }
+        if self.is_sensor_channel(pkt.channel) && is(id, SensorMessageId::SENSOR_MESSAGE_REQUEST) {
+            return sensor_ok(pkt);
+        }
+        None
+    }
+
+    /// Sensor start request of the phone: a sensor an earlier phone started
+    /// still streams, the resumed phone gets the answer from here.
+    fn sensor_request(&mut self, pkt: &Packet) -> Option<Packet> {
+        if !self.is_sensor_channel(self.hu_channel(pkt.channel))
+            || !is(message_id(pkt), SensorMessageId::SENSOR_MESSAGE_REQUEST)
+        {
+            return None;
+        }
+        let request = SensorRequest::parse_from_bytes(pkt.payload.get(2..)?).ok()?;
+        let sensor = request.type_() as i32;
+        if self.started_sensors.insert(sensor) || !self.resumed {
+            return None;
+        }
+        sensor_ok(pkt)
+    }
+
+    /// Answer the phone's device switcher with the parked phones, the HU
+    /// does not know them.
+    fn user_switch(&self, pkt: &Packet) -> Option<Vec<Route>> {
+        if pkt.channel != 0 || self.switch_targets.is_empty() {
+            return None;
+        }
+        let id = control_id(pkt);
+        if is(id, MESSAGE_CAR_CONNECTED_DEVICES_REQUEST) {
+            let mut devices = CarConnectedDevices::new();
+            devices.connected_devices = self
+                .switch_targets
+                .iter()
+                .map(|(id, name)| connected_device(*id, name))
+                .collect();
+            devices.set_final_list(true);
+            let body = devices.write_to_bytes().ok()?;
+            return Some(vec![Route::ToMd(reply_to(
+                pkt,
+                MESSAGE_CAR_CONNECTED_DEVICES_RESPONSE as u16,
+                body,
+            ))]);
+        }
+        if is(id, MESSAGE_USER_SWITCH_REQUEST) {
+            let request = UserSwitchRequest::parse_from_bytes(&pkt.payload[2..]).ok()?;
+            let target = request.selected_device.device_id();
+            // anything else is one of the HU's own devices
+            let (_, name) = self.switch_targets.iter().find(|(id, _)| *id == target)?;
+            let mut response = UserSwitchResponse::new();
+            response.set_status(UserSwitchStatus::STATUS_OK);
+            response.selected_device = Some(connected_device(target, name)).into();
+            let body = response.write_to_bytes().ok()?;
+            return Some(vec![
+                Route::ToMd(reply_to(pkt, MESSAGE_USER_SWITCH_RESPONSE as u16, body)),
+                Route::Switch(target),
+            ]);
+        }
+        None
+    }
+
+    /// Packet from the HU-side proxy.
+    pub fn on_hu_packet(&mut self, pkt: Packet) -> Vec<Route> {
+        self.learn_from_hu(&pkt);
+        // a resumed phone is not ready for the HU before service discovery
+        if !self.attached || !self.discovered {
+            return self.hold(&pkt).map(Route::ToHu).into_iter().collect();
+        }
+        match self.md_channel(pkt.channel) {
+            Some(channel) => vec![Route::ToMd(on_channel(&pkt, channel))],
+            // the resumed phone has not opened this service (yet)
+            None => vec![],
+        }
+    }
+
+    /// Packet from the phone-side proxy.
+    pub fn on_md_packet(&mut self, pkt: Packet) -> Vec<Route> {
+        if let Some(routes) = self.user_switch(&pkt) {
+            return routes;
+        }
+        let id = control_id(&pkt);
+        if pkt.channel != 0 && is(id, MESSAGE_CHANNEL_OPEN_REQUEST) {
+            let service_id = ChannelOpenRequest::parse_from_bytes(&pkt.payload[2..])
+                .map(|r| r.service_id())
+                .ok();
+            if let Some(service_id) = service_id {
+                if self.resumed {
+                    let known = self.service_channels.get(&service_id).and_then(|hu| {
+                        self.open_responses
+                            .get(hu)
+                            .map(|response| (*hu, on_channel(response, pkt.channel)))
+                    });
+                    if let Some((hu_channel, response)) = known {
+                        self.md_to_hu.insert(pkt.channel, hu_channel);
+                        return vec![Route::ToMd(response)];
+                    }
+                    // a service the first phone never opened: the HU opens
+                    // it on the phone's channel, unless another service
+                    // already has that channel there
+                    if self.hu_channel_taken(pkt.channel) {
+                        warn!(
+                            "{} channel {} is taken on the head unit, refusing service {}",
+                            NAME, pkt.channel, service_id
+                        );
+                        return refuse_open(&pkt).map(Route::ToMd).into_iter().collect();
+                    }
+                    self.md_to_hu.insert(pkt.channel, pkt.channel);
+                }
+                self.pending_open
+                    .insert(self.hu_channel(pkt.channel), service_id);
+            }
+        }
+        if let Some(reply) = self.sensor_request(&pkt) {
+            return vec![Route::ToMd(reply)];
+        }
+        let is_media_setup = is(message_id(&pkt), MEDIA_MESSAGE_SETUP);
+        if is_media_setup {
+            self.media_channels.insert(self.hu_channel(pkt.channel));
+        }
+        if self.resumed {
+            if pkt.channel == 0 && is(id, MESSAGE_VERSION_RESPONSE) {
+                return vec![];
+            }
+            if pkt.channel == 0 && is(id, MESSAGE_SERVICE_DISCOVERY_REQUEST) {
+                self.discovered = true;
+                return self
+                    .service_discovery
+                    .iter()
+                    .cloned()
+                    .map(Route::ToMd)
+                    .collect();
+            }
+            if is_media_setup {
+                // the HU keeps the stream configured and focused, it would
+                // not announce focus again
+                let hu_channel = self.hu_channel(pkt.channel);
+                if let Some(config) = self.media_configs.get(&hu_channel) {
+                    return [Some(config), self.video_focus.get(&hu_channel)]
+                        .into_iter()
+                        .flatten()
+                        .map(|reply| Route::ToMd(on_channel(reply, pkt.channel)))
+                        .collect();
+                }
+            }
+        }
+        let channel = self.hu_channel(pkt.channel);
+        vec![Route::ToHu(on_channel(&pkt, channel))]
+    }
+}
+
+/// A phone-side proxy attached to the relay.
+struct PhoneSession {
+    to_md: Sender<Packet>,
+    from_md: Receiver<Packet>,
+}
+
+/// Sits between the HU-side proxy and the current phone-side proxy.
+pub struct Relay {
+    sessions: Sender<PhoneSession>,
+    resumable: Arc<AtomicBool>,
+    capacity: usize,
+}
+
+impl Relay {
+    /// Relay the HU-side proxy's channels, `from_hu` is what it sends towards
+    /// the phone and `to_hu` what it should transmit to the HU. With
+    /// `switching` the phone may pick a parked phone to take over.
+    pub fn spawn(
+        from_hu: Receiver<Packet>,
+        to_hu: Sender<Packet>,
+        capacity: usize,
+        switching: bool,
+    ) -> (Self, JoinHandle<Result<()>>) {
+        let (sessions, sessions_rx) = mpsc::channel(1);
+        let resumable = Arc::new(AtomicBool::new(false));
+        let task = tokio::spawn(run(
+            from_hu,
+            to_hu,
+            sessions_rx,
+            resumable.clone(),
+            switching,
+        ));
+        (
+            Self {
+                sessions,
+                resumable,
+                capacity,
+            },
+            task,
+        )
+    }
+
+    /// Whether a new phone session could take over the HU session.
+    pub fn resumable(&self) -> bool {
+        self.resumable.load(Ordering::Relaxed)
+    }
+
+    /// Channels for a new phone-side proxy: its `tx` and `rx`.
+    pub async fn attach(&self) -> Result<(Sender<Packet>, Receiver<Packet>)> {
+        let (tx, from_md) = mpsc::channel(self.capacity);
+        let (to_md, rx) = mpsc::channel(self.capacity);
+        self.sessions
+            .send(PhoneSession { to_md, from_md })
+            .await
+            .map_err(|_| "handover relay has stopped")?;
+        Ok((tx, rx))
+    }
+}
+
+async fn run(
+    mut from_hu: Receiver<Packet>,
+    to_hu: Sender<Packet>,
+    mut sessions: Receiver<PhoneSession>,
+    resumable: Arc<AtomicBool>,
+    switching: bool,
+) -> Result<()> {
+    let mut cache = SessionCache::default();
+    let mut phone: Option<PhoneSession> = None;
+    loop {
+        let routes = tokio::select! {
+            // attach before anything from the HU is routed
+            biased;
+            Some(session) = sessions.recv() => {
+                let replay = cache.attach();
+                if !replay.is_empty() {
+                    info!("{} 🔁 phone reconnected, replaying the session state", NAME);
+                }
+                phone = Some(session);
+                replay.into_iter().map(Route::ToMd).collect()
+            }
+            pkt = from_hu.recv() => match pkt {
+                Some(pkt) => cache.on_hu_packet(pkt),
+                // HU-side proxy has stopped, the session is over
+                None => return Ok(()),
+            },
+            pkt = async { phone.as_mut().unwrap().from_md.recv().await }, if phone.is_some() => {
+                match pkt {
+                    Some(pkt) => {
+                        // the parked phones only matter to the device switcher
+                        if switching && asks_for_switch_targets(&pkt) {
+                            let parked = multi_phone::status().parked;
+                            cache.set_switch_targets(
+                                parked.into_iter().map(|p| (p.id, p.name)).collect(),
+                            );
+                        }
+                        cache.on_md_packet(pkt)
+                    }
+                    None => {
+                        info!("{} 📵 phone is gone, holding the head unit session", NAME);
+                        cache.detach();
+                        phone = None;
+                        vec![]
+                    }
+                }
+            }
+        };
+        resumable.store(cache.resumable(), Ordering::Relaxed);
+
+        for route in routes {
+            match route {
+                Route::ToHu(pkt) => to_hu.send(pkt).await?,
+                Route::ToMd(pkt) => {
+                    if let Some(ref session) = phone {
+                        if session.to_md.send(pkt).await.is_err() {
+                            cache.detach();
+                            phone = None;
+                        }
+                    }
+                }
+                Route::Switch(id) => {
+                    info!("{} 🔀 phone asked to switch to phone {}", NAME, id);
+                    multi_phone::request_switch(Some(id));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mitm::test_packets::{control, discovery, media, open_request, video_service};
+
+    fn to_md(routes: Vec<Route>) -> Vec<Packet> {
+        routes
+            .into_iter()
+            .map(|r| match r {
+                Route::ToMd(pkt) => pkt,
+                _ => panic!("unexpected route, expected a packet towards MD"),
+            })
+            .collect()
+    }
+
+    /// First session: learn everything while forwarding unchanged.
+    fn learned() -> SessionCache {
+        let mut cache = SessionCache::default();
+        assert!(cache.attach().is_empty());
+        cache.on_hu_packet(control(0, MESSAGE_VERSION_REQUEST as u16, vec![0, 1, 0, 7]));
+        cache.on_hu_packet(control(0, MESSAGE_AUTH_COMPLETE as u16, vec![8, 0]));
+        let mut first = control(0, MESSAGE_SERVICE_DISCOVERY_RESPONSE as u16, vec![1]);
+        first.flags &= !FRAME_TYPE_LAST;
+        let last = Packet {
+            channel: 0,
+            flags: ENCRYPTED | FRAME_TYPE_LAST,
+            final_length: None,
+            payload: vec![3, 4],
+        };
+        cache.on_hu_packet(first);
+        assert!(!cache.resumable());
+        cache.on_hu_packet(last);
+        cache.on_md_packet(open_request(3, 3));
+        cache.on_hu_packet(control(3, MESSAGE_CHANNEL_OPEN_RESPONSE as u16, vec![8, 0]));
+        let mut setup = control(3, MEDIA_MESSAGE_SETUP as u16, vec![]);
+        setup.flags &= !CONTROL;
+        cache.on_md_packet(setup);
+        let mut config = control(3, MEDIA_MESSAGE_CONFIG as u16, vec![8, 1]);
+        config.flags &= !CONTROL;
+        cache.on_hu_packet(config);
+        let mut focus = VideoFocusNotification::new();
+        focus.set_focus(VideoFocusMode::VIDEO_FOCUS_PROJECTED);
+        let mut focus = control(
+            3,
+            MEDIA_MESSAGE_VIDEO_FOCUS_NOTIFICATION as u16,
+            focus.write_to_bytes().unwrap(),
+        );
+        focus.flags &= !CONTROL;
+        cache.on_hu_packet(focus);
+        assert!(cache.resumable());
+        cache
+    }
+
+    #[test]
+    fn holds_the_head_unit_without_a_phone() {
+        let mut cache = learned();
+        cache.detach();
+
+        let mut ping = PingRequest::new();
+        ping.set_timestamp(1234);
+        let routes = cache.on_hu_packet(control(
+            0,
+            MESSAGE_PING_REQUEST as u16,
+            ping.write_to_bytes().unwrap(),
+        ));
+        let Some(Route::ToHu(reply)) = routes.into_iter().next() else {
+            panic!("ping not answered");
+        };
+        assert_eq!(message_id(&reply), Some(MESSAGE_PING_RESPONSE as u16));
+        let response = PingResponse::parse_from_bytes(&reply.payload[2..]).unwrap();
+        assert_eq!(response.timestamp(), 1234);
+
+        // sensor batches share the media config id, and have nowhere to go
+        let mut sensor = control(2, MEDIA_MESSAGE_CONFIG as u16, vec![]);
+        sensor.flags &= !CONTROL;
+        assert!(cache.on_hu_packet(sensor).is_empty());
+        assert!(!cache.media_configs.contains_key(&2));
+    }
+
+    fn sensor_source(id: i32) -> Service {
+        let mut sensor = sensor_source_service::Sensor::new();
+        sensor.set_sensor_type(SensorType::SENSOR_LOCATION);
+        let mut source = SensorSourceService::new();
+        source.sensors.push(sensor);
+        let mut svc = Service::new();
+        svc.set_id(id);
+        svc.sensor_source_service = Some(source).into();
+        svc
+    }
+
+    fn sensor_request(channel: u8, sensor: SensorType) -> Packet {
+        let mut request = SensorRequest::new();
+        request.set_type(sensor);
+        request.set_min_update_period(0);
+        media(
+            channel,
+            SensorMessageId::SENSOR_MESSAGE_REQUEST as u16,
+            request.write_to_bytes().unwrap(),
+        )
+    }
+
+    /// `learned` with a sensor source on channel 2, location started.
+    fn learned_with_sensors() -> SessionCache {
+        let mut cache = learned();
+        cache.on_hu_packet(discovery(vec![video_service(3), sensor_source(2)]));
+        assert_eq!(cache.sensor_service, Some(2));
+        cache.on_md_packet(open_request(2, 2));
+        cache.on_hu_packet(control(2, MESSAGE_CHANNEL_OPEN_RESPONSE as u16, vec![8, 0]));
+        assert!(matches!(
+            cache
+                .on_md_packet(sensor_request(2, SensorType::SENSOR_LOCATION))
+                .as_slice(),
+            [Route::ToHu(_)]
+        ));
+        cache
+    }
+
+    fn assert_sensor_ok(reply: &Packet, channel: u8) {
+        assert_eq!(reply.channel, channel);
+        assert_eq!(
+            message_id(reply),
+            Some(SensorMessageId::SENSOR_MESSAGE_RESPONSE as u16)
+        );
+        let response = SensorResponse::parse_from_bytes(&reply.payload[2..]).unwrap();
+        assert_eq!(response.status(), MessageStatus::STATUS_SUCCESS);
+    }
+
+    #[test]
+    fn holds_video_focus_and_sensors_without_a_phone() {
+        let mut cache = learned_with_sensors();
+        cache.detach();
+
+        let mut request = VideoFocusRequestNotification::new();
+        request.set_mode(VideoFocusMode::VIDEO_FOCUS_PROJECTED);
+        let routes = cache.on_hu_packet(media(
+            3,
+            MEDIA_MESSAGE_VIDEO_FOCUS_REQUEST as u16,
+            request.write_to_bytes().unwrap(),
+        ));
+        let [Route::ToHu(reply)] = routes.as_slice() else {
+            panic!("video focus request not answered");
+        };
+        assert_eq!(reply.channel, 3);
+        assert_eq!(
+            message_id(reply),
+            Some(MEDIA_MESSAGE_VIDEO_FOCUS_NOTIFICATION as u16)
+        );
+        let focus = VideoFocusNotification::parse_from_bytes(&reply.payload[2..]).unwrap();
+        assert_eq!(focus.focus(), VideoFocusMode::VIDEO_FOCUS_PROJECTED);
+
+        // no focus was granted on a channel without video
+        assert!(cache
+            .on_hu_packet(media(
+                4,
+                MEDIA_MESSAGE_VIDEO_FOCUS_REQUEST as u16,
+                request.write_to_bytes().unwrap(),
+            ))
+            .is_empty());
+
+        let routes = cache.on_hu_packet(sensor_request(2, SensorType::SENSOR_SPEED));
+        let [Route::ToHu(reply)] = routes.as_slice() else {
+            panic!("sensor request not answered");
+        };
+        assert_sensor_ok(reply, 2);
+        // sensor data has nowhere to go
+        assert!(cache
+            .on_hu_packet(media(
+                2,
+                SensorMessageId::SENSOR_MESSAGE_BATCH as u16,
+                vec![]
+            ))
+            .is_empty());
+    }
+
+    #[test]
+    fn resumed_phone_finds_its_sensors_running() {
+        let mut cache = learned_with_sensors();
+        cache.detach();
+        cache.attach();
+        cache.on_md_packet(control(0, MESSAGE_VERSION_RESPONSE as u16, vec![]));
+        cache.on_md_packet(control(0, MESSAGE_SERVICE_DISCOVERY_REQUEST as u16, vec![]));
+        to_md(cache.on_md_packet(open_request(2, 2)));
+
+        // the HU still streams location, a sensor nobody started is up to it
+        let reply = to_md(cache.on_md_packet(sensor_request(2, SensorType::SENSOR_LOCATION)));
+        assert_sensor_ok(&reply[0], 2);
+        assert!(matches!(
+            cache
+                .on_md_packet(sensor_request(2, SensorType::SENSOR_SPEED))
+                .as_slice(),
+            [Route::ToHu(_)]
+        ));
+    }
+
+    #[test]
+    fn refuses_a_new_service_on_a_taken_channel() {
+        let mut cache = learned();
+        cache.detach();
+        cache.attach();
+        cache.on_md_packet(control(0, MESSAGE_VERSION_RESPONSE as u16, vec![]));
+        cache.on_md_packet(control(0, MESSAGE_SERVICE_DISCOVERY_REQUEST as u16, vec![]));
+        // the video service moves to channel 5, its HU channel stays 3
+        to_md(cache.on_md_packet(open_request(5, 3)));
+
+        // a new service on the phone's channel 3 would land on the video
+        let reply = to_md(cache.on_md_packet(open_request(3, 9)));
+        assert_eq!(reply[0].channel, 3);
+        assert_eq!(
+            message_id(&reply[0]),
+            Some(MESSAGE_CHANNEL_OPEN_RESPONSE as u16)
+        );
+        let response = ChannelOpenResponse::parse_from_bytes(&reply[0].payload[2..]).unwrap();
+        assert_eq!(response.status(), MessageStatus::STATUS_INVALID_CHANNEL);
+        assert!(cache.pending_open.is_empty());
+        assert_eq!(cache.md_to_hu.get(&3), None);
+
+        // HU traffic of the video service still reaches the phone's channel 5
+        let ack = media(3, MEDIA_MESSAGE_ACK as u16, vec![]);
+        assert_eq!(to_md(cache.on_hu_packet(ack))[0].channel, 5);
+    }
+
+    #[test]
+    fn replays_state_to_a_reconnected_phone() {
+        let mut cache = learned();
+        cache.detach();
+
+        let replay = cache.attach();
+        assert_eq!(replay.len(), 2);
+        let mut ping = PingRequest::new();
+        ping.set_timestamp(1);
+        let ping = control(
+            0,
+            MESSAGE_PING_REQUEST as u16,
+            ping.write_to_bytes().unwrap(),
+        );
+        assert!(matches!(
+            cache.on_hu_packet(ping.clone()).as_slice(),
+            [Route::ToHu(_)]
+        ));
+        assert_eq!(message_id(&replay[0]), Some(MESSAGE_VERSION_REQUEST as u16));
+        assert_eq!(message_id(&replay[1]), Some(MESSAGE_AUTH_COMPLETE as u16));
+
+        // the HU must not see a second version response or discovery
+        assert!(cache
+            .on_md_packet(control(0, MESSAGE_VERSION_RESPONSE as u16, vec![]))
+            .is_empty());
+        let sdr =
+            to_md(cache.on_md_packet(control(0, MESSAGE_SERVICE_DISCOVERY_REQUEST as u16, vec![])));
+        assert_eq!(sdr.len(), 2);
+        assert_eq!(sdr[1].payload, vec![3, 4]);
+        assert_eq!(to_md(cache.on_hu_packet(ping)).len(), 1);
+
+        // same service on another channel gets the cached answer and a mapping
+        let open = to_md(cache.on_md_packet(open_request(5, 3)));
+        assert_eq!(open[0].channel, 5);
+        assert_eq!(
+            message_id(&open[0]),
+            Some(MESSAGE_CHANNEL_OPEN_RESPONSE as u16)
+        );
+        let mut setup = control(5, MEDIA_MESSAGE_SETUP as u16, vec![]);
+        setup.flags &= !CONTROL;
+        let setup = to_md(cache.on_md_packet(setup));
+        assert_eq!(setup[0].channel, 5);
+        assert_eq!(message_id(&setup[0]), Some(MEDIA_MESSAGE_CONFIG as u16));
+        assert_eq!(
+            message_id(&setup[1]),
+            Some(MEDIA_MESSAGE_VIDEO_FOCUS_NOTIFICATION as u16)
+        );
+
+        let mut data = control(5, 0, vec![0xAA]);
+        data.flags &= !CONTROL;
+        let Some(Route::ToHu(data)) = cache.on_md_packet(data).into_iter().next() else {
+            panic!("data not forwarded");
+        };
+        assert_eq!(data.channel, 3);
+        let mut ack = control(3, MEDIA_MESSAGE_ACK as u16, vec![]);
+        ack.flags &= !CONTROL;
+        assert_eq!(to_md(cache.on_hu_packet(ack))[0].channel, 5);
+        // nothing opened the HU's channel 4 in this session
+        assert!(cache.on_hu_packet(control(4, 0x8001, vec![])).is_empty());
+    }
+
+    #[test]
+    fn resumed_phone_opens_a_new_service() {
+        let mut cache = learned();
+        cache.detach();
+        cache.attach();
+        cache.on_md_packet(control(0, MESSAGE_VERSION_RESPONSE as u16, vec![]));
+        cache.on_md_packet(control(0, MESSAGE_SERVICE_DISCOVERY_REQUEST as u16, vec![]));
+
+        let routes = cache.on_md_packet(open_request(6, 9));
+        let [Route::ToHu(open)] = routes.as_slice() else {
+            panic!("open request not forwarded");
+        };
+        assert_eq!(open.channel, 6);
+        let response =
+            to_md(cache.on_hu_packet(control(6, MESSAGE_CHANNEL_OPEN_RESPONSE as u16, vec![8, 0])));
+        assert_eq!(response[0].channel, 6);
+        assert_eq!(
+            to_md(cache.on_hu_packet(control(6, 0x8001, vec![])))[0].channel,
+            6
+        );
+
+        // known from now on, the next phone gets the cached answer
+        cache.detach();
+        cache.attach();
+        let open = to_md(cache.on_md_packet(open_request(7, 9)));
+        assert_eq!(open[0].channel, 7);
+    }
+
+    #[test]
+    fn answers_the_device_switcher_with_parked_phones() {
+        let mut cache = learned();
+        let request = control(0, MESSAGE_CAR_CONNECTED_DEVICES_REQUEST as u16, vec![]);
+        assert!(asks_for_switch_targets(&request));
+        assert!(!asks_for_switch_targets(&control(
+            0,
+            MESSAGE_PING_REQUEST as u16,
+            vec![]
+        )));
+        // nobody is parked, it is up to the HU
+        assert!(matches!(
+            cache.on_md_packet(request.clone()).as_slice(),
+            [Route::ToHu(_)]
+        ));
+
+        cache.set_switch_targets(vec![(7, "Pixel".to_string())]);
+        let reply = to_md(cache.on_md_packet(request));
+        let devices = CarConnectedDevices::parse_from_bytes(&reply[0].payload[2..]).unwrap();
+        assert_eq!(devices.connected_devices.len(), 1);
+        assert_eq!(devices.connected_devices[0].device_id(), 7);
+
+        let mut switch = UserSwitchRequest::new();
+        switch.selected_device = Some(connected_device(7, "Pixel")).into();
+        let routes = cache.on_md_packet(control(
+            0,
+            MESSAGE_USER_SWITCH_REQUEST as u16,
+            switch.write_to_bytes().unwrap(),
+        ));
+        let [Route::ToMd(reply), Route::Switch(7)] = routes.as_slice() else {
+            panic!("switch not accepted");
+        };
+        let response = UserSwitchResponse::parse_from_bytes(&reply.payload[2..]).unwrap();
+        assert_eq!(response.status(), UserSwitchStatus::STATUS_OK);
+
+        // one of the HU's own devices
+        switch.selected_device = Some(connected_device(1, "Other")).into();
+        assert!(matches!(
+            cache
+                .on_md_packet(control(
+                    0,
+                    MESSAGE_USER_SWITCH_REQUEST as u16,
+                    switch.write_to_bytes().unwrap(),
+                ))
+                .as_slice(),
+            [Route::ToHu(_)]
+        ));
+    }
+}
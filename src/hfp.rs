@@ -0,0 +1,741 @@
+use crate::bt_sco::{self, ScoVoiceCodec};
+use crate::mitm::protos::KeyCode;
+use crate::web::ServerEvent;
+use bluer::Uuid;
+use serde::{Deserialize, Serialize};
+use simplelog::*;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::broadcast::Sender as BroadcastSender;
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::timeout;
+
+const NAME: &str = "<i><bright-black> hfp: </>";
+
+// Just a generic Result type to ease error handling for us. Errors in multithreaded
+// async contexts needs some extra restrictions
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+pub const HFP_HF_UUID: Uuid = Uuid::from_u128(0x0000111e00001000800000805f9b34fb);
+pub const HFP_AG_UUID: Uuid = Uuid::from_u128(0x0000111f00001000800000805f9b34fb);
+/// HFP 1.7 profile version for the SDP record.
+pub const HFP_VERSION: u16 = 0x0107;
+
+/// Status updates published by the HF state machine.
+pub const HFP_TOPIC: &str = "hfp";
+/// JSON `HfpCommand`s accepted from web clients and scripts.
+pub const HFP_COMMAND_TOPIC: &str = "hfp-command";
+
+const AT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_VOLUME: u8 = 15;
+
+// HF supported features sent with AT+BRSF.
+const HF_FEATURE_EC_NR: u32 = 1 << 0;
+const HF_FEATURE_CLI: u32 = 1 << 2;
+const HF_FEATURE_REMOTE_VOLUME: u32 = 1 << 4;
+const HF_FEATURE_CODEC_NEGOTIATION: u32 = 1 << 7;
+// AG supported features reported with +BRSF.
+const AG_FEATURE_CODEC_NEGOTIATION: u32 = 1 << 9;
+// SupportedFeatures attribute of the HF SDP record.
+const HF_SDP_FEATURE_EC_NR: u16 = 1 << 0;
+const HF_SDP_FEATURE_CLI: u16 = 1 << 2;
+const HF_SDP_FEATURE_REMOTE_VOLUME: u16 = 1 << 4;
+const HF_SDP_FEATURE_WIDEBAND: u16 = 1 << 5;
+
+const CODEC_ID_CVSD: u8 = 1;
+const CODEC_ID_MSBC: u8 = 2;
+
+#[derive(Debug, Clone, Default)]
+pub struct HfpOptions {
+    /// Offer mSBC during codec negotiation.
+    pub wideband: bool,
+}
+
+impl HfpOptions {
+    /// SupportedFeatures value for the HF profile registration.
+    pub fn sdp_features(&self) -> u16 {
+        let mut features = HF_SDP_FEATURE_EC_NR | HF_SDP_FEATURE_CLI | HF_SDP_FEATURE_REMOTE_VOLUME;
+        if self.wideband {
+            features |= HF_SDP_FEATURE_WIDEBAND;
+        }
+        features
+    }
+
+    fn brsf_features(&self) -> u32 {
+        HF_FEATURE_EC_NR | HF_FEATURE_CLI | HF_FEATURE_REMOTE_VOLUME | HF_FEATURE_CODEC_NEGOTIATION
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CallState {
+    #[default]
+    Idle,
+    Incoming,
+    Outgoing,
+    Alerting,
+    Active,
+}
+
+impl CallState {
+    /// Derive the call state from the standard `call` and `callsetup` indicators.
+    fn from_indicators(indicators: &BTreeMap<String, u32>) -> Self {
+        match indicators.get("callsetup").copied().unwrap_or(0) {
+            1 => Self::Incoming,
+            2 => Self::Outgoing,
+            3 => Self::Alerting,
+            _ if indicators.get("call").copied().unwrap_or(0) == 1 => Self::Active,
+            _ => Self::Idle,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct HfpStatus {
+    pub connected: bool,
+    pub device: Option<String>,
+    /// Service level connection established (AT+CMER done).
+    pub slc: bool,
+    pub ag_features: u32,
+    pub codec: Option<String>,
+    pub call: CallState,
+    pub caller: Option<String>,
+    pub indicators: BTreeMap<String, u32>,
+    pub speaker_volume: u8,
+    pub mic_volume: u8,
+}
+
+/// Call control requests, e.g. `{"action":"dial","number":"+123"}`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum HfpCommand {
+    Answer,
+    Reject,
+    Hangup,
+    Dial {
+        number: String,
+    },
+    Redial,
+    SpeakerVolume {
+        level: u8,
+    },
+    MicVolume {
+        level: u8,
+    },
+    /// Ask the AG to set up the audio connection (AT+BCC).
+    AudioConnect,
+    /// Stop offering mSBC (AT+BAC=1) after a wideband link carried no frames.
+    #[serde(skip)]
+    NarrowbandOnly,
+}
+
+impl HfpCommand {
+    fn at_command(&self) -> Result<String> {
+        Ok(match self {
+            Self::Answer => "ATA".to_string(),
+            Self::Reject | Self::Hangup => "AT+CHUP".to_string(),
+            Self::Dial { number } => {
+                if number.is_empty()
+                    || !number
+                        .chars()
+                        .all(|c| c.is_ascii_digit() || matches!(c, '+' | '*' | '#'))
+                {
+                    return Err(format!("invalid number: {:?}", number).into());
+                }
+                format!("ATD{};", number)
+            }
+            Self::Redial => "AT+BLDN".to_string(),
+            Self::SpeakerVolume { level } => format!("AT+VGS={}", check_volume(*level)?),
+            Self::MicVolume { level } => format!("AT+VGM={}", check_volume(*level)?),
+            Self::AudioConnect => "AT+BCC".to_string(),
+            Self::NarrowbandOnly => "AT+BAC=1".to_string(),
+        })
+    }
+}
+
+fn check_volume(level: u8) -> Result<u8> {
+    if level > MAX_VOLUME {
+        return Err(format!("volume must be 0-{}", MAX_VOLUME).into());
+    }
+    Ok(level)
+}
+
+type CommandRequest = (HfpCommand, oneshot::Sender<Result<()>>);
+
+#[derive(Default)]
+struct Runtime {
+    status: HfpStatus,
+    session: u64,
+    commands: Option<mpsc::Sender<CommandRequest>>,
+    ws_event_tx: Option<BroadcastSender<ServerEvent>>,
+}
+
+static RUNTIME: OnceLock<Mutex<Runtime>> = OnceLock::new();
+static SESSION_ID: AtomicU64 = AtomicU64::new(0);
+
+fn runtime() -> &'static Mutex<Runtime> {
+    RUNTIME.get_or_init(|| Mutex::new(Runtime::default()))
+}
+
+pub fn status() -> HfpStatus {
+    runtime().lock().unwrap().status.clone()
+}
+
+/// Send a call control command to the connected phone and wait for its result.
+pub async fn send_command(command: HfpCommand) -> Result<()> {
+    let tx = runtime()
+        .lock()
+        .unwrap()
+        .commands
+        .clone()
+        .ok_or("no HFP service level connection")?;
+    let (reply_tx, reply_rx) = oneshot::channel();
+    tx.send((command, reply_tx))
+        .await
+        .map_err(|_| "HFP session closed")?;
+    reply_rx.await.map_err(|_| "HFP session closed")?
+}
+
+/// Renegotiate CVSD after a wideband SCO link carried no mSBC: offer CVSD
+/// only and ask the AG to set up the audio connection again. Returns false
+/// without an HFP service level connection.
+pub fn request_narrowband() -> bool {
+    let Some(tx) = runtime().lock().unwrap().commands.clone() else {
+        return false;
+    };
+    [HfpCommand::NarrowbandOnly, HfpCommand::AudioConnect]
+        .into_iter()
+        .all(|command| {
+            let (reply_tx, _) = oneshot::channel();
+            tx.try_send((command, reply_tx)).is_ok()
+        })
+}
+
+/// Answer or end the current call with the HU call keys. Returns true when the
+/// key was consumed, so it is not forwarded to the phone.
+pub fn handle_call_key(keycode: KeyCode, down: bool) -> bool {
+    let call = runtime().lock().unwrap().status.call;
+    let command = match (keycode, call) {
+        (_, CallState::Idle) => return false,
+        (KeyCode::KEYCODE_CALL, CallState::Incoming) => HfpCommand::Answer,
+        (KeyCode::KEYCODE_ENDCALL, CallState::Incoming) => HfpCommand::Reject,
+        (KeyCode::KEYCODE_ENDCALL, _) => HfpCommand::Hangup,
+        _ => return false,
+    };
+    // act on release, swallow the press
+    if !down {
+        info!("{} HU key {:?} -> {:?}", NAME, keycode, command);
+        tokio::spawn(async move {
+            if let Err(e) = send_command(command).await {
+                warn!("{} HU call key failed: {}", NAME, e);
+            }
+        });
+    }
+    true
+}
+
+/// Publish status changes and accept JSON commands on the `hfp-command` ws topic.
+pub async fn ws_listener(ws_event_tx: BroadcastSender<ServerEvent>) {
+    runtime().lock().unwrap().ws_event_tx = Some(ws_event_tx.clone());
+    let mut rx = ws_event_tx.subscribe();
+    loop {
+        let ev = match rx.recv().await {
+            Ok(ev) => ev,
+            Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+        };
+        if ev.topic != HFP_COMMAND_TOPIC {
+            continue;
+        }
+        match serde_json::from_str::<HfpCommand>(&ev.payload) {
+            Ok(command) => {
+                if let Err(e) = send_command(command).await {
+                    warn!("{} ws command failed: {}", NAME, e);
+                }
+            }
+            Err(e) => warn!("{} invalid ws command: {}", NAME, e),
+        }
+    }
+}
+
+/// Update the shared status of session `id` and publish it as `event`.
+fn update_status(id: u64, event: &str, f: impl FnOnce(&mut HfpStatus)) {
+    let mut runtime = runtime().lock().unwrap();
+    if runtime.session != id {
+        return;
+    }
+    f(&mut runtime.status);
+    runtime.status.call = CallState::from_indicators(&runtime.status.indicators);
+    if runtime.status.call == CallState::Idle {
+        runtime.status.caller = None;
+    }
+    if let Some(tx) = &runtime.ws_event_tx {
+        let payload = serde_json::json!({
+            "event": event,
+            "status": runtime.status,
+        });
+        let _ = tx.send(ServerEvent {
+            topic: HFP_TOPIC.to_string(),
+            payload: payload.to_string(),
+        });
+    }
+}
+
+/// Run the hands-free side of HFP on an accepted RFCOMM stream until the phone
+/// disconnects. Only the newest session is controllable.
+pub async fn run_session<S>(stream: S, device: String, options: HfpOptions)
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let id = SESSION_ID.fetch_add(1, Ordering::SeqCst) + 1;
+    let (tx, rx) = mpsc::channel(8);
+    {
+        let mut runtime = runtime().lock().unwrap();
+        runtime.session = id;
+        runtime.commands = Some(tx);
+        runtime.status = HfpStatus::default();
+    }
+    update_status(id, "connected", |status| {
+        status.connected = true;
+        status.device = Some(device.clone());
+    });
+    info!("{} 🎧 HFP session with <b>{}</> started", NAME, device);
+
+    let mut session = Session::new(stream, id, options);
+    let result = match session.establish_slc().await {
+        Ok(()) => session.serve(rx).await,
+        Err(e) => Err(e),
+    };
+    match result {
+        Ok(()) => info!("{} 🎧 HFP session with <b>{}</> closed", NAME, device),
+        Err(e) => warn!("{} 🎧 HFP session with <b>{}</> ended: {}", NAME, device, e),
+    }
+
+    update_status(id, "disconnected", |status| {
+        *status = HfpStatus::default();
+    });
+    let mut runtime = runtime().lock().unwrap();
+    if runtime.session == id {
+        runtime.commands = None;
+        bt_sco::set_negotiated_codec(None);
+    }
+}
+
+struct Session<S> {
+    stream: S,
+    id: u64,
+    options: HfpOptions,
+    buf: Vec<u8>,
+    indicator_names: Vec<String>,
+    codec_negotiation: bool,
+    pending_codec: Option<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Session<S> {
+    fn new(stream: S, id: u64, options: HfpOptions) -> Self {
+        Self {
+            stream,
+            id,
+            options,
+            buf: Vec::new(),
+            indicator_names: Vec::new(),
+            codec_negotiation: false,
+            pending_codec: None,
+        }
+    }
+
+    async fn establish_slc(&mut self) -> Result<()> {
+        let lines = self
+            .command(&format!("AT+BRSF={}", self.options.brsf_features()))
+            .await?;
+        let ag_features = lines
+            .iter()
+            .find_map(|line| response_value(line, "+BRSF"))
+            .and_then(|v| v.trim().parse::<u32>().ok())
+            .unwrap_or(0);
+        self.codec_negotiation = ag_features & AG_FEATURE_CODEC_NEGOTIATION != 0;
+        update_status(self.id, "features", |status| {
+            status.ag_features = ag_features
+        });
+
+        if self.codec_negotiation {
+            self.command(self.available_codecs()).await?;
+        } else {
+            bt_sco::set_negotiated_codec(Some(ScoVoiceCodec::Cvsd));
+            update_status(self.id, "codec", |status| {
+                status.codec = Some("cvsd".to_string())
+            });
+        }
+
+        let lines = self.command("AT+CIND=?").await?;
+        self.indicator_names = lines
+            .iter()
+            .find_map(|line| response_value(line, "+CIND"))
+            .map(parse_indicator_names)
+            .unwrap_or_default();
+        let lines = self.command("AT+CIND?").await?;
+        let values = lines
+            .iter()
+            .find_map(|line| response_value(line, "+CIND"))
+            .map(parse_indicator_values)
+            .unwrap_or_default();
+        let indicators: BTreeMap<String, u32> =
+            self.indicator_names.iter().cloned().zip(values).collect();
+
+        self.command("AT+CMER=3,0,0,1").await?;
+        update_status(self.id, "slc", |status| {
+            status.slc = true;
+            status.indicators = indicators;
+        });
+        info!(
+            "{} service level connection established, ag_features=0x{:x}, codec_negotiation={}",
+            NAME, ag_features, self.codec_negotiation
+        );
+
+        // optional after SLC, an AG rejecting them is not fatal
+        for at in [
+            "AT+CLIP=1".to_string(),
+            format!("AT+VGS={}", MAX_VOLUME),
+            format!("AT+VGM={}", MAX_VOLUME),
+        ] {
+            if let Err(e) = self.command(&at).await {
+                debug!("{} {} rejected: {}", NAME, at, e);
+            }
+        }
+        update_status(self.id, "volume", |status| {
+            status.speaker_volume = MAX_VOLUME;
+            status.mic_volume = MAX_VOLUME;
+        });
+        self.confirm_pending_codec().await
+    }
+
+    fn available_codecs(&self) -> &'static str {
+        if self.options.wideband {
+            "AT+BAC=1,2"
+        } else {
+            "AT+BAC=1"
+        }
+    }
+
+    async fn serve(&mut self, mut commands: mpsc::Receiver<CommandRequest>) -> Result<()> {
+        loop {
+            tokio::select! {
+                line = self.next_line() => {
+                    let line = line?;
+                    self.handle_unsolicited(&line);
+                }
+                request = commands.recv() => {
+                    let Some((command, reply)) = request else {
+                        return Ok(());
+                    };
+                    let result = match command.at_command() {
+                        Ok(at) => self.command(&at).await.map(|_| ()),
+                        Err(e) => Err(e),
+                    };
+                    if result.is_ok() {
+                        self.apply_command(&command);
+                    }
+                    let _ = reply.send(result);
+                }
+            }
+            self.confirm_pending_codec().await?;
+        }
+    }
+
+    /// Reflect locally initiated volume and codec changes in the session.
+    fn apply_command(&mut self, command: &HfpCommand) {
+        match command {
+            HfpCommand::SpeakerVolume { level } => {
+                update_status(self.id, "volume", |status| status.speaker_volume = *level)
+            }
+            HfpCommand::MicVolume { level } => {
+                update_status(self.id, "volume", |status| status.mic_volume = *level)
+            }
+            HfpCommand::NarrowbandOnly => self.options.wideband = false,
+            _ => {}
+        }
+    }
+
+    /// Answer a `+BCS` codec selection from the AG.
+    async fn confirm_pending_codec(&mut self) -> Result<()> {
+        let Some(codec) = self.pending_codec.take() else {
+            return Ok(());
+        };
+        let selected = match codec {
+            CODEC_ID_CVSD => Some(ScoVoiceCodec::Cvsd),
+            CODEC_ID_MSBC if self.options.wideband => Some(ScoVoiceCodec::Msbc),
+            _ => None,
+        };
+        let Some(selected) = selected else {
+            // unsupported codec, tell the AG what we can do so it selects again
+            warn!("{} AG selected unsupported codec {}", NAME, codec);
+            self.command(self.available_codecs()).await?;
+            return Ok(());
+        };
+
+        self.command(&format!("AT+BCS={}", codec)).await?;
+        bt_sco::set_negotiated_codec(Some(selected));
+        info!("{} codec negotiated: {:?}", NAME, selected);
+        update_status(self.id, "codec", |status| {
+            status.codec = Some(
+                match selected {
+                    ScoVoiceCodec::Cvsd => "cvsd",
+                    ScoVoiceCodec::Msbc => "msbc",
+                }
+                .to_string(),
+            )
+        });
+        Ok(())
+    }
+
+    fn handle_unsolicited(&mut self, line: &str) {
+        if line == "RING" {
+            update_status(self.id, "ring", |_| {});
+        } else if let Some(value) = response_value(line, "+CIEV") {
+            let mut parts = value.split(',').map(|v| v.trim().parse::<u32>());
+            let (Some(Ok(index)), Some(Ok(value))) = (parts.next(), parts.next()) else {
+                debug!("{} malformed indicator: {}", NAME, line);
+                return;
+            };
+            let Some(name) = index
+                .checked_sub(1)
+                .and_then(|i| self.indicator_names.get(i as usize))
+                .cloned()
+            else {
+                debug!("{} unknown indicator index {}", NAME, index);
+                return;
+            };
+            debug!("{} indicator {}={}", NAME, name, value);
+            update_status(self.id, "indicator", |status| {
+                status.indicators.insert(name, value);
+            });
+        } else if let Some(value) = response_value(line, "+CLIP") {
+            let number = value
+                .split(',')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .trim_matches('"')
+                .to_string();
+            update_status(self.id, "caller", |status| status.caller = Some(number));
+        } else if let Some(value) = response_value(line, "+BCS") {
+            self.pending_codec = value.trim().parse().ok();
+        } else if let Some(value) = response_value(line, "+VGS") {
+            if let Ok(level) = value.trim().parse::<u8>() {
+                update_status(self.id, "volume", |status| {
+                    status.speaker_volume = level.min(MAX_VOLUME)
+                });
+            }
+        } else if let Some(value) = response_value(line, "+VGM") {
+            if let Ok(level) = value.trim().parse::<u8>() {
+                update_status(self.id, "volume", |status| {
+                    status.mic_volume = level.min(MAX_VOLUME)
+                });
+            }
+        } else {
+            debug!("{} ignoring: {}", NAME, line);
+        }
+    }
+
+    /// Send an AT command and collect its response lines up to the final
+    /// result code. Unsolicited results arriving meanwhile are handled.
+    async fn command(&mut self, at: &str) -> Result<Vec<String>> {
+        debug!("{} -> {}", NAME, at);
+        self.stream
+            .write_all(format!("{}\r", at).as_bytes())
+            .await?;
+        self.stream.flush().await?;
+
+        let mut lines = Vec::new();
+        loop {
+            let line = timeout(AT_RESPONSE_TIMEOUT, self.next_line())
+                .await
+                .map_err(|_| format!("{}: no response", at))??;
+            match line.as_str() {
+                "OK" => return Ok(lines),
+                "ERROR" => return Err(format!("{}: ERROR", at).into()),
+                _ if line.starts_with("+CME ERROR") => {
+                    return Err(format!("{}: {}", at, line).into())
+                }
+                _ if is_unsolicited(&line) => self.handle_unsolicited(&line),
+                _ => lines.push(line),
+            }
+        }
+    }
+
+    /// Next non-empty line from the AG. Cancel safe: bytes are only consumed
+    /// once a full line is buffered.
+    async fn next_line(&mut self) -> Result<String> {
+        loop {
+            if let Some(pos) = self.buf.iter().position(|b| *b == b'\r' || *b == b'\n') {
+                let line: Vec<u8> = self.buf.drain(..=pos).collect();
+                let line = String::from_utf8_lossy(&line).trim().to_string();
+                if line.is_empty() {
+                    continue;
+                }
+                debug!("{} <- {}", NAME, line);
+                return Ok(line);
+            }
+
+            let mut chunk = [0u8; 256];
+            let n = self.stream.read(&mut chunk).await?;
+            if n == 0 {
+                return Err("RFCOMM stream closed".into());
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+/// Value of a `+NAME: value` result line (some AGs use `=` for volume).
+fn response_value<'a>(line: &'a str, name: &str) -> Option<&'a str> {
+    let rest = line.strip_prefix(name)?;
+    rest.strip_prefix(':')
+        .or_else(|| rest.strip_prefix('='))
+        .map(str::trim)
+}
+
+fn is_unsolicited(line: &str) -> bool {
+    line == "RING"
+        || ["+CIEV", "+CLIP", "+BCS", "+VGS", "+VGM"]
+            .iter()
+            .any(|name| response_value(line, name).is_some())
+}
+
+/// Indicator names from `+CIND: ("service",(0,1)),("call",(0,1)),...`.
+fn parse_indicator_names(value: &str) -> Vec<String> {
+    value
+        .split('"')
+        .skip(1)
+        .step_by(2)
+        .map(str::to_string)
+        .collect()
+}
+
+/// Indicator values from `+CIND: 1,0,0,3,0,4,0`.
+fn parse_indicator_values(value: &str) -> Vec<u32> {
+    value
+        .split(',')
+        .map(|v| v.trim().parse().unwrap_or(0))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_indicator_names_and_values() {
+        let names = parse_indicator_names(
+            r#"("service",(0,1)),("call",(0,1)),("callsetup",(0-3)),("battchg",(0-5))"#,
+        );
+        assert_eq!(names, vec!["service", "call", "callsetup", "battchg"]);
+        assert_eq!(parse_indicator_values("1, 0,2,5"), vec![1, 0, 2, 5]);
+    }
+
+    #[test]
+    fn call_state_follows_call_and_callsetup() {
+        let mut indicators = BTreeMap::new();
+        assert_eq!(CallState::from_indicators(&indicators), CallState::Idle);
+        indicators.insert("callsetup".to_string(), 1);
+        assert_eq!(CallState::from_indicators(&indicators), CallState::Incoming);
+        indicators.insert("callsetup".to_string(), 0);
+        indicators.insert("call".to_string(), 1);
+        assert_eq!(CallState::from_indicators(&indicators), CallState::Active);
+        indicators.insert("callsetup".to_string(), 3);
+        assert_eq!(CallState::from_indicators(&indicators), CallState::Alerting);
+    }
+
+    #[test]
+    fn result_lines_are_classified() {
+        assert_eq!(response_value("+BRSF: 871", "+BRSF"), Some("871"));
+        assert_eq!(response_value("+VGS=7", "+VGS"), Some("7"));
+        assert_eq!(response_value("+BRSFX: 1", "+BRSF"), None);
+        assert!(is_unsolicited("+CIEV: 2,1"));
+        assert!(is_unsolicited("RING"));
+        assert!(!is_unsolicited("+CIND: 1,0"));
+    }
+
+    #[test]
+    fn commands_map_to_at() {
+        let dial = HfpCommand::Dial {
+            number: "+4912#".to_string(),
+        };
+        assert_eq!(dial.at_command().unwrap(), "ATD+4912#;");
+        assert!(HfpCommand::Dial {
+            number: "1;ATH".to_string()
+        }
+        .at_command()
+        .is_err());
+        assert_eq!(HfpCommand::Reject.at_command().unwrap(), "AT+CHUP");
+        assert!(HfpCommand::SpeakerVolume { level: 16 }
+            .at_command()
+            .is_err());
+    }
+
+    #[test]
+    fn narrowband_fallback_stops_offering_msbc() {
+        let (hf, _ag) = tokio::io::duplex(64);
+        let mut session = Session::new(hf, 0, HfpOptions { wideband: true });
+        assert_eq!(session.available_codecs(), "AT+BAC=1,2");
+
+        let command = HfpCommand::NarrowbandOnly;
+        assert_eq!(command.at_command().unwrap(), "AT+BAC=1");
+        session.apply_command(&command);
+        assert_eq!(session.available_codecs(), "AT+BAC=1");
+        assert!(serde_json::from_str::<HfpCommand>(r#"{"action":"narrowband_only"}"#).is_err());
+    }
+
+    #[tokio::test]
+    async fn establishes_slc_and_negotiates_msbc() {
+        let (hf, mut ag) = tokio::io::duplex(1024);
+        let mut session = Session::new(hf, 0, HfpOptions { wideband: true });
+        let ag_task = tokio::spawn(async move {
+            let mut buf = Vec::new();
+            let mut chunk = [0u8; 256];
+            let mut commands = Vec::new();
+            while commands.len() < 9 {
+                let n = ag.read(&mut chunk).await.unwrap();
+                buf.extend_from_slice(&chunk[..n]);
+                while let Some(pos) = buf.iter().position(|b| *b == b'\r') {
+                    let at: String = String::from_utf8_lossy(&buf[..pos]).to_string();
+                    buf.drain(..=pos);
+                    let reply = match at.as_str() {
+                        "AT+BRSF=149" => "\r\n+BRSF: 871\r\n\r\nOK\r\n",
+                        "AT+CIND=?" => {
+                            "\r\n+CIND: (\"call\",(0,1)),(\"callsetup\",(0-3))\r\n\r\nOK\r\n"
+                        }
+                        "AT+CIND?" => "\r\n+CIND: 0,1\r\n\r\nOK\r\n",
+                        "AT+VGM=15" => "\r\n+BCS: 2\r\n\r\nOK\r\n",
+                        _ => "\r\nOK\r\n",
+                    };
+                    ag.write_all(reply.as_bytes()).await.unwrap();
+                    commands.push(at);
+                }
+            }
+            commands
+        });
+
+        session.establish_slc().await.unwrap();
+        let commands = ag_task.await.unwrap();
+        assert_eq!(
+            commands,
+            vec![
+                "AT+BRSF=149",
+                "AT+BAC=1,2",
+                "AT+CIND=?",
+                "AT+CIND?",
+                "AT+CMER=3,0,0,1",
+                "AT+CLIP=1",
+                "AT+VGS=15",
+                "AT+VGM=15",
+                "AT+BCS=2",
+            ]
+        );
+        assert_eq!(session.indicator_names, vec!["call", "callsetup"]);
+        assert!(session.codec_negotiation);
+    }
+}
@@ -0,0 +1,304 @@
+use crate::wpa_supplicant::WpaCtrl;
+use serde::Serialize;
+use simplelog::*;
+use std::collections::HashMap;
+use std::sync::OnceLock;
+
+// module name for logging engine
+const NAME: &str = "<i><bright-black> hostapd: </>";
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Beacons announcing the new channel before hostapd switches.
+pub const DEFAULT_CS_COUNT: u8 = 5;
+
+/// `(ctrl_interface directory, interface)` of the running hostapd.
+static CTRL: OnceLock<(String, String)> = OnceLock::new();
+
+/// Remember where hostapd's control socket lives. Changing it requires a
+/// restart, like the hostapd config itself.
+pub fn init(ctrl_dir: &str, iface: &str) {
+    let _ = CTRL.set((ctrl_dir.to_string(), iface.to_string()));
+}
+
+fn open() -> Result<WpaCtrl> {
+    let (ctrl_dir, iface) = CTRL
+        .get()
+        .ok_or("hostapd control interface not initialized")?;
+    WpaCtrl::open(ctrl_dir, iface)
+}
+
+/// A client associated with our AP, from `STA`/`STA-FIRST`/`STA-NEXT`.
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct AssociatedStation {
+    pub mac: String,
+    pub signal_dbm: Option<i32>,
+    pub avg_signal_dbm: Option<i32>,
+    pub rx_rate_mbps: Option<f64>,
+    pub tx_rate_mbps: Option<f64>,
+    pub connected_secs: Option<u64>,
+    pub inactive_ms: Option<u64>,
+    pub rx_bytes: Option<u64>,
+    pub tx_bytes: Option<u64>,
+    /// Driver totals since association, when it reports them.
+    pub tx_retry_count: Option<u64>,
+    pub tx_retry_failed: Option<u64>,
+    /// `wpa_psk_file` key id the station authenticated with.
+    pub keyid: Option<String>,
+}
+
+impl AssociatedStation {
+    /// `None` for the empty or `FAIL` reply after the last station.
+    fn parse(reply: &str) -> Option<Self> {
+        let mut lines = reply.lines();
+        let mac = lines.next()?.trim();
+        if mac.parse::<bluer::Address>().is_err() {
+            return None;
+        }
+        let fields: HashMap<&str, &str> = lines.filter_map(|l| l.split_once('=')).collect();
+        let number = |key: &str| fields.get(key).and_then(|v| v.trim().parse().ok());
+        // "<rate in 100 kbps> [vhtmcs 9 vhtnss 2 ...]"
+        let rate = |key: &str| {
+            fields
+                .get(key)
+                .and_then(|v| v.split_whitespace().next())
+                .and_then(|v| v.parse::<u32>().ok())
+                .map(|v| v as f64 / 10.0)
+        };
+        Some(Self {
+            mac: mac.to_string(),
+            signal_dbm: fields.get("signal").and_then(|v| v.trim().parse().ok()),
+            avg_signal_dbm: fields.get("avg_signal").and_then(|v| v.trim().parse().ok()),
+            rx_rate_mbps: rate("rx_rate_info"),
+            tx_rate_mbps: rate("tx_rate_info"),
+            connected_secs: number("connected_time"),
+            inactive_ms: number("inactive_msec"),
+            rx_bytes: number("rx_bytes"),
+            tx_bytes: number("tx_bytes"),
+            tx_retry_count: number("tx_retry_count"),
+            tx_retry_failed: number("tx_retry_failed"),
+            keyid: fields.get("keyid").map(|v| v.trim().to_string()),
+        })
+    }
+}
+
+/// All stations currently associated with the AP.
+pub async fn stations() -> Result<Vec<AssociatedStation>> {
+    let ctrl = open()?;
+    let mut stations: Vec<AssociatedStation> = Vec::new();
+    let mut reply = ctrl.request("STA-FIRST").await?;
+    while let Some(station) = AssociatedStation::parse(&reply) {
+        reply = ctrl.request(&format!("STA-NEXT {}", station.mac)).await?;
+        stations.push(station);
+    }
+    Ok(stations)
+}
+
+/// The associated station `mac`, `None` when it is not associated.
+pub async fn station(mac: &str) -> Result<Option<AssociatedStation>> {
+    let reply = open()?.request(&format!("STA {}", mac)).await?;
+    Ok(AssociatedStation::parse(&reply))
+}
+
+pub async fn deauthenticate(mac: &str) -> Result<()> {
+    open()?.ok(&format!("DEAUTHENTICATE {}", mac)).await
+}
+
+pub async fn disassociate(mac: &str) -> Result<()> {
+    open()?.ok(&format!("DISASSOCIATE {}", mac)).await
+}
+
+/// Deauthenticate the stations that joined with the `wpa_psk_file` entry
+/// `keyid`, returns how many were kicked.
+pub async fn deauthenticate_keyid(keyid: &str) -> Result<usize> {
+    let stations = stations().await?;
+    let ctrl = open()?;
+    let mut kicked = 0;
+    for station in stations
+        .iter()
+        .filter(|s| s.keyid.as_deref() == Some(keyid))
+    {
+        ctrl.ok(&format!("DEAUTHENTICATE {}", station.mac)).await?;
+        kicked += 1;
+    }
+    Ok(kicked)
+}
+
+/// Deauthenticate every associated station, returns how many were kicked.
+pub async fn deauthenticate_all() -> Result<usize> {
+    let stations = stations().await?;
+    let ctrl = open()?;
+    for station in &stations {
+        ctrl.ok(&format!("DEAUTHENTICATE {}", station.mac)).await?;
+    }
+    Ok(stations.len())
+}
+
+/// Center frequency in MHz of `channel` on `band` (`2.4`, `5` or `6`).
+fn channel_freq(band: &str, channel: u8) -> Option<u32> {
+    let ch = channel as u32;
+    match band {
+        "2.4" => match ch {
+            1..=13 => Some(2407 + 5 * ch),
+            14 => Some(2484),
+            _ => None,
+        },
+        "5" => (32..=177).contains(&ch).then_some(5000 + 5 * ch),
+        "6" => (1..=233).contains(&ch).then_some(5950 + 5 * ch),
+        _ => None,
+    }
+}
+
+/// `CHAN_SWITCH` command moving the AP to `channel` with `bandwidth` MHz.
+/// The HT/VHT/HE flags follow `wifi_version` so the new channel keeps the
+/// current PHY mode.
+pub fn chan_switch_command(
+    band: &str,
+    channel: u8,
+    bandwidth: u16,
+    wifi_version: u16,
+    cs_count: u8,
+) -> Result<String> {
+    let freq = channel_freq(band, channel)
+        .ok_or_else(|| format!("channel {} is not valid on the {} GHz band", channel, band))?;
+    let ch = channel as u32;
+    let mut cmd = format!("CHAN_SWITCH {} {}", cs_count, freq);
+    match (band, bandwidth) {
+        (_, 20) => {}
+        ("2.4", 40) => {
+            // upper secondary channel for the lower half of the band
+            let offset: i32 = if ch <= 7 { 1 } else { -1 };
+            let center = freq as i32 + offset * 10;
+            cmd.push_str(&format!(
+                " sec_channel_offset={} center_freq1={} bandwidth=40",
+                offset, center
+            ));
+        }
+        ("5", 40) | ("5", 80) => {
+            // 5 GHz channels are bonded in fixed blocks, the second UNII-3
+            // block starts at 149 instead of following on from 144
+            let base = if ch >= 149 { 149 } else { 36 };
+            if ch < base || !(ch - base).is_multiple_of(4) {
+                return Err(format!("channel {} cannot be bonded", channel).into());
+            }
+            let block = bandwidth as u32 / 5;
+            let first = base + (ch - base) / block * block;
+            let offset: i32 = if ((ch - base) / 4).is_multiple_of(2) {
+                1
+            } else {
+                -1
+            };
+            let center = 5000 + 5 * (first + block / 2 - 2);
+            cmd.push_str(&format!(
+                " sec_channel_offset={} center_freq1={} bandwidth={}",
+                offset, center, bandwidth
+            ));
+        }
+        _ => {
+            return Err(format!(
+                "{} MHz is not supported on the {} GHz band",
+                bandwidth, band
+            )
+            .into())
+        }
+    }
+    if wifi_version >= 4 {
+        cmd.push_str(" ht");
+    }
+    if wifi_version >= 5 && band == "5" {
+        cmd.push_str(" vht");
+    }
+    if wifi_version >= 6 {
+        cmd.push_str(" he");
+    }
+    Ok(cmd)
+}
+
+/// Move the AP to another channel with a channel switch announcement, so
+/// associated phones follow instead of reconnecting.
+pub async fn switch_channel(
+    band: &str,
+    channel: u8,
+    bandwidth: u16,
+    wifi_version: u16,
+    cs_count: u8,
+) -> Result<()> {
+    let cmd = chan_switch_command(band, channel, bandwidth, wifi_version, cs_count)?;
+    open()?.ok(&cmd).await?;
+    info!(
+        "{} 📡 switching to channel {} ({} MHz) in {} beacons",
+        NAME, channel, bandwidth, cs_count
+    );
+    Ok(())
+}
+
+/// Apply a new SSID and/or shared passphrase to the running AP. Associated
+/// stations are dropped by the reload and have to rejoin.
+pub async fn reload_credentials(ssid: Option<&str>, passphrase: Option<&str>) -> Result<()> {
+    let ctrl = open()?;
+    if let Some(ssid) = ssid {
+        // hex form, the SSID may contain anything but control characters
+        ctrl.ok(&format!("SET ssid2 {}", hex::encode(ssid.as_bytes())))
+            .await?;
+    }
+    if let Some(passphrase) = passphrase {
+        ctrl.ok(&format!("SET wpa_passphrase {}", passphrase))
+            .await?;
+    }
+    ctrl.ok("RELOAD").await?;
+    info!("{} 🔐 reloaded AP credentials", NAME);
+    Ok(())
+}
+
+/// Make hostapd re-read `wpa_psk_file`.
+pub async fn reload_wpa_psk() -> Result<()> {
+    open()?.ok("RELOAD_WPA_PSK").await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sta_reply() {
+        let station = AssociatedStation::parse(
+            "aa:bb:cc:dd:ee:ff\nflags=[AUTH][ASSOC][AUTHORIZED]\naid=1\n\
+             rx_bytes=123456\ntx_bytes=654321\ninactive_msec=40\nsignal=-52\n\
+             rx_rate_info=8667 vhtmcs 9 vhtnss 2 shortGI\ntx_rate_info=65\n\
+             connected_time=93\ntx_retry_count=12\ntx_retry_failed=1\n\
+             keyid=AABBCCDDEE01\n",
+        )
+        .unwrap();
+        assert_eq!(station.mac, "aa:bb:cc:dd:ee:ff");
+        assert_eq!(station.signal_dbm, Some(-52));
+        assert_eq!(station.rx_rate_mbps, Some(866.7));
+        assert_eq!(station.tx_rate_mbps, Some(6.5));
+        assert_eq!(station.connected_secs, Some(93));
+        assert_eq!(station.rx_bytes, Some(123456));
+        assert_eq!(station.tx_retry_count, Some(12));
+        assert_eq!(station.tx_retry_failed, Some(1));
+        assert_eq!(station.avg_signal_dbm, None);
+        assert_eq!(station.keyid.as_deref(), Some("AABBCCDDEE01"));
+
+        assert_eq!(AssociatedStation::parse(""), None);
+        assert_eq!(AssociatedStation::parse("FAIL\n"), None);
+    }
+
+    #[test]
+    fn builds_chan_switch_commands() {
+        assert_eq!(
+            chan_switch_command("2.4", 6, 20, 4, 5).unwrap(),
+            "CHAN_SWITCH 5 2437 ht"
+        );
+        assert_eq!(
+            chan_switch_command("5", 44, 80, 5, 3).unwrap(),
+            "CHAN_SWITCH 3 5220 sec_channel_offset=1 center_freq1=5210 bandwidth=80 ht vht"
+        );
+        assert_eq!(
+            chan_switch_command("5", 153, 40, 5, 5).unwrap(),
+            "CHAN_SWITCH 5 5765 sec_channel_offset=-1 center_freq1=5755 bandwidth=40 ht vht"
+        );
+        assert!(chan_switch_command("5", 14, 20, 5, 5).is_err());
+        assert!(chan_switch_command("6", 37, 80, 6, 5).is_err());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::hfp;
 use crate::mitm::protos::KeyCode::{self, *};
 use crate::mitm::protos::{InputMessageId, InputReport};
 use crate::mitm::send_key_event;
@@ -140,6 +141,42 @@ pub async fn handle_hu_input(
     PacketAction::Forward
 }
 
+/// Routes the HU call / end-call keys to the HFP session while a call is in
+/// progress, so calls can be answered or ended without the phone's AA UI.
+///
+/// Returns [`PacketAction::Drop`] when the key was consumed by HFP.
+pub fn handle_hfp_call_keys(pkt: &Packet) -> PacketAction {
+    let message_id: i32 = match pkt.payload.get(0..2) {
+        Some(bytes) => u16::from_be_bytes(bytes.try_into().unwrap()) as i32,
+        None => return PacketAction::Forward,
+    };
+
+    if InputMessageId::from_i32(message_id) != Some(InputMessageId::INPUT_MESSAGE_INPUT_REPORT) {
+        return PacketAction::Forward;
+    }
+
+    let report = match InputReport::parse_from_bytes(&pkt.payload[2..]) {
+        Ok(r) => r,
+        Err(_) => return PacketAction::Forward,
+    };
+
+    let key = match report.key_event.as_ref().and_then(|ke| ke.keys.first()) {
+        Some(key) => key,
+        None => return PacketAction::Forward,
+    };
+
+    match key.keycode.and_then(|c| KeyCode::from_i32(c as i32)) {
+        Some(k @ (KEYCODE_CALL | KEYCODE_ENDCALL)) => {
+            if hfp::handle_call_key(k, key.down.unwrap_or(false)) {
+                PacketAction::Drop
+            } else {
+                PacketAction::Forward
+            }
+        }
+        _ => PacketAction::Forward,
+    }
+}
+
 /// Spawns the configured handler script with the key code and elapsed time as arguments.
 ///
 /// The command string is split on whitespace (shell-word rules), so arguments
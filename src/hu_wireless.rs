@@ -0,0 +1,322 @@
+use crate::bluetooth::{MessageId, AAWG_PROFILE_UUID};
+use crate::wpa_supplicant::{self, StationOptions};
+use bluer::rfcomm::{Profile, ProfileHandle, Role, Stream};
+use bluer::Address;
+use futures::StreamExt;
+use simplelog::*;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+
+include!(concat!(env!("OUT_DIR"), "/protos/mod.rs"));
+use protobuf::Message;
+
+// module name for logging engine
+const NAME: &str = "<i><bright-black> hu-wireless: </>";
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+const HEADER_LEN: usize = 4;
+/// Upper bound for each bootstrap frame from the head unit.
+const FRAME_TIMEOUT: Duration = Duration::from_secs(10);
+/// Joining the car's AP including DHCP.
+const WIFI_JOIN_TIMEOUT: Duration = Duration::from_secs(30);
+const STATUS_SUCCESS: i32 = 0;
+const STATUS_FAILED: i32 = -1;
+
+/// Where the head unit wants the phone: its AP and the AA TCP endpoint.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HuWifiInfo {
+    pub ip_address: String,
+    pub port: i32,
+    pub ssid: String,
+    pub key: String,
+    pub bssid: String,
+}
+
+/// How to reach a wireless head unit as the phone (MD role).
+#[derive(Debug, Clone)]
+pub struct WirelessHuOptions {
+    /// Bluetooth address of the (paired) head unit.
+    pub bt_addr: Option<Address>,
+    /// Skip the Bluetooth/Wi-Fi bootstrap and connect here directly, e.g. a
+    /// local stand-in for the head unit.
+    pub tcp_addr: Option<SocketAddr>,
+    pub bt_connect_timeout: Duration,
+    pub wpa_supplicant_ctrl_dir: String,
+    pub iface: String,
+}
+
+/// Keeps the RFCOMM link of the bootstrap open for the whole session, some
+/// head units end the wireless session when it goes away.
+pub struct HuLink {
+    _session: bluer::Session,
+    _handle: ProfileHandle,
+    stream: Stream,
+}
+
+async fn write_frame<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    id: MessageId,
+    payload: &[u8],
+) -> Result<()> {
+    let mut packet = Vec::with_capacity(HEADER_LEN + payload.len());
+    packet.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    packet.extend_from_slice(&(id.clone() as u16).to_be_bytes());
+    packet.extend_from_slice(payload);
+    debug!("{} 📨 sending {:?} to head unit", NAME, id);
+    stream.write_all(&packet).await?;
+    Ok(())
+}
+
+/// Read frames until one with `id` arrives, skipping anything else the head
+/// unit sends first (e.g. a version request).
+async fn expect_frame<S: AsyncRead + Unpin>(stream: &mut S, id: MessageId) -> Result<Vec<u8>> {
+    loop {
+        let mut header = [0u8; HEADER_LEN];
+        timeout(FRAME_TIMEOUT, stream.read_exact(&mut header))
+            .await
+            .map_err(|_| format!("no {:?} from head unit", id))??;
+        let len = u16::from_be_bytes([header[0], header[1]]) as usize;
+        let message_id = u16::from_be_bytes([header[2], header[3]]);
+        let mut payload = vec![0u8; len];
+        stream.read_exact(&mut payload).await?;
+        if message_id == id.clone() as u16 {
+            debug!("{} 📨 received {:?} from head unit", NAME, id);
+            return Ok(payload);
+        }
+        debug!(
+            "{} ignoring message {} ({} bytes) while waiting for {:?}",
+            NAME, message_id, len, id
+        );
+    }
+}
+
+/// First half of the bootstrap, as the phone: take the head unit's TCP
+/// endpoint and ask for its AP credentials.
+pub async fn request_wifi_info<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+) -> Result<HuWifiInfo> {
+    use WifiInfoResponse::WifiInfoResponse;
+    use WifiStartRequest::WifiStartRequest;
+
+    let payload = expect_frame(stream, MessageId::WifiStartRequest).await?;
+    let start = WifiStartRequest::parse_from_bytes(&payload)?;
+    write_frame(stream, MessageId::WifiInfoRequest, &[]).await?;
+    let payload = expect_frame(stream, MessageId::WifiInfoResponse).await?;
+    let info = WifiInfoResponse::parse_from_bytes(&payload)?;
+    Ok(HuWifiInfo {
+        ip_address: start.ip_address().to_string(),
+        port: start.port(),
+        ssid: info.ssid().to_string(),
+        key: info.key().to_string(),
+        bssid: info.bssid().to_string(),
+    })
+}
+
+/// Second half: tell the head unit whether we joined its AP.
+pub async fn report_connected<S: AsyncWrite + Unpin>(
+    stream: &mut S,
+    local_ip: &str,
+    port: i32,
+    joined: bool,
+) -> Result<()> {
+    use WifiConnectStatus::WifiConnectStatus;
+    use WifiStartResponse::WifiStartResponse;
+
+    let status = if joined {
+        STATUS_SUCCESS
+    } else {
+        STATUS_FAILED
+    };
+    let mut response = WifiStartResponse::new();
+    response.set_ip_address(local_ip.to_string());
+    response.set_port(port);
+    response.set_status(status);
+    write_frame(
+        stream,
+        MessageId::WifiStartResponse,
+        &response.write_to_bytes()?,
+    )
+    .await?;
+    let mut connect_status = WifiConnectStatus::new();
+    connect_status.set_status(status);
+    write_frame(
+        stream,
+        MessageId::WifiConnectStatus,
+        &connect_status.write_to_bytes()?,
+    )
+    .await
+}
+
+/// Offer the AA Wireless RFCOMM service like a phone does and accept the
+/// head unit's connection to it.
+async fn rfcomm_accept(addr: Address, connect_timeout: Duration) -> Result<HuLink> {
+    let session = bluer::Session::new().await?;
+    let adapter = session.default_adapter().await?;
+    adapter.set_powered(true).await?;
+    let profile = Profile {
+        uuid: AAWG_PROFILE_UUID,
+        name: Some("AA Wireless".to_string()),
+        role: Some(Role::Server),
+        require_authentication: Some(false),
+        require_authorization: Some(false),
+        ..Default::default()
+    };
+    let mut handle = session.register_profile(profile).await?;
+
+    let device = adapter.device(addr)?;
+    if !device.is_paired().await? {
+        return Err(format!("head unit {} is not paired", addr).into());
+    }
+    info!("{} 🧲 waiting for head unit {}...", NAME, addr);
+    // bring the link up so the head unit looks for our service, it may
+    // already be connecting by itself
+    if let Err(e) = device.connect().await {
+        debug!("{} connecting to {} failed: {}", NAME, addr, e);
+    }
+    let accept = async {
+        loop {
+            let req = handle
+                .next()
+                .await
+                .ok_or("profile handle closed before the head unit connected")?;
+            if req.device() != addr {
+                // dropping the request rejects it
+                info!(
+                    "{} ignoring AA Wireless connection from {}",
+                    NAME,
+                    req.device()
+                );
+                continue;
+            }
+            break Result::<Stream>::Ok(req.accept()?);
+        }
+    };
+    let stream = timeout(connect_timeout, accept)
+        .await
+        .map_err(|_| format!("head unit {} did not connect", addr))??;
+    info!("{} 🔗 RFCOMM connected to head unit {}", NAME, addr);
+    Ok(HuLink {
+        _session: session,
+        _handle: handle,
+        stream,
+    })
+}
+
+/// Do what a phone does to start wireless Android Auto and return the head
+/// unit's TCP endpoint. The returned link must be kept for the session.
+pub async fn connect(options: &WirelessHuOptions) -> Result<(SocketAddr, Option<HuLink>)> {
+    if let Some(addr) = options.tcp_addr {
+        info!(
+            "{} 🛰️ skipping Bluetooth/Wi-Fi bootstrap, head unit at <u>{}</u>",
+            NAME, addr
+        );
+        return Ok((addr, None));
+    }
+    let bt_addr = options.bt_addr.ok_or("wireless_hu_bt_addr is not set")?;
+
+    let mut link = rfcomm_accept(bt_addr, options.bt_connect_timeout).await?;
+    let info = request_wifi_info(&mut link.stream).await?;
+    info!(
+        "{} 🛜 head unit AP <b>{}</> ({}), AA endpoint {}:{}",
+        NAME, info.ssid, info.bssid, info.ip_address, info.port
+    );
+
+    let joined = wpa_supplicant::connect(&StationOptions {
+        ctrl_dir: options.wpa_supplicant_ctrl_dir.clone(),
+        iface: options.iface.clone(),
+        ssid: info.ssid.clone(),
+        passphrase: info.key.clone(),
+        timeout: WIFI_JOIN_TIMEOUT,
+    })
+    .await;
+    let local_ip = joined
+        .as_ref()
+        .ok()
+        .and_then(|status| status.ip_address.clone())
+        .unwrap_or_default();
+    report_connected(&mut link.stream, &local_ip, info.port, joined.is_ok()).await?;
+    joined.map_err(|e| format!("cannot join head unit AP {}: {}", info.ssid, e))?;
+
+    let addr: SocketAddr = format!("{}:{}", info.ip_address, info.port)
+        .parse()
+        .map_err(|e| format!("invalid head unit endpoint {}: {}", info.ip_address, e))?;
+    Ok((addr, Some(link)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Plays the head unit side of the bootstrap over an in-memory stream.
+    #[tokio::test]
+    async fn bootstrap_against_stand_in_head_unit() {
+        let (mut phone, mut hu) = tokio::io::duplex(1024);
+
+        let head_unit = tokio::spawn(async move {
+            let mut start = WifiStartRequest::WifiStartRequest::new();
+            start.set_ip_address("192.168.43.1".to_string());
+            start.set_port(5288);
+            // unrelated frame first, must be skipped
+            write_frame(&mut hu, MessageId::WifiVersionRequest, &[])
+                .await
+                .unwrap();
+            write_frame(
+                &mut hu,
+                MessageId::WifiStartRequest,
+                &start.write_to_bytes().unwrap(),
+            )
+            .await
+            .unwrap();
+            expect_frame(&mut hu, MessageId::WifiInfoRequest)
+                .await
+                .unwrap();
+
+            let mut info = WifiInfoResponse::WifiInfoResponse::new();
+            info.set_ssid("CarAP".to_string());
+            info.set_key("secret123".to_string());
+            info.set_bssid("00:11:22:33:44:55".to_string());
+            info.set_security_mode(WifiInfoResponse::SecurityMode::WPA2_PERSONAL);
+            info.set_access_point_type(WifiInfoResponse::AccessPointType::DYNAMIC);
+            write_frame(
+                &mut hu,
+                MessageId::WifiInfoResponse,
+                &info.write_to_bytes().unwrap(),
+            )
+            .await
+            .unwrap();
+
+            let response = expect_frame(&mut hu, MessageId::WifiStartResponse)
+                .await
+                .unwrap();
+            let response =
+                WifiStartResponse::WifiStartResponse::parse_from_bytes(&response).unwrap();
+            let status = expect_frame(&mut hu, MessageId::WifiConnectStatus)
+                .await
+                .unwrap();
+            let status = WifiConnectStatus::WifiConnectStatus::parse_from_bytes(&status).unwrap();
+            (response.ip_address().to_string(), status.status())
+        });
+
+        let info = request_wifi_info(&mut phone).await.unwrap();
+        assert_eq!(
+            info,
+            HuWifiInfo {
+                ip_address: "192.168.43.1".to_string(),
+                port: 5288,
+                ssid: "CarAP".to_string(),
+                key: "secret123".to_string(),
+                bssid: "00:11:22:33:44:55".to_string(),
+            }
+        );
+        report_connected(&mut phone, "192.168.43.20", info.port, true)
+            .await
+            .unwrap();
+
+        let (ip, status) = head_unit.await.unwrap();
+        assert_eq!(ip, "192.168.43.20");
+        assert_eq!(status, STATUS_SUCCESS);
+    }
+}
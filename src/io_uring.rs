@@ -13,6 +13,8 @@ use std::cell::RefCell;
 use std::collections::HashMap;
 use std::marker::PhantomData;
 use std::net::IpAddr;
+use std::os::fd::AsRawFd;
+use std::path::Path;
 use std::rc::Rc;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
@@ -20,7 +22,6 @@ use std::time::{Duration, Instant};
 use tokio::fs::File as TokioFile;
 use tokio::io::{self, copy_bidirectional, AsyncBufReadExt, BufReader};
 use tokio::net::TcpStream as TokioTcpStream;
-use tokio::process::Command;
 use tokio::sync::broadcast::Sender as BroadcastSender;
 use tokio::sync::mpsc::{Receiver, Sender};
 use tokio::sync::{mpsc, Mutex, Notify, RwLock};
@@ -51,20 +52,40 @@ const COMP_APP_TCP_PORT_WS: u16 = 9998;
 const COMP_APP_TCP_PORT_SWUPDATE: u16 = 9997;
 // Original queue depth was 10. Keep this small to avoid queue-induced latency.
 const MITM_QUEUE_CAPACITY: usize = 10;
-
-use crate::config::{Action, SharedConfig};
-use crate::config::{TCP_DHU_PORT, TCP_SERVER_PORT};
+// copies for the mirror HU waiting to be processed
+const MIRROR_TAP_CAPACITY: usize = 64;
+// the recording should keep up with video bursts
+const SESSION_RECORD_CAPACITY: usize = 1024;
+// lets the answer to a UserSwitchRequest reach the phone before it is dropped
+const PHONE_SWITCH_DELAY: Duration = Duration::from_millis(300);
+// how often parked phones are checked for having hung up
+const PARKED_CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+use crate::bt_sco_tap;
+use crate::config::{Action, AppConfig, SharedConfig, WifiMode};
+use crate::config::{TCP_DHU_PORT, TCP_MIRROR_PORT, TCP_SERVER_PORT};
+use crate::demo;
 use crate::ev::spawn_ev_client_task;
 use crate::ev::BatteryData;
 use crate::ev::EvTaskCommand;
+use crate::handover::Relay;
+use crate::hostapd;
+use crate::hu_wireless::{self, WirelessHuOptions};
+use crate::media_stats::media_stats_publisher;
+use crate::media_tap::{
+    SharedMediaSinks, MIC_SINK_OFFSET, SCO_DOWNLINK_SINK_OFFSET, SCO_UPLINK_SINK_OFFSET,
+};
+use crate::mirror::{self, Tapped};
 use crate::mitm::endpoint_reader;
 use crate::mitm::media_tcp_server;
 use crate::mitm::proxy;
 use crate::mitm::MediaSink;
 use crate::mitm::Packet;
 use crate::mitm::ProxyType;
+use crate::multi_phone::{self, Phone, Phones};
 use crate::usb_stream;
 use crate::usb_stream::{UsbStreamRead, UsbStreamWrite};
+use crate::wifi_link;
 
 // tokio_uring::fs::File and tokio_uring::net::TcpStream are using different
 // read and write calls:
@@ -113,6 +134,7 @@ async fn transfer_monitor(
     tcp_bytes_written: Arc<AtomicUsize>,
     read_timeout: Duration,
     config: SharedConfig,
+    phone_held: Arc<AtomicBool>,
 ) -> Result<()> {
     let mut usb_bytes_out_last: usize = 0;
     let mut tcp_bytes_out_last: usize = 0;
@@ -165,6 +187,10 @@ async fn transfer_monitor(
                 tcp_speed.to_string_as(true),
                 tcp_transferred_total.to_string_as(true),
             );
+            // link quality of the current session, if it is being sampled
+            if let Some(link) = wifi_link::latest_within(stats_interval.unwrap()) {
+                info!("{} 📶 {} {}", NAME, link.mac, link.summary());
+            }
 
             // save values for next iteration
             report_time = Instant::now();
@@ -178,7 +204,10 @@ async fn transfer_monitor(
             stall_usb_bytes_last = usb_bytes_out - stall_usb_bytes_last;
             stall_tcp_bytes_last = tcp_bytes_out - stall_tcp_bytes_last;
 
-            if stall_usb_bytes_last == 0 || stall_tcp_bytes_last == 0 {
+            // nothing flows towards the phone while a handover is pending
+            if (stall_usb_bytes_last == 0 || stall_tcp_bytes_last == 0)
+                && !phone_held.load(Ordering::Relaxed)
+            {
                 return Err("unexpected transfer stall".into());
             }
 
@@ -210,6 +239,87 @@ async fn flatten<T>(handle: &mut JoinHandle<Result<T>>) -> Result<T> {
     }
 }
 
+/// A phone that connected while another one is projected (`multi_phone`).
+struct ParkedPhone {
+    phone: Phone,
+    stream: TcpStream,
+    addr: SocketAddr,
+    cancel: CancellationToken,
+}
+
+impl ParkedPhone {
+    fn close(self) {
+        self.cancel.cancel();
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+    }
+
+    /// Whether the phone hung up while waiting. Peeks, so nothing it sent is
+    /// lost for the session.
+    fn hung_up(&self) -> bool {
+        let mut byte = 0u8;
+        let n = unsafe {
+            libc::recv(
+                self.stream.as_raw_fd(),
+                &mut byte as *mut u8 as *mut libc::c_void,
+                1,
+                libc::MSG_PEEK | libc::MSG_DONTWAIT,
+            )
+        };
+        match n {
+            0 => true,
+            n if n > 0 => false,
+            _ => io::Error::last_os_error().kind() != io::ErrorKind::WouldBlock,
+        }
+    }
+}
+
+/// Name the phone that connected from Wi-Fi `mac`, see [`multi_phone::identify`].
+async fn identify_phone(mac: Option<MacAddress>) -> Phone {
+    let mac = mac.map(|mac| mac.to_string());
+    let keyid = match &mac {
+        Some(mac) => hostapd::station(mac)
+            .await
+            .ok()
+            .flatten()
+            .and_then(|s| s.keyid),
+        None => None,
+    };
+    multi_phone::identify(mac.as_deref(), keyid.as_deref())
+}
+
+/// Something that changes the projected phone of a relayed session.
+enum PhoneEvent {
+    Lost(Box<dyn std::error::Error + Send + Sync>),
+    Arrived(Result<(TcpStream, SocketAddr, CancellationToken)>),
+    Switch(Option<i32>),
+    /// Time to drop the parked phones that hung up.
+    CheckParked,
+}
+
+fn publish_phones(active: &Option<Phone>, parked: &[ParkedPhone]) {
+    multi_phone::set(Phones {
+        active: active.clone(),
+        parked: parked.iter().map(|p| p.phone.clone()).collect(),
+    });
+}
+
+/// Stop the phone-side tasks of the projected phone and close its connection.
+fn retire_phone(
+    reader: &JoinHandle<Result<()>>,
+    proxy: &JoinHandle<Result<()>>,
+    stream: Option<Rc<TcpStream>>,
+    cancel: Option<CancellationToken>,
+) {
+    reader.abort();
+    proxy.abort();
+    if let Some(stream) = stream {
+        let _ = stream.shutdown(std::net::Shutdown::Both);
+    }
+    if let Some(cancel) = cancel {
+        cancel.cancel();
+    }
+}
+
 async fn tcp_bridge(remote_addr: &str, local_addr: &str, cancel: CancellationToken) {
     loop {
         debug!(
@@ -479,6 +589,82 @@ async fn tcp_connect_to_aa_server(addr: &str) -> Result<TcpStream> {
     Ok(stream)
 }
 
+/// Connects to the AA endpoint of a wireless head unit, after
+/// [`hu_wireless::connect`] has bootstrapped it.
+async fn tcp_connect_to_hu(addr: SocketAddr) -> Result<TcpStream> {
+    info!(
+        "{} 🚘 connecting to wireless head unit at <u>{}</u>...",
+        NAME, addr
+    );
+    let stream = timeout(TCP_CLIENT_TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| format!("connect timeout to head unit {addr}"))??;
+    stream.set_nodelay(true)?;
+    info!(
+        "{} 🚘 connected to wireless head unit at <u>{}</u>",
+        NAME, addr
+    );
+
+    Ok(stream)
+}
+
+/// Play the `demo_phone` recording to the HU connected via USB or TCP.
+async fn demo_session(
+    hu_usb: Option<File>,
+    hu_tcp: Option<TcpStream>,
+    recording: &Path,
+    config: SharedConfig,
+) -> Result<()> {
+    if let Some(hu) = hu_usb {
+        let hu = Rc::new(hu);
+        return demo::play(
+            IoDevice::EndpointIo(hu.clone()),
+            IoDevice::EndpointIo(hu),
+            recording,
+            config,
+        )
+        .await;
+    }
+    let hu = Rc::new(hu_tcp.ok_or("no head unit connection")?);
+    let res = demo::play::<File>(
+        IoDevice::TcpStreamIo(hu.clone()),
+        IoDevice::TcpStreamIo(hu.clone()),
+        recording,
+        config,
+    )
+    .await;
+    let _ = hu.shutdown(std::net::Shutdown::Both);
+    res
+}
+
+fn wireless_hu_options(config: &AppConfig) -> Result<WirelessHuOptions> {
+    let bt_addr = config.wireless_hu_bt_addr.trim();
+    let tcp_addr = config.wireless_hu_tcp_addr.trim();
+    Ok(WirelessHuOptions {
+        bt_addr: if bt_addr.is_empty() {
+            None
+        } else {
+            Some(
+                bt_addr
+                    .parse()
+                    .map_err(|e| format!("invalid wireless_hu_bt_addr {bt_addr:?}: {e}"))?,
+            )
+        },
+        tcp_addr: if tcp_addr.is_empty() {
+            None
+        } else {
+            Some(
+                tcp_addr
+                    .parse()
+                    .map_err(|e| format!("invalid wireless_hu_tcp_addr {tcp_addr:?}: {e}"))?,
+            )
+        },
+        bt_connect_timeout: Duration::from_secs(config.bt_connect_timeout_secs.into()),
+        wpa_supplicant_ctrl_dir: config.wpa_supplicant_ctrl_dir.clone(),
+        iface: config.iface.clone(),
+    })
+}
+
 pub async fn io_loop(
     need_restart: BroadcastSender<Option<Action>>,
     tcp_start: Arc<Notify>,
@@ -492,6 +678,7 @@ pub async fn io_loop(
     usb_connected: Arc<AtomicBool>,
     script_registry: Option<Arc<ScriptRegistry>>,
     ws_event_tx: BroadcastSender<ServerEvent>,
+    shared_media_sinks: SharedMediaSinks,
 ) -> Result<()> {
     let shared_config = config.clone();
     #[allow(unused_variables)]
@@ -506,6 +693,18 @@ pub async fn io_loop(
     let bind_addr = format!("0.0.0.0:{}", TCP_DHU_PORT).parse().unwrap();
     let mut dhu_listener = Some(TcpListener::bind(bind_addr).unwrap());
     info!("{} 🛰️ DHU TCP server bound to: <u>{}</u>", NAME, bind_addr);
+    let mirror_listener = if config.read().await.mirror {
+        info!("{} 🛰️ Starting TCP server for the mirror HU...", NAME);
+        let bind_addr = format!("0.0.0.0:{}", TCP_MIRROR_PORT).parse().unwrap();
+        let listener = Rc::new(TcpListener::bind(bind_addr).unwrap());
+        info!(
+            "{} 🛰️ mirror HU TCP server bound to: <u>{}</u>",
+            NAME, bind_addr
+        );
+        Some(listener)
+    } else {
+        None
+    };
 
     // create media tap sinks once — they persist across reconnects (requires mitm=true)
     let persistent_media_sinks: HashMap<u8, MediaSink> = {
@@ -525,6 +724,9 @@ pub async fn io_loop(
                     (4u8, "audio-system"),
                     (5u8, "audio-media"),
                     (6u8, "audio-telephony"),
+                    (MIC_SINK_OFFSET, "audio-mic"),
+                    (SCO_DOWNLINK_SINK_OFFSET, "audio-sco-downlink"),
+                    (SCO_UPLINK_SINK_OFFSET, "audio-sco-uplink"),
                 ];
                 for (offset, label) in labels {
                     let sink = MediaSink::new(128);
@@ -535,10 +737,26 @@ pub async fn io_loop(
                         sink.clone(),
                         config_snapshot.media_wait_for_live_idr,
                     ));
+                    shared_media_sinks
+                        .write()
+                        .await
+                        .insert(label.to_string(), sink.clone());
                     map.insert(offset, sink);
                 }
+                if let (Some(downlink), Some(uplink)) = (
+                    map.get(&SCO_DOWNLINK_SINK_OFFSET),
+                    map.get(&SCO_UPLINK_SINK_OFFSET),
+                ) {
+                    bt_sco_tap::set_sinks(downlink.clone(), uplink.clone()).await;
+                }
             }
         }
+        if !map.is_empty() {
+            tokio::spawn(media_stats_publisher(
+                shared_media_sinks.clone(),
+                ws_event_tx.clone(),
+            ));
+        }
         map
     };
 
@@ -561,6 +779,8 @@ pub async fn io_loop(
         let mut md_usb = None;
         let mut hu_tcp = None;
         let mut hu_usb = None;
+        // RFCOMM link to a wireless head unit, kept open for the session
+        let mut hu_link = None;
         let mut usb_used = false;
         // CancellationToken for tcp_bridge tasks spawned for this session
         let mut bridge_cancel: Option<CancellationToken> = None;
@@ -568,7 +788,14 @@ pub async fn io_loop(
         let aa_server_tcp_addr = config.aa_server_tcp_addr.trim().to_string();
         let aa_server_tcp_enabled = !aa_server_tcp_addr.is_empty();
 
-        if aa_server_tcp_enabled {
+        if let Some(recording) = &config.demo_phone {
+            info!(
+                "{} 🎬 demo phone mode enabled, not waiting for a phone: <u>{}</u>",
+                NAME,
+                recording.display()
+            );
+            usb_connected.store(false, Ordering::Relaxed);
+        } else if aa_server_tcp_enabled {
             // Direct Android Auto Head Unit Server mode replaces the MD/phone-side
             // USB/Bluetooth/Wi-Fi transport only. Do not connect yet: open the
             // HU/DHU side first, then create a fresh MD TCP connection immediately
@@ -634,7 +861,28 @@ pub async fn io_loop(
             }
         }
 
-        if config.dhu {
+        if config.wireless_hu {
+            let connected = match wireless_hu_options(&config) {
+                Ok(options) => match hu_wireless::connect(&options).await {
+                    Ok((addr, link)) => {
+                        hu_link = link;
+                        tcp_connect_to_hu(addr).await
+                    }
+                    Err(e) => Err(e),
+                },
+                Err(e) => Err(e),
+            };
+            match connected {
+                Ok(s) => hu_tcp = Some(s),
+                Err(e) => {
+                    error!("{} 🔴 wireless head unit unavailable: {}", NAME, e);
+                    // notify main loop to restart
+                    let _ = need_restart.send(None);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            }
+        } else if config.dhu {
             info!(
                 "{} 🛰️ DHU TCP server: listening for `Desktop Head Unit` connection...",
                 NAME
@@ -670,6 +918,23 @@ pub async fn io_loop(
             }
         }
 
+        if let Some(recording) = &config.demo_phone {
+            info!("{} 🎬 Playing the demo phone to the HU...", NAME);
+            let started = Instant::now();
+            if let Err(e) = demo_session(hu_usb, hu_tcp, recording, shared_config.clone()).await {
+                error!("{} 🔴 demo phone: {}", NAME, e);
+            }
+            drop(hu_link);
+            info!(
+                "{} ⌛ session time: {}",
+                NAME,
+                format_duration(started.elapsed()).to_string()
+            );
+            let _ = need_restart.send(None);
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            continue;
+        }
+
         if aa_server_tcp_enabled {
             match tcp_connect_to_aa_server(&aa_server_tcp_addr).await {
                 Ok(s) => {
@@ -718,6 +983,89 @@ pub async fn io_loop(
         let (txr_md, rxr_hu): (Sender<Packet>, Receiver<Packet>) =
             mpsc::channel(MITM_QUEUE_CAPACITY);
 
+        // The mirror HU and the session recording are fed with copies of
+        // what passes the HU-side proxy
+        let mirroring = config.mitm && mirror_listener.is_some();
+        let recording = config.mitm && config.session_record;
+        let mut mirror_tasks = vec![];
+        let mut mirror_taps = None;
+        let (rx_md, rx_hu) = if mirroring || recording {
+            let mut taps = vec![];
+            if mirroring {
+                let (tx, rx) = mpsc::channel(MIRROR_TAP_CAPACITY);
+                taps.push(tx);
+                mirror_taps = Some(rx);
+            }
+            if recording {
+                let (tx, rx) = mpsc::channel(SESSION_RECORD_CAPACITY);
+                taps.push(tx);
+                let dir = config.media_record_dir.clone();
+                let max_bytes = u64::from(config.session_record_max_mb) * 1024 * 1024;
+                tokio::task::spawn_blocking(move || {
+                    if let Err(e) = demo::record(rx, dir, max_bytes) {
+                        error!("{} 🔴 session recording failed: {}", NAME, e);
+                    }
+                });
+            }
+            let (rx_md, task_hu) =
+                mirror::tap(rx_md, MITM_QUEUE_CAPACITY, taps.clone(), Tapped::FromHu);
+            let (rx_hu, task_md) = mirror::tap(rx_hu, MITM_QUEUE_CAPACITY, taps, Tapped::FromPhone);
+            mirror_tasks = vec![task_hu, task_md];
+            (rx_md, rx_hu)
+        } else {
+            (rx_md, rx_hu)
+        };
+
+        // With a handover grace period or several phones the MD proxy talks to
+        // a relay instead, which keeps the HU proxy going while the phone
+        // reconnects or another phone takes over.
+        let relayed = config.mitm && md_usb.is_none() && !aa_server_tcp_enabled;
+        let handover_grace = (relayed && config.handover_grace_secs > 0)
+            .then(|| Duration::from_secs(config.handover_grace_secs.into()));
+        let switching = relayed && config.multi_phone;
+        let mut relay = None;
+        let (tx_md, rx_md) = if handover_grace.is_some() || switching {
+            let (r, task) = Relay::spawn(rx_md, tx_md, MITM_QUEUE_CAPACITY, switching);
+            match r.attach().await {
+                Ok(channels) => {
+                    relay = Some((r, task));
+                    channels
+                }
+                Err(e) => {
+                    error!("{} 🔴 handover relay: {}", NAME, e);
+                    if let Some(cancel) = bridge_cancel.take() {
+                        cancel.cancel();
+                    }
+                    let _ = need_restart.send(None);
+                    continue;
+                }
+            }
+        } else {
+            (tx_md, rx_md)
+        };
+        let mirror_server = mirror_listener
+            .clone()
+            .zip(mirror_taps)
+            .map(|(listener, taps)| {
+                tokio_uring::spawn(mirror::serve(
+                    listener,
+                    taps,
+                    tx_hu.clone(),
+                    shared_config.clone(),
+                ))
+            });
+        let phone_held = Arc::new(AtomicBool::new(false));
+        multi_phone::reset();
+        let mut active_phone = if switching {
+            Some(identify_phone(client_mac).await)
+        } else {
+            None
+        };
+        let mut parked: Vec<ParkedPhone> = Vec::new();
+        if switching {
+            publish_phones(&active_phone, &parked);
+        }
+
         // selecting I/O device for reading and writing
         // and creating desired objects for proxy functions
         let hu_r;
@@ -748,7 +1096,7 @@ pub async fn io_loop(
             hu_r = IoDevice::EndpointIo(hu.clone());
             hu_w = IoDevice::EndpointIo(hu.clone());
         } else {
-            // Head Unit Emulator via TCP
+            // Head Unit Emulator or wireless head unit via TCP
             let hu = Rc::new(hu_tcp.unwrap());
             hu_r = IoDevice::TcpStreamIo(hu.clone());
             hu_w = IoDevice::TcpStreamIo(hu.clone());
@@ -808,11 +1156,25 @@ pub async fn io_loop(
         let mut monitor = tokio::spawn(transfer_monitor(
             stats_interval,
             file_bytes,
-            stream_bytes,
+            stream_bytes.clone(),
             read_timeout,
             shared_config.clone(),
+            phone_held.clone(),
         ));
 
+        // Wi-Fi link quality of the phone, only visible on our own AP
+        let start_link_monitor = |mac: Option<MacAddress>| {
+            mac.filter(|_| config.wifi_mode == WifiMode::Ap && config.wifi_link_interval > 0)
+                .map(|mac| {
+                    tokio::spawn(wifi_link::monitor(
+                        mac.to_string(),
+                        Duration::from_secs(config.wifi_link_interval.into()),
+                        ws_event_tx.clone(),
+                    ))
+                })
+        };
+        let mut link_monitor = start_link_monitor(client_mac);
+
         // Background task to interrupt wireless session if USB is plugged in
         let wired_clone = config.wired.clone();
         let mut usb_monitor = tokio::spawn(async move {
@@ -831,14 +1193,266 @@ pub async fn io_loop(
         });
 
         // Stop as soon as one of them errors
-        let res = tokio::try_join!(
-            flatten(&mut reader_hu),
-            flatten(&mut reader_md),
-            flatten(&mut from_file),
-            flatten(&mut from_stream),
-            flatten(&mut monitor),
-            flatten(&mut usb_monitor)
-        );
+        let res = match &mut relay {
+            Some((relay, relay_task)) => {
+                // the projected phone may change within the session: a lost
+                // phone can come back (handover) or a parked one can take
+                // over (multi_phone)
+                let phone_side = async {
+                    loop {
+                        let event = tokio::select! {
+                            res = async {
+                                tokio::try_join!(flatten(&mut reader_md), flatten(&mut from_stream))
+                            } => match res {
+                                Ok(_) => return Ok(()),
+                                Err(e) => PhoneEvent::Lost(e),
+                            },
+                            conn = async {
+                                tcp_start.notified().await;
+                                tcp_wait_for_connection(md_listener.as_mut().unwrap(), true).await
+                            }, if switching => PhoneEvent::Arrived(conn),
+                            target = multi_phone::switch_requested(), if switching => {
+                                PhoneEvent::Switch(target)
+                            }
+                            _ = sleep(PARKED_CHECK_INTERVAL), if !parked.is_empty() => {
+                                PhoneEvent::CheckParked
+                            }
+                        };
+                        // the next phone and whether the projected one is
+                        // still there and has to be dropped
+                        let (next, drop_active) = match event {
+                            PhoneEvent::Arrived(Err(_)) => continue,
+                            PhoneEvent::CheckParked => {
+                                let (gone, waiting): (Vec<_>, Vec<_>) =
+                                    parked.drain(..).partition(|p| p.hung_up());
+                                parked = waiting;
+                                if !gone.is_empty() {
+                                    for phone in gone {
+                                        info!(
+                                            "{} 🅿️ <b>{}</> hung up while waiting",
+                                            NAME, phone.phone.name
+                                        );
+                                        phone.close();
+                                    }
+                                    publish_phones(&active_phone, &parked);
+                                }
+                                continue;
+                            }
+                            PhoneEvent::Arrived(Ok((stream, addr, cancel))) => {
+                                let mac = mac_from_ipv4(addr).await.unwrap_or(None);
+                                let newcomer = ParkedPhone {
+                                    phone: identify_phone(mac).await,
+                                    stream,
+                                    addr,
+                                    cancel,
+                                };
+                                if newcomer.phone.bt_addr.is_none() {
+                                    warn!(
+                                        "{} 📵 {} connected without a Bluetooth handshake, rejecting",
+                                        NAME, addr
+                                    );
+                                    newcomer.close();
+                                    continue;
+                                }
+                                // a phone that connects again replaces its
+                                // stale parked connection
+                                if let Some(idx) = parked
+                                    .iter()
+                                    .position(|p| p.phone.bt_addr == newcomer.phone.bt_addr)
+                                {
+                                    parked.remove(idx).close();
+                                }
+                                let wins = relay.resumable()
+                                    && active_phone.as_ref().is_some_and(|active| {
+                                        active.bt_addr == newcomer.phone.bt_addr
+                                            || multi_phone::takes_over(
+                                                config.multi_phone_policy,
+                                                active,
+                                                &newcomer.phone,
+                                            )
+                                    });
+                                if !wins {
+                                    info!(
+                                        "{} 🅿️ <b>{}</> is waiting for a switch",
+                                        NAME, newcomer.phone.name
+                                    );
+                                    parked.push(newcomer);
+                                    publish_phones(&active_phone, &parked);
+                                    continue;
+                                }
+                                info!(
+                                    "{} 🔀 <b>{}</> takes over the head unit session",
+                                    NAME, newcomer.phone.name
+                                );
+                                (newcomer, true)
+                            }
+                            PhoneEvent::Switch(target) => {
+                                let Some(idx) =
+                                    multi_phone::pick(parked.iter().map(|p| &p.phone), target)
+                                else {
+                                    warn!("{} 🔀 no such phone waiting, not switching", NAME);
+                                    continue;
+                                };
+                                if !relay.resumable() {
+                                    warn!(
+                                        "{} 🔀 head unit session is not set up yet, not switching",
+                                        NAME
+                                    );
+                                    continue;
+                                }
+                                info!("{} 🔀 switching to <b>{}</>", NAME, parked[idx].phone.name);
+                                sleep(PHONE_SWITCH_DELAY).await;
+                                (parked.remove(idx), true)
+                            }
+                            PhoneEvent::Lost(e) => {
+                                let waiting =
+                                    multi_phone::pick(parked.iter().map(|p| &p.phone), None)
+                                        .filter(|_| relay.resumable());
+                                if let Some(idx) = waiting {
+                                    warn!(
+                                        "{} 📵 phone lost ({}), switching to <b>{}</>",
+                                        NAME, e, parked[idx].phone.name
+                                    );
+                                    (parked.remove(idx), false)
+                                } else {
+                                    let Some(grace) = handover_grace.filter(|_| relay.resumable())
+                                    else {
+                                        return Err(e);
+                                    };
+                                    warn!(
+                                        "{} 📵 phone lost ({}), holding the head unit for {}s...",
+                                        NAME,
+                                        e,
+                                        grace.as_secs()
+                                    );
+                                    retire_phone(
+                                        &reader_md,
+                                        &from_stream,
+                                        md_tcp_stream.take(),
+                                        bridge_cancel.take(),
+                                    );
+                                    phone_held.store(true, Ordering::Relaxed);
+                                    let _ = need_restart.send(Some(Action::Handover));
+
+                                    let reconnected = timeout(grace, async {
+                                        tcp_start.notified().await;
+                                        info!(
+                                            "{} 🛰️ MD TCP server: waiting for the phone to come back...",
+                                            NAME
+                                        );
+                                        tcp_wait_for_connection(md_listener.as_mut().unwrap(), true)
+                                            .await
+                                    })
+                                    .await;
+                                    let Ok(Ok((stream, addr, cancel))) = reconnected else {
+                                        return Err(format!(
+                                            "phone did not come back within {}s",
+                                            grace.as_secs()
+                                        )
+                                        .into());
+                                    };
+                                    let mac = mac_from_ipv4(addr).await.unwrap_or(None);
+                                    let phone = identify_phone(mac).await;
+                                    (
+                                        ParkedPhone {
+                                            phone,
+                                            stream,
+                                            addr,
+                                            cancel,
+                                        },
+                                        false,
+                                    )
+                                }
+                            }
+                        };
+                        retire_phone(
+                            &reader_md,
+                            &from_stream,
+                            md_tcp_stream.take(),
+                            bridge_cancel.take(),
+                        );
+                        // the dropped phone has no session any more, send it
+                        // back to its own Wi-Fi
+                        if let Some(mac) =
+                            client_mac.filter(|_| drop_active && config.wifi_mode == WifiMode::Ap)
+                        {
+                            if let Err(e) = hostapd::disassociate(&mac.to_string()).await {
+                                warn!("{} failed to disassociate {}: {}", NAME, mac, e);
+                            }
+                        }
+
+                        let ParkedPhone {
+                            phone,
+                            stream,
+                            addr,
+                            cancel,
+                        } = next;
+                        client_mac = mac_from_ipv4(addr).await.unwrap_or(None);
+                        // follow the new phone, the old station may be gone
+                        if let Some(link_monitor) = link_monitor.take() {
+                            link_monitor.abort();
+                        }
+                        link_monitor = start_link_monitor(client_mac);
+                        bridge_cancel = Some(cancel);
+                        let (tx_md, rx_md) = relay.attach().await?;
+                        let (txr_md, rxr_hu) = mpsc::channel(MITM_QUEUE_CAPACITY);
+                        let md = Rc::new(stream);
+                        md_tcp_stream = Some(md.clone());
+                        reader_md = tokio_uring::spawn(endpoint_reader(
+                            IoDevice::EndpointIo(md.clone()),
+                            txr_md,
+                            false,
+                        ));
+                        from_stream = tokio_uring::spawn(proxy(
+                            ProxyType::MobileDevice,
+                            IoDevice::EndpointIo(md),
+                            stream_bytes.clone(),
+                            tx_md.clone(),
+                            rx_md,
+                            rxr_hu,
+                            shared_config.clone(),
+                            sensor_channel.clone(),
+                            input_channel.clone(),
+                            last_battery.clone(),
+                            last_speed.clone(),
+                            last_service_discovery_response.clone(),
+                            ev_tx.clone(),
+                            Some(tx_md),
+                            script_registry.clone(),
+                            persistent_media_sinks.clone(),
+                            ws_event_tx.clone(),
+                        ));
+                        phone_held.store(false, Ordering::Relaxed);
+                        info!(
+                            "{} 🔁 head unit session handed over to <b>{}</>",
+                            NAME, phone.name
+                        );
+                        if switching {
+                            active_phone = Some(phone);
+                            publish_phones(&active_phone, &parked);
+                        }
+                    }
+                };
+                tokio::try_join!(
+                    flatten(&mut reader_hu),
+                    flatten(&mut from_file),
+                    flatten(&mut monitor),
+                    flatten(&mut usb_monitor),
+                    flatten(relay_task),
+                    phone_side
+                )
+                .map(|_| ())
+            }
+            None => tokio::try_join!(
+                flatten(&mut reader_hu),
+                flatten(&mut reader_md),
+                flatten(&mut from_file),
+                flatten(&mut from_stream),
+                flatten(&mut monitor),
+                flatten(&mut usb_monitor)
+            )
+            .map(|_| ()),
+        };
         if let Err(e) = res {
             error!("{} 🔴 Connection error: {}", NAME, e);
             if let Some(dev) = usb_dev {
@@ -861,6 +1475,22 @@ pub async fn io_loop(
         from_stream.abort();
         monitor.abort();
         usb_monitor.abort();
+        if let Some((_, relay_task)) = relay {
+            relay_task.abort();
+        }
+        for task in mirror_tasks {
+            task.abort();
+        }
+        if let Some(mirror_server) = mirror_server {
+            mirror_server.abort();
+        }
+        for phone in parked {
+            phone.close();
+        }
+        multi_phone::reset();
+        if let Some(link_monitor) = link_monitor {
+            link_monitor.abort();
+        }
 
         // make sure TCP connections are closed before next connection attempts
         if let Some(stream) = md_tcp_stream {
@@ -869,18 +1499,21 @@ pub async fn io_loop(
         if let Some(stream) = hu_tcp_stream {
             let _ = stream.shutdown(std::net::Shutdown::Both);
         }
+        // the head unit ends its wireless session with the RFCOMM link
+        drop(hu_link);
 
         // Disassociate a client from the WiFi AP.
         // Mainly needed when a button was used to switch to the next device,
         // or when the stop_on_disconnect option was used.
         // Otherwise, the WiFi/AA connection remains hanging and the phone
         // won't switch back to the regular WiFi.
-        if let Some(mac) = client_mac {
+        // In station mode the phone is on a foreign AP we cannot control.
+        if let Some(mac) = client_mac.filter(|_| config.wifi_mode == WifiMode::Ap) {
             info!("{} disassociating WiFi client: {}", NAME, mac);
 
-            let _ = Command::new("/usr/bin/hostapd_cli")
-                .args(&["disassociate", &mac.to_string()])
-                .spawn();
+            if let Err(e) = hostapd::disassociate(&mac.to_string()).await {
+                warn!("{} failed to disassociate {}: {}", NAME, mac, e);
+            }
         }
 
         // set webserver context EV stuff to None
@@ -914,3 +1547,32 @@ pub async fn io_loop(
     ev_tx.send(EvTaskCommand::Terminate).await?;
     client_handler.await?;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn connects_to_wireless_hu_tcp_addr() {
+        tokio_uring::start(async {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            let mut config = AppConfig {
+                wireless_hu: true,
+                wireless_hu_tcp_addr: listener.local_addr().unwrap().to_string(),
+                wifi_mode: WifiMode::Station,
+                ..Default::default()
+            };
+
+            let options = wireless_hu_options(&config).unwrap();
+            let (addr, link) = hu_wireless::connect(&options).await.unwrap();
+            assert_eq!(addr, listener.local_addr().unwrap());
+            // no Bluetooth bootstrap, so no RFCOMM link to keep
+            assert!(link.is_none());
+            let _stream = tcp_connect_to_hu(addr).await.unwrap();
+            listener.accept().unwrap();
+
+            config.wireless_hu_tcp_addr = "head-unit:5288".to_string();
+            assert!(wireless_hu_options(&config).is_err());
+        });
+    }
+}
@@ -0,0 +1,392 @@
+use bluer::Address;
+use serde::{Deserialize, Serialize};
+use simplelog::*;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// module name for logging engine
+const NAME: &str = "<i><bright-black> known-devices: </>";
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// JSON list of [`KnownDevice`]. Older versions stored one address per line,
+/// such files are still read and rewritten as JSON on the next change.
+pub const KNOWN_DEVICES_FILE: &str = concat!(crate::base_config_dir!(), "/known_devices");
+
+/// Serializes read-modify-write cycles of the store file.
+static STORE_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct KnownDevice {
+    pub mac: String,
+    /// Friendly name, defaults to the Bluetooth name of the phone.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Higher values are tried first.
+    #[serde(default)]
+    pub priority: i32,
+    /// Phones with auto-connect disabled are only accepted when they connect
+    /// by themselves.
+    #[serde(default = "default_auto_connect")]
+    pub auto_connect: bool,
+    /// Unix time of the last successful AA handshake.
+    #[serde(default)]
+    pub last_connected: Option<u64>,
+    /// Outgoing profile connection attempts made by us.
+    #[serde(default)]
+    pub connect_attempts: u32,
+    #[serde(default)]
+    pub connect_successes: u32,
+    /// Completed Wi-Fi handshakes, regardless of who connected.
+    #[serde(default)]
+    pub sessions: u32,
+    /// Own Wi-Fi passphrase when `wifi_per_phone_psk` is enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wifi_psk: Option<String>,
+}
+
+fn default_auto_connect() -> bool {
+    true
+}
+
+impl KnownDevice {
+    pub fn new(addr: Address) -> Self {
+        Self {
+            mac: addr.to_string(),
+            name: None,
+            priority: 0,
+            auto_connect: true,
+            last_connected: None,
+            connect_attempts: 0,
+            connect_successes: 0,
+            sessions: 0,
+            wifi_psk: None,
+        }
+    }
+
+    pub fn address(&self) -> Option<Address> {
+        self.mac.parse().ok()
+    }
+}
+
+/// A [`KnownDevice`] as served via REST, its Wi-Fi passphrase stays on the
+/// device.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct KnownDeviceView {
+    #[serde(flatten)]
+    pub device: KnownDevice,
+    pub has_wifi_psk: bool,
+}
+
+impl From<KnownDevice> for KnownDeviceView {
+    fn from(mut device: KnownDevice) -> Self {
+        let has_wifi_psk = device.wifi_psk.take().is_some();
+        Self {
+            device,
+            has_wifi_psk,
+        }
+    }
+}
+
+/// Fields of a device that can be changed via REST.
+#[derive(Debug, Default, Deserialize)]
+pub struct KnownDeviceUpdate {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub priority: Option<i32>,
+    #[serde(default)]
+    pub auto_connect: Option<bool>,
+}
+
+impl KnownDeviceUpdate {
+    fn apply(self, device: &mut KnownDevice) {
+        if let Some(name) = self.name {
+            let name = name.trim().to_string();
+            device.name = (!name.is_empty()).then_some(name);
+        }
+        if let Some(priority) = self.priority {
+            device.priority = priority;
+        }
+        if let Some(auto_connect) = self.auto_connect {
+            device.auto_connect = auto_connect;
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn parse(contents: &str) -> Vec<KnownDevice> {
+    if contents.trim_start().starts_with('[') {
+        return match serde_json::from_str::<Vec<KnownDevice>>(contents) {
+            Ok(devices) => devices
+                .into_iter()
+                .filter(|d| d.address().is_some_and(|a| a != Address::any()))
+                .collect(),
+            Err(e) => {
+                warn!("{} invalid {}: {}", NAME, KNOWN_DEVICES_FILE, e);
+                Vec::new()
+            }
+        };
+    }
+
+    // legacy format: one address per line, in connect order
+    let mut devices: Vec<KnownDevice> = Vec::new();
+    for line in contents.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() {
+            continue;
+        }
+        match trimmed.parse::<Address>() {
+            Ok(addr) if addr != Address::any() => {
+                if !devices.iter().any(|d| d.address() == Some(addr)) {
+                    devices.push(KnownDevice::new(addr));
+                }
+            }
+            _ => warn!("{} skipping invalid line: {}", NAME, trimmed),
+        }
+    }
+    devices
+}
+
+fn load_unlocked() -> Vec<KnownDevice> {
+    match std::fs::read_to_string(KNOWN_DEVICES_FILE) {
+        Ok(contents) => parse(&contents),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn save_unlocked(devices: &[KnownDevice]) -> Result<()> {
+    let json = serde_json::to_string_pretty(devices)?;
+    let tmp = format!("{}.tmp", KNOWN_DEVICES_FILE);
+    std::fs::write(&tmp, json)?;
+    std::fs::rename(&tmp, KNOWN_DEVICES_FILE)?;
+    Ok(())
+}
+
+/// Apply `f` to the stored list and write it back when it returns true.
+fn modify<T>(f: impl FnOnce(&mut Vec<KnownDevice>) -> (bool, T)) -> Result<T> {
+    let _lock = STORE_LOCK.lock().unwrap();
+    let mut devices = load_unlocked();
+    let (changed, result) = f(&mut devices);
+    if changed {
+        save_unlocked(&devices)?;
+    }
+    Ok(result)
+}
+
+/// All known devices in the stored order.
+pub fn list() -> Vec<KnownDevice> {
+    let _lock = STORE_LOCK.lock().unwrap();
+    load_unlocked()
+}
+
+pub fn get(addr: Address) -> Option<KnownDevice> {
+    list().into_iter().find(|d| d.address() == Some(addr))
+}
+
+/// Sort devices for auto-connect: highest priority first, then the most
+/// recently used phone. Devices with auto-connect disabled are dropped.
+fn connect_order(mut devices: Vec<KnownDevice>) -> Vec<Address> {
+    devices.retain(|d| d.auto_connect);
+    // stable sort keeps the stored order for devices that never connected
+    devices.sort_by(|a, b| {
+        b.priority
+            .cmp(&a.priority)
+            .then(b.last_connected.cmp(&a.last_connected))
+    });
+    devices.iter().filter_map(KnownDevice::address).collect()
+}
+
+/// Addresses to try when connecting to "any" known phone, preferred first.
+pub fn auto_connect_addresses() -> Vec<Address> {
+    let devices = list();
+    let total = devices.len();
+    let addrs = connect_order(devices);
+    if total > 0 {
+        info!(
+            "{} 📋 Loaded {} known device(s), {} with auto-connect",
+            NAME,
+            total,
+            addrs.len()
+        );
+    }
+    addrs
+}
+
+/// Add a device or update its fields. Returns the stored entry.
+pub fn upsert(addr: Address, update: KnownDeviceUpdate) -> Result<KnownDevice> {
+    modify(|devices| {
+        let idx = match devices.iter().position(|d| d.address() == Some(addr)) {
+            Some(idx) => idx,
+            None => {
+                devices.push(KnownDevice::new(addr));
+                devices.len() - 1
+            }
+        };
+        update.apply(&mut devices[idx]);
+        (true, devices[idx].clone())
+    })
+}
+
+/// Update an existing device. Returns None when it is not known.
+pub fn update(addr: Address, update: KnownDeviceUpdate) -> Result<Option<KnownDevice>> {
+    modify(
+        |devices| match devices.iter_mut().find(|d| d.address() == Some(addr)) {
+            Some(device) => {
+                update.apply(device);
+                (true, Some(device.clone()))
+            }
+            None => (false, None),
+        },
+    )
+}
+
+/// Forget one device. Returns false when it was not known.
+pub fn remove(addr: Address) -> Result<bool> {
+    modify(|devices| {
+        let before = devices.len();
+        devices.retain(|d| d.address() != Some(addr));
+        let removed = devices.len() != before;
+        (removed, removed)
+    })
+}
+
+/// Wi-Fi passphrase of `addr`, adding the device and creating the passphrase
+/// with `generate` when needed. The flag is true when a new one was stored.
+pub fn ensure_wifi_psk(
+    addr: Address,
+    generate: impl FnOnce() -> std::io::Result<String>,
+) -> Result<(String, bool)> {
+    let result = modify(|devices| {
+        let idx = match devices.iter().position(|d| d.address() == Some(addr)) {
+            Some(idx) => idx,
+            None => {
+                devices.push(KnownDevice::new(addr));
+                devices.len() - 1
+            }
+        };
+        if let Some(psk) = &devices[idx].wifi_psk {
+            return (false, Ok((psk.clone(), false)));
+        }
+        match generate() {
+            Ok(psk) => {
+                devices[idx].wifi_psk = Some(psk.clone());
+                (true, Ok((psk, true)))
+            }
+            Err(e) => (false, Err(e)),
+        }
+    })?;
+    Ok(result?)
+}
+
+/// Count an outgoing profile connection attempt to a known device.
+pub fn record_connect_attempt(addr: Address, success: bool) {
+    let result = modify(
+        |devices| match devices.iter_mut().find(|d| d.address() == Some(addr)) {
+            Some(device) => {
+                device.connect_attempts = device.connect_attempts.saturating_add(1);
+                if success {
+                    device.connect_successes = device.connect_successes.saturating_add(1);
+                }
+                (true, ())
+            }
+            None => (false, ()),
+        },
+    );
+    if let Err(e) = result {
+        warn!("{} failed to update {}: {}", NAME, addr, e);
+    }
+}
+
+/// Record a completed AA handshake. Unknown devices are only added when
+/// `add` is set, i.e. when we were connecting to any known phone.
+pub fn record_session(addr: Address, bt_name: Option<String>, add: bool) {
+    if addr == Address::any() {
+        return;
+    }
+    let result = modify(|devices| {
+        let idx = match devices.iter().position(|d| d.address() == Some(addr)) {
+            Some(idx) => idx,
+            None if add => {
+                info!("{} 💾 Saved {} to known devices", NAME, addr);
+                devices.push(KnownDevice::new(addr));
+                devices.len() - 1
+            }
+            None => return (false, ()),
+        };
+        let device = &mut devices[idx];
+        device.last_connected = Some(now());
+        device.sessions = device.sessions.saturating_add(1);
+        if device.name.is_none() {
+            device.name = bt_name;
+        }
+        (true, ())
+    });
+    if let Err(e) = result {
+        warn!("{} failed to record session of {}: {}", NAME, addr, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(mac: &str, priority: i32, last_connected: Option<u64>) -> KnownDevice {
+        KnownDevice {
+            priority,
+            last_connected,
+            ..KnownDevice::new(mac.parse().unwrap())
+        }
+    }
+
+    #[test]
+    fn legacy_file_is_migrated_in_order() {
+        let devices =
+            parse("AA:BB:CC:DD:EE:01\n\nnot-a-mac\nAA:BB:CC:DD:EE:02\nAA:BB:CC:DD:EE:01\n");
+        let macs: Vec<&str> = devices.iter().map(|d| d.mac.as_str()).collect();
+        assert_eq!(macs, ["AA:BB:CC:DD:EE:01", "AA:BB:CC:DD:EE:02"]);
+        assert!(devices.iter().all(|d| d.auto_connect));
+
+        let json = serde_json::to_string(&devices).unwrap();
+        assert_eq!(parse(&json), devices);
+    }
+
+    #[test]
+    fn connect_order_prefers_priority_then_recent_use() {
+        let mut disabled = device("AA:BB:CC:DD:EE:05", 10, Some(500));
+        disabled.auto_connect = false;
+        let order = connect_order(vec![
+            device("AA:BB:CC:DD:EE:01", 0, None),
+            device("AA:BB:CC:DD:EE:02", 0, Some(100)),
+            device("AA:BB:CC:DD:EE:03", 1, Some(50)),
+            device("AA:BB:CC:DD:EE:04", 0, Some(200)),
+            disabled,
+        ]);
+        let order: Vec<String> = order.iter().map(|a| a.to_string()).collect();
+        assert_eq!(
+            order,
+            [
+                "AA:BB:CC:DD:EE:03",
+                "AA:BB:CC:DD:EE:04",
+                "AA:BB:CC:DD:EE:02",
+                "AA:BB:CC:DD:EE:01",
+            ]
+        );
+    }
+
+    #[test]
+    fn view_hides_the_wifi_passphrase() {
+        let mut known = device("AA:BB:CC:DD:EE:01", 0, None);
+        known.wifi_psk = Some("secret-passphrase".to_string());
+        let json = serde_json::to_string(&KnownDeviceView::from(known)).unwrap();
+        assert!(!json.contains("secret-passphrase"));
+        assert!(json.contains("\"has_wifi_psk\":true"));
+        assert!(json.contains("\"mac\":\"AA:BB:CC:DD:EE:01\""));
+    }
+}
@@ -1,24 +1,43 @@
 pub mod aoa;
+pub mod audio_inject;
 pub mod bluetooth;
 pub mod bt_helper;
+pub mod bt_pairing;
 pub mod bt_sco;
 pub mod bt_sco_echo;
 pub mod bt_sco_media_bridge;
+pub mod bt_sco_msbc;
+pub mod bt_sco_tap;
+pub mod bt_sco_voice;
 pub mod btle;
 pub mod button;
+pub mod channel_survey;
 pub mod config;
 pub mod config_types;
 pub mod crash;
+pub mod demo;
 pub mod device_info;
 pub mod display;
 pub mod ev;
+pub mod handover;
+pub mod hfp;
+pub mod hostapd;
 pub mod hu_input;
+pub mod hu_wireless;
 pub mod io_uring;
+pub mod known_devices;
 pub mod led;
+pub mod media_record;
+pub mod media_stats;
 pub mod media_tap;
+pub mod mic_inject;
+pub mod mirror;
 pub mod mitm;
 pub mod mitm_prettyprint;
 pub mod mpegts;
+pub mod multi_phone;
+pub mod pcm_sink;
+pub mod resampler;
 #[cfg(feature = "wasm-scripting")]
 pub mod script_wasm;
 pub mod sdr_ui;
@@ -28,3 +47,6 @@ pub mod vendor_ext;
 #[cfg(feature = "wasm-scripting")]
 pub mod wasm_config;
 pub mod web;
+pub mod wifi_link;
+pub mod wifi_security;
+pub mod wpa_supplicant;
@@ -1,17 +1,25 @@
+use aa_proxy_rs::audio_inject;
 use aa_proxy_rs::bluetooth;
+use aa_proxy_rs::bt_pairing;
 use aa_proxy_rs::bt_sco::{self, BtScoOptions};
 use aa_proxy_rs::bt_sco_echo::BtScoEchoSettings;
+use aa_proxy_rs::bt_sco_voice::BtScoVoiceSettings;
 use aa_proxy_rs::button::button_handler;
+use aa_proxy_rs::channel_survey;
 use aa_proxy_rs::config::SharedConfig;
 use aa_proxy_rs::config::SharedConfigJson;
+use aa_proxy_rs::config::SharedWifiConfig;
 use aa_proxy_rs::config::WifiConfig;
-use aa_proxy_rs::config::{Action, AppConfig};
+use aa_proxy_rs::config::{Action, AppConfig, BtScoCodec, WifiMode};
 use aa_proxy_rs::config::{DEFAULT_WLAN_ADDR, TCP_SERVER_PORT};
 use aa_proxy_rs::crash;
 use aa_proxy_rs::device_info;
 use aa_proxy_rs::ev::BatteryData;
+use aa_proxy_rs::hfp::{self, HfpOptions};
+use aa_proxy_rs::hostapd;
 use aa_proxy_rs::io_uring::io_loop;
 use aa_proxy_rs::led::{LedColor, LedManager, LedMode};
+use aa_proxy_rs::media_tap::SharedMediaSinks;
 use aa_proxy_rs::mitm::send_byebye;
 use aa_proxy_rs::mitm::OdometerData;
 use aa_proxy_rs::mitm::Packet;
@@ -29,12 +37,15 @@ use aa_proxy_rs::usb_gadget::uevent_listener;
 use aa_proxy_rs::usb_gadget::UsbGadgetState;
 use aa_proxy_rs::web;
 use aa_proxy_rs::web::ServerEvent;
+use aa_proxy_rs::wifi_security;
+use aa_proxy_rs::wpa_supplicant::{self, StationOptions, StationStatus};
 use clap::Parser;
 use humantime::format_duration;
 use simplelog::*;
 use std::os::unix::fs::PermissionsExt;
 use time::macros::format_description;
 
+use std::collections::HashMap;
 use std::fs;
 use std::fs::OpenOptions;
 use std::path::PathBuf;
@@ -63,6 +74,7 @@ type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>
 const NAME: &str = "<i><bright-black> main: </>";
 const HOSTAPD_CONF_IN: &str = "/etc/hostapd.conf.in";
 const HOSTAPD_CONF_OUT: &str = "/var/run/hostapd.conf";
+const STATION_CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
 const UMTPRD_CONF_IN: &str = "/etc/umtprd/umtprd.conf.in";
 const UMTPRD_CONF_OUT: &str = "/var/run/umtprd.conf";
 const GADGET_INIT_IN: &str = "/etc/S92usb_gadget.in";
@@ -124,9 +136,45 @@ fn init_wifi_config(cfg: &AppConfig) -> Result<WifiConfig> {
         ssid: cfg.ssid.clone(),
         bssid,
         wpa_key: cfg.wpa_passphrase.clone(),
+        security: wifi_security::effective_security(cfg.wifi_security, cfg.wifi_per_phone_psk),
+        per_phone_psk: cfg.wifi_per_phone_psk,
+        station: false,
     })
 }
 
+fn station_options(cfg: &AppConfig) -> StationOptions {
+    StationOptions {
+        ctrl_dir: cfg.wpa_supplicant_ctrl_dir.clone(),
+        iface: cfg.iface.clone(),
+        ssid: cfg.sta_ssid.clone(),
+        passphrase: cfg.sta_passphrase.clone(),
+        timeout: STATION_CONNECT_TIMEOUT,
+    }
+}
+
+/// Station mode: the phone joins the same network, so announce what
+/// wpa_supplicant actually got instead of our AP defaults.
+fn station_wifi_config(cfg: &AppConfig, status: &StationStatus) -> Result<WifiConfig> {
+    Ok(WifiConfig {
+        ip_addr: status
+            .ip_address
+            .clone()
+            .ok_or("station has no IPv4 address")?,
+        port: TCP_SERVER_PORT,
+        ssid: status.ssid.clone().unwrap_or_else(|| cfg.sta_ssid.clone()),
+        bssid: status.bssid.clone().ok_or("station is not associated")?,
+        wpa_key: cfg.sta_passphrase.clone(),
+        security: status.security().unwrap_or_default(),
+        per_phone_psk: false,
+        station: true,
+    })
+}
+
+async fn init_station_wifi_config(cfg: &AppConfig) -> Result<WifiConfig> {
+    let status = wpa_supplicant::connect(&station_options(cfg)).await?;
+    station_wifi_config(cfg, &status)
+}
+
 fn logging_init(debug: bool, disable_console_debug: bool, log_path: &PathBuf) {
     let conf = ConfigBuilder::new()
         .set_time_format_custom(format_description!(
@@ -235,6 +283,21 @@ async fn clean_disconnect_and_exit(
         tokio::time::sleep(Duration::from_millis(50)).await;
     }
 
+    // Kick all phones off the AP, otherwise they stay associated to a hotspot
+    // without Android Auto and don't fall back to their regular Wi-Fi.
+    if config.read().await.wifi_mode == WifiMode::Ap {
+        match hostapd::deauthenticate_all().await {
+            Ok(count) => info!(
+                "{} {}: deauthenticated {} Wi-Fi client(s)",
+                NAME, reason, count
+            ),
+            Err(e) => warn!(
+                "{} {}: Wi-Fi clients not deauthenticated: {}",
+                NAME, reason, e
+            ),
+        }
+    }
+
     info!("{} {}: exiting process after teardown", NAME, reason);
     std::process::exit(0);
 }
@@ -259,9 +322,11 @@ async fn tokio_main(
     usb_connected: Arc<AtomicBool>,
     ws_event_tx: broadcast::Sender<ServerEvent>,
     script_registry: Option<Arc<ScriptRegistry>>,
+    media_sinks: SharedMediaSinks,
 ) -> Result<()> {
     let accessory_started = Arc::new(Notify::new());
     let accessory_started_cloned = accessory_started.clone();
+    let wifi_config: SharedWifiConfig = Arc::new(RwLock::new(None));
     let state = web::AppState {
         config: config.clone(),
         config_json: config_json.clone(),
@@ -276,6 +341,8 @@ async fn tokio_main(
         last_tire_pressure_data,
         ws_event_tx,
         script_registry,
+        media_sinks,
+        wifi_config: wifi_config.clone(),
     };
 
     // Handle process-exit signals with a protocol-clean teardown.
@@ -340,6 +407,25 @@ async fn tokio_main(
             cfg.aa_server_tcp_addr.trim()
         );
     }
+    if cfg.wireless_hu {
+        info!(
+            "{} 🚘 wireless head unit mode: connecting to the car as the phone",
+            NAME
+        );
+        if cfg.wired.is_none() && !aa_server_tcp_enabled {
+            warn!(
+                "{} wireless_hu needs the phone on USB (wired) or aa_server_tcp_addr",
+                NAME
+            );
+        }
+    }
+    if let Some(recording) = &cfg.demo_phone {
+        info!(
+            "{} 🎬 demo phone mode: playing <u>{}</u> to the head unit",
+            NAME,
+            recording.display()
+        );
+    }
     let bt_sco_enabled = cfg.bt_sco || cfg.bt_sco_media_bridge || cfg.bt_sco_mic_bridge;
 
     if bt_sco_enabled {
@@ -355,7 +441,20 @@ async fn tokio_main(
                 duck_threshold: cfg.bt_sco_mic_duck_threshold,
                 duck_percent: cfg.bt_sco_mic_duck_percent,
                 duck_hold_ms: cfg.bt_sco_mic_duck_hold_ms,
+                aec_filter_ms: cfg.bt_sco_mic_aec_filter_ms,
+                aec_max_delay_ms: cfg.bt_sco_mic_aec_max_delay_ms,
+            },
+            voice_settings: BtScoVoiceSettings {
+                noise_suppression: cfg.bt_sco_mic_noise_suppression,
+                ns_strength_percent: cfg.bt_sco_mic_ns_strength_percent,
+                agc: cfg.bt_sco_mic_agc,
+                agc_target_dbfs: cfg.bt_sco_mic_agc_target_dbfs,
+                agc_max_gain_db: cfg.bt_sco_mic_agc_max_gain_db,
             },
+            codec: cfg.bt_sco_codec,
+            record_dir: cfg
+                .bt_sco_record_calls
+                .then(|| cfg.media_record_dir.clone()),
         }) {
             Ok(_) => {
                 info!(
@@ -369,6 +468,14 @@ async fn tokio_main(
         }
     }
 
+    // audio injection requests published on the ws topic (web clients, WASM scripts)
+    audio_inject::set_inject_dir(cfg.audio_inject_dir.clone());
+    tokio::spawn(audio_inject::ws_listener(state.ws_event_tx.clone()));
+    // HFP call indicators and call-control commands on the ws topic
+    tokio::spawn(hfp::ws_listener(state.ws_event_tx.clone()));
+    // pairing requests of the BlueZ agent and their answers
+    tokio::spawn(bt_pairing::ws_listener(state.ws_event_tx.clone()));
+
     if let Some(ref bindaddr) = cfg.webserver {
         // preparing AppState and starting webserver
         let app = web::app(state.clone().into());
@@ -392,14 +499,19 @@ async fn tokio_main(
         }
     }
 
-    let wifi_config = init_wifi_config(&cfg)
-        .map_err(|e| {
-            error!("{} WiFi config init failed: {}", NAME, e);
-            e
-        })
-        .ok();
+    // in wireless HU mode the interface joins the car's AP during io_loop,
+    // in station mode the main connection loop joins the network
+    let station_mode = cfg.wifi_mode == WifiMode::Station && !cfg.wireless_hu;
+    if !station_mode {
+        *wifi_config.write().await = init_wifi_config(&cfg)
+            .map_err(|e| {
+                error!("{} WiFi config init failed: {}", NAME, e);
+                e
+            })
+            .ok();
+    }
     let mut usb = None;
-    if !cfg.dhu {
+    if !cfg.dhu && !cfg.wireless_hu {
         if cfg.legacy {
             // start uevent listener in own task
             std::thread::spawn(|| uevent_listener(accessory_started_cloned));
@@ -433,9 +545,28 @@ async fn tokio_main(
             "{} 🛰️ Skipping Bluetooth AA setup because aa_server_tcp_addr is set",
             NAME
         );
+    } else if cfg.wireless_hu {
+        // the head unit bootstrap registers the AA Wireless profile itself
+        info!(
+            "{} 🚘 Skipping phone-side Bluetooth AA setup in wireless head unit mode",
+            NAME
+        );
+    } else if cfg.demo_phone.is_some() {
+        info!(
+            "{} 🎬 Skipping phone-side Bluetooth AA setup in demo phone mode",
+            NAME
+        );
     } else {
         loop {
-            match bluetooth::init(cfg.btalias.clone(), cfg.advertise, cfg.dongle_mode).await {
+            match bluetooth::init(
+                cfg.btalias.clone(),
+                cfg.advertise,
+                cfg.dongle_mode,
+                cfg.bt_pairing_agent
+                    .then(|| Duration::from_secs(cfg.bt_pairing_timeout_secs.into())),
+            )
+            .await
+            {
                 Ok(result) => {
                     bluetooth = Some(result);
                     break;
@@ -460,6 +591,23 @@ async fn tokio_main(
     let change_usb_order = cfg.change_usb_order;
     let mut need_restart = restart_tx.subscribe();
     loop {
+        if station_mode {
+            // pick up a new BSSID after roaming or a new DHCP lease, (re)join
+            // when the network was lost or is not in range yet
+            let refreshed =
+                match wpa_supplicant::status(&cfg.wpa_supplicant_ctrl_dir, &cfg.iface).await {
+                    Ok(status) if status.is_connected() => station_wifi_config(&cfg, &status),
+                    _ => init_station_wifi_config(&cfg).await,
+                };
+            match refreshed {
+                Ok(config) => *wifi_config.write().await = Some(config),
+                Err(e) => {
+                    error!("{} 🛜 station mode: {}", NAME, e);
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                    continue;
+                }
+            }
+        }
         if let Some(ref mut leds) = led_manager {
             leds.set_led(LedColor::Green, LedMode::Heartbeat).await;
         }
@@ -475,33 +623,35 @@ async fn tokio_main(
 
         // run only if not handling this in handshake task
         let aa_server_tcp_enabled = !cfg.aa_server_tcp_addr.trim().is_empty();
-        if aa_server_tcp_enabled {
+        let bt_handshake = !aa_server_tcp_enabled && !cfg.wireless_hu && cfg.demo_phone.is_none();
+        if bt_handshake && !station_mode {
+            // the AP SSID/passphrase can be changed at runtime from the web UI
+            match init_wifi_config(&cfg) {
+                Ok(config) => *wifi_config.write().await = Some(config),
+                Err(e) => error!("{} WiFi config refresh failed: {}", NAME, e),
+            }
+        }
+        if !bt_handshake {
             // Direct MD TCP mode does not use the Bluetooth/Wi-Fi AA handshake.
             // io_loop will connect to aa_server_tcp_addr after the HU/DHU side is ready.
-        } else if let Some(ref wifi_conf) = wifi_config {
+            // In wireless HU mode the phone is wired and io_loop bootstraps the HU.
+            // A demo phone needs no phone at all.
+        } else if wifi_config.read().await.is_some() {
             if !usb_connected.load(Ordering::Relaxed)
                 && (!(cfg.quick_reconnect && profile_connected.load(Ordering::Relaxed))
                     || cfg.action_requested == Some(Action::Stop))
             {
                 if let Some(ref mut bluetooth) = bluetooth {
                     // bluetooth handshake
-                    if let Err(e) = bluetooth
-                        .aa_handshake(
-                            cfg.connect.clone(),
-                            wifi_conf.clone(),
-                            tcp_start.clone(),
-                            Duration::from_secs(cfg.bt_timeout_secs.into()),
-                            Duration::from_secs(cfg.bt_connect_timeout_secs.into()),
-                            cfg.action_requested == Some(Action::Stop),
-                            cfg.quick_reconnect,
-                            cfg.bt_poweroff,
-                            cfg.bt_sco || cfg.bt_sco_media_bridge || cfg.bt_sco_mic_bridge,
-                            cfg.bt_sco_keep_bluetooth_alive,
-                            restart_tx.subscribe(),
-                            restart_tx.clone(),
-                            profile_connected.clone(),
-                        )
-                        .await
+                    if let Err(e) = bt_aa_handshake(
+                        bluetooth,
+                        &cfg,
+                        &wifi_config,
+                        tcp_start.clone(),
+                        &restart_tx,
+                        profile_connected.clone(),
+                    )
+                    .await
                     {
                         error!("{} bluetooth AA handshake error: {}", NAME, e);
                         tokio::time::sleep(std::time::Duration::from_secs(1)).await;
@@ -526,8 +676,55 @@ async fn tokio_main(
         if let Some(ref mut leds) = led_manager {
             leds.set_led(LedColor::Blue, LedMode::On).await;
         }
+        // with multi_phone another phone may bootstrap while one is projected
+        let standby = cfg.multi_phone
+            && cfg.mitm
+            && !aa_server_tcp_enabled
+            && !cfg.wireless_hu
+            && cfg.demo_phone.is_none()
+            && !cfg.bt_poweroff
+            && !usb_connected.load(Ordering::Relaxed);
         // wait for restart notification
-        let _ = need_restart.recv().await;
+        loop {
+            let have_wifi_config = wifi_config.read().await.is_some();
+            let action = match &mut bluetooth {
+                Some(bluetooth) if standby && have_wifi_config => tokio::select! {
+                    action = need_restart.recv() => action,
+                    res = bt_standby_handshake(bluetooth, &cfg, &wifi_config, tcp_start.clone(), &restart_tx) => {
+                        if let Err(e) = res {
+                            debug!("{} standby bluetooth handshake: {}", NAME, e);
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                        }
+                        continue;
+                    }
+                },
+                _ => need_restart.recv().await,
+            };
+            if !matches!(action, Ok(Some(Action::Handover))) {
+                break;
+            }
+            // the HU session is held by io_loop, only bring the phone back
+            let have_wifi_config = wifi_config.read().await.is_some();
+            if let (true, Some(bluetooth)) = (have_wifi_config, &mut bluetooth) {
+                if !usb_connected.load(Ordering::Relaxed)
+                    && !(cfg.quick_reconnect && profile_connected.load(Ordering::Relaxed))
+                {
+                    info!("{} 🔁 phone lost, redoing the Bluetooth handshake", NAME);
+                    if let Err(e) = bt_aa_handshake(
+                        bluetooth,
+                        &cfg,
+                        &wifi_config,
+                        tcp_start.clone(),
+                        &restart_tx,
+                        profile_connected.clone(),
+                    )
+                    .await
+                    {
+                        error!("{} bluetooth AA handshake error: {}", NAME, e);
+                    }
+                }
+            }
+        }
         if !(cfg.quick_reconnect && profile_connected.load(Ordering::Relaxed)) {
             info!(
                 "{} 📵 TCP/USB connection closed or not started, trying again...",
@@ -547,6 +744,65 @@ async fn tokio_main(
     }
 }
 
+async fn bt_aa_handshake(
+    bluetooth: &mut bluetooth::Bluetooth,
+    cfg: &AppConfig,
+    wifi_config: &SharedWifiConfig,
+    tcp_start: Arc<Notify>,
+    restart_tx: &BroadcastSender<Option<Action>>,
+    profile_connected: Arc<AtomicBool>,
+) -> Result<()> {
+    bluetooth
+        .aa_handshake(
+            cfg.connect.clone(),
+            wifi_config.clone(),
+            tcp_start,
+            Duration::from_secs(cfg.bt_timeout_secs.into()),
+            Duration::from_secs(cfg.bt_connect_timeout_secs.into()),
+            cfg.action_requested == Some(Action::Stop),
+            cfg.quick_reconnect,
+            cfg.bt_poweroff,
+            cfg.bt_sco || cfg.bt_sco_media_bridge || cfg.bt_sco_mic_bridge,
+            cfg.bt_sco_keep_bluetooth_alive,
+            cfg.bt_hfp.then(|| HfpOptions {
+                wideband: cfg.bt_sco_codec != BtScoCodec::Cvsd,
+            }),
+            restart_tx.subscribe(),
+            restart_tx.clone(),
+            profile_connected,
+        )
+        .await
+}
+
+/// Bootstrap a further phone while one is projected. Only waits for phones
+/// connecting by themselves and never keeps their Bluetooth link.
+async fn bt_standby_handshake(
+    bluetooth: &mut bluetooth::Bluetooth,
+    cfg: &AppConfig,
+    wifi_config: &SharedWifiConfig,
+    tcp_start: Arc<Notify>,
+    restart_tx: &BroadcastSender<Option<Action>>,
+) -> Result<()> {
+    bluetooth
+        .aa_handshake(
+            cfg.connect.clone(),
+            wifi_config.clone(),
+            tcp_start,
+            Duration::from_secs(cfg.bt_timeout_secs.into()),
+            Duration::from_secs(cfg.bt_connect_timeout_secs.into()),
+            true,
+            false,
+            false,
+            false,
+            false,
+            None,
+            restart_tx.subscribe(),
+            restart_tx.clone(),
+            Arc::new(AtomicBool::new(false)),
+        )
+        .await
+}
+
 /// Returns the full device serial number from Device Tree
 pub fn get_serial_number() -> Result<String> {
     Ok(
@@ -581,6 +837,15 @@ fn generate_hostapd_conf(config: AppConfig) -> std::io::Result<()> {
 
     let template = fs::read_to_string(HOSTAPD_CONF_IN)?;
 
+    // channel 0: pick one from a survey while hostapd is not running yet
+    let channel = if config.channel == 0 {
+        channel_survey::select_channel(&config.iface, &config.band, &config.country_code)
+    } else {
+        // don't show the outcome of an older survey
+        let _ = fs::remove_file(channel_survey::SURVEY_FILE);
+        config.channel
+    };
+
     // Eventually: For 6 GHz, we will need more options like opclass.
     let rendered = render_template(
         &template,
@@ -591,11 +856,26 @@ fn generate_hostapd_conf(config: AppConfig) -> std::io::Result<()> {
             ("AC_MODE", if config.wifi_version >= 5 { "1" } else { "0" }),
             ("N_MODE", if config.wifi_version >= 4 { "1" } else { "0" }),
             ("COUNTRY_CODE", &config.country_code),
-            ("CHANNEL", &config.channel.to_string()),
+            ("CHANNEL", &channel.to_string()),
             ("SSID", &config.ssid),
             ("WPA_PASSPHRASE", &config.wpa_passphrase),
         ],
     );
+    // key management is set here, so older templates without SAE placeholders
+    // still work
+    let mut options =
+        wifi_security::hostapd_options(config.wifi_security, config.wifi_per_phone_psk);
+    // control socket for runtime management (stations, channel, credentials)
+    options.push(("ctrl_interface", Some(config.hostapd_ctrl_dir.clone())));
+    let rendered = wifi_security::apply_hostapd_options(&rendered, &options);
+    if config.wifi_per_phone_psk {
+        info!(
+            "{} 🔐 Writing per-phone passphrases to: <bold><green>{}</>",
+            NAME,
+            wifi_security::WPA_PSK_FILE
+        );
+        wifi_security::write_psk_file()?;
+    }
 
     info!(
         "{} 💾 Saving generated file as: <bold><green>{}</>",
@@ -664,6 +944,7 @@ fn main() -> Result<()> {
     let config_json = AppConfig::load_config_json().expect("Invalid embedded config.json");
 
     crash::install_panic_handler(config.crash_dir.clone(), config.crash_handler_enabled);
+    hostapd::init(&config.hostapd_ctrl_dir, &config.iface);
 
     logging_init(config.debug, config.disable_console_debug, &config.logfile);
     info!(
@@ -792,6 +1073,8 @@ fn main() -> Result<()> {
     let usb_connected_cloned = usb_connected.clone();
     let (ws_event_tx, _ws_event_rx) = broadcast::channel(256);
     let ws_event_tx_cloned = ws_event_tx.clone();
+    let media_sinks: SharedMediaSinks = Arc::new(RwLock::new(HashMap::new()));
+    let media_sinks_cloned = media_sinks.clone();
 
     // build and spawn main tokio runtime
     let mut runtime = Builder::new_multi_thread().enable_all().build().unwrap();
@@ -839,6 +1122,7 @@ fn main() -> Result<()> {
             usb_connected_cloned,
             ws_event_tx_cloned,
             script_registry_cloned,
+            media_sinks_cloned,
         )
         .await
     });
@@ -857,6 +1141,7 @@ fn main() -> Result<()> {
         usb_connected,
         script_registry.clone(),
         ws_event_tx.clone(),
+        media_sinks,
     ));
 
     info!(
@@ -0,0 +1,276 @@
+use simplelog::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+
+use crate::media_tap::{MediaSink, MediaStreamKind};
+use crate::mitm::protos::MediaCodecType;
+
+const NAME: &str = "<i><bright-black> media-record: </>";
+const WAV_HEADER_LEN: u64 = 44;
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Minimal streaming PCM WAV writer. Sizes in the header are patched on
+/// `finalize()`, so an interrupted file is still readable by most tools.
+pub struct WavWriter {
+    file: File,
+    data_len: u64,
+}
+
+impl WavWriter {
+    pub fn create(path: &Path, sample_rate: u32, channels: u16, bits: u16) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        let block_align = channels * (bits / 8);
+        let byte_rate = sample_rate * block_align as u32;
+
+        let mut header = Vec::with_capacity(WAV_HEADER_LEN as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&u32::MAX.to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        header.extend_from_slice(&channels.to_le_bytes());
+        header.extend_from_slice(&sample_rate.to_le_bytes());
+        header.extend_from_slice(&byte_rate.to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&bits.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&u32::MAX.to_le_bytes());
+        file.write_all(&header)?;
+
+        Ok(Self { file, data_len: 0 })
+    }
+
+    pub fn write_pcm(&mut self, pcm: &[u8]) -> io::Result<()> {
+        self.file.write_all(pcm)?;
+        self.data_len += pcm.len() as u64;
+        Ok(())
+    }
+
+    pub fn data_len(&self) -> u64 {
+        self.data_len
+    }
+
+    pub fn finalize(mut self) -> io::Result<u64> {
+        let data_len = self.data_len.min(u32::MAX as u64 - WAV_HEADER_LEN) as u32;
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file
+            .write_all(&(data_len + WAV_HEADER_LEN as u32 - 8).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&data_len.to_le_bytes())?;
+        self.file.flush()?;
+        Ok(self.data_len)
+    }
+}
+
+struct ActiveRecording {
+    path: PathBuf,
+    cancel: CancellationToken,
+}
+
+static RECORDINGS: OnceLock<Mutex<HashMap<String, ActiveRecording>>> = OnceLock::new();
+
+fn recordings() -> &'static Mutex<HashMap<String, ActiveRecording>> {
+    RECORDINGS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Labels and output files of the recordings currently running.
+pub fn active_recordings() -> Vec<(String, PathBuf)> {
+    recordings()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(label, rec)| (label.clone(), rec.path.clone()))
+        .collect()
+}
+
+/// Register a recording written by another producer (e.g. the per-call SCO
+/// recorder), so it is listed and can be stopped like a sink recording.
+pub(crate) fn register_recording(label: &str, path: &Path) -> Result<CancellationToken> {
+    let mut active = recordings().lock().unwrap();
+    if active.contains_key(label) {
+        return Err(format!("{} is already being recorded", label).into());
+    }
+    let cancel = CancellationToken::new();
+    active.insert(
+        label.to_string(),
+        ActiveRecording {
+            path: path.to_path_buf(),
+            cancel: cancel.clone(),
+        },
+    );
+    Ok(cancel)
+}
+
+/// Remove a finished recording, unless the label was already reused.
+pub(crate) fn unregister_recording(label: &str, path: &Path) {
+    let mut active = recordings().lock().unwrap();
+    if active.get(label).is_some_and(|rec| rec.path == path) {
+        active.remove(label);
+    }
+}
+
+/// Start writing the PCM frames of an audio sink to a WAV file in `dir`.
+/// Returns the path of the new file.
+pub async fn start_wav_recording(label: &str, sink: MediaSink, dir: &Path) -> Result<PathBuf> {
+    let info = sink
+        .get_stream_info()
+        .await
+        .ok_or("stream not known yet (no ServiceDiscovery)")?;
+    let MediaStreamKind::Audio {
+        codec: MediaCodecType::MEDIA_CODEC_AUDIO_PCM,
+        ..
+    } = info.kind
+    else {
+        return Err("only PCM audio sinks can be recorded to WAV".into());
+    };
+    let cfg = info.audio_config.ok_or("missing PCM audio configuration")?;
+
+    if recordings().lock().unwrap().contains_key(label) {
+        return Err(format!("{} is already being recorded", label).into());
+    }
+
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!(
+        "{}-{}.wav",
+        label,
+        chrono::Local::now().format("%Y%m%d-%H%M%S")
+    ));
+    let mut writer =
+        WavWriter::create(&path, cfg.sample_rate, cfg.channels as u16, cfg.bits as u16)?;
+
+    let cancel = register_recording(label, &path)?;
+    info!(
+        "{} recording <b>{}</> ({}Hz, {}ch, {}bit) to {}",
+        NAME,
+        label,
+        cfg.sample_rate,
+        cfg.channels,
+        cfg.bits,
+        path.display()
+    );
+
+    let mut rx = sink.subscribe();
+    let label = label.to_string();
+    let task_path = path.clone();
+    // the WAV writer blocks on file I/O, only the receive is awaited
+    let runtime = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        loop {
+            let item = runtime.block_on(async {
+                tokio::select! {
+                    _ = cancel.cancelled() => None,
+                    item = rx.recv() => Some(item),
+                }
+            });
+            match item {
+                None => break,
+                Some(Ok(frame)) => {
+                    if frame.codec_config {
+                        continue;
+                    }
+                    if let Err(e) = writer.write_pcm(&frame.data) {
+                        error!("{} {}: write failed: {}", NAME, label, e);
+                        break;
+                    }
+                }
+                Some(Err(broadcast::error::RecvError::Lagged(n))) => {
+                    warn!(
+                        "{} {}: lagged by {} frames, gap in recording",
+                        NAME, label, n
+                    );
+                }
+                Some(Err(broadcast::error::RecvError::Closed)) => break,
+            }
+        }
+        unregister_recording(&label, &task_path);
+        match writer.finalize() {
+            Ok(bytes) => info!(
+                "{} {}: finished {} ({} bytes of PCM)",
+                NAME,
+                label,
+                task_path.display(),
+                bytes
+            ),
+            Err(e) => error!("{} {}: finalize failed: {}", NAME, label, e),
+        }
+    });
+
+    Ok(path)
+}
+
+/// Stop a running recording. Returns the file path if one was active.
+pub fn stop_recording(label: &str) -> Option<PathBuf> {
+    let rec = recordings().lock().unwrap().remove(label)?;
+    rec.cancel.cancel();
+    Some(rec.path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn le_u32(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap())
+    }
+
+    fn le_u16(bytes: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes(bytes[at..at + 2].try_into().unwrap())
+    }
+
+    fn temp_wav(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!(
+            "media-record-test-{}-{}.wav",
+            name,
+            std::process::id()
+        ))
+    }
+
+    #[test]
+    fn header_describes_the_pcm_format() {
+        let path = temp_wav("header");
+        let wav = WavWriter::create(&path, 48_000, 2, 16).unwrap();
+        assert_eq!(wav.data_len(), 0);
+        drop(wav);
+
+        let bytes = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(bytes.len() as u64, WAV_HEADER_LEN);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(le_u32(&bytes, 16), 16);
+        assert_eq!(le_u16(&bytes, 20), 1);
+        assert_eq!(le_u16(&bytes, 22), 2);
+        assert_eq!(le_u32(&bytes, 24), 48_000);
+        assert_eq!(le_u32(&bytes, 28), 48_000 * 4);
+        assert_eq!(le_u16(&bytes, 32), 4);
+        assert_eq!(le_u16(&bytes, 34), 16);
+        assert_eq!(&bytes[36..40], b"data");
+        // sizes stay open-ended until finalize
+        assert_eq!(le_u32(&bytes, 4), u32::MAX);
+        assert_eq!(le_u32(&bytes, 40), u32::MAX);
+    }
+
+    #[test]
+    fn finalize_patches_the_sizes() {
+        let path = temp_wav("finalize");
+        let mut wav = WavWriter::create(&path, 16_000, 1, 16).unwrap();
+        wav.write_pcm(&[1, 2, 3, 4]).unwrap();
+        wav.write_pcm(&[5, 6]).unwrap();
+        assert_eq!(wav.data_len(), 6);
+        assert_eq!(wav.finalize().unwrap(), 6);
+
+        let bytes = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(bytes.len() as u64, WAV_HEADER_LEN + 6);
+        assert_eq!(le_u32(&bytes, 4), WAV_HEADER_LEN as u32 - 8 + 6);
+        assert_eq!(le_u32(&bytes, 40), 6);
+        assert_eq!(&bytes[WAV_HEADER_LEN as usize..], &[1, 2, 3, 4, 5, 6]);
+    }
+}
@@ -0,0 +1,283 @@
+use serde::Serialize;
+use simplelog::*;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast::Sender as BroadcastSender;
+
+use crate::media_tap::{MediaStreamKind, SharedMediaSinks};
+use crate::web::ServerEvent;
+
+/// Rolling window used for rate/size/RTT metrics.
+const STATS_WINDOW: Duration = Duration::from_secs(5);
+/// Interval at which reports are published on the `media-stats` ws topic.
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(1);
+/// Upper bound of DATA messages waiting for an HU ACK; protects against
+/// a HU which stopped acknowledging (or ACKs we failed to observe).
+const MAX_PENDING_ACKS: usize = 256;
+
+struct FrameSample {
+    at: Instant,
+    pts_us: u64,
+    bytes: usize,
+    /// Unacked DATA messages right after this frame was sent.
+    unacked: usize,
+}
+
+/// Per-channel video statistics fed from the media tap and HU ACKs.
+#[derive(Default)]
+pub struct VideoStats {
+    frames: VecDeque<FrameSample>,
+    ack_rtts: VecDeque<(Instant, Duration)>,
+    /// Send time of every DATA message the HU has not acknowledged yet.
+    pending: VecDeque<Instant>,
+    max_unacked: Option<u32>,
+    last_idr_at: Option<Instant>,
+    frames_since_idr: u64,
+    idr_interval: Option<Duration>,
+    idr_interval_frames: Option<u64>,
+    total_frames: u64,
+    total_bytes: u64,
+    total_acks: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct VideoStatsReport {
+    pub window_ms: u64,
+    pub fps: f64,
+    pub bitrate_kbps: f64,
+    pub avg_frame_bytes: u64,
+    pub max_frame_bytes: u64,
+    pub idr_interval_ms: Option<u64>,
+    pub idr_interval_frames: Option<u64>,
+    pub ack_rtt_avg_ms: Option<f64>,
+    pub ack_rtt_max_ms: Option<f64>,
+    pub unacked: usize,
+    pub unacked_peak: usize,
+    pub max_unacked: Option<u32>,
+    pub last_pts_us: Option<u64>,
+    pub total_frames: u64,
+    pub total_bytes: u64,
+    pub total_acks: u64,
+}
+
+impl VideoStats {
+    /// Forget in-flight state at the start of a new phone session.
+    pub fn reset_session(&mut self) {
+        self.pending.clear();
+        self.max_unacked = None;
+        self.last_idr_at = None;
+        self.frames_since_idr = 0;
+    }
+
+    /// Record one `MEDIA_MESSAGE_DATA` sent by the phone. Messages sharing
+    /// a PTS are accounted as a single frame.
+    pub fn record_data(&mut self, now: Instant, pts_us: u64, bytes: usize, idr: bool) {
+        if self.pending.len() >= MAX_PENDING_ACKS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(now);
+        self.total_bytes += bytes as u64;
+
+        let unacked = self.pending.len();
+        match self.frames.back_mut() {
+            Some(last) if last.pts_us == pts_us => {
+                last.bytes += bytes;
+                last.unacked = last.unacked.max(unacked);
+            }
+            _ => {
+                if idr {
+                    if let Some(prev) = self.last_idr_at {
+                        self.idr_interval = Some(now.duration_since(prev));
+                        self.idr_interval_frames = Some(self.frames_since_idr);
+                    }
+                    self.last_idr_at = Some(now);
+                    self.frames_since_idr = 0;
+                }
+                self.frames_since_idr += 1;
+                self.total_frames += 1;
+                self.frames.push_back(FrameSample {
+                    at: now,
+                    pts_us,
+                    bytes,
+                    unacked,
+                });
+            }
+        }
+        self.prune(now);
+    }
+
+    /// Record an HU `Ack` acknowledging `count` DATA messages.
+    pub fn record_ack(&mut self, now: Instant, count: u32) {
+        for _ in 0..count.max(1) {
+            let Some(sent_at) = self.pending.pop_front() else {
+                break;
+            };
+            self.ack_rtts.push_back((now, now.duration_since(sent_at)));
+            self.total_acks += 1;
+        }
+        self.prune(now);
+    }
+
+    /// Record the `max_unacked` window granted by the HU in its media `Config`.
+    pub fn record_config(&mut self, max_unacked: u32) {
+        self.max_unacked = Some(max_unacked);
+    }
+
+    pub fn has_data(&self) -> bool {
+        self.total_frames > 0
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while self
+            .frames
+            .front()
+            .is_some_and(|f| now.duration_since(f.at) > STATS_WINDOW)
+        {
+            self.frames.pop_front();
+        }
+        while self
+            .ack_rtts
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > STATS_WINDOW)
+        {
+            self.ack_rtts.pop_front();
+        }
+    }
+
+    pub fn report(&mut self, now: Instant) -> VideoStatsReport {
+        self.prune(now);
+
+        let span = self
+            .frames
+            .front()
+            .map(|f| now.duration_since(f.at))
+            .unwrap_or(STATS_WINDOW)
+            .clamp(Duration::from_secs(1), STATS_WINDOW)
+            .as_secs_f64();
+        let window_bytes: usize = self.frames.iter().map(|f| f.bytes).sum();
+        let frame_count = self.frames.len();
+
+        let (ack_rtt_avg_ms, ack_rtt_max_ms) = if self.ack_rtts.is_empty() {
+            (None, None)
+        } else {
+            let sum: Duration = self.ack_rtts.iter().map(|(_, rtt)| *rtt).sum();
+            let max = self.ack_rtts.iter().map(|(_, rtt)| *rtt).max().unwrap();
+            (
+                Some(sum.as_secs_f64() * 1000.0 / self.ack_rtts.len() as f64),
+                Some(max.as_secs_f64() * 1000.0),
+            )
+        };
+
+        VideoStatsReport {
+            window_ms: STATS_WINDOW.as_millis() as u64,
+            fps: frame_count as f64 / span,
+            bitrate_kbps: window_bytes as f64 * 8.0 / 1000.0 / span,
+            avg_frame_bytes: if frame_count > 0 {
+                (window_bytes / frame_count) as u64
+            } else {
+                0
+            },
+            max_frame_bytes: self.frames.iter().map(|f| f.bytes).max().unwrap_or(0) as u64,
+            idr_interval_ms: self.idr_interval.map(|d| d.as_millis() as u64),
+            idr_interval_frames: self.idr_interval_frames,
+            ack_rtt_avg_ms,
+            ack_rtt_max_ms,
+            unacked: self.pending.len(),
+            unacked_peak: self.frames.iter().map(|f| f.unacked).max().unwrap_or(0),
+            max_unacked: self.max_unacked,
+            last_pts_us: self.frames.back().map(|f| f.pts_us),
+            total_frames: self.total_frames,
+            total_bytes: self.total_bytes,
+            total_acks: self.total_acks,
+        }
+    }
+}
+
+/// Collect a report for every video sink which has seen at least one frame.
+pub async fn collect_video_stats(sinks: &SharedMediaSinks) -> BTreeMap<String, VideoStatsReport> {
+    let sinks = sinks.read().await.clone();
+    let now = Instant::now();
+    let mut reports = BTreeMap::new();
+    for (label, sink) in sinks {
+        let is_video = matches!(
+            sink.get_stream_info().await.map(|info| info.kind),
+            Some(MediaStreamKind::Video { .. })
+        );
+        if !is_video {
+            continue;
+        }
+        if let Some(report) = sink.video_stats_report(now) {
+            reports.insert(label, report);
+        }
+    }
+    reports
+}
+
+/// Periodically publish video statistics on the `media-stats` ws topic.
+pub async fn media_stats_publisher(
+    sinks: SharedMediaSinks,
+    ws_event_tx: BroadcastSender<ServerEvent>,
+) {
+    let mut ticker = tokio::time::interval(PUBLISH_INTERVAL);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    loop {
+        ticker.tick().await;
+        let reports = collect_video_stats(&sinks).await;
+        if reports.is_empty() {
+            continue;
+        }
+        match serde_json::to_string(&reports) {
+            Ok(payload) => {
+                let _ = ws_event_tx.send(ServerEvent {
+                    topic: "media-stats".to_string(),
+                    payload,
+                });
+            }
+            Err(e) => {
+                warn!("media stats: failed to serialize report: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn data_messages_with_same_pts_form_one_frame() {
+        let mut stats = VideoStats::default();
+        let t0 = Instant::now();
+        stats.record_data(t0, 1_000, 4_000, true);
+        stats.record_data(t0, 1_000, 1_000, false);
+        stats.record_data(t0 + Duration::from_millis(33), 34_000, 500, false);
+
+        let report = stats.report(t0 + Duration::from_millis(40));
+        assert_eq!(report.total_frames, 2);
+        assert_eq!(report.total_bytes, 5_500);
+        assert_eq!(report.max_frame_bytes, 5_000);
+        assert_eq!(report.unacked, 3);
+        assert_eq!(report.last_pts_us, Some(34_000));
+    }
+
+    #[test]
+    fn acks_measure_rtt_and_idr_interval() {
+        let mut stats = VideoStats::default();
+        let t0 = Instant::now();
+        stats.record_config(2);
+        stats.record_data(t0, 0, 100, true);
+        stats.record_data(t0 + Duration::from_millis(10), 10_000, 100, false);
+        stats.record_ack(t0 + Duration::from_millis(20), 1);
+        stats.record_data(t0 + Duration::from_millis(500), 500_000, 100, true);
+        stats.record_ack(t0 + Duration::from_millis(520), 2);
+
+        let report = stats.report(t0 + Duration::from_millis(600));
+        assert_eq!(report.unacked, 0);
+        assert_eq!(report.total_acks, 3);
+        assert_eq!(report.max_unacked, Some(2));
+        assert_eq!(report.idr_interval_ms, Some(500));
+        assert_eq!(report.idr_interval_frames, Some(2));
+        assert_eq!(report.ack_rtt_max_ms.map(|ms| ms.round()), Some(510.0));
+        assert_eq!(report.unacked_peak, 2);
+    }
+}
@@ -8,8 +8,9 @@ use tokio::io::AsyncWriteExt;
 use tokio::net::TcpListener;
 use tokio::sync::broadcast;
 
+use crate::media_stats::{VideoStats, VideoStatsReport};
 use crate::mitm::protos;
-use crate::mitm::protos::{AudioStreamType, DisplayType, MediaCodecType};
+use crate::mitm::protos::{AudioStreamType, DisplayType, MediaCodecType, VideoCodecResolutionType};
 use crate::mitm::{Packet, ProxyType, FRAME_TYPE_FIRST, FRAME_TYPE_LAST, FRAME_TYPE_MASK};
 use crate::mpegts::{MpegTsState, TsStreamKind};
 
@@ -28,7 +29,8 @@ pub enum MediaStreamKind {
     },
     Audio {
         codec: MediaCodecType,
-        audio_type: AudioStreamType,
+        /// `None` for the HU microphone (media source) stream.
+        audio_type: Option<AudioStreamType>,
     },
 }
 
@@ -36,20 +38,71 @@ pub enum MediaStreamKind {
 pub struct MediaStreamInfo {
     pub kind: MediaStreamKind,
     pub audio_config: Option<AudioStreamConfig>,
+    /// Resolution of the video configuration negotiated with the HU, the
+    /// first one advertised in ServiceDiscovery until then.
+    pub video_resolution: Option<VideoCodecResolutionType>,
+}
+
+/// Upper bounds for the per-sink GOP cache. When a GOP grows beyond these
+/// limits the cache is dropped until the next IDR arrives.
+const GOP_CACHE_MAX_FRAMES: usize = 600;
+const GOP_CACHE_MAX_BYTES: usize = 8 * 1024 * 1024;
+
+/// Frames of the current GOP: the last IDR and every frame received after it.
+#[derive(Default)]
+struct GopCache {
+    frames: Vec<(u64, Vec<u8>)>,
+    bytes: usize,
+}
+
+/// Decodable still-frame snapshot of a video sink.
+pub struct MediaSnapshot {
+    /// Annex-B elementary stream: codec config + IDR + following frames.
+    pub data: Vec<u8>,
+    pub codec: MediaCodecType,
+    pub resolution: Option<VideoCodecResolutionType>,
+    /// PTS of the IDR the snapshot starts with.
+    pub idr_pts_us: u64,
+    /// PTS of the most recent frame included in the snapshot.
+    pub pts_us: u64,
+    pub frames: usize,
+}
+
+/// Tap port offset of the HU microphone (media source) sink; 0-6 are used by
+/// the media sink services (video by display type, audio by audio type + 2).
+pub const MIC_SINK_OFFSET: u8 = 7;
+/// Tap port offsets of the Bluetooth SCO call audio (phone -> car, car -> phone).
+pub const SCO_DOWNLINK_SINK_OFFSET: u8 = 8;
+pub const SCO_UPLINK_SINK_OFFSET: u8 = 9;
+
+/// Media sinks by label (e.g. `video-main`), shared with the web server.
+pub type SharedMediaSinks = Arc<tokio::sync::RwLock<HashMap<String, MediaSink>>>;
+
+/// One item broadcast by a [`MediaSink`].
+pub struct MediaFrame {
+    pub pts_us: u64,
+    pub data: Vec<u8>,
+    /// Codec config (e.g. SPS/PPS) rather than media samples.
+    pub codec_config: bool,
 }
 
 /// Broadcast-based sink for tapping a single media channel over TCP.
 #[derive(Clone)]
 pub struct MediaSink {
-    /// Each broadcast item is `(pts_us, data)`. For codec-config frames the
-    /// pts_us field is 0 and `codec_cfg` is also populated.
-    tx: broadcast::Sender<Arc<(u64, Vec<u8>)>>,
+    /// Codec-config frames are also cached in `codec_cfg`.
+    tx: broadcast::Sender<Arc<MediaFrame>>,
     /// Cached codec config frame sent to every new client on connect.
     codec_cfg: Arc<tokio::sync::Mutex<Option<Arc<Vec<u8>>>>>,
     /// Stream metadata learned from ServiceDiscovery.
     stream_info: Arc<tokio::sync::Mutex<Option<MediaStreamInfo>>>,
+    /// Advertised video configurations, by configuration index.
+    video_configs: Arc<tokio::sync::Mutex<Vec<VideoCodecResolutionType>>>,
     /// Monotonic counter bumped each time a TCP client connects to this sink.
     client_connect_gen: Arc<AtomicU64>,
+    /// Last IDR and the frames following it, used for still-frame snapshots.
+    gop: Arc<tokio::sync::Mutex<GopCache>>,
+    /// Rate, size and ACK statistics for video channels.
+    stats: Arc<std::sync::Mutex<VideoStats>>,
 }
 
 impl MediaSink {
@@ -59,30 +112,56 @@ impl MediaSink {
             tx,
             codec_cfg: Arc::new(tokio::sync::Mutex::new(None)),
             stream_info: Arc::new(tokio::sync::Mutex::new(None)),
+            video_configs: Arc::new(tokio::sync::Mutex::new(Vec::new())),
             client_connect_gen: Arc::new(AtomicU64::new(0)),
+            gop: Arc::new(tokio::sync::Mutex::new(GopCache::default())),
+            stats: Arc::new(std::sync::Mutex::new(VideoStats::default())),
         }
     }
 
-    pub async fn set_video_stream_info(&self, codec: MediaCodecType, display_type: DisplayType) {
+    pub async fn set_video_stream_info(
+        &self,
+        codec: MediaCodecType,
+        display_type: DisplayType,
+        video_configs: Vec<VideoCodecResolutionType>,
+    ) {
         *self.stream_info.lock().await = Some(MediaStreamInfo {
             kind: MediaStreamKind::Video {
                 codec,
                 display_type,
             },
             audio_config: None,
+            video_resolution: video_configs.first().copied(),
         });
+        *self.video_configs.lock().await = video_configs;
+        self.stats.lock().unwrap().reset_session();
+        // frames of the previous session do not decode with the new config
+        *self.gop.lock().await = GopCache::default();
+    }
+
+    /// Take the resolution of advertised video configuration `index`, as
+    /// chosen by the HU's media config or the phone's media start.
+    pub async fn select_video_config(&self, index: u32) {
+        let Some(resolution) = self.video_configs.lock().await.get(index as usize).copied() else {
+            return;
+        };
+        if let Some(info) = self.stream_info.lock().await.as_mut() {
+            info.video_resolution = Some(resolution);
+        }
     }
 
     pub async fn set_audio_stream_info(
         &self,
         codec: MediaCodecType,
-        audio_type: AudioStreamType,
+        audio_type: Option<AudioStreamType>,
         audio_config: Option<AudioStreamConfig>,
     ) {
         *self.stream_info.lock().await = Some(MediaStreamInfo {
             kind: MediaStreamKind::Audio { codec, audio_type },
             audio_config,
+            video_resolution: None,
         });
+        self.video_configs.lock().await.clear();
     }
 
     pub async fn get_stream_info(&self) -> Option<MediaStreamInfo> {
@@ -92,14 +171,23 @@ impl MediaSink {
     pub async fn send_codec_config(&self, data: Vec<u8>) {
         let buf = Arc::new(data);
         *self.codec_cfg.lock().await = Some(buf.clone());
-        let _ = self.tx.send(Arc::new((0u64, buf.as_ref().clone())));
+        let _ = self.tx.send(Arc::new(MediaFrame {
+            pts_us: 0,
+            data: buf.as_ref().clone(),
+            codec_config: true,
+        }));
     }
 
-    pub async fn send_frame(&self, pts_us: u64, data: Vec<u8>) {
-        let _ = self.tx.send(Arc::new((pts_us, data)));
+    /// Broadcasting never waits, producers on plain threads use it as well.
+    pub fn send_frame(&self, pts_us: u64, data: Vec<u8>) {
+        let _ = self.tx.send(Arc::new(MediaFrame {
+            pts_us,
+            data,
+            codec_config: false,
+        }));
     }
 
-    pub fn subscribe(&self) -> broadcast::Receiver<Arc<(u64, Vec<u8>)>> {
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<MediaFrame>> {
         self.tx.subscribe()
     }
 
@@ -118,9 +206,114 @@ impl MediaSink {
     pub async fn get_codec_cfg(&self) -> Option<Arc<Vec<u8>>> {
         self.codec_cfg.lock().await.clone()
     }
+
+    pub fn record_video_data(&self, pts_us: u64, bytes: usize, idr: bool) {
+        self.stats
+            .lock()
+            .unwrap()
+            .record_data(Instant::now(), pts_us, bytes, idr);
+    }
+
+    pub fn record_ack(&self, count: u32) {
+        self.stats.lock().unwrap().record_ack(Instant::now(), count);
+    }
+
+    pub fn record_media_config(&self, max_unacked: u32) {
+        self.stats.lock().unwrap().record_config(max_unacked);
+    }
+
+    /// Current video statistics, or `None` if no video frame was tapped yet.
+    pub fn video_stats_report(&self, now: Instant) -> Option<VideoStatsReport> {
+        let mut stats = self.stats.lock().unwrap();
+        stats.has_data().then(|| stats.report(now))
+    }
+
+    /// Record a video frame in the GOP cache. An IDR starts a new GOP; frames
+    /// arriving before the first IDR (or after an overflow) are not cached.
+    pub async fn cache_video_frame(&self, pts_us: u64, data: &[u8], idr: bool) {
+        let mut gop = self.gop.lock().await;
+        if idr {
+            gop.frames.clear();
+            gop.bytes = 0;
+        } else if gop.frames.is_empty() {
+            return;
+        }
+        if gop.frames.len() >= GOP_CACHE_MAX_FRAMES || gop.bytes + data.len() > GOP_CACHE_MAX_BYTES
+        {
+            warn!(
+                "media tap: GOP cache overflow ({} frames, {} bytes), waiting for next IDR",
+                gop.frames.len(),
+                gop.bytes
+            );
+            gop.frames.clear();
+            gop.bytes = 0;
+            return;
+        }
+        gop.bytes += data.len();
+        gop.frames.push((pts_us, data.to_vec()));
+    }
+
+    /// Build a decodable Annex-B snapshot from the cached codec config and GOP.
+    /// Returns `None` for audio sinks or when no IDR has been seen yet.
+    pub async fn snapshot(&self) -> Option<MediaSnapshot> {
+        let info = self.get_stream_info().await?;
+        let MediaStreamKind::Video { codec, .. } = info.kind else {
+            return None;
+        };
+        let codec_cfg = self.get_codec_cfg().await;
+        let gop = self.gop.lock().await;
+        let (idr_pts_us, _) = gop.frames.first()?;
+        let (pts_us, _) = gop.frames.last()?;
+
+        let cfg_len = codec_cfg.as_ref().map(|cfg| cfg.len()).unwrap_or(0);
+        let mut data = Vec::with_capacity(cfg_len + gop.bytes);
+        if let Some(cfg) = codec_cfg {
+            data.extend_from_slice(&cfg);
+        }
+        for (_, frame) in gop.frames.iter() {
+            data.extend_from_slice(frame);
+        }
+
+        Some(MediaSnapshot {
+            data,
+            codec,
+            resolution: info.video_resolution,
+            idr_pts_us: *idr_pts_us,
+            pts_us: *pts_us,
+            frames: gop.frames.len(),
+        })
+    }
+}
+
+/// Width and height in pixels for an AA video codec resolution.
+pub fn video_resolution_dims(resolution: VideoCodecResolutionType) -> (u32, u32) {
+    match resolution {
+        VideoCodecResolutionType::VIDEO_800x480 => (800, 480),
+        VideoCodecResolutionType::VIDEO_1280x720 => (1280, 720),
+        VideoCodecResolutionType::VIDEO_1920x1080 => (1920, 1080),
+        VideoCodecResolutionType::VIDEO_2560x1440 => (2560, 1440),
+        VideoCodecResolutionType::VIDEO_3840x2160 => (3840, 2160),
+        VideoCodecResolutionType::VIDEO_720x1280 => (720, 1280),
+        VideoCodecResolutionType::VIDEO_1080x1920 => (1080, 1920),
+        VideoCodecResolutionType::VIDEO_1440x2560 => (1440, 2560),
+        VideoCodecResolutionType::VIDEO_2160x3840 => (2160, 3840),
+    }
 }
 
-fn audio_codec_name(codec: MediaCodecType) -> &'static str {
+/// Elementary stream name of a video codec, also the snapshot file extension.
+pub(crate) fn video_codec_name(codec: MediaCodecType) -> &'static str {
+    match codec {
+        MediaCodecType::MEDIA_CODEC_VIDEO_H264_BP => "h264",
+        MediaCodecType::MEDIA_CODEC_VIDEO_H265 => "h265",
+        MediaCodecType::MEDIA_CODEC_VIDEO_VP9 => "vp9",
+        MediaCodecType::MEDIA_CODEC_VIDEO_AV1 => "av1",
+        MediaCodecType::MEDIA_CODEC_AUDIO_PCM
+        | MediaCodecType::MEDIA_CODEC_AUDIO_AAC_LC
+        | MediaCodecType::MEDIA_CODEC_AUDIO_AAC_LC_ADTS => "bin",
+    }
+}
+
+pub(crate) fn audio_codec_name(codec: MediaCodecType) -> &'static str {
     match codec {
         MediaCodecType::MEDIA_CODEC_AUDIO_PCM => "pcm",
         MediaCodecType::MEDIA_CODEC_AUDIO_AAC_LC => "aac-lc",
@@ -373,10 +566,10 @@ pub async fn media_tcp_server(port: u16, label: String, sink: MediaSink, _wait_f
                                 item = rx.recv() => {
                                     match item {
                                         Ok(item) => {
-                                            let (pts_us, ref data) = *item;
-                                            if pts_us == 0 {
+                                            if item.codec_config {
                                                 continue;
                                             }
+                                            let (pts_us, data) = (item.pts_us, &item.data);
                                             if first_audio_frame_at.is_none() {
                                                 first_audio_frame_at = Some(Instant::now());
                                                 info!(
@@ -471,10 +664,10 @@ pub async fn media_tcp_server(port: u16, label: String, sink: MediaSink, _wait_f
                             item = rx.recv() => {
                                 match item {
                                     Ok(item) => {
-                                        let (pts_us, ref data) = *item;
-                                        if pts_us == 0 {
+                                        if item.codec_config {
                                             continue;
                                         }
+                                        let (pts_us, data) = (item.pts_us, &item.data);
                                             if first_video_frame_at.is_none() {
                                                 first_video_frame_at = Some(Instant::now());
                                                 info!(
@@ -803,7 +996,7 @@ pub(crate) async fn tap_media_message(
                         pts_us,
                         media_data.len()
                     );
-                    sink.send_frame(pts_us, media_data.to_vec()).await;
+                    sink.send_frame(pts_us, media_data.to_vec());
                 } else {
                     let idr = is_idr_frame(media_data);
                     if idr {
@@ -825,10 +1018,112 @@ pub(crate) async fn tap_media_message(
                             &media_data[..media_data.len().min(8)]
                         );
                     }
-                    sink.send_frame(pts_us, media_data.to_vec()).await;
+                    sink.record_video_data(pts_us, media_data.len(), idr);
+                    sink.cache_video_frame(pts_us, media_data, idr).await;
+                    sink.send_frame(pts_us, media_data.to_vec());
                 }
             }
         }
         _ => {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn video_sink() -> MediaSink {
+        let sink = MediaSink::new(4);
+        sink.set_video_stream_info(
+            MediaCodecType::MEDIA_CODEC_VIDEO_H264_BP,
+            DisplayType::DISPLAY_TYPE_MAIN,
+            vec![
+                VideoCodecResolutionType::VIDEO_800x480,
+                VideoCodecResolutionType::VIDEO_1920x1080,
+            ],
+        )
+        .await;
+        sink
+    }
+
+    #[tokio::test]
+    async fn no_snapshot_before_the_first_idr() {
+        let sink = video_sink().await;
+        sink.cache_video_frame(1, &[1], false).await;
+        sink.cache_video_frame(2, &[2], false).await;
+        assert!(sink.snapshot().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn idr_starts_a_new_gop() {
+        let sink = video_sink().await;
+        sink.send_codec_config(vec![0xc]).await;
+        sink.cache_video_frame(1, &[1], true).await;
+        sink.cache_video_frame(2, &[2], false).await;
+        sink.cache_video_frame(3, &[3], true).await;
+        sink.cache_video_frame(4, &[4], false).await;
+
+        let snapshot = sink.snapshot().await.unwrap();
+        assert_eq!(snapshot.data, vec![0xc, 3, 4]);
+        assert_eq!((snapshot.idr_pts_us, snapshot.pts_us), (3, 4));
+        assert_eq!(snapshot.frames, 2);
+    }
+
+    #[tokio::test]
+    async fn overflow_drops_the_gop_until_the_next_idr() {
+        let sink = video_sink().await;
+        sink.cache_video_frame(0, &[0], true).await;
+        for pts in 1..GOP_CACHE_MAX_FRAMES as u64 {
+            sink.cache_video_frame(pts, &[1], false).await;
+        }
+        assert_eq!(sink.snapshot().await.unwrap().frames, GOP_CACHE_MAX_FRAMES);
+
+        sink.cache_video_frame(1000, &[1], false).await;
+        sink.cache_video_frame(1001, &[1], false).await;
+        assert!(sink.snapshot().await.is_none());
+
+        sink.cache_video_frame(1002, &[2], true).await;
+        sink.cache_video_frame(1003, &vec![0; GOP_CACHE_MAX_BYTES], false)
+            .await;
+        assert!(sink.snapshot().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn new_stream_info_drops_the_cached_gop() {
+        let sink = video_sink().await;
+        sink.cache_video_frame(1, &[1], true).await;
+        sink.cache_video_frame(2, &[2], false).await;
+        assert!(sink.snapshot().await.is_some());
+
+        sink.set_video_stream_info(
+            MediaCodecType::MEDIA_CODEC_VIDEO_H265,
+            DisplayType::DISPLAY_TYPE_MAIN,
+            vec![VideoCodecResolutionType::VIDEO_1920x1080],
+        )
+        .await;
+        assert!(sink.snapshot().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn snapshot_uses_the_negotiated_resolution() {
+        let sink = video_sink().await;
+        sink.cache_video_frame(1, &[1], true).await;
+        assert_eq!(
+            sink.snapshot().await.unwrap().resolution,
+            Some(VideoCodecResolutionType::VIDEO_800x480)
+        );
+
+        sink.select_video_config(1).await;
+        assert_eq!(
+            sink.snapshot().await.unwrap().resolution,
+            Some(VideoCodecResolutionType::VIDEO_1920x1080)
+        );
+
+        // an index past the advertised configs keeps the current one
+        sink.select_video_config(2).await;
+        assert_eq!(
+            sink.snapshot().await.unwrap().resolution,
+            Some(VideoCodecResolutionType::VIDEO_1920x1080)
+        );
+    }
+}
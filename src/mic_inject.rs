@@ -0,0 +1,453 @@
+use crate::audio_inject::{PcmConverter, PcmFormat, PcmSource};
+use crate::media_tap::AudioStreamConfig;
+use crate::mitm::protos::{
+    MediaCodecType, MediaMessageId, MicrophoneRequest, MicrophoneResponse, ServiceDiscoveryResponse,
+};
+use crate::mitm::Packet;
+use crate::pcm_sink::{media_packet, s16le_bytes, AckWindow};
+use protobuf::Message;
+use serde::{Deserialize, Serialize};
+use simplelog::*;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::sync::mpsc::Sender;
+use tokio::sync::oneshot;
+use tokio::time::{timeout_at, Instant};
+use tokio_util::sync::CancellationToken;
+
+const NAME: &str = "<i><bright-black> mic-inject: </>";
+const MIC_INJECT_SESSION_ID: i32 = 0x4d49_4301; // "MIC\x01"
+/// Duration of audio carried by a single DATA packet sent to the phone.
+const CHUNK_MS: u64 = 20;
+/// Give up waiting for phone ACKs after this long and keep sending.
+const ACK_WAIT_TIMEOUT: Duration = Duration::from_millis(500);
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+fn default_gain_percent() -> u32 {
+    100
+}
+
+fn default_once() -> bool {
+    true
+}
+
+/// Replacement microphone input armed via REST.
+///
+/// While armed, phone `MicrophoneRequest`s are answered by the proxy and
+/// never reach the HU; the phone receives PCM from `source` instead.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct MicInjectRequest {
+    #[serde(flatten)]
+    pub source: PcmSource,
+    #[serde(default = "default_gain_percent")]
+    pub gain_percent: u32,
+    /// Restart a WAV file from the beginning when it ends. Otherwise silence
+    /// is sent until the phone closes the microphone.
+    #[serde(default)]
+    pub repeat: bool,
+    /// Disarm after the first microphone session; the next request goes to
+    /// the HU microphone again.
+    #[serde(default = "default_once")]
+    pub once: bool,
+}
+
+struct MicSession {
+    id: u64,
+    cancel: CancellationToken,
+}
+
+#[derive(Default)]
+struct State {
+    /// tx towards the phone
+    tx: Option<Sender<Packet>>,
+    channel: Option<u8>,
+    config: Option<AudioStreamConfig>,
+    armed: Option<MicInjectRequest>,
+    session: Option<MicSession>,
+    sessions_served: u64,
+}
+
+struct Runtime {
+    state: Mutex<State>,
+    next_id: AtomicU64,
+    /// DATA packets the phone has not acknowledged yet.
+    acks: AckWindow,
+}
+
+static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+
+fn runtime() -> &'static Runtime {
+    RUNTIME.get_or_init(|| Runtime {
+        state: Mutex::new(State::default()),
+        next_id: AtomicU64::new(1),
+        acks: AckWindow::new(),
+    })
+}
+
+/// Learn the HU microphone source from ServiceDiscovery and the tx towards
+/// the phone.
+pub fn set_source(msg: &ServiceDiscoveryResponse, tx: Option<Sender<Packet>>) {
+    let mic = msg.services.iter().find_map(|svc| {
+        let source = &svc.media_source_service;
+        let acfg = source.audio_config.as_ref()?;
+        if source.available_type() != MediaCodecType::MEDIA_CODEC_AUDIO_PCM {
+            return None;
+        }
+        Some((
+            svc.id() as u8,
+            AudioStreamConfig {
+                sample_rate: acfg.sampling_rate(),
+                channels: acfg.number_of_channels(),
+                bits: acfg.number_of_bits(),
+            },
+        ))
+    });
+
+    let mut state = runtime().state.lock().unwrap();
+    if let Some(session) = state.session.take() {
+        session.cancel.cancel();
+    }
+    state.tx = tx;
+    state.channel = mic.map(|(channel, _)| channel);
+    state.config = mic.map(|(_, cfg)| cfg);
+    if let Some((channel, cfg)) = mic {
+        debug!(
+            "{} HU microphone ch=<b>{:#04x}</> ({}Hz, {}ch, {}bit)",
+            NAME, channel, cfg.sample_rate, cfg.channels, cfg.bits
+        );
+    }
+}
+
+/// Handle a phone `MicrophoneRequest`. When an injection is armed, returns
+/// the payload of the `MicrophoneResponse` to send back to the phone; the
+/// request must then not be forwarded to the HU.
+pub fn handle_microphone_request(channel: u8, req: &MicrophoneRequest) -> Option<Vec<u8>> {
+    let rt = runtime();
+    let mut state = rt.state.lock().unwrap();
+    if state.channel != Some(channel) {
+        return None;
+    }
+
+    if !req.open() {
+        // close our own session; with nothing running the HU owns the mic
+        let session = state.session.take()?;
+        info!("{} phone closed microphone, session #{}", NAME, session.id);
+        session.cancel.cancel();
+        if state.armed.as_ref().is_some_and(|armed| armed.once) {
+            state.armed = None;
+        }
+        return Some(microphone_response(false));
+    }
+
+    let armed = state.armed.clone()?;
+    let Some(config) = state.config else {
+        warn!("{} no HU microphone config, forwarding request", NAME);
+        return None;
+    };
+    let Some(tx) = state.tx.clone() else {
+        return None;
+    };
+    if config.bits != 16 {
+        warn!(
+            "{} unsupported microphone bit depth {}, forwarding request",
+            NAME, config.bits
+        );
+        return None;
+    }
+    if let Some(session) = state.session.take() {
+        session.cancel.cancel();
+    }
+
+    let id = rt.next_id.fetch_add(1, Ordering::SeqCst);
+    let cancel = CancellationToken::new();
+    state.session = Some(MicSession {
+        id,
+        cancel: cancel.clone(),
+    });
+    state.sessions_served += 1;
+    drop(state);
+
+    info!(
+        "{} #{} answering MICROPHONE_REQUEST ch=<b>{:#04x}</> with {} ({}Hz, {}ch), max_unacked={}",
+        NAME,
+        id,
+        channel,
+        armed.source.describe(),
+        config.sample_rate,
+        config.channels,
+        req.max_unacked()
+    );
+    rt.acks.reset();
+
+    let job = MicJob {
+        tx,
+        channel,
+        config,
+        max_unacked: req.max_unacked().max(0) as u32,
+    };
+    tokio::spawn(async move {
+        let result = tokio::select! {
+            r = job.run(&armed) => r,
+            _ = cancel.cancelled() => Ok(()),
+        };
+        if let Err(e) = result {
+            warn!("{} #{} ended: {}", NAME, id, e);
+        }
+        let mut state = runtime().state.lock().unwrap();
+        if state.session.as_ref().is_some_and(|s| s.id == id) {
+            state.session = None;
+        }
+    });
+
+    Some(microphone_response(true))
+}
+
+fn microphone_response(open: bool) -> Vec<u8> {
+    let mut response = MicrophoneResponse::new();
+    response.set_status(0);
+    if open {
+        response.set_session_id(MIC_INJECT_SESSION_ID);
+    }
+    response.write_to_bytes().unwrap_or_default()
+}
+
+/// Whether the phone currently receives injected microphone audio on `channel`.
+/// HU microphone DATA and phone ACKs on that channel must then be dropped.
+pub fn is_active(channel: u8) -> bool {
+    let state = runtime().state.lock().unwrap();
+    state.channel == Some(channel) && state.session.is_some()
+}
+
+/// Consume a phone ACK for injected microphone DATA.
+pub fn handle_ack(count: u32) {
+    runtime().acks.acked(count);
+}
+
+/// Arm the replacement microphone input for the next `MicrophoneRequest`.
+pub fn arm(req: MicInjectRequest) -> Result<()> {
+    req.source.validate()?;
+    if req.repeat && req.source.file.is_none() {
+        return Err("`repeat` is only supported for WAV files".into());
+    }
+    info!(
+        "{} armed with {} (once={})",
+        NAME,
+        req.source.describe(),
+        req.once
+    );
+    runtime().state.lock().unwrap().armed = Some(req);
+    Ok(())
+}
+
+/// Disarm and stop a running injected microphone session. The phone keeps
+/// its microphone open; it gets HU audio again on its next request.
+pub fn disarm() -> bool {
+    let mut state = runtime().state.lock().unwrap();
+    let had_session = match state.session.take() {
+        Some(session) => {
+            session.cancel.cancel();
+            true
+        }
+        None => false,
+    };
+    let was_armed = state.armed.take().is_some();
+    if was_armed || had_session {
+        info!("{} disarmed", NAME);
+    }
+    was_armed || had_session
+}
+
+pub fn status() -> serde_json::Value {
+    let state = runtime().state.lock().unwrap();
+    serde_json::json!({
+        "channel": state.channel,
+        "sample_rate": state.config.map(|c| c.sample_rate),
+        "channels": state.config.map(|c| c.channels),
+        "bits": state.config.map(|c| c.bits),
+        "armed": state.armed,
+        "active_session": state.session.as_ref().map(|s| s.id),
+        "sessions_served": state.sessions_served,
+    })
+}
+
+struct MicJob {
+    tx: Sender<Packet>,
+    channel: u8,
+    config: AudioStreamConfig,
+    max_unacked: u32,
+}
+
+impl MicJob {
+    async fn send_data(&self, pts_us: u64, pcm: &[i16]) -> Result<()> {
+        let acks = &runtime().acks;
+        if !acks.wait_for_room(self.max_unacked, ACK_WAIT_TIMEOUT).await {
+            debug!("{} phone ACK wait timed out, resetting window", NAME);
+        }
+
+        let mut payload = pts_us.to_be_bytes().to_vec();
+        payload.extend_from_slice(&s16le_bytes(pcm));
+        self.tx
+            .send(media_packet(
+                self.channel,
+                MediaMessageId::MEDIA_MESSAGE_DATA,
+                payload,
+            ))
+            .await?;
+        acks.sent();
+        Ok(())
+    }
+
+    /// Stream the armed source in real time until cancelled. Silence is sent
+    /// while a socket source is not connected yet and after the source ends,
+    /// like a live microphone would.
+    async fn run(&self, req: &MicInjectRequest) -> Result<()> {
+        let out_rate = self.config.sample_rate.max(1);
+        let out_channels = self.config.channels.max(1);
+        let chunk_samples = (out_rate as u64 * CHUNK_MS / 1000) as usize * out_channels as usize;
+
+        let (source_tx, source_rx) = oneshot::channel();
+        let source = req.source.clone();
+        let opener = tokio::spawn(async move {
+            let _ = source_tx.send(source.open(NAME).await);
+        });
+        let mut input: Option<(Box<dyn AsyncRead + Unpin + Send>, PcmFormat, PcmConverter)> = None;
+        let mut pending_source = Some(source_rx);
+        let mut read_buf = Vec::new();
+        let mut carry: Vec<u8> = Vec::new();
+        let mut out: Vec<i16> = Vec::new();
+
+        let started_at = Instant::now();
+        let mut out_frames_sent = 0u64;
+        let result = loop {
+            if let Some(source_rx) = pending_source.as_mut() {
+                match source_rx.try_recv() {
+                    Ok(Ok((reader, format))) => {
+                        let converter =
+                            PcmConverter::new(format, out_rate, out_channels, req.gain_percent);
+                        read_buf = match format.bytes_for_ms(CHUNK_MS) {
+                            Ok(len) => vec![0u8; len],
+                            Err(e) => break Err(e),
+                        };
+                        input = Some((reader, format, converter));
+                        pending_source = None;
+                    }
+                    Ok(Err(e)) => break Err(e),
+                    Err(oneshot::error::TryRecvError::Empty) => {}
+                    Err(oneshot::error::TryRecvError::Closed) => pending_source = None,
+                }
+            }
+
+            let pts_us = out_frames_sent * 1_000_000 / out_rate as u64;
+            let due = started_at + Duration::from_micros(pts_us);
+            if let Some((reader, format, converter)) = input.as_mut() {
+                let in_frame_bytes = format.channels as usize * 2;
+                while out.len() < chunk_samples {
+                    // a stalled source gets silence, the HU wants its frames on time
+                    let Ok(read) = timeout_at(due, reader.read(&mut read_buf)).await else {
+                        break;
+                    };
+                    let n = read?;
+                    if n == 0 {
+                        if req.repeat {
+                            let (reader_again, _) = req.source.open(NAME).await?;
+                            *reader = reader_again;
+                            continue;
+                        }
+                        info!("{} source ended, sending silence", NAME);
+                        input = None;
+                        break;
+                    }
+                    carry.extend_from_slice(&read_buf[..n]);
+                    let whole = carry.len() - carry.len() % in_frame_bytes;
+                    let samples: Vec<i16> = carry[..whole]
+                        .chunks_exact(2)
+                        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+                        .collect();
+                    carry.drain(..whole);
+                    converter.process(&samples, &mut out);
+                }
+            }
+
+            let mut chunk: Vec<i16> = out.drain(..out.len().min(chunk_samples)).collect();
+            chunk.resize(chunk_samples, 0);
+            tokio::time::sleep_until(due).await;
+            if let Err(e) = self.send_data(pts_us, &chunk).await {
+                break Err(e);
+            }
+            out_frames_sent += (chunk_samples / out_channels as usize) as u64;
+        };
+        opener.abort();
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mitm::protos::{AudioConfiguration, MediaSourceService, Service};
+    use tokio::sync::mpsc;
+
+    fn discovery(channel: i32) -> ServiceDiscoveryResponse {
+        let mut config = AudioConfiguration::new();
+        config.set_sampling_rate(16_000);
+        config.set_number_of_bits(16);
+        config.set_number_of_channels(1);
+        let mut source = MediaSourceService::new();
+        source.audio_config = Some(config).into();
+        let mut svc = Service::new();
+        svc.set_id(channel);
+        svc.media_source_service = Some(source).into();
+        let mut response = ServiceDiscoveryResponse::new();
+        response.services.push(svc);
+        response
+    }
+
+    fn request(open: bool) -> MicrophoneRequest {
+        let mut req = MicrophoneRequest::new();
+        req.set_open(open);
+        req.set_max_unacked(1);
+        req
+    }
+
+    fn session_id(payload: Option<Vec<u8>>) -> Option<i32> {
+        MicrophoneResponse::parse_from_bytes(&payload.unwrap())
+            .unwrap()
+            .session_id
+    }
+
+    #[tokio::test]
+    async fn answers_only_while_armed() {
+        let (tx, _rx) = mpsc::channel(8);
+        set_source(&discovery(7), Some(tx));
+        assert_eq!(handle_microphone_request(7, &request(true)), None);
+
+        arm(MicInjectRequest {
+            source: PcmSource {
+                file: None,
+                tcp: Some("127.0.0.1:0".to_string()),
+                unix: None,
+                sample_rate: 16_000,
+                channels: 1,
+            },
+            gain_percent: 100,
+            repeat: false,
+            once: true,
+        })
+        .unwrap();
+        // other channels are none of our business
+        assert_eq!(handle_microphone_request(8, &request(true)), None);
+        let opened = handle_microphone_request(7, &request(true));
+        assert_eq!(session_id(opened), Some(MIC_INJECT_SESSION_ID));
+        assert!(is_active(7));
+
+        let closed = handle_microphone_request(7, &request(false));
+        assert_eq!(session_id(closed), None);
+        assert!(!is_active(7));
+
+        // disarmed after the first session, the HU microphone is back
+        assert_eq!(handle_microphone_request(7, &request(true)), None);
+    }
+}
@@ -0,0 +1,978 @@
+//! Mirrors the projected phone to a second, read-only head unit.
+//!
+//! With `mirror` another HU, e.g. a DHU on a rear-seat tablet, can connect on
+//! TCP 5276 while the car's HU stays primary. io_loop taps the plaintext
+//! packets between the HU-side proxy and the phone. [`MirrorState`] keeps
+//! what a mirror needs to join at any time (version response, service
+//! discovery request, channel opens and media setup) and replays it with the
+//! channels mapped to the mirror's own services. After that the phone's
+//! video and audio are copied as they are, video from its next keyframe and
+//! again after copies were dropped.
+//!
+//! Nothing the mirror sends reaches the phone: its ACKs, focus and config
+//! messages are dropped, so the phone only sees the primary HU's. Pings are
+//! answered in the phone's place and with `mirror_touch` its touch input is
+//! scaled to the primary HU's touch screen and passed to the phone.
+use crate::config::SharedConfig;
+use crate::handover::{answer_ping, control_id, is, message_id, on_channel, reply_to};
+use crate::io_uring::IoDevice;
+use crate::media_tap::is_idr_frame;
+use crate::mitm::protos::ControlMessageType::*;
+use crate::mitm::protos::InputMessageId::*;
+use crate::mitm::protos::MediaMessageId::*;
+use crate::mitm::protos::*;
+use crate::mitm::{
+    endpoint_reader, hu_endpoint, Packet, FRAME_TYPE_FIRST, FRAME_TYPE_LAST, FRAME_TYPE_MASK,
+};
+use protobuf::Message;
+use simplelog::*;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::rc::Rc;
+use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::task::JoinHandle;
+use tokio_uring::net::{TcpListener, TcpStream};
+
+// module name for logging engine
+const NAME: &str = "<i><bright-black> mirror: </>";
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+// queue depth towards the mirror HU
+const MIRROR_QUEUE_CAPACITY: usize = 10;
+
+/// Packet seen between the HU-side proxy and the phone.
+#[derive(Clone)]
+pub enum Tapped {
+    /// Sent by the primary HU.
+    FromHu(Packet),
+    /// Sent by the phone.
+    FromPhone(Packet),
+    /// Copies on this channel were dropped, the tap fell behind.
+    Lost(u8),
+}
+
+/// Where a packet goes.
+pub enum Route {
+    ToMirror(Packet),
+    ToPhone(Packet),
+}
+
+/// What a service is, to find its counterpart on the other HU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ServiceKind {
+    Video(DisplayType),
+    Audio(AudioStreamType),
+    Input,
+}
+
+/// Services worth mirroring, by service id.
+fn service_kinds(sdr: &ServiceDiscoveryResponse, touch: bool) -> HashMap<i32, ServiceKind> {
+    sdr.services
+        .iter()
+        .filter_map(|svc| {
+            let sink = &svc.media_sink_service;
+            let kind = if !sink.video_configs.is_empty() {
+                ServiceKind::Video(sink.display_type())
+            } else if sink.audio_type.is_some() || !sink.audio_configs.is_empty() {
+                ServiceKind::Audio(sink.audio_type())
+            } else if touch && svc.input_source_service.is_some() {
+                ServiceKind::Input
+            } else {
+                return None;
+            };
+            Some((svc.id(), kind))
+        })
+        .collect()
+}
+
+/// Size of the first touch screen of each input service, by service id.
+fn touch_screens(sdr: &ServiceDiscoveryResponse) -> HashMap<i32, (u32, u32)> {
+    sdr.services
+        .iter()
+        .filter_map(|svc| {
+            let screen = svc.input_source_service.touchscreen.first()?;
+            let size = (screen.width(), screen.height());
+            (size.0 > 0 && size.1 > 0).then(|| (svc.id(), (size.0 as u32, size.1 as u32)))
+        })
+        .collect()
+}
+
+/// Map a touch coordinate between screens of `from` and `to` pixels.
+fn scale_coordinate(value: u32, from: u32, to: u32) -> u32 {
+    (value as u64 * to as u64 / from as u64).min(to.saturating_sub(1) as u64) as u32
+}
+
+/// Input report `pkt` with its touch points moved from the `from` to the
+/// `to` screen. Reports that do not parse are passed as they are.
+fn scale_touch(pkt: &Packet, from: (u32, u32), to: (u32, u32)) -> Packet {
+    let single = pkt.flags & FRAME_TYPE_MASK == FRAME_TYPE_FIRST | FRAME_TYPE_LAST;
+    let Some(mut report) = pkt
+        .payload
+        .get(2..)
+        .filter(|_| single)
+        .and_then(|body| InputReport::parse_from_bytes(body).ok())
+    else {
+        return pkt.clone();
+    };
+    let Some(touch) = report.touch_event.as_mut() else {
+        return pkt.clone();
+    };
+    for pointer in touch.pointer_data.iter_mut() {
+        pointer.set_x(scale_coordinate(pointer.x(), from.0, to.0));
+        pointer.set_y(scale_coordinate(pointer.y(), from.1, to.1));
+    }
+    match report.write_to_bytes() {
+        Ok(body) => reply_to(pkt, INPUT_MESSAGE_INPUT_REPORT as u16, body),
+        Err(_) => pkt.clone(),
+    }
+}
+
+/// Collects the frames of a service discovery response on channel 0.
+#[derive(Default)]
+struct Discovery {
+    data: Option<Vec<u8>>,
+}
+
+impl Discovery {
+    /// Returns the parsed response once its last frame arrived.
+    fn push(&mut self, pkt: &Packet) -> Option<ServiceDiscoveryResponse> {
+        if pkt.channel != 0 {
+            return None;
+        }
+        if pkt.flags & FRAME_TYPE_FIRST != 0 {
+            self.data = is(control_id(pkt), MESSAGE_SERVICE_DISCOVERY_RESPONSE)
+                .then(|| pkt.payload.clone());
+        } else {
+            self.data.as_mut()?.extend_from_slice(&pkt.payload);
+        }
+        if pkt.flags & FRAME_TYPE_LAST == 0 {
+            return None;
+        }
+        let data = self.data.take()?;
+        ServiceDiscoveryResponse::parse_from_bytes(&data[2..]).ok()
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+enum Stage {
+    #[default]
+    Closed,
+    /// Open request sent to the mirror.
+    Opening,
+    /// Open, waiting for the phone's setup.
+    Opened,
+    /// Setup sent to the mirror.
+    SettingUp,
+    Streaming,
+}
+
+/// A phone channel of a mirrored service.
+struct Stream {
+    kind: ServiceKind,
+    open: Packet,
+    /// Media setup or input key binding request.
+    setup: Option<Packet>,
+    /// Last media start while the phone streams.
+    start: Option<Packet>,
+    codec: Option<Packet>,
+    mirror_channel: u8,
+    stage: Stage,
+    /// The frames of the current message are copied.
+    in_sync: bool,
+    /// Video is held back until a codec config or IDR, after joining the
+    /// stream or losing some of it.
+    awaits_keyframe: bool,
+    /// Touch screen size of the primary HU's input service.
+    touch_screen: Option<(u32, u32)>,
+}
+
+impl Stream {
+    fn is_media(&self) -> bool {
+        self.kind != ServiceKind::Input
+    }
+
+    /// Drop the rest of the current message, video resumes at a keyframe.
+    fn resync(&mut self) {
+        self.in_sync = false;
+        self.awaits_keyframe = matches!(self.kind, ServiceKind::Video(_));
+    }
+
+    /// Whether the message starting with `pkt` is copied to the mirror.
+    fn copies(&mut self, pkt: &Packet) -> bool {
+        let id = message_id(pkt);
+        let data = is(id, MEDIA_MESSAGE_DATA);
+        // DATA carries an 8 byte timestamp before the access unit
+        if is(id, MEDIA_MESSAGE_CODEC_CONFIG)
+            || (data && pkt.payload.get(10..).is_some_and(is_idr_frame))
+        {
+            self.awaits_keyframe = false;
+        }
+        !(data && self.awaits_keyframe)
+    }
+}
+
+/// What the mirror needs from the primary session, and the mirror's
+/// progress through it.
+pub struct MirrorState {
+    touch: bool,
+    version_response: Option<Packet>,
+    discovery_request: Option<Packet>,
+    primary_discovery: Discovery,
+    /// Mirrored services of the primary HU.
+    primary: HashMap<i32, ServiceKind>,
+    /// Touch screen sizes of the primary HU's input services.
+    primary_touch: HashMap<i32, (u32, u32)>,
+    /// Phone channels of mirrored services.
+    streams: BTreeMap<u8, Stream>,
+    attached: bool,
+    version_requested: bool,
+    authenticated: bool,
+    mirror_discovery: Discovery,
+    /// Mirror service per kind, once its service discovery response came.
+    mirror: Option<HashMap<ServiceKind, i32>>,
+    /// Touch screen size of the mirror's input service.
+    mirror_touch: Option<(u32, u32)>,
+}
+
+impl MirrorState {
+    pub fn new(touch: bool) -> Self {
+        Self {
+            touch,
+            version_response: None,
+            discovery_request: None,
+            primary_discovery: Discovery::default(),
+            primary: HashMap::new(),
+            primary_touch: HashMap::new(),
+            streams: BTreeMap::new(),
+            attached: false,
+            version_requested: false,
+            authenticated: false,
+            mirror_discovery: Discovery::default(),
+            mirror: None,
+            mirror_touch: None,
+        }
+    }
+
+    /// A mirror HU connected, it starts with its version request.
+    pub fn attach(&mut self) {
+        self.detach();
+        self.attached = true;
+    }
+
+    pub fn detach(&mut self) {
+        self.attached = false;
+        self.version_requested = false;
+        self.authenticated = false;
+        self.mirror_discovery = Discovery::default();
+        self.mirror = None;
+        self.mirror_touch = None;
+        for stream in self.streams.values_mut() {
+            stream.stage = Stage::Closed;
+        }
+    }
+
+    /// The mirror HU answered the service discovery.
+    pub fn discovered(&self) -> bool {
+        self.mirror.is_some()
+    }
+
+    /// Next step of `channel` that the mirror is ready for.
+    fn advance(&mut self, channel: u8) -> Vec<Route> {
+        let Some(mirror) = self.mirror.as_ref().filter(|_| self.attached) else {
+            return vec![];
+        };
+        let Some(stream) = self.streams.get_mut(&channel) else {
+            return vec![];
+        };
+        match stream.stage {
+            Stage::Closed => {
+                let Some(&service_id) = mirror.get(&stream.kind) else {
+                    return vec![];
+                };
+                let Some(mut request) =
+                    ChannelOpenRequest::parse_from_bytes(&stream.open.payload[2..]).ok()
+                else {
+                    return vec![];
+                };
+                request.set_service_id(service_id);
+                let Ok(body) = request.write_to_bytes() else {
+                    return vec![];
+                };
+                stream.mirror_channel = service_id as u8;
+                stream.stage = Stage::Opening;
+                let open = reply_to(&stream.open, MESSAGE_CHANNEL_OPEN_REQUEST as u16, body);
+                vec![Route::ToMirror(on_channel(&open, stream.mirror_channel))]
+            }
+            Stage::Opened => match &stream.setup {
+                Some(setup) => {
+                    stream.stage = Stage::SettingUp;
+                    vec![Route::ToMirror(on_channel(setup, stream.mirror_channel))]
+                }
+                None => vec![],
+            },
+            _ => vec![],
+        }
+    }
+
+    fn advance_all(&mut self) -> Vec<Route> {
+        let channels: Vec<u8> = self.streams.keys().copied().collect();
+        channels
+            .into_iter()
+            .flat_map(|channel| self.advance(channel))
+            .collect()
+    }
+
+    /// Packet passing between the primary HU and the phone.
+    pub fn on_tapped(&mut self, tapped: Tapped) -> Vec<Route> {
+        match tapped {
+            Tapped::FromHu(pkt) => {
+                if let Some(sdr) = self.primary_discovery.push(&pkt) {
+                    self.primary = service_kinds(&sdr, self.touch);
+                    self.primary_touch = touch_screens(&sdr);
+                }
+                vec![]
+            }
+            Tapped::FromPhone(pkt) => self.on_phone_packet(pkt),
+            Tapped::Lost(channel) => {
+                if let Some(stream) = self.streams.get_mut(&channel) {
+                    debug!(
+                        "{} 🪞 copies of channel {} were dropped, resyncing",
+                        NAME, channel
+                    );
+                    stream.resync();
+                }
+                vec![]
+            }
+        }
+    }
+
+    fn on_phone_packet(&mut self, pkt: Packet) -> Vec<Route> {
+        let id = control_id(&pkt);
+        if pkt.channel == 0 {
+            if is(id, MESSAGE_VERSION_RESPONSE) {
+                self.version_response = Some(pkt.clone());
+                if self.attached && self.version_requested {
+                    return vec![Route::ToMirror(pkt)];
+                }
+            } else if is(id, MESSAGE_SERVICE_DISCOVERY_REQUEST) {
+                self.discovery_request = Some(pkt.clone());
+                if self.attached && self.authenticated {
+                    return vec![Route::ToMirror(pkt)];
+                }
+            }
+            return vec![];
+        }
+        if is(id, MESSAGE_CHANNEL_OPEN_REQUEST) {
+            let service_id = ChannelOpenRequest::parse_from_bytes(&pkt.payload[2..])
+                .ok()
+                .map(|request| request.service_id());
+            let kind = service_id.and_then(|id| self.primary.get(&id).copied());
+            if let Some(kind) = kind {
+                self.streams.insert(
+                    pkt.channel,
+                    Stream {
+                        kind,
+                        open: pkt.clone(),
+                        setup: None,
+                        start: None,
+                        codec: None,
+                        mirror_channel: 0,
+                        stage: Stage::Closed,
+                        in_sync: false,
+                        awaits_keyframe: false,
+                        touch_screen: service_id
+                            .and_then(|id| self.primary_touch.get(&id).copied()),
+                    },
+                );
+                return self.advance(pkt.channel);
+            }
+            return vec![];
+        }
+        let Some(stream) = self.streams.get_mut(&pkt.channel) else {
+            return vec![];
+        };
+        let id = message_id(&pkt);
+        let single = pkt.flags & FRAME_TYPE_MASK == FRAME_TYPE_FIRST | FRAME_TYPE_LAST;
+        if stream.is_media() {
+            if is(id, MEDIA_MESSAGE_SETUP) {
+                stream.setup = Some(pkt.clone());
+            } else if is(id, MEDIA_MESSAGE_START) {
+                stream.start = Some(pkt.clone());
+            } else if is(id, MEDIA_MESSAGE_STOP) {
+                stream.start = None;
+            } else if is(id, MEDIA_MESSAGE_CODEC_CONFIG) && single {
+                stream.codec = Some(pkt.clone());
+            }
+        } else if is(id, INPUT_MESSAGE_KEY_BINDING_REQUEST) {
+            stream.setup = Some(pkt.clone());
+        }
+        match stream.stage {
+            Stage::Opened => self.advance(pkt.channel),
+            // the phone's input feedback is meant for the primary HU
+            Stage::Streaming if stream.is_media() => {
+                if pkt.flags & FRAME_TYPE_FIRST != 0 {
+                    stream.in_sync = stream.copies(&pkt);
+                }
+                if !stream.in_sync {
+                    return vec![];
+                }
+                vec![Route::ToMirror(on_channel(&pkt, stream.mirror_channel))]
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Packet from the mirror HU.
+    pub fn on_mirror_packet(&mut self, pkt: Packet) -> Vec<Route> {
+        let id = control_id(&pkt);
+        if pkt.channel == 0 {
+            if let Some(sdr) = self.mirror_discovery.push(&pkt) {
+                let mut mirror = HashMap::new();
+                for (service_id, kind) in service_kinds(&sdr, self.touch) {
+                    mirror
+                        .entry(kind)
+                        .and_modify(|id: &mut i32| *id = (*id).min(service_id))
+                        .or_insert(service_id);
+                }
+                info!(
+                    "{} 🪞 mirror head unit offers {} of the mirrored services",
+                    NAME,
+                    mirror.len()
+                );
+                self.mirror_touch = mirror
+                    .get(&ServiceKind::Input)
+                    .and_then(|id| touch_screens(&sdr).get(id).copied());
+                self.mirror = Some(mirror);
+                return self.advance_all();
+            }
+            if let Some(pong) = answer_ping(&pkt) {
+                return vec![Route::ToMirror(pong)];
+            }
+            if is(id, MESSAGE_VERSION_REQUEST) {
+                self.version_requested = true;
+                return self
+                    .version_response
+                    .clone()
+                    .map(Route::ToMirror)
+                    .into_iter()
+                    .collect();
+            }
+            if is(id, MESSAGE_AUTH_COMPLETE) {
+                self.authenticated = true;
+                return self
+                    .discovery_request
+                    .clone()
+                    .map(Route::ToMirror)
+                    .into_iter()
+                    .collect();
+            }
+            if is(id, MESSAGE_BYEBYE_REQUEST) {
+                let body = ByeByeResponse::new().write_to_bytes().unwrap_or_default();
+                return vec![Route::ToMirror(reply_to(
+                    &pkt,
+                    MESSAGE_BYEBYE_RESPONSE as u16,
+                    body,
+                ))];
+            }
+            return vec![];
+        }
+        let Some((&channel, stream)) = self
+            .streams
+            .iter_mut()
+            .find(|(_, s)| s.stage != Stage::Closed && s.mirror_channel == pkt.channel)
+        else {
+            return vec![];
+        };
+        if is(id, MESSAGE_CHANNEL_OPEN_RESPONSE) {
+            if stream.stage == Stage::Opening {
+                stream.stage = Stage::Opened;
+                return self.advance(channel);
+            }
+            return vec![];
+        }
+        let id = message_id(&pkt);
+        if stream.stage == Stage::SettingUp {
+            if stream.is_media() && is(id, MEDIA_MESSAGE_CONFIG) {
+                stream.stage = Stage::Streaming;
+                stream.resync();
+                // a running stream continues with its next message
+                let Some(start) = &stream.start else {
+                    return vec![];
+                };
+                return [Some(start), stream.codec.as_ref()]
+                    .into_iter()
+                    .flatten()
+                    .map(|pkt| Route::ToMirror(on_channel(pkt, stream.mirror_channel)))
+                    .collect();
+            }
+            if !stream.is_media() && is(id, INPUT_MESSAGE_KEY_BINDING_RESPONSE) {
+                stream.stage = Stage::Streaming;
+            }
+            return vec![];
+        }
+        if stream.stage == Stage::Streaming
+            && !stream.is_media()
+            && is(id, INPUT_MESSAGE_INPUT_REPORT)
+        {
+            // the phone lays out touch for the primary HU's screen
+            let report = match (self.mirror_touch, stream.touch_screen) {
+                (Some(from), Some(to)) if from != to => scale_touch(&pkt, from, to),
+                _ => pkt,
+            };
+            return vec![Route::ToPhone(on_channel(&report, channel))];
+        }
+        // ACKs, focus notifications, ...
+        vec![]
+    }
+}
+
+/// Copy everything passing `rx` into each of `taps`. The copies are dropped
+/// while a tap falls behind, the session never waits for it. Once it has
+/// room again, the tap learns which channels lost copies from a
+/// [`Tapped::Lost`] before the next copy.
+pub fn tap(
+    mut rx: Receiver<Packet>,
+    capacity: usize,
+    taps: Vec<Sender<Tapped>>,
+    tapped: fn(Packet) -> Tapped,
+) -> (Receiver<Packet>, JoinHandle<()>) {
+    let (tx, out) = mpsc::channel(capacity);
+    let task = tokio::spawn(async move {
+        let mut lost = vec![BTreeSet::new(); taps.len()];
+        while let Some(pkt) = rx.recv().await {
+            for (tap, lost) in taps.iter().zip(lost.iter_mut()) {
+                copy_to_tap(tap, lost, pkt.channel, tapped(pkt.clone()));
+            }
+            if tx.send(pkt).await.is_err() {
+                break;
+            }
+        }
+    });
+    (out, task)
+}
+
+/// Hand `tapped` to `tap` after the `lost` channels not reported yet, or
+/// add its channel to them.
+fn copy_to_tap(tap: &Sender<Tapped>, lost: &mut BTreeSet<u8>, channel: u8, tapped: Tapped) {
+    while let Some(&gap) = lost.first() {
+        if tap.try_send(Tapped::Lost(gap)).is_err() {
+            lost.insert(channel);
+            return;
+        }
+        lost.remove(&gap);
+    }
+    if tap.try_send(tapped).is_err() {
+        lost.insert(channel);
+    }
+}
+
+/// Connection of a mirror HU and its tasks.
+struct MirrorLink {
+    stream: Rc<TcpStream>,
+    to_mirror: Sender<Packet>,
+    from_mirror: Receiver<Packet>,
+    reader: JoinHandle<Result<()>>,
+    endpoint: JoinHandle<Result<()>>,
+}
+
+impl MirrorLink {
+    fn spawn(stream: TcpStream, config: SharedConfig) -> Self {
+        let stream = Rc::new(stream);
+        let (txr, rxr) = mpsc::channel(MIRROR_QUEUE_CAPACITY);
+        let (to_mirror, rx) = mpsc::channel(MIRROR_QUEUE_CAPACITY);
+        let (tx, from_mirror) = mpsc::channel(MIRROR_QUEUE_CAPACITY);
+        let reader = tokio_uring::spawn(endpoint_reader(
+            IoDevice::TcpStreamIo(stream.clone()),
+            txr,
+            true,
+        ));
+        let device = IoDevice::TcpStreamIo(stream.clone());
+        let endpoint = tokio_uring::spawn(async move {
+            let res = hu_endpoint(device, tx, rx, rxr, config).await;
+            if let Err(e) = &res {
+                warn!("{} 🪞 mirror head unit: {}", NAME, e);
+            }
+            res
+        });
+        Self {
+            stream,
+            to_mirror,
+            from_mirror,
+            reader,
+            endpoint,
+        }
+    }
+}
+
+impl Drop for MirrorLink {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.endpoint.abort();
+        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+/// Serve mirror HUs connecting on `listener` during a session, one at a
+/// time. `taps` ends with the session, `to_phone` takes the mirror's touch
+/// input.
+pub async fn serve(
+    listener: Rc<TcpListener>,
+    mut taps: Receiver<Tapped>,
+    to_phone: Sender<Packet>,
+    config: SharedConfig,
+) -> Result<()> {
+    let mut state = MirrorState::new(config.read().await.mirror_touch);
+    let mut link: Option<MirrorLink> = None;
+    loop {
+        let routes = tokio::select! {
+            conn = listener.accept() => {
+                let (stream, addr) = match conn {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        warn!("{} 🪞 mirror TCP server: {}", NAME, e);
+                        continue;
+                    }
+                };
+                if link.is_some() {
+                    info!("{} 🪞 replacing the mirror head unit", NAME);
+                }
+                info!("{} 🪞 mirror head unit connected: <b>{:?}</b>", NAME, addr);
+                link = Some(MirrorLink::spawn(stream, config.clone()));
+                state.attach();
+                vec![]
+            }
+            tapped = taps.recv() => match tapped {
+                Some(tapped) => state.on_tapped(tapped),
+                // the session is over
+                None => return Ok(()),
+            },
+            pkt = async { link.as_mut().unwrap().from_mirror.recv().await }, if link.is_some() => {
+                match pkt {
+                    Some(pkt) => state.on_mirror_packet(pkt),
+                    None => {
+                        info!("{} 🪞 mirror head unit disconnected", NAME);
+                        link = None;
+                        state.detach();
+                        vec![]
+                    }
+                }
+            }
+        };
+        for route in routes {
+            match route {
+                Route::ToMirror(pkt) => {
+                    let gone = match &link {
+                        Some(link) => link.to_mirror.send(pkt).await.is_err(),
+                        None => false,
+                    };
+                    if gone {
+                        info!("{} 🪞 mirror head unit is gone", NAME);
+                        link = None;
+                        state.detach();
+                    }
+                }
+                Route::ToPhone(pkt) => to_phone.send(pkt).await?,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mitm::test_packets::{
+        audio_service, control, media, open_request, touch_service, video_service,
+    };
+    use crate::mitm::ENCRYPTED;
+
+    /// Service discovery response with a touchscreen sized after its service id.
+    fn discovery(video: i32, audio: i32, input: i32) -> Packet {
+        crate::mitm::test_packets::discovery(vec![
+            video_service(video),
+            audio_service(audio),
+            touch_service(input, input * 100, input * 50),
+        ])
+    }
+
+    /// Video DATA with a timestamp and one NAL unit of `nal_type`.
+    fn video(channel: u8, nal_type: u8) -> Packet {
+        let mut body = vec![0; 8];
+        body.extend([0, 0, 0, 1, nal_type, 0x88]);
+        media(channel, MEDIA_MESSAGE_DATA as u16, body)
+    }
+
+    fn to_mirror(routes: Vec<Route>) -> Vec<Packet> {
+        routes
+            .into_iter()
+            .map(|r| match r {
+                Route::ToMirror(pkt) => pkt,
+                _ => panic!("unexpected route, expected a packet towards the mirror"),
+            })
+            .collect()
+    }
+
+    /// A running primary session with its video on channel 3.
+    fn streaming(touch: bool) -> MirrorState {
+        let mut state = MirrorState::new(touch);
+        let phone = |state: &mut MirrorState, pkt| state.on_tapped(Tapped::FromPhone(pkt));
+        assert!(phone(
+            &mut state,
+            control(0, MESSAGE_VERSION_RESPONSE as u16, vec![0, 1])
+        )
+        .is_empty());
+        phone(
+            &mut state,
+            control(0, MESSAGE_SERVICE_DISCOVERY_REQUEST as u16, vec![]),
+        );
+        // a service discovery response spread over two frames
+        let mut first = discovery(3, 4, 5);
+        let rest = first.payload.split_off(4);
+        first.flags &= !FRAME_TYPE_LAST;
+        state.on_tapped(Tapped::FromHu(first));
+        state.on_tapped(Tapped::FromHu(Packet {
+            channel: 0,
+            flags: ENCRYPTED | FRAME_TYPE_LAST,
+            final_length: None,
+            payload: rest,
+        }));
+        assert_eq!(state.primary.len(), if touch { 3 } else { 2 });
+        phone(&mut state, open_request(3, 3));
+        phone(&mut state, open_request(5, 5));
+        phone(&mut state, media(3, MEDIA_MESSAGE_SETUP as u16, vec![1]));
+        phone(&mut state, media(3, MEDIA_MESSAGE_START as u16, vec![2]));
+        phone(
+            &mut state,
+            media(3, MEDIA_MESSAGE_CODEC_CONFIG as u16, vec![3]),
+        );
+        phone(
+            &mut state,
+            media(5, INPUT_MESSAGE_KEY_BINDING_REQUEST as u16, vec![4]),
+        );
+        assert!(phone(&mut state, media(3, MEDIA_MESSAGE_DATA as u16, vec![5])).is_empty());
+        state
+    }
+
+    #[test]
+    fn replays_the_session_to_a_late_mirror() {
+        let mut state = streaming(false);
+        state.attach();
+
+        let version = to_mirror(state.on_mirror_packet(control(
+            0,
+            MESSAGE_VERSION_REQUEST as u16,
+            vec![0, 1, 0, 7],
+        )));
+        assert_eq!(
+            message_id(&version[0]),
+            Some(MESSAGE_VERSION_RESPONSE as u16)
+        );
+        let request =
+            to_mirror(state.on_mirror_packet(control(0, MESSAGE_AUTH_COMPLETE as u16, vec![])));
+        assert_eq!(
+            message_id(&request[0]),
+            Some(MESSAGE_SERVICE_DISCOVERY_REQUEST as u16)
+        );
+
+        // the mirror has its video on channel 1, input is not mirrored
+        let open = to_mirror(state.on_mirror_packet(discovery(1, 2, 9)));
+        assert_eq!(open.len(), 1);
+        assert_eq!(open[0].channel, 1);
+        let request = ChannelOpenRequest::parse_from_bytes(&open[0].payload[2..]).unwrap();
+        assert_eq!(request.service_id(), 1);
+
+        let setup = to_mirror(state.on_mirror_packet(control(
+            1,
+            MESSAGE_CHANNEL_OPEN_RESPONSE as u16,
+            vec![8, 0],
+        )));
+        assert_eq!(setup[0].channel, 1);
+        assert_eq!(message_id(&setup[0]), Some(MEDIA_MESSAGE_SETUP as u16));
+        let running =
+            to_mirror(state.on_mirror_packet(media(1, MEDIA_MESSAGE_CONFIG as u16, vec![8, 0])));
+        let ids: Vec<_> = running.iter().map(message_id).collect();
+        assert_eq!(
+            ids,
+            [
+                Some(MEDIA_MESSAGE_START as u16),
+                Some(MEDIA_MESSAGE_CODEC_CONFIG as u16)
+            ]
+        );
+
+        // video now goes to the mirror's channel from the next IDR on, its
+        // ACKs stay with it
+        assert!(state
+            .on_tapped(Tapped::FromPhone(video(3, 0x41)))
+            .is_empty());
+        let data = to_mirror(state.on_tapped(Tapped::FromPhone(video(3, 0x65))));
+        assert_eq!(data[0].channel, 1);
+        assert_eq!(&data[0].payload[10..], [0, 0, 0, 1, 0x65, 0x88]);
+        let data = to_mirror(state.on_tapped(Tapped::FromPhone(video(3, 0x41))));
+        assert_eq!(data.len(), 1);
+        assert!(state
+            .on_mirror_packet(media(1, MEDIA_MESSAGE_ACK as u16, vec![]))
+            .is_empty());
+
+        let mut ping = PingRequest::new();
+        ping.set_timestamp(42);
+        let pong = to_mirror(state.on_mirror_packet(control(
+            0,
+            MESSAGE_PING_REQUEST as u16,
+            ping.write_to_bytes().unwrap(),
+        )));
+        assert_eq!(message_id(&pong[0]), Some(MESSAGE_PING_RESPONSE as u16));
+    }
+
+    #[test]
+    fn reports_lost_copies_before_the_next_one() {
+        let (tx, mut rx) = mpsc::channel(2);
+        let mut lost = BTreeSet::new();
+        let copy = |lost: &mut BTreeSet<u8>, channel| {
+            copy_to_tap(
+                &tx,
+                lost,
+                channel,
+                Tapped::FromPhone(media(channel, MEDIA_MESSAGE_DATA as u16, vec![])),
+            )
+        };
+        copy(&mut lost, 3);
+        copy(&mut lost, 4);
+        // full: both copies are lost
+        copy(&mut lost, 3);
+        copy(&mut lost, 5);
+        assert_eq!(lost, BTreeSet::from([3, 5]));
+
+        rx.try_recv().unwrap();
+        rx.try_recv().unwrap();
+        // room for the gap markers only, the copy itself is lost as well
+        copy(&mut lost, 4);
+        assert_eq!(lost, BTreeSet::from([4]));
+        assert!(matches!(rx.try_recv(), Ok(Tapped::Lost(3))));
+        assert!(matches!(rx.try_recv(), Ok(Tapped::Lost(5))));
+
+        copy(&mut lost, 3);
+        assert!(lost.is_empty());
+        assert!(matches!(rx.try_recv(), Ok(Tapped::Lost(4))));
+        assert!(matches!(rx.try_recv(), Ok(Tapped::FromPhone(pkt)) if pkt.channel == 3));
+    }
+
+    #[test]
+    fn passes_mirror_touch_only_when_allowed() {
+        for touch in [false, true] {
+            let mut state = streaming(touch);
+            state.attach();
+            state.on_mirror_packet(control(0, MESSAGE_VERSION_REQUEST as u16, vec![]));
+            state.on_mirror_packet(control(0, MESSAGE_AUTH_COMPLETE as u16, vec![]));
+            let opens = to_mirror(state.on_mirror_packet(discovery(1, 2, 7)));
+            assert_eq!(opens.len(), if touch { 2 } else { 1 });
+            if !touch {
+                assert!(state
+                    .on_mirror_packet(media(7, INPUT_MESSAGE_INPUT_REPORT as u16, vec![]))
+                    .is_empty());
+                continue;
+            }
+            let binding = to_mirror(state.on_mirror_packet(control(
+                7,
+                MESSAGE_CHANNEL_OPEN_RESPONSE as u16,
+                vec![8, 0],
+            )));
+            assert_eq!(
+                message_id(&binding[0]),
+                Some(INPUT_MESSAGE_KEY_BINDING_REQUEST as u16)
+            );
+            state.on_mirror_packet(media(
+                7,
+                INPUT_MESSAGE_KEY_BINDING_RESPONSE as u16,
+                vec![8, 0],
+            ));
+            let routes =
+                state.on_mirror_packet(media(7, INPUT_MESSAGE_INPUT_REPORT as u16, vec![1]));
+            let Some(Route::ToPhone(report)) = routes.into_iter().next() else {
+                panic!("touch not passed to the phone");
+            };
+            assert_eq!(report.channel, 5);
+        }
+    }
+
+    /// A mirror that joined `streaming` and shows its video on channel 1.
+    fn mirrored(touch: bool) -> MirrorState {
+        let mut state = streaming(touch);
+        state.attach();
+        state.on_mirror_packet(control(0, MESSAGE_VERSION_REQUEST as u16, vec![]));
+        state.on_mirror_packet(control(0, MESSAGE_AUTH_COMPLETE as u16, vec![]));
+        state.on_mirror_packet(discovery(1, 2, 7));
+        state.on_mirror_packet(control(1, MESSAGE_CHANNEL_OPEN_RESPONSE as u16, vec![8, 0]));
+        state.on_mirror_packet(media(1, MEDIA_MESSAGE_CONFIG as u16, vec![8, 0]));
+        state
+    }
+
+    #[test]
+    fn lost_copies_hold_video_until_a_keyframe() {
+        let mut state = mirrored(false);
+        assert_eq!(
+            to_mirror(state.on_tapped(Tapped::FromPhone(video(3, 0x65)))).len(),
+            1
+        );
+
+        // a multi-frame access unit loses its middle frame
+        let mut first = video(3, 0x41);
+        first.flags &= !FRAME_TYPE_LAST;
+        let mut last = media(3, 0, vec![9]);
+        last.flags &= !FRAME_TYPE_FIRST;
+        assert_eq!(
+            to_mirror(state.on_tapped(Tapped::FromPhone(first))).len(),
+            1
+        );
+        assert!(state.on_tapped(Tapped::Lost(3)).is_empty());
+        assert!(state.on_tapped(Tapped::FromPhone(last)).is_empty());
+        assert!(state
+            .on_tapped(Tapped::FromPhone(video(3, 0x41)))
+            .is_empty());
+
+        // a codec config ends the wait as well as an IDR does
+        let config = media(3, MEDIA_MESSAGE_CODEC_CONFIG as u16, vec![3]);
+        assert_eq!(
+            to_mirror(state.on_tapped(Tapped::FromPhone(config))).len(),
+            1
+        );
+        assert_eq!(
+            to_mirror(state.on_tapped(Tapped::FromPhone(video(3, 0x41)))).len(),
+            1
+        );
+    }
+
+    #[test]
+    fn scales_mirror_touch_to_the_primary_screen() {
+        let mut state = mirrored(true);
+        state.on_mirror_packet(control(7, MESSAGE_CHANNEL_OPEN_RESPONSE as u16, vec![8, 0]));
+        state.on_mirror_packet(media(
+            7,
+            INPUT_MESSAGE_KEY_BINDING_RESPONSE as u16,
+            vec![8, 0],
+        ));
+
+        // the mirror's screen is 700x350, the primary HU's 500x250
+        let mut report = InputReport::new();
+        report.set_timestamp(1);
+        let mut pointer = touch_event::Pointer::new();
+        pointer.set_x(350);
+        pointer.set_y(349);
+        pointer.set_pointer_id(0);
+        report
+            .touch_event
+            .mut_or_insert_default()
+            .pointer_data
+            .push(pointer);
+        let routes = state.on_mirror_packet(media(
+            7,
+            INPUT_MESSAGE_INPUT_REPORT as u16,
+            report.write_to_bytes().unwrap(),
+        ));
+        let Some(Route::ToPhone(pkt)) = routes.into_iter().next() else {
+            panic!("touch not passed to the phone");
+        };
+        assert_eq!(pkt.channel, 5);
+        let report = InputReport::parse_from_bytes(&pkt.payload[2..]).unwrap();
+        let pointer = &report.touch_event.pointer_data[0];
+        assert_eq!((pointer.x(), pointer.y()), (250, 249));
+    }
+}
@@ -1,7 +1,9 @@
+use crate::audio_inject;
 use crate::bt_sco;
 use crate::bt_sco_media_bridge;
 use crate::ev::send_ev_data;
 use crate::ev::BatteryData;
+use crate::mic_inject;
 #[cfg(feature = "wasm-scripting")]
 use crate::script_wasm::bindings::aa::packet::types::Decision;
 #[cfg(feature = "wasm-scripting")]
@@ -66,14 +68,16 @@ use protos::ControlMessageType::{self, *};
 use crate::config::{Action::Stop, AppConfig, BtScoMediaBridgeAudioType, SharedConfig};
 use crate::config_types::HexdumpLevel;
 use crate::ev::EvTaskCommand;
-use crate::hu_input::{handle_hu_input, HuInputState};
+use crate::hu_input::{handle_hfp_call_keys, handle_hu_input, HuInputState};
 use crate::io_uring::Endpoint;
 use crate::io_uring::IoDevice;
 use crate::io_uring::BUFFER_LEN;
 pub use crate::media_tap::{
     media_tcp_server, AudioStreamConfig, MediaSink, MediaStreamInfo, MediaStreamKind,
 };
-use crate::media_tap::{reassemble_media_packet, tap_media_message, MediaFrameBuffer};
+use crate::media_tap::{
+    reassemble_media_packet, tap_media_message, MediaFrameBuffer, MIC_SINK_OFFSET,
+};
 
 // module name for logging engine
 pub fn get_name(proxy_type: ProxyType) -> String {
@@ -96,7 +100,7 @@ pub const HEADER_LENGTH: usize = 4;
 pub const FRAME_TYPE_FIRST: u8 = 1 << 0;
 pub const FRAME_TYPE_LAST: u8 = 1 << 1;
 pub const FRAME_TYPE_MASK: u8 = FRAME_TYPE_FIRST | FRAME_TYPE_LAST;
-const _CONTROL: u8 = 1 << 2;
+pub const CONTROL: u8 = 1 << 2;
 pub const ENCRYPTED: u8 = 1 << 3;
 
 // location for hu_/md_ private keys and certificates:
@@ -115,11 +119,14 @@ pub struct ModifyContext {
     pub(crate) input_channel: Option<u8>,
     pub(crate) hu_tx: Option<Sender<Packet>>,
     pub(crate) hu_input_state: HuInputState,
-    /// Offset→sink map (keys 0-6). Used only at SDR time to look up which sink
-    /// to assign to each real channel. Never used for tapping.
+    /// Offset→sink map (keys 0-9; 8-9 are fed by the SCO bridge). Used only at SDR
+    /// time to look up which sink to assign to each real channel. Never used for tapping.
     pub(crate) media_sinks: HashMap<u8, MediaSink>,
     /// channel_id→sink map. Populated from SDR. Used for tapping data packets.
     pub(crate) media_channels: HashMap<u8, MediaSink>,
+    /// HU microphone (media source) channel. Its DATA flows HU → phone, so it
+    /// is tapped in the opposite direction to the media sink channels.
+    pub(crate) mic_channel: Option<u8>,
     /// Per-channel reassembly state for tapped media messages that span multiple
     /// AA transport frames.
     pub(crate) media_fragments: HashMap<u8, MediaFrameBuffer>,
@@ -232,6 +239,7 @@ impl SslMemBuf {
     }
 }
 
+#[derive(Clone)]
 pub struct Packet {
     pub channel: u8,
     pub flags: u8,
@@ -322,6 +330,91 @@ impl Packet {
     }
 }
 
+/// Packet builders shared by the tests of the session relaying modules.
+#[cfg(test)]
+pub(crate) mod test_packets {
+    use super::{Packet, CONTROL, ENCRYPTED, FRAME_TYPE_FIRST, FRAME_TYPE_LAST};
+    use crate::mitm::protos::ControlMessageType::*;
+    use crate::mitm::protos::*;
+    use protobuf::Message;
+
+    /// Unfragmented control message `id` on `channel`.
+    pub(crate) fn control(channel: u8, id: u16, body: Vec<u8>) -> Packet {
+        let mut payload = id.to_be_bytes().to_vec();
+        payload.extend(body);
+        Packet {
+            channel,
+            flags: ENCRYPTED
+                | FRAME_TYPE_FIRST
+                | FRAME_TYPE_LAST
+                | if channel == 0 { 0 } else { CONTROL },
+            final_length: None,
+            payload,
+        }
+    }
+
+    /// Unfragmented media message `id` on `channel`.
+    pub(crate) fn media(channel: u8, id: u16, body: Vec<u8>) -> Packet {
+        let mut pkt = control(channel, id, body);
+        pkt.flags &= !CONTROL;
+        pkt
+    }
+
+    pub(crate) fn open_request(channel: u8, service_id: i32) -> Packet {
+        let mut request = ChannelOpenRequest::new();
+        request.set_priority(0);
+        request.set_service_id(service_id);
+        control(
+            channel,
+            MESSAGE_CHANNEL_OPEN_REQUEST as u16,
+            request.write_to_bytes().unwrap(),
+        )
+    }
+
+    /// Main display video sink with a single default configuration.
+    pub(crate) fn video_service(id: i32) -> Service {
+        let mut svc = Service::new();
+        svc.set_id(id);
+        let mut sink = MediaSinkService::new();
+        sink.set_display_type(DisplayType::DISPLAY_TYPE_MAIN);
+        sink.video_configs.push(VideoConfiguration::new());
+        svc.media_sink_service = Some(sink).into();
+        svc
+    }
+
+    pub(crate) fn audio_service(id: i32) -> Service {
+        let mut svc = Service::new();
+        svc.set_id(id);
+        let mut sink = MediaSinkService::new();
+        sink.set_audio_type(AudioStreamType::AUDIO_STREAM_MEDIA);
+        svc.media_sink_service = Some(sink).into();
+        svc
+    }
+
+    pub(crate) fn touch_service(id: i32, width: i32, height: i32) -> Service {
+        let mut svc = Service::new();
+        svc.set_id(id);
+        let mut source = InputSourceService::new();
+        let mut screen = input_source_service::TouchScreen::new();
+        screen.set_width(width);
+        screen.set_height(height);
+        source.touchscreen.push(screen);
+        svc.input_source_service = Some(source).into();
+        svc
+    }
+
+    /// Service discovery response of an HU offering `services`.
+    pub(crate) fn discovery(services: Vec<Service>) -> Packet {
+        let mut response = ServiceDiscoveryResponse::new();
+        response.services = services;
+        control(
+            0,
+            MESSAGE_SERVICE_DISCOVERY_RESPONSE as u16,
+            response.write_to_bytes().unwrap(),
+        )
+    }
+}
+
 impl fmt::Display for Packet {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "packet dump:\n")?;
@@ -593,6 +686,14 @@ pub async fn pkt_modify_hook(
         }
     }
 
+    // HU call keys answer/end calls over HFP while a call is in progress
+    if proxy_type == ProxyType::HeadUnit && cfg.bt_hfp && ctx.input_channel == Some(pkt.channel) {
+        if let PacketAction::Drop = handle_hfp_call_keys(pkt) {
+            debug!("{} hu_input: call key handled by HFP", get_name(proxy_type));
+            return Ok(PacketAction::Drop);
+        }
+    }
+
     // HU button interception (only active when a handler command is configured)
     if proxy_type == ProxyType::HeadUnit && cfg.hu_button_handler.is_some() {
         if let Some(input_ch) = ctx.input_channel {
@@ -980,6 +1081,97 @@ pub async fn pkt_modify_hook(
         }
     }
 
+    // audio injection: follow media sessions on the PCM sinks and swallow HU
+    // ACKs for our own injected session
+    if proxy_type == ProxyType::MobileDevice && pkt.channel != 0 && (pkt.flags & CONTROL) == 0 {
+        match (flow, protos::MediaMessageId::from_i32(message_id)) {
+            (PacketFlow::FromEndpoint, Some(MEDIA_MESSAGE_START)) => {
+                audio_inject::notify_phone_media(pkt.channel, true);
+            }
+            (PacketFlow::FromEndpoint, Some(MEDIA_MESSAGE_STOP)) => {
+                audio_inject::notify_phone_media(pkt.channel, false);
+            }
+            (PacketFlow::ToEndpoint, Some(MEDIA_MESSAGE_CONFIG)) => {
+                if let Ok(msg) = AudioConfig::parse_from_bytes(data) {
+                    audio_inject::notify_media_config(pkt.channel, &msg);
+                }
+            }
+            (PacketFlow::ToEndpoint, Some(MEDIA_MESSAGE_ACK)) => {
+                if let Ok(msg) = Ack::parse_from_bytes(data) {
+                    if audio_inject::handle_ack(&msg) {
+                        return Ok(PacketAction::Drop);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // microphone injection: answer phone MicrophoneRequests ourselves and
+    // keep HU mic DATA and the phone ACKs for our session off the wire
+    if proxy_type == ProxyType::HeadUnit && pkt.channel != 0 && (pkt.flags & CONTROL) == 0 {
+        match (flow, protos::MediaMessageId::from_i32(message_id)) {
+            (PacketFlow::ToEndpoint, Some(MEDIA_MESSAGE_MICROPHONE_REQUEST)) => {
+                if let Ok(msg) = MicrophoneRequest::parse_from_bytes(data) {
+                    if let Some(response) = mic_inject::handle_microphone_request(pkt.channel, &msg)
+                    {
+                        let id = MEDIA_MESSAGE_MICROPHONE_RESPONSE as u16;
+                        pkt.payload = response;
+                        pkt.payload.insert(0, (id >> 8) as u8);
+                        pkt.payload.insert(1, (id & 0xff) as u8);
+                        return Ok(PacketAction::SendBack);
+                    }
+                }
+            }
+            (PacketFlow::ToEndpoint, Some(MEDIA_MESSAGE_ACK)) => {
+                if mic_inject::is_active(pkt.channel) {
+                    if let Ok(msg) = Ack::parse_from_bytes(data) {
+                        mic_inject::handle_ack(msg.ack());
+                    }
+                    return Ok(PacketAction::Drop);
+                }
+            }
+            (PacketFlow::FromEndpoint, Some(MEDIA_MESSAGE_DATA)) => {
+                if mic_inject::is_active(pkt.channel) {
+                    return Ok(PacketAction::Drop);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // feed HU media flow control (HU → phone direction) into the tap statistics
+    if proxy_type == ProxyType::MobileDevice && flow == PacketFlow::ToEndpoint {
+        if let Some(sink) = ctx.media_channels.get(&pkt.channel) {
+            match protos::MediaMessageId::from_i32(message_id) {
+                Some(MEDIA_MESSAGE_ACK) => {
+                    if let Ok(msg) = Ack::parse_from_bytes(data) {
+                        sink.record_ack(msg.ack());
+                    }
+                }
+                Some(MEDIA_MESSAGE_CONFIG) => {
+                    if let Ok(msg) = AudioConfig::parse_from_bytes(data) {
+                        sink.record_media_config(msg.max_unacked());
+                        if let Some(&index) = msg.configuration_indices.first() {
+                            sink.select_video_config(index).await;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    // the phone starts the stream with the configuration it picked
+    if proxy_type == ProxyType::MobileDevice && flow == PacketFlow::FromEndpoint {
+        if let Some(sink) = ctx.media_channels.get(&pkt.channel) {
+            if protos::MediaMessageId::from_i32(message_id) == Some(MEDIA_MESSAGE_START) {
+                if let Ok(msg) = Start::parse_from_bytes(data) {
+                    sink.select_video_config(msg.configuration_index()).await;
+                }
+            }
+        }
+    }
+
     // tap media frames for debug streaming (only on MobileDevice path = phone → HU direction)
     if tap_media && proxy_type == ProxyType::MobileDevice {
         if let Some(frame_data) = reassemble_media_packet(&mut ctx.media_fragments, pkt) {
@@ -991,13 +1183,27 @@ pub async fn pkt_modify_hook(
         }
     }
 
+    // tap HU microphone frames (HU → phone direction)
+    if proxy_type == ProxyType::MobileDevice
+        && flow == PacketFlow::ToEndpoint
+        && ctx.mic_channel == Some(pkt.channel)
+    {
+        if let Some(frame_data) = reassemble_media_packet(&mut ctx.media_fragments, pkt) {
+            if frame_data.len() >= 2 {
+                if let Some(sink) = ctx.media_channels.get(&pkt.channel).cloned() {
+                    tap_media_message(proxy_type, pkt, &sink, &frame_data).await;
+                }
+            }
+        }
+    }
+
     let control = protos::ControlMessageType::from_i32(message_id);
 
     if pkt.channel != 0 {
         // Non-zero channel AAP lifecycle/control frame.
         // Keep this separate from our custom vendor app-data parser.
         // The custom parser below does not inspect CONTROL flags or AAP control message ids.
-        if pkt.payload.len() >= 2 && (pkt.flags & _CONTROL) == _CONTROL {
+        if pkt.payload.len() >= 2 && (pkt.flags & CONTROL) == CONTROL {
             let control_msg_id = u16::from_be_bytes([pkt.payload[0], pkt.payload[1]]);
 
             if control_msg_id == MESSAGE_CHANNEL_OPEN_REQUEST as u16 {
@@ -1265,7 +1471,7 @@ pub async fn pkt_modify_hook(
                                 });
                             sink.set_audio_stream_info(
                                 svc.media_sink_service.available_type(),
-                                svc.media_sink_service.audio_type(),
+                                Some(svc.media_sink_service.audio_type()),
                                 audio_config,
                             )
                             .await;
@@ -1313,6 +1519,49 @@ pub async fn pkt_modify_hook(
                 }
             }
 
+            // HU microphone source (media source service) gets its own sink
+            if let Some(sink) = ctx.media_sinks.get(&MIC_SINK_OFFSET).cloned() {
+                let mic = msg.services.iter().find(|svc| {
+                    svc.media_source_service.audio_config.is_some()
+                        && svc.media_source_service.available_type()
+                            == MediaCodecType::MEDIA_CODEC_AUDIO_PCM
+                });
+                if let Some(svc) = mic {
+                    let ch = svc.id() as u8;
+                    let acfg = svc.media_source_service.audio_config.as_ref().unwrap();
+                    let audio_config = AudioStreamConfig {
+                        sample_rate: acfg.sampling_rate(),
+                        channels: acfg.number_of_channels(),
+                        bits: acfg.number_of_bits(),
+                    };
+                    sink.set_audio_stream_info(
+                        MediaCodecType::MEDIA_CODEC_AUDIO_PCM,
+                        None,
+                        Some(audio_config),
+                    )
+                    .await;
+                    ctx.media_channels.insert(ch, sink);
+                    ctx.mic_channel = Some(ch);
+                    info!(
+                        "{} <blue>media tap:</> microphone channel <b>{:#04x}</> → port offset <b>{}</> ({}Hz, {}ch, {}bit)",
+                        get_name(proxy_type),
+                        ch,
+                        MIC_SINK_OFFSET,
+                        audio_config.sample_rate,
+                        audio_config.channels,
+                        audio_config.bits
+                    );
+                }
+            }
+
+            if proxy_type == ProxyType::MobileDevice {
+                audio_inject::set_sinks(&msg, ctx.hu_tx.clone());
+            }
+            if proxy_type == ProxyType::HeadUnit {
+                // in the HU proxy `hu_tx` feeds the MD side, i.e. goes to the phone
+                mic_inject::set_source(&msg, ctx.hu_tx.clone());
+            }
+
             if cfg.bt_sco_media_bridge && proxy_type == ProxyType::MobileDevice {
                 if let Some((bridge_channel, acfg, audio_type, score)) =
                     choose_bt_sco_media_bridge_sink(&msg, cfg.bt_sco_media_bridge_audio_type)
@@ -1789,6 +2038,11 @@ pub async fn pkt_modify_hook(
                             sink.set_video_stream_info(
                                 svc.media_sink_service.available_type(),
                                 svc.media_sink_service.display_type(),
+                                svc.media_sink_service
+                                    .video_configs
+                                    .iter()
+                                    .map(|vcfg| vcfg.codec_resolution())
+                                    .collect(),
                             )
                             .await;
                             ctx.media_channels.insert(ch, sink);
@@ -1823,7 +2077,7 @@ pub async fn pkt_modify_hook(
                                 });
                             sink.set_audio_stream_info(
                                 svc.media_sink_service.available_type(),
-                                svc.media_sink_service.audio_type(),
+                                Some(svc.media_sink_service.audio_type()),
                                 audio_config,
                             )
                             .await;
@@ -2466,12 +2720,164 @@ fn build_control_reply_on_channel(
         // Non-zero service-channel control frames observed from real HU/DHU use 0x0f:
         // ENCRYPTED | CONTROL | FIRST | LAST. Without CONTROL (0x04), Android may
         // not treat our synthetic CHANNEL_OPEN_RESPONSE as a channel-control frame.
-        flags: ENCRYPTED | _CONTROL | FRAME_TYPE_FIRST | FRAME_TYPE_LAST,
+        flags: ENCRYPTED | CONTROL | FRAME_TYPE_FIRST | FRAME_TYPE_LAST,
         final_length: None,
         payload,
     }
 }
 
+/// HU side of the initial phase: passes the HU's version request to `tx`,
+/// transmits the version response from `rx` and does the SSL handshake as
+/// the SSL server. A failing handshake marks `config` as `runtime_mitm_failed`.
+async fn hu_handshake<A: Endpoint<A>>(
+    device: &mut IoDevice<A>,
+    tx: &Sender<Packet>,
+    rx: &mut Receiver<Packet>,
+    rxr: &mut Receiver<Packet>,
+    mem_buf: &mut SslMemBuf,
+    server: &mut openssl::ssl::SslStream<SslMemBuf>,
+    cfg: &AppConfig,
+    config: Option<&SharedConfig>,
+) -> Result<()> {
+    let proxy_type = ProxyType::HeadUnit;
+    let hex_requested = cfg.hexdump_level;
+
+    // waiting for initial version frame (HU is starting transmission)
+    let pkt = rxr.recv().await.ok_or("reader channel hung up")?;
+    let _ = pkt_debug(
+        proxy_type,
+        HexdumpLevel::DecryptedInput, // the packet is not encrypted
+        hex_requested,
+        &pkt,
+        cfg,
+        None,
+    )
+    .await;
+    // sending to the MD
+    tx.send(pkt).await?;
+    // waiting for MD reply
+    let pkt = rx.recv().await.ok_or("rx channel hung up")?;
+    // sending reply back to the HU
+    let _ = pkt_debug(
+        proxy_type,
+        HexdumpLevel::RawOutput,
+        hex_requested,
+        &pkt,
+        cfg,
+        None,
+    )
+    .await;
+    pkt.transmit(device)
+        .await
+        .with_context(|| format!("proxy/{}: transmit failed", get_name(proxy_type)))?;
+
+    // doing SSL handshake
+    const STEPS: u8 = 2;
+    for i in 1..=STEPS {
+        let pkt = rxr.recv().await.ok_or("reader channel hung up")?;
+        let _ = pkt_debug(
+            proxy_type,
+            HexdumpLevel::RawInput,
+            hex_requested,
+            &pkt,
+            cfg,
+            None,
+        )
+        .await;
+        pkt.ssl_decapsulate_write(mem_buf).await?;
+        if let Err(e) = ssl_check_failure(server.accept()) {
+            if let Some(config) = config {
+                config.write().await.runtime_mitm_failed = true;
+            }
+            return Err(e);
+        }
+        info!(
+            "{} 🔒 stage #{} of {}: SSL handshake: {}",
+            get_name(proxy_type),
+            i,
+            STEPS,
+            server.ssl().state_string_long(),
+        );
+        if server.ssl().is_init_finished() {
+            info!(
+                "{} 🔒 SSL init complete, negotiated cipher: <b><blue>{}</>",
+                get_name(proxy_type),
+                server.ssl().current_cipher().unwrap().name(),
+            );
+        }
+        let pkt = ssl_encapsulate(mem_buf.clone()).await?;
+        let _ = pkt_debug(
+            proxy_type,
+            HexdumpLevel::RawOutput,
+            hex_requested,
+            &pkt,
+            cfg,
+            None,
+        )
+        .await;
+        pkt.transmit(device)
+            .await
+            .with_context(|| format!("proxy/{}: transmit failed", get_name(proxy_type)))?;
+    }
+
+    Ok(())
+}
+
+/// HU side of a session without any packet modification: after the
+/// handshake it only encrypts what comes from `rx` and passes the decrypted
+/// HU packets to `tx`. For head units that are not fed by the phone's proxy
+/// directly, like the mirror HU.
+pub async fn hu_endpoint<A: Endpoint<A>>(
+    mut device: IoDevice<A>,
+    tx: Sender<Packet>,
+    mut rx: Receiver<Packet>,
+    mut rxr: Receiver<Packet>,
+    config: SharedConfig,
+) -> Result<()> {
+    let cfg = config.read().await.clone();
+    let proxy_type = ProxyType::HeadUnit;
+    let hex_requested = cfg.hexdump_level;
+
+    let ssl = ssl_builder(proxy_type).await?;
+    let mut mem_buf = SslMemBuf {
+        client_stream: Arc::new(Mutex::new(VecDeque::new())),
+        server_stream: Arc::new(Mutex::new(VecDeque::new())),
+    };
+    let mut server = openssl::ssl::SslStream::new(ssl, mem_buf.clone())?;
+    hu_handshake(
+        &mut device,
+        &tx,
+        &mut rx,
+        &mut rxr,
+        &mut mem_buf,
+        &mut server,
+        &cfg,
+        None,
+    )
+    .await?;
+
+    loop {
+        tokio::select! {
+        pkt = rx.recv() => {
+            let mut pkt = pkt.ok_or("rx channel hung up")?;
+            pkt.encrypt_payload(&mut mem_buf, &mut server).await?;
+            let _ = pkt_debug(proxy_type, HexdumpLevel::RawOutput, hex_requested, &pkt, &cfg, None).await;
+            pkt.transmit(&mut device)
+                .await
+                .with_context(|| format!("proxy/{}: transmit failed", get_name(proxy_type)))?;
+        }
+        pkt = rxr.recv() => {
+            let mut pkt = pkt.ok_or("reader channel hung up")?;
+            let _ = pkt_debug(proxy_type, HexdumpLevel::RawInput, hex_requested, &pkt, &cfg, None).await;
+            match pkt.decrypt_payload(&mut mem_buf, &mut server).await {
+                Ok(_) => tx.send(pkt).await?,
+                Err(e) => error!("decrypt_payload: {:?}", e),
+            }
+        }
+        }
+    }
+}
+
 /// main thread doing all packet processing of an endpoint/device
 pub async fn proxy<A: Endpoint<A> + 'static>(
     proxy_type: ProxyType,
@@ -2542,81 +2948,17 @@ pub async fn proxy<A: Endpoint<A> + 'static>(
     // initial phase: passing version and doing SSL handshake
     // for both HU and MD
     if proxy_type == ProxyType::HeadUnit {
-        // waiting for initial version frame (HU is starting transmission)
-        let pkt = rxr.recv().await.ok_or("reader channel hung up")?;
-        let _ = pkt_debug(
-            proxy_type,
-            HexdumpLevel::DecryptedInput, // the packet is not encrypted
-            hex_requested,
-            &pkt,
-            &cfg,
-            None,
-        )
-        .await;
-        // sending to the MD
-        tx.send(pkt).await?;
-        // waiting for MD reply
-        let pkt = rx.recv().await.ok_or("rx channel hung up")?;
-        // sending reply back to the HU
-        let _ = pkt_debug(
-            proxy_type,
-            HexdumpLevel::RawOutput,
-            hex_requested,
-            &pkt,
+        hu_handshake(
+            &mut device,
+            &tx,
+            &mut rx,
+            &mut rxr,
+            &mut mem_buf,
+            &mut server,
             &cfg,
-            None,
+            Some(&config),
         )
-        .await;
-        pkt.transmit(&mut device)
-            .await
-            .with_context(|| format!("proxy/{}: transmit failed", get_name(proxy_type)))?;
-
-        // doing SSL handshake
-        const STEPS: u8 = 2;
-        for i in 1..=STEPS {
-            let pkt = rxr.recv().await.ok_or("reader channel hung up")?;
-            let _ = pkt_debug(
-                proxy_type,
-                HexdumpLevel::RawInput,
-                hex_requested,
-                &pkt,
-                &cfg,
-                None,
-            )
-            .await;
-            pkt.ssl_decapsulate_write(&mut mem_buf).await?;
-            if let Err(e) = ssl_check_failure(server.accept()) {
-                config.write().await.runtime_mitm_failed = true;
-                return Err(e);
-            }
-            info!(
-                "{} 🔒 stage #{} of {}: SSL handshake: {}",
-                get_name(proxy_type),
-                i,
-                STEPS,
-                server.ssl().state_string_long(),
-            );
-            if server.ssl().is_init_finished() {
-                info!(
-                    "{} 🔒 SSL init complete, negotiated cipher: <b><blue>{}</>",
-                    get_name(proxy_type),
-                    server.ssl().current_cipher().unwrap().name(),
-                );
-            }
-            let pkt = ssl_encapsulate(mem_buf.clone()).await?;
-            let _ = pkt_debug(
-                proxy_type,
-                HexdumpLevel::RawOutput,
-                hex_requested,
-                &pkt,
-                &cfg,
-                None,
-            )
-            .await;
-            pkt.transmit(&mut device)
-                .await
-                .with_context(|| format!("proxy/{}: transmit failed", get_name(proxy_type)))?;
-        }
+        .await?;
     } else if proxy_type == ProxyType::MobileDevice {
         // expecting version request from the HU here...
         let pkt = rx.recv().await.ok_or("rx channel hung up")?;
@@ -2712,6 +3054,7 @@ pub async fn proxy<A: Endpoint<A> + 'static>(
         hu_input_state: HuInputState::default(),
         media_sinks,
         media_channels: HashMap::new(),
+        mic_channel: None,
         media_fragments: HashMap::new(),
         hu_service_ids: HashSet::new(),
         injected_service_ids: HashSet::new(),
@@ -2904,6 +3247,7 @@ mod tests {
             hu_input_state: HuInputState::default(),
             media_sinks: HashMap::new(),
             media_channels: HashMap::new(),
+            mic_channel: None,
             media_fragments: HashMap::new(),
             hu_service_ids: HashSet::new(),
             injected_service_ids: HashSet::new(),
@@ -0,0 +1,261 @@
+//! Several phones sharing one head unit session.
+//!
+//! With `multi_phone` a second phone can finish the Wi-Fi bootstrap while
+//! another one is projected. io_loop parks it on its TCP connection and the
+//! projected phone sees it in `CarConnectedDevices`. A switch, requested via
+//! REST, the button or a `UserSwitchRequest` from the phone, hands the head
+//! unit session over to the parked phone through the handover relay; the
+//! phone that was projected is disconnected.
+use crate::config::MultiPhonePolicy;
+use crate::known_devices;
+use crate::wifi_security;
+use bluer::Address;
+use serde::Serialize;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// How long a Bluetooth bootstrap waits to be claimed by a TCP client.
+const BOOTSTRAP_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Phone {
+    /// Unique per process, also sent as `ConnectedDevice.device_id`.
+    pub id: i32,
+    pub name: String,
+    /// Bluetooth address of the bootstrap, None when the phone connected
+    /// without one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bt_addr: Option<String>,
+    /// Priority from the known devices store.
+    pub priority: i32,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Phones {
+    pub active: Option<Phone>,
+    /// In order of arrival.
+    pub parked: Vec<Phone>,
+}
+
+/// A phone that sent the Wi-Fi parameters over Bluetooth.
+#[derive(Debug, Clone)]
+struct Bootstrap {
+    addr: Address,
+    bt_name: Option<String>,
+    at: Instant,
+}
+
+static NEXT_ID: AtomicI32 = AtomicI32::new(1);
+/// Bootstraps not claimed by a TCP client yet, oldest first.
+static BOOTSTRAPPED: Mutex<Vec<Bootstrap>> = Mutex::new(Vec::new());
+/// Bootstrap of the TCP clients identified so far, by Wi-Fi MAC.
+static CLIENTS: Mutex<Vec<(String, Bootstrap)>> = Mutex::new(Vec::new());
+static PHONES: Mutex<Phones> = Mutex::new(Phones {
+    active: None,
+    parked: Vec::new(),
+});
+/// Pending switch: Some(None) for "any parked phone".
+static SWITCH: Mutex<Option<Option<i32>>> = Mutex::new(None);
+static SWITCH_REQUESTED: OnceLock<Notify> = OnceLock::new();
+
+fn switch_requested_notify() -> &'static Notify {
+    SWITCH_REQUESTED.get_or_init(Notify::new)
+}
+
+/// Remember who sent the Wi-Fi parameters, the phone connects over TCP next.
+pub fn bootstrapped(addr: Address, bt_name: Option<String>) {
+    let mut pending = BOOTSTRAPPED.lock().unwrap();
+    pending.retain(|b| b.addr != addr);
+    pending.push(Bootstrap {
+        addr,
+        bt_name,
+        at: Instant::now(),
+    });
+}
+
+/// The pending bootstrap of a TCP client: the one of the per-phone Wi-Fi
+/// key it joined with, a new one of the phone it was identified as before,
+/// or the only one pending. Anything else would be a guess.
+fn claim(pending: &[Bootstrap], keyid: Option<&str>, known: Option<Address>) -> Option<usize> {
+    if let Some(keyid) = keyid {
+        return pending
+            .iter()
+            .position(|b| wifi_security::psk_keyid(&b.addr.to_string()) == keyid);
+    }
+    if let Some(addr) = known {
+        return pending.iter().position(|b| b.addr == addr);
+    }
+    (pending.len() == 1).then_some(0)
+}
+
+/// Name the phone that just connected over TCP, from its Wi-Fi MAC and
+/// the `wpa_psk_file` key id it authenticated with, when known.
+pub fn identify(mac: Option<&str>, keyid: Option<&str>) -> Phone {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let mut pending = BOOTSTRAPPED.lock().unwrap();
+    pending.retain(|b| b.at.elapsed() < BOOTSTRAP_TTL);
+    let mut clients = CLIENTS.lock().unwrap();
+    let known = mac.and_then(|mac| clients.iter().find(|(m, _)| m == mac).map(|(_, b)| b));
+    let bootstrap = match claim(&pending, keyid, known.map(|b| b.addr)) {
+        Some(idx) => Some(pending.remove(idx)),
+        None => known.cloned(),
+    };
+    if let (Some(mac), Some(bootstrap)) = (mac, &bootstrap) {
+        clients.retain(|(m, _)| m != mac);
+        clients.push((mac.to_string(), bootstrap.clone()));
+    }
+    match bootstrap {
+        Some(Bootstrap { addr, bt_name, .. }) => {
+            let known = known_devices::get(addr);
+            Phone {
+                id,
+                name: known
+                    .as_ref()
+                    .and_then(|d| d.name.clone())
+                    .or(bt_name)
+                    .unwrap_or_else(|| addr.to_string()),
+                bt_addr: Some(addr.to_string()),
+                priority: known.map(|d| d.priority).unwrap_or(0),
+            }
+        }
+        None => Phone {
+            id,
+            name: format!("Phone {}", id),
+            bt_addr: None,
+            priority: 0,
+        },
+    }
+}
+
+/// Whether `newcomer` takes over from the projected phone.
+pub fn takes_over(policy: MultiPhonePolicy, active: &Phone, newcomer: &Phone) -> bool {
+    match policy {
+        MultiPhonePolicy::Active => false,
+        MultiPhonePolicy::Newest => true,
+        MultiPhonePolicy::Priority => newcomer.priority > active.priority,
+    }
+}
+
+/// Index of the parked phone to project next: the requested one, otherwise
+/// the highest priority and on a tie the one waiting longest.
+pub fn pick<'a>(parked: impl IntoIterator<Item = &'a Phone>, target: Option<i32>) -> Option<usize> {
+    let mut best: Option<(usize, &Phone)> = None;
+    for (idx, phone) in parked.into_iter().enumerate() {
+        match target {
+            Some(id) if phone.id == id => return Some(idx),
+            Some(_) => {}
+            None => {
+                if best.is_none_or(|(_, b)| phone.priority > b.priority) {
+                    best = Some((idx, phone));
+                }
+            }
+        }
+    }
+    best.map(|(idx, _)| idx)
+}
+
+/// Publish the phones of the running session.
+pub fn set(phones: Phones) {
+    *PHONES.lock().unwrap() = phones;
+}
+
+pub fn status() -> Phones {
+    PHONES.lock().unwrap().clone()
+}
+
+/// Forget the phones and any switch request of the previous session.
+pub fn reset() {
+    set(Phones::default());
+    SWITCH.lock().unwrap().take();
+}
+
+/// Ask io_loop to project `target`, or the preferred parked phone. Returns
+/// false when there is no such phone.
+pub fn request_switch(target: Option<i32>) -> bool {
+    if pick(&PHONES.lock().unwrap().parked, target).is_none() {
+        return false;
+    }
+    *SWITCH.lock().unwrap() = Some(target);
+    switch_requested_notify().notify_one();
+    true
+}
+
+/// Wait for [`request_switch`], returns its target.
+pub async fn switch_requested() -> Option<i32> {
+    loop {
+        switch_requested_notify().notified().await;
+        if let Some(target) = SWITCH.lock().unwrap().take() {
+            return target;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bootstrap(addr: &str) -> Bootstrap {
+        Bootstrap {
+            addr: addr.parse().unwrap(),
+            bt_name: None,
+            at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn claims_only_the_bootstrap_of_the_client() {
+        let one = [bootstrap("AA:BB:CC:DD:EE:01")];
+        let two = [
+            bootstrap("AA:BB:CC:DD:EE:01"),
+            bootstrap("AA:BB:CC:DD:EE:02"),
+        ];
+        // the per-phone key names the phone
+        assert_eq!(claim(&two, Some("AABBCCDDEE02"), None), Some(1));
+        assert_eq!(claim(&one, Some("AABBCCDDEE02"), None), None);
+        // a client seen before only claims its own phone's bootstrap
+        assert_eq!(claim(&two, None, Some(two[1].addr)), Some(1));
+        assert_eq!(claim(&one, None, Some(two[1].addr)), None);
+        // without either, only an unambiguous bootstrap
+        assert_eq!(claim(&one, None, None), Some(0));
+        assert_eq!(claim(&two, None, None), None);
+        assert_eq!(claim(&[], None, None), None);
+    }
+
+    fn phone(id: i32, priority: i32) -> Phone {
+        Phone {
+            id,
+            name: format!("Phone {}", id),
+            bt_addr: None,
+            priority,
+        }
+    }
+
+    #[test]
+    fn policy_decides_who_is_projected() {
+        let active = phone(1, 5);
+        assert!(!takes_over(MultiPhonePolicy::Active, &active, &phone(2, 9)));
+        assert!(takes_over(MultiPhonePolicy::Newest, &active, &phone(2, 0)));
+        assert!(takes_over(
+            MultiPhonePolicy::Priority,
+            &active,
+            &phone(2, 9)
+        ));
+        assert!(!takes_over(
+            MultiPhonePolicy::Priority,
+            &active,
+            &phone(2, 5)
+        ));
+    }
+
+    #[test]
+    fn picks_requested_or_preferred_parked_phone() {
+        let parked = [phone(3, 0), phone(4, 2), phone(5, 2)];
+        assert_eq!(pick(&parked, Some(5)), Some(2));
+        assert_eq!(pick(&parked, Some(9)), None);
+        // highest priority, the earlier arrival on a tie
+        assert_eq!(pick(&parked, None), Some(1));
+        assert_eq!(pick(&[], None), None);
+    }
+}
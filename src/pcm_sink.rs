@@ -0,0 +1,254 @@
+//! Proxy-driven PCM sessions on HU audio sinks.
+//!
+//! The SCO media bridge and audio injection both play PCM into a media sink
+//! the HU advertised for the phone: START with the configuration index from
+//! the HU's media `Config`, timestamped DATA, then STOP. This module frames
+//! those messages and keeps the session PTS and HU ACK window.
+
+use crate::mitm::protos::{Config as AudioConfig, MediaMessageId, Start, Stop};
+use crate::mitm::{Packet, ENCRYPTED, FRAME_TYPE_FIRST, FRAME_TYPE_LAST};
+use protobuf::Message;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// Sink parameters the HU sends in its media `Config`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SinkConfig {
+    pub configuration_index: u32,
+    pub max_unacked: u32,
+}
+
+impl SinkConfig {
+    pub fn from_config(cfg: &AudioConfig) -> Self {
+        Self {
+            configuration_index: cfg.configuration_indices.first().copied().unwrap_or(0),
+            max_unacked: cfg.max_unacked(),
+        }
+    }
+}
+
+/// Single-frame encrypted media message with the id prepended.
+pub fn media_packet(channel: u8, id: MediaMessageId, mut payload: Vec<u8>) -> Packet {
+    let id = id as u16;
+    payload.insert(0, (id & 0xff) as u8);
+    payload.insert(0, (id >> 8) as u8);
+    Packet {
+        channel,
+        flags: ENCRYPTED | FRAME_TYPE_FIRST | FRAME_TYPE_LAST,
+        final_length: None,
+        payload,
+    }
+}
+
+/// START/DATA/STOP state of one session on an s16le PCM sink.
+pub struct SinkSession {
+    channel: u8,
+    session_id: i32,
+    sample_rate: u32,
+    channels: u32,
+    frames_sent: u64,
+    data_packets: u64,
+}
+
+impl SinkSession {
+    pub fn new(channel: u8, session_id: i32, sample_rate: u32, channels: u32) -> Self {
+        Self {
+            channel,
+            session_id,
+            sample_rate: sample_rate.max(1),
+            channels: channels.max(1),
+            frames_sent: 0,
+            data_packets: 0,
+        }
+    }
+
+    pub fn channel(&self) -> u8 {
+        self.channel
+    }
+
+    pub fn session_id(&self) -> i32 {
+        self.session_id
+    }
+
+    /// PTS of the next DATA packet.
+    pub fn pts_us(&self) -> u64 {
+        self.frames_sent * 1_000_000 / self.sample_rate as u64
+    }
+
+    pub fn data_packets(&self) -> u64 {
+        self.data_packets
+    }
+
+    /// Restart the timeline at PTS 0.
+    pub fn reset(&mut self) {
+        self.frames_sent = 0;
+        self.data_packets = 0;
+    }
+
+    /// START for a new session; the timeline restarts at PTS 0.
+    pub fn start(&mut self, configuration_index: u32) -> Packet {
+        self.reset();
+        let mut start = Start::new();
+        start.set_session_id(self.session_id);
+        start.set_configuration_index(configuration_index);
+        media_packet(
+            self.channel,
+            MediaMessageId::MEDIA_MESSAGE_START,
+            start.write_to_bytes().unwrap_or_default(),
+        )
+    }
+
+    pub fn stop(&self) -> Packet {
+        media_packet(
+            self.channel,
+            MediaMessageId::MEDIA_MESSAGE_STOP,
+            Stop::new().write_to_bytes().unwrap_or_default(),
+        )
+    }
+
+    /// DATA carrying interleaved s16le `pcm`, stamped with the session PTS.
+    pub fn data(&mut self, pcm: &[u8]) -> Packet {
+        let mut payload = Vec::with_capacity(8 + pcm.len());
+        payload.extend_from_slice(&self.pts_us().to_be_bytes());
+        payload.extend_from_slice(pcm);
+        self.frames_sent += (pcm.len() / (self.channels as usize * 2)) as u64;
+        self.data_packets += 1;
+        media_packet(self.channel, MediaMessageId::MEDIA_MESSAGE_DATA, payload)
+    }
+}
+
+/// Interleaved samples as s16le bytes.
+pub fn s16le_bytes(samples: &[i16]) -> Vec<u8> {
+    samples.iter().flat_map(|s| s.to_le_bytes()).collect()
+}
+
+/// DATA packets of a proxy-driven session the receiver has not ACKed yet.
+#[derive(Default)]
+pub struct AckWindow {
+    unacked: AtomicU32,
+}
+
+impl AckWindow {
+    pub const fn new() -> Self {
+        Self {
+            unacked: AtomicU32::new(0),
+        }
+    }
+
+    pub fn reset(&self) {
+        self.unacked.store(0, Ordering::SeqCst);
+    }
+
+    pub fn sent(&self) {
+        self.unacked.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Account for an ACK covering `count` packets (at least one).
+    pub fn acked(&self, count: u32) {
+        let _ = self
+            .unacked
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                Some(n.saturating_sub(count.max(1)))
+            });
+    }
+
+    pub fn unacked(&self) -> u32 {
+        self.unacked.load(Ordering::SeqCst)
+    }
+
+    /// Wait until fewer than `max_unacked` packets are outstanding
+    /// (0 = unlimited). After `timeout` the window is assumed lost and
+    /// reset; returns `false` in that case.
+    pub async fn wait_for_room(&self, max_unacked: u32, timeout: Duration) -> bool {
+        if max_unacked == 0 {
+            return true;
+        }
+        let waited = Instant::now();
+        while self.unacked() >= max_unacked {
+            if waited.elapsed() >= timeout {
+                self.reset();
+                return false;
+            }
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message_id(pkt: &Packet) -> u16 {
+        u16::from_be_bytes([pkt.payload[0], pkt.payload[1]])
+    }
+
+    fn pts(pkt: &Packet) -> u64 {
+        u64::from_be_bytes(pkt.payload[2..10].try_into().unwrap())
+    }
+
+    #[test]
+    fn data_pts_follows_sent_frames() {
+        let mut session = SinkSession::new(4, 1, 16_000, 2);
+        // 20 ms of 16 kHz stereo
+        let chunk = vec![0u8; 320 * 2 * 2];
+
+        let first = session.data(&chunk);
+        let second = session.data(&chunk);
+
+        assert_eq!(
+            message_id(&first),
+            MediaMessageId::MEDIA_MESSAGE_DATA as u16
+        );
+        assert_eq!(first.channel, 4);
+        assert_eq!(pts(&first), 0);
+        assert_eq!(pts(&second), 20_000);
+        assert_eq!(&second.payload[10..], &chunk[..]);
+        assert_eq!(session.pts_us(), 40_000);
+        assert_eq!(session.data_packets(), 2);
+    }
+
+    #[test]
+    fn start_restarts_timeline() {
+        let mut session = SinkSession::new(4, 0x1234, 48_000, 1);
+        session.data(&[0u8; 960]);
+
+        let start = session.start(3);
+        let msg = Start::parse_from_bytes(&start.payload[2..]).unwrap();
+
+        assert_eq!(
+            message_id(&start),
+            MediaMessageId::MEDIA_MESSAGE_START as u16
+        );
+        assert_eq!(msg.session_id(), 0x1234);
+        assert_eq!(msg.configuration_index(), 3);
+        assert_eq!(session.pts_us(), 0);
+        assert_eq!(session.data_packets(), 0);
+        assert_eq!(
+            message_id(&session.stop()),
+            MediaMessageId::MEDIA_MESSAGE_STOP as u16
+        );
+    }
+
+    #[test]
+    fn ack_window_counts_at_least_one_per_ack() {
+        let window = AckWindow::new();
+        window.sent();
+        window.sent();
+        window.sent();
+        window.acked(0);
+        assert_eq!(window.unacked(), 2);
+        window.acked(5);
+        assert_eq!(window.unacked(), 0);
+    }
+
+    #[tokio::test]
+    async fn ack_window_resets_after_timeout() {
+        let window = AckWindow::new();
+        window.sent();
+        assert!(!window.wait_for_room(1, Duration::from_millis(10)).await);
+        assert_eq!(window.unacked(), 0);
+        assert!(window.wait_for_room(1, Duration::from_millis(10)).await);
+    }
+}
@@ -1,5 +1,8 @@
-use crate::bluetooth::{load_known_devices, KNOWN_DEVICES_FILE};
+use crate::audio_inject::{self, InjectRequest};
 use crate::bt_helper;
+use crate::bt_pairing::{self, AnswerError, PairingAnswer};
+use crate::bt_sco_voice;
+use crate::channel_survey;
 #[cfg(feature = "wasm-scripting")]
 use crate::config::wasm_script_limits_config_section;
 use crate::config::Action;
@@ -7,12 +10,21 @@ use crate::config::AppConfig;
 use crate::config::ConfigJson;
 use crate::config::SharedConfig;
 use crate::config::SharedConfigJson;
+use crate::config::SharedWifiConfig;
+use crate::config::WifiMode;
 use crate::config::BASE_CONFIG_DIR;
 use crate::crash;
 use crate::device_info;
 use crate::ev::send_ev_data;
 use crate::ev::BatteryData;
 use crate::ev::EV_MODEL_FILE;
+use crate::hfp::{self, HfpCommand};
+use crate::hostapd;
+use crate::known_devices::{self, KnownDeviceUpdate, KnownDeviceView, KNOWN_DEVICES_FILE};
+use crate::media_record;
+use crate::media_stats::collect_video_stats;
+use crate::media_tap::{video_codec_name, video_resolution_dims, SharedMediaSinks};
+use crate::mic_inject::{self, MicInjectRequest};
 use crate::mitm::protos::KeyCode;
 use crate::mitm::send_byebye;
 use crate::mitm::send_input_key;
@@ -24,9 +36,13 @@ use crate::mitm::Result;
 use crate::mitm::SharedServiceDiscoveryResponse;
 use crate::mitm::{send_odometer_data, OdometerData};
 use crate::mitm::{send_tire_pressure_data, TirePressureData};
+use crate::multi_phone;
 #[cfg(feature = "wasm-scripting")]
 use crate::script_wasm::{LoadedScript, ScriptRegistry};
 use crate::sdr_ui;
+use crate::wifi_link;
+use crate::wifi_security;
+use crate::wpa_supplicant;
 #[cfg(not(feature = "wasm-scripting"))]
 type ScriptRegistry = ();
 use axum::{
@@ -139,6 +155,8 @@ pub struct AppState {
     pub last_tire_pressure_data: Arc<RwLock<Option<TirePressureData>>>,
     pub ws_event_tx: broadcast::Sender<ServerEvent>,
     pub script_registry: Option<Arc<ScriptRegistry>>,
+    pub media_sinks: SharedMediaSinks,
+    pub wifi_config: SharedWifiConfig,
 }
 
 pub fn app(state: Arc<AppState>) -> Router {
@@ -189,6 +207,21 @@ pub fn app(state: Arc<AppState>) -> Router {
             "/service-discovery-response",
             get(service_discovery_response_handler),
         )
+        .route(
+            "/audio/inject",
+            get(audio_inject_status_handler).post(audio_inject_handler),
+        )
+        .route("/audio/inject/stop", post(audio_inject_stop_handler))
+        .route(
+            "/audio/mic-inject",
+            get(mic_inject_status_handler).post(mic_inject_handler),
+        )
+        .route("/audio/mic-inject/stop", post(mic_inject_stop_handler))
+        .route("/media/snapshot/:label", get(media_snapshot_handler))
+        .route("/media/stats", get(media_stats_handler))
+        .route("/media/record", get(media_record_list_handler))
+        .route("/media/record/:label", post(media_record_start_handler))
+        .route("/media/record/:label/stop", post(media_record_stop_handler))
         .route("/version", get(version_handler))
         .route("/ws", get(ws_handler))
         .route("/raw-topic-data", post(raw_topic_data_handler))
@@ -197,15 +230,53 @@ pub fn app(state: Arc<AppState>) -> Router {
             "/bt/devices/paired",
             get(bt_helper::bt_paired_devices_handler),
         )
+        .route("/bt/devices/pair", post(bt_helper::bt_pair_device_handler))
         .route(
             "/bt/devices/:id",
-            delete(bt_helper::bt_remove_device_handler),
+            get(bt_helper::bt_device_handler)
+                .patch(bt_helper::bt_update_device_handler)
+                .delete(bt_helper::bt_remove_device_handler),
+        )
+        .route("/bt/discovery", get(bt_helper::bt_discovery_status_handler))
+        .route(
+            "/bt/discovery/start",
+            post(bt_helper::bt_discovery_start_handler),
+        )
+        .route(
+            "/bt/discovery/stop",
+            post(bt_helper::bt_discovery_stop_handler),
         )
         .route(
             "/bt/known-devices",
-            get(bt_known_devices_handler).delete(bt_forget_known_devices_handler),
+            get(bt_known_devices_handler)
+                .post(bt_add_known_device_handler)
+                .delete(bt_forget_known_devices_handler),
         )
+        .route(
+            "/bt/known-devices/:id",
+            get(bt_known_device_handler)
+                .patch(bt_update_known_device_handler)
+                .delete(bt_remove_known_device_handler),
+        )
+        .route("/bt/pairing", get(bt_pairing_list_handler))
+        .route("/bt/pairing/:id", post(bt_pairing_answer_handler))
+        .route("/bt/sco/voice", get(bt_sco_voice_handler))
+        .route("/bt/hfp", get(bt_hfp_status_handler))
+        .route("/bt/hfp/command", post(bt_hfp_command_handler))
+        .route("/wifi/station", get(wifi_station_handler))
+        .route("/wifi/stations", get(wifi_stations_handler))
+        .route("/wifi/link", get(wifi_link_handler))
+        .route("/wifi/link/history", get(wifi_link_history_handler))
+        .route(
+            "/wifi/stations/:mac/deauthenticate",
+            post(wifi_deauthenticate_handler),
+        )
+        .route("/wifi/channel", post(wifi_channel_handler))
+        .route("/wifi/survey", get(wifi_survey_handler))
+        .route("/wifi/credentials", post(wifi_credentials_handler))
         .route("/disconnect", post(disconnect_handler))
+        .route("/phones", get(phones_handler))
+        .route("/phones/switch", post(phone_switch_handler))
         .with_state(state)
 }
 
@@ -706,6 +777,32 @@ async fn disconnect_handler(State(state): State<Arc<AppState>>) -> impl IntoResp
         .unwrap()
 }
 
+async fn phones_handler() -> impl IntoResponse {
+    Json(multi_phone::status())
+}
+
+#[derive(Debug, Deserialize)]
+struct PhoneSwitchRequest {
+    /// Phone from `GET /phones`, the preferred waiting phone when omitted.
+    #[serde(default)]
+    id: Option<i32>,
+}
+
+async fn phone_switch_handler(body: Option<Json<PhoneSwitchRequest>>) -> impl IntoResponse {
+    let target = body.and_then(|Json(req)| req.id);
+    if !multi_phone::request_switch(target) {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({
+                "status": "error",
+                "message": "no such phone is waiting",
+            })),
+        )
+            .into_response();
+    }
+    Json(json!({ "status": "success" })).into_response()
+}
+
 async fn restart_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
     state.config.write().await.action_requested = Some(Action::Reconnect);
 
@@ -1261,14 +1358,207 @@ pub async fn factory_reset_handler(State(state): State<Arc<AppState>>) -> impl I
 }
 
 async fn bt_known_devices_handler() -> impl IntoResponse {
-    let devices: Vec<String> = load_known_devices()
+    let devices: Vec<KnownDeviceView> = known_devices::list()
         .into_iter()
-        .map(|addr| addr.to_string())
+        .map(KnownDeviceView::from)
         .collect();
     Json(devices).into_response()
 }
 
-async fn bt_forget_known_devices_handler() -> impl IntoResponse {
+#[derive(Deserialize)]
+struct AddKnownDeviceRequest {
+    mac: String,
+    #[serde(flatten)]
+    update: KnownDeviceUpdate,
+}
+
+fn known_device_error(status: StatusCode, message: String) -> axum::response::Response {
+    (
+        status,
+        Json(json!({
+            "status": "error",
+            "message": message,
+        })),
+    )
+        .into_response()
+}
+
+fn parse_known_device_mac(
+    mac: &str,
+) -> std::result::Result<bluer::Address, axum::response::Response> {
+    if !bt_helper::validate_bt_mac(mac) {
+        return Err(known_device_error(
+            StatusCode::BAD_REQUEST,
+            format!("Invalid Bluetooth MAC address: {}", mac),
+        ));
+    }
+    mac.parse::<bluer::Address>()
+        .map_err(|e| known_device_error(StatusCode::BAD_REQUEST, e.to_string()))
+}
+
+async fn bt_add_known_device_handler(
+    Json(req): Json<AddKnownDeviceRequest>,
+) -> axum::response::Response {
+    let addr = match parse_known_device_mac(&req.mac) {
+        Ok(addr) => addr,
+        Err(resp) => return resp,
+    };
+    match known_devices::upsert(addr, req.update) {
+        Ok(device) => Json(KnownDeviceView::from(device)).into_response(),
+        Err(e) => known_device_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+async fn bt_known_device_handler(
+    axum::extract::Path(mac): axum::extract::Path<String>,
+) -> axum::response::Response {
+    let addr = match parse_known_device_mac(&mac) {
+        Ok(addr) => addr,
+        Err(resp) => return resp,
+    };
+    match known_devices::get(addr) {
+        Some(device) => Json(KnownDeviceView::from(device)).into_response(),
+        None => known_device_error(
+            StatusCode::NOT_FOUND,
+            format!("{} is not a known device", mac),
+        ),
+    }
+}
+
+async fn bt_update_known_device_handler(
+    axum::extract::Path(mac): axum::extract::Path<String>,
+    Json(update): Json<KnownDeviceUpdate>,
+) -> axum::response::Response {
+    let addr = match parse_known_device_mac(&mac) {
+        Ok(addr) => addr,
+        Err(resp) => return resp,
+    };
+    match known_devices::update(addr, update) {
+        Ok(Some(device)) => Json(KnownDeviceView::from(device)).into_response(),
+        Ok(None) => known_device_error(
+            StatusCode::NOT_FOUND,
+            format!("{} is not a known device", mac),
+        ),
+        Err(e) => known_device_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+async fn bt_remove_known_device_handler(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Path(mac): axum::extract::Path<String>,
+) -> axum::response::Response {
+    let addr = match parse_known_device_mac(&mac) {
+        Ok(addr) => addr,
+        Err(resp) => return resp,
+    };
+    match known_devices::remove(addr) {
+        Ok(true) => {
+            info!("{} 🗑️ Forgot known device {}", NAME, addr);
+            if !state.config.read().await.wifi_per_phone_psk {
+                return Json(json!({ "status": "success" })).into_response();
+            }
+            // unpair first, the next Bluetooth bootstrap would hand out a new
+            // passphrase; then revoke the phone's own Wi-Fi passphrase
+            let unpaired = unpair_then_revoke(
+                unpair_forgotten_phone(addr),
+                wifi_security::revoke_phone(addr),
+            )
+            .await;
+            if let Err(e) = unpaired {
+                return known_device_error(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    format!(
+                        "failed to remove the Bluetooth pairing of {}: {} (Wi-Fi access revoked)",
+                        mac, e
+                    ),
+                );
+            }
+            Json(json!({ "status": "success" })).into_response()
+        }
+        Ok(false) => known_device_error(
+            StatusCode::NOT_FOUND,
+            format!("{} is not a known device", mac),
+        ),
+        Err(e) => known_device_error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+/// Run `unpair`, then `revoke` whatever its outcome: a phone that cannot be
+/// unpaired must not keep its Wi-Fi access.
+async fn unpair_then_revoke<E>(
+    unpair: impl std::future::Future<Output = std::result::Result<(), E>>,
+    revoke: impl std::future::Future<Output = ()>,
+) -> std::result::Result<(), E> {
+    let unpaired = unpair.await;
+    revoke.await;
+    unpaired
+}
+
+/// Remove the Bluetooth pairing of a forgotten phone, if it is still paired.
+async fn unpair_forgotten_phone(addr: bluer::Address) -> bluer::Result<()> {
+    match bt_helper::remove_device(addr).await {
+        Err(e)
+            if matches!(
+                e.kind,
+                bluer::ErrorKind::NotFound | bluer::ErrorKind::DoesNotExist
+            ) =>
+        {
+            Ok(())
+        }
+        result => result,
+    }
+}
+
+async fn bt_sco_voice_handler() -> impl IntoResponse {
+    Json(bt_sco_voice::metrics())
+}
+
+async fn bt_hfp_status_handler() -> impl IntoResponse {
+    Json(hfp::status())
+}
+
+async fn bt_hfp_command_handler(Json(command): Json<HfpCommand>) -> impl IntoResponse {
+    match hfp::send_command(command).await {
+        Ok(()) => Json(json!({ "status": "success" })).into_response(),
+        Err(e) => (
+            StatusCode::CONFLICT,
+            Json(json!({
+                "status": "error",
+                "message": e.to_string(),
+            })),
+        )
+            .into_response(),
+    }
+}
+
+async fn bt_pairing_list_handler() -> impl IntoResponse {
+    Json(bt_pairing::pending())
+}
+
+async fn bt_pairing_answer_handler(
+    axum::extract::Path(id): axum::extract::Path<u64>,
+    Json(answer): Json<PairingAnswer>,
+) -> impl IntoResponse {
+    match bt_pairing::answer(id, answer) {
+        Ok(()) => Json(json!({ "status": "success" })).into_response(),
+        Err(e) => {
+            let status = match e {
+                AnswerError::NotFound => StatusCode::NOT_FOUND,
+                AnswerError::Invalid(_) => StatusCode::BAD_REQUEST,
+            };
+            (
+                status,
+                Json(json!({
+                    "status": "error",
+                    "message": e.to_string(),
+                })),
+            )
+                .into_response()
+        }
+    }
+}
+
+async fn bt_forget_known_devices_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
     let path = std::path::Path::new(KNOWN_DEVICES_FILE);
     if !path.exists() {
         info!(
@@ -1280,9 +1570,27 @@ async fn bt_forget_known_devices_handler() -> impl IntoResponse {
             "No known devices file to remove".to_string(),
         );
     }
+    let forgotten = known_devices::list();
     match fs::remove_file(path).await {
         Ok(_) => {
             info!("{} 🗑️ Known devices file deleted", NAME);
+            if state.config.read().await.wifi_per_phone_psk {
+                // same as forgetting each phone on its own, but a phone that
+                // cannot be unpaired must not keep its Wi-Fi access
+                for addr in forgotten.iter().filter_map(|d| d.address()) {
+                    let unpaired = unpair_then_revoke(
+                        unpair_forgotten_phone(addr),
+                        wifi_security::revoke_phone(addr),
+                    )
+                    .await;
+                    if let Err(e) = unpaired {
+                        warn!(
+                            "{} failed to remove the Bluetooth pairing of {}: {}",
+                            NAME, addr, e
+                        );
+                    }
+                }
+            }
             (StatusCode::OK, "Known devices cleared".to_string())
         }
         Err(e) => (
@@ -1292,6 +1600,223 @@ async fn bt_forget_known_devices_handler() -> impl IntoResponse {
     }
 }
 
+async fn wifi_station_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    let cfg = state.config.read().await.clone();
+    if cfg.wifi_mode != WifiMode::Station {
+        return (
+            StatusCode::CONFLICT,
+            Json(json!({
+                "status": "error",
+                "message": "wifi_mode is not station",
+            })),
+        )
+            .into_response();
+    }
+    match wpa_supplicant::status(&cfg.wpa_supplicant_ctrl_dir, &cfg.iface).await {
+        Ok(status) => Json(status).into_response(),
+        Err(e) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(json!({
+                "status": "error",
+                "message": e.to_string(),
+            })),
+        )
+            .into_response(),
+    }
+}
+
+async fn wifi_link_handler() -> impl IntoResponse {
+    match wifi_link::latest() {
+        Some(sample) => Json(sample).into_response(),
+        None => (StatusCode::NO_CONTENT, "No Wi-Fi link data yet").into_response(),
+    }
+}
+
+async fn wifi_link_history_handler() -> impl IntoResponse {
+    Json(wifi_link::history())
+}
+
+fn wifi_ap_error(status: StatusCode, message: String) -> axum::response::Response {
+    (
+        status,
+        Json(json!({
+            "status": "error",
+            "message": message,
+        })),
+    )
+        .into_response()
+}
+
+/// The hostapd endpoints only make sense while we run the AP.
+async fn require_ap_mode(state: &AppState) -> std::result::Result<(), axum::response::Response> {
+    if state.config.read().await.wifi_mode != WifiMode::Ap {
+        return Err(wifi_ap_error(
+            StatusCode::CONFLICT,
+            "wifi_mode is not ap".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+async fn wifi_stations_handler(State(state): State<Arc<AppState>>) -> axum::response::Response {
+    if let Err(resp) = require_ap_mode(&state).await {
+        return resp;
+    }
+    match hostapd::stations().await {
+        Ok(stations) => Json(stations).into_response(),
+        Err(e) => wifi_ap_error(StatusCode::SERVICE_UNAVAILABLE, e.to_string()),
+    }
+}
+
+async fn wifi_deauthenticate_handler(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Path(mac): axum::extract::Path<String>,
+) -> axum::response::Response {
+    if let Err(resp) = require_ap_mode(&state).await {
+        return resp;
+    }
+    let addr = match mac.parse::<bluer::Address>() {
+        Ok(addr) => addr,
+        Err(_) => {
+            return wifi_ap_error(
+                StatusCode::BAD_REQUEST,
+                format!("invalid MAC address: {}", mac),
+            )
+        }
+    };
+    match hostapd::deauthenticate(&addr.to_string()).await {
+        Ok(()) => {
+            info!("{} 🛜 Deauthenticated Wi-Fi client {}", NAME, addr);
+            Json(json!({ "status": "success" })).into_response()
+        }
+        Err(e) => wifi_ap_error(StatusCode::SERVICE_UNAVAILABLE, e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct ChannelSwitchRequest {
+    channel: u8,
+    /// Channel width in MHz.
+    #[serde(default = "default_channel_bandwidth")]
+    bandwidth: u16,
+    #[serde(default = "default_cs_count")]
+    cs_count: u8,
+}
+
+fn default_channel_bandwidth() -> u16 {
+    20
+}
+
+fn default_cs_count() -> u8 {
+    hostapd::DEFAULT_CS_COUNT
+}
+
+async fn wifi_channel_handler(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<ChannelSwitchRequest>,
+) -> axum::response::Response {
+    if let Err(resp) = require_ap_mode(&state).await {
+        return resp;
+    }
+    let (band, wifi_version) = {
+        let cfg = state.config.read().await;
+        (cfg.band.clone(), cfg.wifi_version)
+    };
+    if let Err(e) = hostapd::chan_switch_command(
+        &band,
+        req.channel,
+        req.bandwidth,
+        wifi_version,
+        req.cs_count,
+    ) {
+        return wifi_ap_error(StatusCode::BAD_REQUEST, e.to_string());
+    }
+    if let Err(e) = hostapd::switch_channel(
+        &band,
+        req.channel,
+        req.bandwidth,
+        wifi_version,
+        req.cs_count,
+    )
+    .await
+    {
+        return wifi_ap_error(StatusCode::SERVICE_UNAVAILABLE, e.to_string());
+    }
+    // keep the channel for the hostapd config generated on the next boot
+    let mut cfg = state.config.write().await;
+    cfg.channel = req.channel;
+    cfg.save((&state.config_file).to_path_buf());
+    Json(json!({ "status": "success" })).into_response()
+}
+
+async fn wifi_survey_handler() -> impl IntoResponse {
+    match channel_survey::last_report() {
+        Some(report) => Json(report).into_response(),
+        None => (StatusCode::NO_CONTENT, "No channel survey was done").into_response(),
+    }
+}
+
+#[derive(Deserialize)]
+struct WifiCredentialsRequest {
+    #[serde(default)]
+    ssid: Option<String>,
+    #[serde(default)]
+    wpa_passphrase: Option<String>,
+}
+
+async fn wifi_credentials_handler(
+    State(state): State<Arc<AppState>>,
+    Json(req): Json<WifiCredentialsRequest>,
+) -> axum::response::Response {
+    if let Err(resp) = require_ap_mode(&state).await {
+        return resp;
+    }
+    if let Some(ssid) = &req.ssid {
+        // the SSID also ends up as a line of the generated hostapd config
+        if ssid.is_empty() || ssid.len() > 32 || ssid.chars().any(char::is_control) {
+            return wifi_ap_error(
+                StatusCode::BAD_REQUEST,
+                "ssid must be 1 to 32 bytes without control characters".to_string(),
+            );
+        }
+    }
+    if let Some(passphrase) = &req.wpa_passphrase {
+        if let Err(e) = wpa_supplicant::validate_passphrase(passphrase) {
+            return wifi_ap_error(StatusCode::BAD_REQUEST, format!("wpa_passphrase: {}", e));
+        }
+    }
+
+    let per_phone_psk = state.config.read().await.wifi_per_phone_psk;
+    // with per-phone passphrases the shared one is not part of the AP config
+    let passphrase = req.wpa_passphrase.as_deref().filter(|_| !per_phone_psk);
+    if let Err(e) = hostapd::reload_credentials(req.ssid.as_deref(), passphrase).await {
+        return wifi_ap_error(StatusCode::SERVICE_UNAVAILABLE, e.to_string());
+    }
+    if per_phone_psk {
+        wifi_security::sync_hostapd_psk();
+    }
+
+    // phones bootstrapped from now on must get the new credentials
+    if let Some(wifi_config) = state.wifi_config.write().await.as_mut() {
+        if let Some(ssid) = &req.ssid {
+            wifi_config.ssid = ssid.clone();
+        }
+        if let Some(passphrase) = passphrase {
+            wifi_config.wpa_key = passphrase.to_string();
+        }
+    }
+
+    let mut cfg = state.config.write().await;
+    if let Some(ssid) = req.ssid {
+        cfg.ssid = ssid;
+    }
+    if let Some(passphrase) = req.wpa_passphrase {
+        cfg.wpa_passphrase = passphrase;
+    }
+    cfg.save((&state.config_file).to_path_buf());
+    Json(json!({ "status": "success" })).into_response()
+}
+
 async fn speed_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
     let data = state.last_speed.read().await;
     if let Some(d) = *data {
@@ -1433,6 +1958,191 @@ async fn service_discovery_response_handler(
     }
 }
 
+async fn audio_inject_status_handler() -> impl IntoResponse {
+    Json(audio_inject::status())
+}
+
+async fn audio_inject_handler(Json(req): Json<InjectRequest>) -> impl IntoResponse {
+    match audio_inject::start(req) {
+        Ok(id) => Json(json!({
+            "status": "success",
+            "id": id,
+        }))
+        .into_response(),
+        Err(e) => (
+            StatusCode::CONFLICT,
+            Json(json!({
+                "status": "error",
+                "message": e.to_string(),
+            })),
+        )
+            .into_response(),
+    }
+}
+
+async fn audio_inject_stop_handler() -> impl IntoResponse {
+    Json(json!({
+        "status": "success",
+        "stopped": audio_inject::stop(),
+    }))
+}
+
+async fn mic_inject_status_handler() -> impl IntoResponse {
+    Json(mic_inject::status())
+}
+
+async fn mic_inject_handler(Json(req): Json<MicInjectRequest>) -> impl IntoResponse {
+    match mic_inject::arm(req) {
+        Ok(()) => Json(json!({ "status": "success" })).into_response(),
+        Err(e) => (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "status": "error",
+                "message": e.to_string(),
+            })),
+        )
+            .into_response(),
+    }
+}
+
+async fn mic_inject_stop_handler() -> impl IntoResponse {
+    Json(json!({
+        "status": "success",
+        "stopped": mic_inject::disarm(),
+    }))
+}
+
+async fn media_stats_handler(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(collect_video_stats(&state.media_sinks).await)
+}
+
+async fn media_snapshot_handler(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Path(label): axum::extract::Path<String>,
+) -> impl IntoResponse {
+    let sink = state.media_sinks.read().await.get(&label).cloned();
+    let Some(sink) = sink else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "error": "media_sink_not_available",
+                "message": format!(
+                    "No media sink named '{}' (media_dump_base_port and mitm must be enabled)",
+                    label
+                )
+            })),
+        )
+            .into_response();
+    };
+
+    let Some(snapshot) = sink.snapshot().await else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "error": "media_snapshot_not_available",
+                "message": format!("No decodable video frame has been received on '{}' yet", label)
+            })),
+        )
+            .into_response();
+    };
+
+    let codec = video_codec_name(snapshot.codec);
+    let filename = format!(
+        "{}-{}-{}.{}",
+        label,
+        Local::now().format("%Y%m%d-%H%M%S"),
+        snapshot.pts_us,
+        codec
+    );
+    let mut builder = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", filename),
+        )
+        .header("X-Media-Codec", codec)
+        .header("X-Media-Pts-Us", snapshot.pts_us.to_string())
+        .header("X-Media-Idr-Pts-Us", snapshot.idr_pts_us.to_string())
+        .header("X-Media-Frames", snapshot.frames.to_string());
+    if let Some(resolution) = snapshot.resolution {
+        let (width, height) = video_resolution_dims(resolution);
+        builder = builder
+            .header("X-Media-Width", width.to_string())
+            .header("X-Media-Height", height.to_string());
+    }
+
+    builder
+        .body(Body::from(snapshot.data))
+        .unwrap()
+        .into_response()
+}
+
+async fn media_record_list_handler() -> impl IntoResponse {
+    let recordings: Vec<Value> = media_record::active_recordings()
+        .into_iter()
+        .map(|(label, path)| json!({ "label": label, "file": path.display().to_string() }))
+        .collect();
+    Json(json!({ "recordings": recordings }))
+}
+
+async fn media_record_start_handler(
+    State(state): State<Arc<AppState>>,
+    axum::extract::Path(label): axum::extract::Path<String>,
+) -> impl IntoResponse {
+    let sink = state.media_sinks.read().await.get(&label).cloned();
+    let Some(sink) = sink else {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "error": "media_sink_not_available",
+                "message": format!(
+                    "No media sink named '{}' (media_dump_base_port and mitm must be enabled)",
+                    label
+                )
+            })),
+        )
+            .into_response();
+    };
+
+    let dir = state.config.read().await.media_record_dir.clone();
+    match media_record::start_wav_recording(&label, sink, &dir).await {
+        Ok(path) => Json(json!({
+            "status": "success",
+            "file": path.display().to_string(),
+        }))
+        .into_response(),
+        Err(e) => (
+            StatusCode::CONFLICT,
+            Json(json!({
+                "status": "error",
+                "message": e.to_string(),
+            })),
+        )
+            .into_response(),
+    }
+}
+
+async fn media_record_stop_handler(
+    axum::extract::Path(label): axum::extract::Path<String>,
+) -> impl IntoResponse {
+    match media_record::stop_recording(&label) {
+        Some(path) => Json(json!({
+            "status": "success",
+            "file": path.display().to_string(),
+        }))
+        .into_response(),
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({
+                "status": "error",
+                "message": format!("'{}' is not being recorded", label),
+            })),
+        )
+            .into_response(),
+    }
+}
+
 pub async fn version_handler() -> impl IntoResponse {
     Json(json!({
         "version": env!("CARGO_PKG_VERSION"),
@@ -1729,6 +2439,16 @@ async fn set_config(
                 .into_response();
         }
     };
+    if let Err(err) = new_cfg.validate() {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({
+                "status": "error",
+                "message": err
+            })),
+        )
+            .into_response();
+    }
 
     {
         crash::set_crash_handler_enabled(new_cfg.crash_handler_enabled);
@@ -1953,3 +2673,20 @@ async fn handle_socket(socket: WebSocket, state: Arc<AppState>) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    #[tokio::test]
+    async fn forgets_a_phone_that_cannot_be_unpaired() {
+        let revoked = AtomicBool::new(false);
+        let unpaired = unpair_then_revoke(async { Err("adapter is gone") }, async {
+            revoked.store(true, Ordering::SeqCst);
+        })
+        .await;
+        assert_eq!(unpaired, Err("adapter is gone"));
+        assert!(revoked.load(Ordering::SeqCst));
+    }
+}
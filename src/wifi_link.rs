@@ -0,0 +1,212 @@
+use crate::hostapd::{self, AssociatedStation};
+use crate::web::ServerEvent;
+use serde::Serialize;
+use simplelog::*;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::Sender as BroadcastSender;
+
+// module name for logging engine
+const NAME: &str = "<i><bright-black> wifi-link: </>";
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+pub const WIFI_TOPIC: &str = "wifi";
+/// Samples kept for the history endpoint, about 10 minutes at 2 s.
+const HISTORY_LEN: usize = 300;
+
+static HISTORY: Mutex<VecDeque<LinkSample>> = Mutex::new(VecDeque::new());
+
+/// Link quality of the phone as seen by our AP (hostapd station info).
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct LinkSample {
+    /// Unix time in milliseconds.
+    pub timestamp_ms: u64,
+    pub mac: String,
+    pub signal_dbm: Option<i32>,
+    pub signal_avg_dbm: Option<i32>,
+    pub tx_bitrate_mbps: Option<f64>,
+    pub rx_bitrate_mbps: Option<f64>,
+    /// Totals since association.
+    pub tx_retries: Option<u64>,
+    pub tx_failed: Option<u64>,
+    /// Increase since the previous sample, what actually hurts the stream.
+    pub tx_retries_delta: Option<u64>,
+    pub tx_failed_delta: Option<u64>,
+    pub inactive_ms: Option<u64>,
+}
+
+impl LinkSample {
+    fn from_station(station: AssociatedStation) -> Self {
+        Self {
+            timestamp_ms: now_ms(),
+            mac: station.mac,
+            signal_dbm: station.signal_dbm,
+            signal_avg_dbm: station.avg_signal_dbm,
+            tx_bitrate_mbps: station.tx_rate_mbps,
+            rx_bitrate_mbps: station.rx_rate_mbps,
+            tx_retries: station.tx_retry_count,
+            tx_failed: station.tx_retry_failed,
+            tx_retries_delta: None,
+            tx_failed_delta: None,
+            inactive_ms: station.inactive_ms,
+        }
+    }
+
+    /// Fill in the deltas from the previous sample of the same station.
+    fn set_deltas(&mut self, previous: Option<&LinkSample>) {
+        let previous = previous.filter(|p| p.mac == self.mac);
+        let delta = |now: Option<u64>, before: Option<u64>| match (now, before) {
+            // counters restart after a reassociation, all of `now` is new
+            (Some(now), Some(before)) if now < before => Some(now),
+            (Some(now), Some(before)) => Some(now - before),
+            _ => None,
+        };
+        self.tx_retries_delta = delta(self.tx_retries, previous.and_then(|p| p.tx_retries));
+        self.tx_failed_delta = delta(self.tx_failed, previous.and_then(|p| p.tx_failed));
+    }
+
+    /// One-line summary for the log, next to the transfer statistics.
+    pub fn summary(&self) -> String {
+        let value = |v: Option<String>| v.unwrap_or_else(|| "?".to_string());
+        format!(
+            "{} dBm, tx {} / rx {} Mbit/s, retries +{}, failed +{}",
+            value(self.signal_dbm.map(|v| v.to_string())),
+            value(self.tx_bitrate_mbps.map(|v| format!("{:.1}", v))),
+            value(self.rx_bitrate_mbps.map(|v| format!("{:.1}", v))),
+            value(self.tx_retries_delta.map(|v| v.to_string())),
+            value(self.tx_failed_delta.map(|v| v.to_string())),
+        )
+    }
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+async fn station_info(mac: &str) -> Result<LinkSample> {
+    let station = hostapd::station(mac)
+        .await?
+        .ok_or_else(|| format!("{} is not associated", mac))?;
+    Ok(LinkSample::from_station(station))
+}
+
+/// Stored samples, oldest first.
+pub fn history() -> Vec<LinkSample> {
+    HISTORY.lock().unwrap().iter().cloned().collect()
+}
+
+pub fn latest() -> Option<LinkSample> {
+    HISTORY.lock().unwrap().back().cloned()
+}
+
+/// Latest sample if it was taken within `max_age`, i.e. belongs to the
+/// running session.
+pub fn latest_within(max_age: Duration) -> Option<LinkSample> {
+    latest().filter(|s| now_ms().saturating_sub(s.timestamp_ms) <= max_age.as_millis() as u64)
+}
+
+fn record(mut sample: LinkSample) -> LinkSample {
+    let mut history = HISTORY.lock().unwrap();
+    sample.set_deltas(history.back());
+    if history.len() == HISTORY_LEN {
+        history.pop_front();
+    }
+    history.push_back(sample.clone());
+    sample
+}
+
+/// Sample the phone's link every `interval` for the whole session. Runs until
+/// aborted together with the other session tasks.
+pub async fn monitor(mac: String, interval: Duration, ws_event_tx: BroadcastSender<ServerEvent>) {
+    info!(
+        "{} 📶 monitoring Wi-Fi link of {} every {}s",
+        NAME,
+        mac,
+        interval.as_secs()
+    );
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+    let mut failing = false;
+    loop {
+        ticker.tick().await;
+        let sample = match station_info(&mac).await {
+            Ok(sample) => {
+                failing = false;
+                record(sample)
+            }
+            Err(e) => {
+                // log once, the phone may just be roaming or reconnecting
+                if !failing {
+                    warn!("{} no station info for {}: {}", NAME, mac, e);
+                    failing = true;
+                }
+                continue;
+            }
+        };
+        match serde_json::to_string(&sample) {
+            Ok(payload) => {
+                let _ = ws_event_tx.send(ServerEvent {
+                    topic: WIFI_TOPIC.to_string(),
+                    payload,
+                });
+            }
+            Err(e) => warn!("{} failed to serialize sample: {}", NAME, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn station() -> AssociatedStation {
+        AssociatedStation {
+            mac: "12:34:56:78:9a:bc".to_string(),
+            signal_dbm: Some(-47),
+            tx_rate_mbps: Some(866.7),
+            rx_rate_mbps: Some(6.0),
+            inactive_ms: Some(304),
+            tx_retry_count: Some(12),
+            tx_retry_failed: Some(1),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn samples_hostapd_station_info() {
+        let sample = LinkSample::from_station(station());
+        assert_eq!(sample.mac, "12:34:56:78:9a:bc");
+        assert_eq!(sample.signal_dbm, Some(-47));
+        assert_eq!(sample.signal_avg_dbm, None);
+        assert_eq!(sample.tx_bitrate_mbps, Some(866.7));
+        assert_eq!(sample.rx_bitrate_mbps, Some(6.0));
+        assert_eq!(sample.tx_retries, Some(12));
+        assert_eq!(sample.tx_failed, Some(1));
+        assert_eq!(sample.inactive_ms, Some(304));
+    }
+
+    #[test]
+    fn deltas_only_against_same_station() {
+        let previous = LinkSample::from_station(station());
+        let mut sample = previous.clone();
+        sample.tx_retries = Some(20);
+        sample.tx_failed = Some(0);
+        sample.set_deltas(Some(&previous));
+        assert_eq!(sample.tx_retries_delta, Some(8));
+        assert_eq!(sample.tx_failed_delta, Some(0));
+
+        // reassociated, the counters started over
+        sample.tx_retries = Some(5);
+        sample.set_deltas(Some(&previous));
+        assert_eq!(sample.tx_retries_delta, Some(5));
+
+        sample.mac = "aa:bb:cc:dd:ee:ff".to_string();
+        sample.set_deltas(Some(&previous));
+        assert_eq!(sample.tx_retries_delta, None);
+    }
+}
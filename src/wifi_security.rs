@@ -0,0 +1,205 @@
+use crate::config::WifiSecurity;
+use crate::hostapd;
+use crate::known_devices::{self, KnownDevice};
+use bluer::Address;
+use simplelog::*;
+use std::io::Read;
+
+// module name for logging engine
+const NAME: &str = "<i><bright-black> wifi-security: </>";
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+/// Per-phone passphrases read by hostapd (`wpa_psk_file`).
+pub const WPA_PSK_FILE: &str = "/var/run/hostapd.wpa_psk";
+/// 20 characters from a 32 symbol alphabet, 100 bits of entropy.
+const PASSPHRASE_LEN: usize = 20;
+const PASSPHRASE_ALPHABET: &[u8] = b"abcdefghijkmnpqrstuvwxyz23456789";
+
+/// Security actually used for the AP. `wpa_psk_file` only applies to WPA-PSK,
+/// with SAE the shared passphrase would still let a revoked phone in.
+pub fn effective_security(security: WifiSecurity, per_phone_psk: bool) -> WifiSecurity {
+    if per_phone_psk && security != WifiSecurity::Wpa2 {
+        warn!(
+            "{} wifi_per_phone_psk requires WPA2-PSK, ignoring wifi_security = {}",
+            NAME, security
+        );
+        return WifiSecurity::Wpa2;
+    }
+    security
+}
+
+/// hostapd options for `security`. `None` removes the option from the
+/// template, e.g. the shared passphrase when per-phone passphrases are used.
+pub fn hostapd_options(
+    security: WifiSecurity,
+    per_phone_psk: bool,
+) -> Vec<(&'static str, Option<String>)> {
+    let security = effective_security(security, per_phone_psk);
+    let (key_mgmt, ieee80211w) = match security {
+        WifiSecurity::Wpa2 => ("WPA-PSK", "0"),
+        WifiSecurity::Transition => ("WPA-PSK SAE", "1"),
+        WifiSecurity::Wpa3 => ("SAE", "2"),
+    };
+    let mut options = vec![
+        ("wpa", Some("2".to_string())),
+        ("wpa_key_mgmt", Some(key_mgmt.to_string())),
+        ("rsn_pairwise", Some("CCMP".to_string())),
+        ("ieee80211w", Some(ieee80211w.to_string())),
+    ];
+    if security != WifiSecurity::Wpa2 {
+        // allow both hunting-and-pecking and hash-to-element
+        options.push(("sae_pwe", Some("2".to_string())));
+        options.push(("sae_require_mfp", Some("1".to_string())));
+    }
+    if per_phone_psk {
+        options.push(("wpa_passphrase", None));
+        options.push(("wpa_psk_file", Some(WPA_PSK_FILE.to_string())));
+    }
+    options
+}
+
+/// Replace `key=...` lines of a hostapd config, append missing ones and drop
+/// options set to `None`.
+pub fn apply_hostapd_options(conf: &str, options: &[(&str, Option<String>)]) -> String {
+    let mut output = String::with_capacity(conf.len());
+    let mut written: Vec<&str> = Vec::new();
+    for line in conf.lines() {
+        let key = line.split('=').next().unwrap_or_default().trim();
+        match options.iter().find(|(k, _)| *k == key) {
+            Some((k, value)) => {
+                if let (Some(value), false) = (value, written.contains(k)) {
+                    output.push_str(&format!("{}={}\n", k, value));
+                }
+                written.push(*k);
+            }
+            None => {
+                output.push_str(line);
+                output.push('\n');
+            }
+        }
+    }
+    for (key, value) in options {
+        if let (Some(value), false) = (value, written.contains(key)) {
+            output.push_str(&format!("{}={}\n", key, value));
+        }
+    }
+    output
+}
+
+pub fn generate_passphrase() -> std::io::Result<String> {
+    let mut random = [0u8; PASSPHRASE_LEN];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut random)?;
+    Ok(random
+        .iter()
+        .map(|b| PASSPHRASE_ALPHABET[*b as usize % PASSPHRASE_ALPHABET.len()] as char)
+        .collect())
+}
+
+/// `wpa_psk_file` key id of a phone: its Bluetooth address without colons.
+pub(crate) fn psk_keyid(mac: &str) -> String {
+    mac.replace(':', "").to_uppercase()
+}
+
+/// One line per phone. The station address is a wildcard because phones
+/// randomize their Wi-Fi MAC; the Bluetooth address only serves as key id.
+fn render_psk_file(devices: &[KnownDevice]) -> String {
+    let mut output = String::from("# generated by aa-proxy-rs from known devices\n");
+    for device in devices {
+        if let Some(psk) = &device.wifi_psk {
+            output.push_str(&format!(
+                "keyid={} 00:00:00:00:00:00 {}\n",
+                psk_keyid(&device.mac),
+                psk
+            ));
+        }
+    }
+    output
+}
+
+/// Write the passphrases of all known phones for hostapd.
+pub fn write_psk_file() -> std::io::Result<()> {
+    let devices = known_devices::list();
+    std::fs::write(WPA_PSK_FILE, render_psk_file(&devices))
+}
+
+/// Rewrite the passphrase file and make the running hostapd re-read it.
+/// Associated stations are not touched, see [`revoke_phone`].
+pub fn sync_hostapd_psk() {
+    if let Err(e) = write_psk_file() {
+        warn!("{} failed to write {}: {}", NAME, WPA_PSK_FILE, e);
+        return;
+    }
+    tokio::spawn(async {
+        match hostapd::reload_wpa_psk().await {
+            Ok(()) => debug!("{} hostapd reloaded {}", NAME, WPA_PSK_FILE),
+            Err(e) => warn!("{} hostapd RELOAD_WPA_PSK failed: {}", NAME, e),
+        }
+    });
+}
+
+/// Revoke the Wi-Fi access of a phone that was removed from the known
+/// devices: drop its passphrase from hostapd and kick the station that
+/// joined with it. The caller removes the Bluetooth pairing first, otherwise
+/// the next bootstrap would hand out a new passphrase.
+pub async fn revoke_phone(addr: Address) {
+    if let Err(e) = write_psk_file() {
+        warn!("{} failed to write {}: {}", NAME, WPA_PSK_FILE, e);
+        return;
+    }
+    if let Err(e) = hostapd::reload_wpa_psk().await {
+        warn!("{} hostapd RELOAD_WPA_PSK failed: {}", NAME, e);
+        return;
+    }
+    match hostapd::deauthenticate_keyid(&psk_keyid(&addr.to_string())).await {
+        Ok(0) => info!("{} 🔐 revoked Wi-Fi passphrase of {}", NAME, addr),
+        Ok(n) => info!(
+            "{} 🔐 revoked Wi-Fi passphrase of {}, disconnected {} station(s)",
+            NAME, addr, n
+        ),
+        Err(e) => warn!("{} failed to disconnect {}: {}", NAME, addr, e),
+    }
+}
+
+/// Passphrase handed to `addr` in the Wi-Fi handshake. A new phone is added
+/// to the known devices with a fresh passphrase and hostapd is reloaded.
+pub fn phone_passphrase(addr: Address) -> Result<String> {
+    let (psk, created) = known_devices::ensure_wifi_psk(addr, generate_passphrase)?;
+    if created {
+        info!("{} 🔐 generated Wi-Fi passphrase for {}", NAME, addr);
+        sync_hostapd_psk();
+    }
+    Ok(psk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn options_replace_template_lines() {
+        let template = "ssid=aa-proxy\nwpa=2\nwpa_key_mgmt=WPA-PSK\nwpa_passphrase=secret\n";
+        let conf =
+            apply_hostapd_options(template, &hostapd_options(WifiSecurity::Transition, false));
+        assert!(conf.contains("wpa_key_mgmt=WPA-PSK SAE\n"));
+        assert!(conf.contains("ieee80211w=1\n"));
+        assert!(conf.contains("wpa_passphrase=secret\n"));
+        assert_eq!(conf.matches("wpa=").count(), 1);
+
+        // per-phone passphrases drop the shared one and force WPA2-PSK
+        let conf = apply_hostapd_options(template, &hostapd_options(WifiSecurity::Wpa3, true));
+        assert!(!conf.contains("wpa_passphrase"));
+        assert!(conf.contains("wpa_key_mgmt=WPA-PSK\n"));
+        assert!(conf.contains(&format!("wpa_psk_file={}\n", WPA_PSK_FILE)));
+    }
+
+    #[test]
+    fn psk_file_lists_phones_with_a_passphrase() {
+        let mut with_psk = KnownDevice::new("AA:BB:CC:DD:EE:01".parse().unwrap());
+        with_psk.wifi_psk = Some("abc23".to_string());
+        let without = KnownDevice::new("AA:BB:CC:DD:EE:02".parse().unwrap());
+        let file = render_psk_file(&[with_psk, without]);
+        let lines: Vec<&str> = file.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(lines, ["keyid=AABBCCDDEE01 00:00:00:00:00:00 abc23"]);
+    }
+}
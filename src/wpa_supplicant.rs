@@ -0,0 +1,328 @@
+use crate::config::WifiSecurity;
+use serde::Serialize;
+use simplelog::*;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+use tokio::net::UnixDatagram;
+use tokio::time::timeout;
+
+// module name for logging engine
+const NAME: &str = "<i><bright-black> wpa_supplicant: </>";
+
+type Result<T> = std::result::Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const REPLY_BUF_LEN: usize = 4096;
+/// `id_str` of the network we add, other configured networks are left alone.
+const NETWORK_ID_STR: &str = "aa-proxy";
+
+static CLIENT_ID: AtomicU32 = AtomicU32::new(0);
+
+/// Client end of a wpa_supplicant or hostapd control interface
+/// (`ctrl_interface`), both speak the same protocol.
+pub struct WpaCtrl {
+    sock: UnixDatagram,
+    local: PathBuf,
+}
+
+impl WpaCtrl {
+    /// Connect to `<ctrl_dir>/<iface>`.
+    pub fn open(ctrl_dir: &str, iface: &str) -> Result<Self> {
+        let local = PathBuf::from(format!(
+            "/tmp/aa-proxy-wpa-{}-{}",
+            std::process::id(),
+            CLIENT_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        let _ = std::fs::remove_file(&local);
+        let sock = UnixDatagram::bind(&local)?;
+        let remote = Path::new(ctrl_dir).join(iface);
+        if let Err(e) = sock.connect(&remote) {
+            let _ = std::fs::remove_file(&local);
+            return Err(format!("cannot connect to {}: {}", remote.display(), e).into());
+        }
+        Ok(Self { sock, local })
+    }
+
+    /// Send a command and return its reply.
+    pub async fn request(&self, cmd: &str) -> Result<String> {
+        self.sock.send(cmd.as_bytes()).await?;
+        let mut buf = vec![0u8; REPLY_BUF_LEN];
+        loop {
+            let n = timeout(REPLY_TIMEOUT, self.sock.recv(&mut buf))
+                .await
+                .map_err(|_| format!("no reply to {}", command_name(cmd)))??;
+            let reply = String::from_utf8_lossy(&buf[..n]).to_string();
+            // unsolicited event messages start with "<level>"
+            if reply.starts_with('<') {
+                continue;
+            }
+            return Ok(reply);
+        }
+    }
+
+    /// Send a command that answers with `OK`.
+    pub async fn ok(&self, cmd: &str) -> Result<()> {
+        let reply = self.request(cmd).await?;
+        if reply.trim() == "OK" {
+            Ok(())
+        } else {
+            Err(format!("{} failed: {}", command_name(cmd), reply.trim()).into())
+        }
+    }
+
+    pub async fn status(&self) -> Result<StationStatus> {
+        Ok(StationStatus::parse(&self.request("STATUS").await?))
+    }
+}
+
+impl Drop for WpaCtrl {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.local);
+    }
+}
+
+/// Command without arguments, so passphrases do not end up in logs.
+fn command_name(cmd: &str) -> &str {
+    cmd.split_whitespace().next().unwrap_or(cmd)
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct StationStatus {
+    pub wpa_state: String,
+    pub ssid: Option<String>,
+    pub bssid: Option<String>,
+    pub ip_address: Option<String>,
+    pub freq: Option<u32>,
+    pub key_mgmt: Option<String>,
+}
+
+impl StationStatus {
+    fn parse(reply: &str) -> Self {
+        let fields: HashMap<&str, &str> = reply
+            .lines()
+            .filter_map(|line| line.split_once('='))
+            .collect();
+        let get = |key: &str| fields.get(key).map(|v| v.to_string());
+        Self {
+            wpa_state: get("wpa_state").unwrap_or_default(),
+            ssid: get("ssid"),
+            bssid: get("bssid"),
+            ip_address: get("ip_address"),
+            freq: fields.get("freq").and_then(|f| f.parse().ok()),
+            key_mgmt: get("key_mgmt"),
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.wpa_state == "COMPLETED" && self.bssid.is_some()
+    }
+
+    /// Security of the joined network, `None` for an open network.
+    pub fn security(&self) -> Option<WifiSecurity> {
+        let key_mgmt = self.key_mgmt.as_deref().unwrap_or_default();
+        if key_mgmt.is_empty() || key_mgmt == "NONE" {
+            return None;
+        }
+        if key_mgmt.contains("SAE") {
+            Some(WifiSecurity::Wpa3)
+        } else {
+            Some(WifiSecurity::Wpa2)
+        }
+    }
+}
+
+/// Network to join in station mode.
+#[derive(Debug, Clone)]
+pub struct StationOptions {
+    pub ctrl_dir: String,
+    pub iface: String,
+    pub ssid: String,
+    pub passphrase: String,
+    pub timeout: Duration,
+}
+
+/// Replace the network we added before with `options.ssid`, prefer it and
+/// wait until it is associated and has an IPv4 address. Networks configured
+/// by the user stay enabled, ours only gets the highest priority.
+pub async fn connect(options: &StationOptions) -> Result<StationStatus> {
+    let psk = match options.passphrase.as_str() {
+        "" => None,
+        passphrase => Some(psk_value(passphrase)?),
+    };
+    let ctrl = WpaCtrl::open(&options.ctrl_dir, &options.iface)?;
+    if ctrl.request("PING").await?.trim() != "PONG" {
+        return Err("unexpected reply to PING".into());
+    }
+
+    let current = ctrl.status().await?;
+    if current.is_connected()
+        && current.ssid.as_deref() == Some(options.ssid.as_str())
+        && current.ip_address.is_some()
+    {
+        info!(
+            "{} 🛜 already connected to <b>{}</> ({})",
+            NAME,
+            options.ssid,
+            current.bssid.as_deref().unwrap_or_default()
+        );
+        return Ok(current);
+    }
+
+    info!("{} 🛜 joining <b>{}</>", NAME, options.ssid);
+    remove_own_networks(&ctrl).await?;
+    let priority = max_priority(&ctrl).await? + 1;
+    let id = ctrl.request("ADD_NETWORK").await?.trim().to_string();
+    if id.parse::<u32>().is_err() {
+        return Err(format!("ADD_NETWORK failed: {}", id).into());
+    }
+    ctrl.ok(&format!("SET_NETWORK {} id_str \"{}\"", id, NETWORK_ID_STR))
+        .await?;
+    // hex form avoids quoting issues with arbitrary SSIDs
+    ctrl.ok(&format!(
+        "SET_NETWORK {} ssid {}",
+        id,
+        hex::encode(options.ssid.as_bytes())
+    ))
+    .await?;
+    if let Some(psk) = psk {
+        ctrl.ok(&format!("SET_NETWORK {} psk {}", id, psk)).await?;
+        // SAE when the AP offers it, optional management frame protection
+        // keeps WPA2-only APs working
+        ctrl.ok(&format!("SET_NETWORK {} key_mgmt WPA-PSK SAE", id))
+            .await?;
+        ctrl.ok(&format!("SET_NETWORK {} ieee80211w 1", id)).await?;
+    } else {
+        ctrl.ok(&format!("SET_NETWORK {} key_mgmt NONE", id))
+            .await?;
+    }
+    ctrl.ok(&format!("SET_NETWORK {} priority {}", id, priority))
+        .await?;
+    ctrl.ok(&format!("ENABLE_NETWORK {}", id)).await?;
+    ctrl.ok("REASSOCIATE").await?;
+
+    wait_connected(&ctrl, options.timeout).await
+}
+
+/// WPA passphrases are 8 to 63 printable ASCII characters, anything else
+/// would break the control interface command or config line it ends up in.
+pub(crate) fn validate_passphrase(passphrase: &str) -> Result<()> {
+    if !(8..=63).contains(&passphrase.len())
+        || !passphrase.chars().all(|c| (' '..='~').contains(&c))
+    {
+        return Err("passphrase must be 8 to 63 printable ASCII characters".into());
+    }
+    Ok(())
+}
+
+/// Quoted `psk` value for `passphrase`.
+fn psk_value(passphrase: &str) -> Result<String> {
+    validate_passphrase(passphrase)?;
+    Ok(format!("\"{}\"", passphrase))
+}
+
+/// Ids of the configured networks.
+async fn network_ids(ctrl: &WpaCtrl) -> Result<Vec<String>> {
+    let list = ctrl.request("LIST_NETWORKS").await?;
+    // a header line, then "id\tssid\tbssid\tflags" rows
+    Ok(list
+        .lines()
+        .skip(1)
+        .filter_map(|l| l.split('\t').next())
+        .map(str::to_string)
+        .collect())
+}
+
+/// Highest priority of the configured networks, 0 without any.
+async fn max_priority(ctrl: &WpaCtrl) -> Result<i32> {
+    let mut max = 0;
+    for id in network_ids(ctrl).await? {
+        let priority = ctrl
+            .request(&format!("GET_NETWORK {} priority", id))
+            .await?;
+        max = max.max(priority.trim().parse().unwrap_or(0));
+    }
+    Ok(max)
+}
+
+/// Remove the networks added by an earlier [`connect`].
+async fn remove_own_networks(ctrl: &WpaCtrl) -> Result<()> {
+    for id in network_ids(ctrl).await? {
+        let id_str = ctrl.request(&format!("GET_NETWORK {} id_str", id)).await?;
+        if id_str.trim().trim_matches('"') == NETWORK_ID_STR {
+            ctrl.ok(&format!("REMOVE_NETWORK {}", id)).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn wait_connected(ctrl: &WpaCtrl, limit: Duration) -> Result<StationStatus> {
+    let started = Instant::now();
+    let mut last_state = String::new();
+    loop {
+        let status = ctrl.status().await?;
+        if status.wpa_state != last_state {
+            debug!("{} state: {}", NAME, status.wpa_state);
+            last_state = status.wpa_state.clone();
+        }
+        if status.is_connected() && status.ip_address.is_some() {
+            info!(
+                "{} 🛜 connected to <b>{}</> bssid {} ip {}",
+                NAME,
+                status.ssid.as_deref().unwrap_or_default(),
+                status.bssid.as_deref().unwrap_or_default(),
+                status.ip_address.as_deref().unwrap_or_default()
+            );
+            return Ok(status);
+        }
+        if started.elapsed() >= limit {
+            return Err(format!(
+                "not connected after {}s (state {})",
+                limit.as_secs(),
+                status.wpa_state
+            )
+            .into());
+        }
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Current station status, e.g. to pick up a new BSSID after roaming.
+pub async fn status(ctrl_dir: &str, iface: &str) -> Result<StationStatus> {
+    WpaCtrl::open(ctrl_dir, iface)?.status().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_status_reply() {
+        let status = StationStatus::parse(
+            "bssid=00:11:22:33:44:55\nfreq=5180\nssid=lab\nid=0\nmode=station\n\
+             pairwise_cipher=CCMP\nkey_mgmt=SAE\nwpa_state=COMPLETED\n\
+             ip_address=192.168.1.23\naddress=aa:bb:cc:dd:ee:ff\n",
+        );
+        assert!(status.is_connected());
+        assert_eq!(status.ip_address.as_deref(), Some("192.168.1.23"));
+        assert_eq!(status.freq, Some(5180));
+        assert_eq!(status.security(), Some(WifiSecurity::Wpa3));
+
+        let scanning = StationStatus::parse("wpa_state=SCANNING\naddress=aa:bb:cc:dd:ee:ff\n");
+        assert!(!scanning.is_connected());
+        assert_eq!(scanning.security(), None);
+    }
+
+    #[test]
+    fn validates_passphrase() {
+        assert_eq!(psk_value("secret \"pw\"").unwrap(), "\"secret \"pw\"\"");
+        assert!(psk_value(&"x".repeat(63)).is_ok());
+
+        assert!(psk_value("short").is_err());
+        assert!(psk_value(&"x".repeat(64)).is_err());
+        assert!(psk_value("new\nline pw").is_err());
+        assert!(psk_value("pässwörter").is_err());
+    }
+}